@@ -24,5 +24,359 @@ fn bitmap(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bitmap);
+/// Apply a random workload of allocations and frees of orders 0 to 3, after filling the tree to
+/// about half full, so that every descent goes all the way down through fragmented levels. Frees
+/// are as likely as allocations, so the tree stays about half full.
+fn bitmap_steady_state(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_bitmap::*;
+    use buddy_allocator_workshop::workload::{Op, Workload, WorkloadSpec};
+
+    let mut tree = Tree::new();
+    let fill = WorkloadSpec {
+        free_ratio: 0.0,
+        ..WorkloadSpec::uniform(40_000, 3, 0x2545_f491_4f6c_dd1d)
+    };
+    let mut live: Vec<(*const u8, u8)> = Workload::new(&fill)
+        .map(|op| match op {
+            Op::Alloc(order) => (tree.alloc_exact(order).unwrap(), order),
+            Op::Free(_) => unreachable!(),
+        })
+        .collect();
+
+    let steady = WorkloadSpec {
+        free_ratio: 0.5,
+        ..WorkloadSpec::uniform(usize::max_value(), 3, 474)
+    };
+    let mut ops = Workload::new(&steady);
+
+    c.bench_function("bitmap steady state free and allocate", move |b| {
+        b.iter(|| match ops.next().unwrap() {
+            Op::Alloc(order) => {
+                if let Some(addr) = tree.alloc_exact(order) {
+                    live.push((addr, order));
+                }
+            }
+            Op::Free(nth) if !live.is_empty() => {
+                let (addr, order) = live.swap_remove(nth % live.len());
+                assert!(tree.dealloc_exact(addr, order));
+            }
+            Op::Free(_) => {}
+        });
+    });
+}
+
+/// Allocate blocks of order 0 from a tree in which a random half of the blocks of order 0 are used,
+/// so that which child each descent moves to cannot be predicted. Each allocation is paired with
+/// freeing a random used block so that the tree stays half full.
+fn bitmap_fragmented(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_bitmap::*;
+    use buddy_allocator_workshop::testing::XorShift;
+
+    let mut tree = Tree::new();
+    let mut rng = XorShift::new(457);
+    let mut live: Vec<*const u8> = Vec::new();
+    while let Some(addr) = tree.alloc_exact(0) {
+        live.push(addr);
+    }
+
+    for _ in 0..live.len() / 2 {
+        let addr = live.swap_remove(rng.below(live.len() as u64) as usize);
+        assert!(tree.dealloc_exact(addr, 0));
+    }
+
+    c.bench_function("bitmap fragmented allocate_exact", move |b| {
+        b.iter(|| {
+            let addr = tree.alloc_exact(0).unwrap();
+            let index = rng.below(live.len() as u64) as usize;
+            assert!(tree.dealloc_exact(live[index], 0));
+            live[index] = addr;
+        });
+    });
+}
+
+/// Allocate from and free to a random one of 64 half full trees for every iteration. The trees'
+/// blocks take 32 MiB together, so most descents begin with the lower levels out of the cache,
+/// which is where the `prefetch` feature should help.
+fn bitmap_cold_cache(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_bitmap::*;
+    use buddy_allocator_workshop::testing::XorShift;
+    use buddy_allocator_workshop::MAX_ORDER_SIZE;
+
+    let mut rng = XorShift::new(458);
+    let mut trees: Vec<(Tree, Vec<*const u8>)> = (0..64)
+        .map(|tree| {
+            let mut tree = Tree::new_at(tree << MAX_ORDER_SIZE);
+            let mut live = Vec::new();
+            while let Some(addr) = tree.alloc_exact(0) {
+                live.push(addr);
+            }
+
+            for _ in 0..live.len() / 2 {
+                let addr = live.swap_remove(rng.below(live.len() as u64) as usize);
+                assert!(tree.dealloc_exact(addr, 0));
+            }
+
+            (tree, live)
+        })
+        .collect();
+
+    c.bench_function("bitmap cold cache allocate_exact", move |b| {
+        b.iter(|| {
+            let tree_count = trees.len() as u64;
+            let (ref mut tree, ref mut live) = trees[rng.below(tree_count) as usize];
+            let addr = tree.alloc_exact(0).unwrap();
+            let index = rng.below(live.len() as u64) as usize;
+            assert!(tree.dealloc_exact(live[index], 0));
+            live[index] = addr;
+        });
+    });
+}
+
+/// Free a random live block of order 0 and allocate another, with half of the tree used at
+/// random. Run with and without the `leaf_bitmap` feature to compare the fast path for order 0
+/// against the descent.
+fn bitmap_order_0_steady_state(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_bitmap::*;
+    use buddy_allocator_workshop::testing::XorShift;
+
+    let mut tree = Tree::new();
+    let mut rng = XorShift::new(461);
+    let mut live = Vec::new();
+    while let Some(addr) = tree.alloc_exact(0) {
+        live.push(addr);
+    }
+    for _ in 0..live.len() / 2 {
+        let addr = live.swap_remove(rng.below(live.len() as u64) as usize);
+        assert!(tree.dealloc_exact(addr, 0));
+    }
+
+    c.bench_function("bitmap order 0 steady state free and allocate", move |b| {
+        b.iter(|| {
+            let index = rng.below(live.len() as u64) as usize;
+            assert!(tree.dealloc_exact(live[index], 0));
+            live[index] = tree.alloc_exact(0).unwrap();
+        });
+    });
+}
+
+/// Free and allocate blocks of order 0 in a half full tree, as the order 0 steady state bench
+/// does, under a name which says how the blocks are stored. Running it with and without the
+/// `packed_bitmap` feature keeps both results, so that packing two blocks into each byte can be
+/// weighed against the shifting and masking it costs.
+fn bitmap_block_layout(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_bitmap::*;
+    use buddy_allocator_workshop::testing::XorShift;
+
+    let mut tree = Tree::new();
+    let mut rng = XorShift::new(522);
+    let mut live = Vec::new();
+    while let Some(addr) = tree.alloc_exact(0) {
+        live.push(addr);
+    }
+    for _ in 0..live.len() / 2 {
+        let addr = live.swap_remove(rng.below(live.len() as u64) as usize);
+        assert!(tree.dealloc_exact(addr, 0));
+    }
+
+    let name = if cfg!(feature = "packed_bitmap") {
+        "bitmap packed blocks free and allocate"
+    } else {
+        "bitmap byte blocks free and allocate"
+    };
+    c.bench_function(name, move |b| {
+        b.iter(|| {
+            let index = rng.below(live.len() as u64) as usize;
+            assert!(tree.dealloc_exact(live[index], 0));
+            live[index] = tree.alloc_exact(0).unwrap();
+        });
+    });
+}
+
+/// Allocate a block of order 0 from a fresh tree and free it again. With the `paranoid` feature
+/// the tree is checked against its sealed copy and sealed again by both calls, so comparing this
+/// with and without the feature measures what sealing costs.
+fn bitmap_allocate_free(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_bitmap::*;
+
+    let mut tree = Tree::new();
+
+    c.bench_function("bitmap allocate and free", move |b| {
+        b.iter(|| {
+            let addr = tree.alloc_exact(0).unwrap();
+            assert!(tree.dealloc_exact(addr, 0));
+        });
+    });
+}
+
+/// Free a random live block of order 0 and allocate another, as the order 0 steady state bench
+/// does, in trees of a sixteenth of the full size up to the full size, to see how the depth of
+/// the tree affects each descent.
+fn bitmap_levels(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_bitmap::*;
+    use buddy_allocator_workshop::testing::XorShift;
+    use buddy_allocator_workshop::LEVEL_COUNT;
+
+    c.bench_function_over_inputs(
+        "bitmap order 0 steady state by levels",
+        |b, &levels| {
+            let mut tree = Tree::with_levels(levels);
+            let mut rng = XorShift::new(506);
+            let mut live = Vec::new();
+            while let Some(addr) = tree.alloc_exact(0) {
+                live.push(addr);
+            }
+            for _ in 0..live.len() / 2 {
+                let addr = live.swap_remove(rng.below(live.len() as u64) as usize);
+                assert!(tree.dealloc_exact(addr, 0));
+            }
+
+            b.iter(|| {
+                let index = rng.below(live.len() as u64) as usize;
+                assert!(tree.dealloc_exact(live[index], 0));
+                live[index] = tree.alloc_exact(0).unwrap();
+            });
+        },
+        vec![LEVEL_COUNT - 4, LEVEL_COUNT - 1, LEVEL_COUNT],
+    );
+}
+
+/// Create a full size tree, which fills every level of its blocks with their free order. Filling
+/// each level as one slice rather than a block at a time took this from about 590 µs to 26 µs.
+fn tree_new(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_bitmap::*;
+
+    c.bench_function("tree_new", |b| b.iter(Tree::new));
+}
+
+/// Create a tree and allocate a large block from it, eagerly and with a lazy tree which only fills
+/// the levels the allocation reaches.
+fn tree_new_first_allocation(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_bitmap::*;
+    use buddy_allocator_workshop::MAX_ORDER;
+
+    c.bench_function("tree_new and first allocation", |b| {
+        b.iter(|| {
+            let mut tree = Tree::new();
+            tree.alloc_exact(MAX_ORDER - 4);
+            tree
+        });
+    });
+
+    c.bench_function("lazy tree_new and first allocation", |b| {
+        b.iter(|| {
+            let mut tree = LazyTree::new();
+            tree.alloc_exact(MAX_ORDER - 4);
+            tree
+        });
+    });
+}
+
+/// Build a forest of 64 trees, as the cold cache bench does, one tree at a time. With the `rayon`
+/// feature, the same forest is also built with the trees and their levels filled in parallel.
+fn bitmap_forest_construction(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_bitmap::*;
+    use buddy_allocator_workshop::MAX_ORDER_SIZE;
+
+    let bases: Vec<usize> = (0..64).map(|tree| tree << MAX_ORDER_SIZE).collect();
+
+    c.bench_function("bitmap construct 64 trees", {
+        let bases = bases.clone();
+        move |b| {
+            b.iter(|| {
+                let mut forest = Forest::new();
+                for &base in &bases {
+                    forest.create_top_level(base);
+                }
+                forest
+            });
+        }
+    });
+
+    #[cfg(feature = "rayon")]
+    c.bench_function("bitmap construct 64 trees in parallel", move |b| {
+        b.iter(|| Forest::new_parallel(&bases));
+    });
+}
+
+/// Share one tree between 1 and 4 threads, each allocating 1000 blocks of order 0 and then freeing
+/// them, behind the spinlock of a locked tree and with the lock free atomic tree.
+fn bitmap_contention(c: &mut Criterion) {
+    use buddy_allocator_workshop::atomic_tree::AtomicTree;
+    use buddy_allocator_workshop::buddy_allocator_bitmap::*;
+    use buddy_allocator_workshop::locked::LockedTree;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Run `threads` threads at once, each allocating 1000 blocks with `alloc` then freeing them
+    fn hammer<T, A, F>(tree: &Arc<T>, threads: usize, alloc: A, free: F)
+    where
+        T: Send + Sync + 'static,
+        A: Fn(&T) -> usize + Send + Copy + 'static,
+        F: Fn(&T, usize) + Send + Copy + 'static,
+    {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let tree = tree.clone();
+                thread::spawn(move || {
+                    let addresses: Vec<usize> = (0..1000).map(|_| alloc(&tree)).collect();
+                    for addr in addresses {
+                        free(&tree, addr);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    let locked = Arc::new(LockedTree::new(Tree::new()));
+    c.bench_function_over_inputs(
+        "locked tree allocate and free by threads",
+        move |b, &threads| {
+            b.iter(|| {
+                hammer(
+                    &locked,
+                    threads,
+                    |tree| tree.alloc_exact(0).unwrap() as usize,
+                    |tree, addr| tree.dealloc(addr as *const u8).unwrap(),
+                )
+            });
+        },
+        vec![1, 4],
+    );
+
+    let atomic = Arc::new(AtomicTree::new_at(0));
+    c.bench_function_over_inputs(
+        "atomic tree allocate and free by threads",
+        move |b, &threads| {
+            b.iter(|| {
+                hammer(
+                    &atomic,
+                    threads,
+                    |tree| tree.alloc_exact(0).unwrap() as usize,
+                    |tree, addr| assert!(tree.dealloc_exact(addr as *const u8, 0)),
+                )
+            });
+        },
+        vec![1, 4],
+    );
+}
+
+criterion_group!(
+    benches,
+    bitmap,
+    bitmap_steady_state,
+    bitmap_fragmented,
+    bitmap_cold_cache,
+    bitmap_order_0_steady_state,
+    bitmap_block_layout,
+    bitmap_allocate_free,
+    bitmap_levels,
+    tree_new,
+    tree_new_first_allocation,
+    bitmap_forest_construction,
+    bitmap_contention
+);
 criterion_main!(benches);
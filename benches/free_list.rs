@@ -0,0 +1,101 @@
+#[macro_use]
+extern crate criterion;
+extern crate buddy_allocator_workshop;
+extern crate intrusive_collections;
+
+use buddy_allocator_workshop::buddy_allocator_tree::{Block, BlockPtrAdapter, FreeList};
+use buddy_allocator_workshop::testing::XorShift;
+use criterion::{Bencher, Criterion, ParameterizedBenchmark};
+use intrusive_collections::SinglyLinkedList;
+
+/// The lengths of the lists measured, from a nearly empty order to the order 0 list of a
+/// fragmented top level block
+const LENGTHS: &[usize] = &[16, 1 << 10, 1 << 16];
+
+/// Free blocks which are boxed for as long as the bench runs, so that the pointers in the lists are
+/// always valid.
+struct Blocks {
+    blocks: Vec<Box<Block>>,
+}
+
+impl Blocks {
+    fn new(len: usize) -> Self {
+        Blocks {
+            blocks: (0..len).map(|n| Box::new(Block::new_free(n << 12, 0))).collect(),
+        }
+    }
+
+    fn ptr(&self, index: usize) -> *const Block {
+        &*self.blocks[index]
+    }
+
+    /// A list with a pointer to every block, in order
+    fn list<L: FreeList>(&self, mut list: L, pool: &mut L::Pool) -> L {
+        for index in 0..self.blocks.len() {
+            list.push(self.ptr(index), pool);
+        }
+
+        list
+    }
+}
+
+/// Remove a uniformly random block and push it back, so that the list keeps its length
+fn remove_random<L: FreeList>(b: &mut Bencher, len: usize, new: fn() -> L) {
+    let blocks = Blocks::new(len);
+    let mut pool = L::Pool::default();
+    let mut list = blocks.list(new(), &mut pool);
+    let mut rng = XorShift::new(475);
+
+    b.iter(|| {
+        let block = blocks.ptr(rng.below(len as u64) as usize);
+        list.remove(block, &mut pool).unwrap();
+        list.push(block, &mut pool);
+    });
+}
+
+/// Pop a block and push it back, so that the list keeps its length
+fn pop_push<L: FreeList>(b: &mut Bencher, len: usize, new: fn() -> L) {
+    let blocks = Blocks::new(len);
+    let mut pool = L::Pool::default();
+    let mut list = blocks.list(new(), &mut pool);
+
+    b.iter(|| {
+        let block = list.pop(&mut pool).unwrap();
+        list.push(block, &mut pool);
+    });
+}
+
+fn new_vec() -> Vec<*const Block> {
+    Vec::new()
+}
+
+fn new_singly_linked_list() -> SinglyLinkedList<BlockPtrAdapter> {
+    SinglyLinkedList::new(BlockPtrAdapter::new())
+}
+
+/// Each operation on every free list implementation, with the implementations side by side at
+/// each length so that the lengths at which one overtakes another stand out. The pairs of
+/// operations keep the lengths fixed, so pushing is measured as part of both.
+fn free_lists(c: &mut Criterion) {
+    c.bench(
+        "free list remove random and push",
+        ParameterizedBenchmark::new(
+            "vec",
+            |b, &len| remove_random(b, len, new_vec),
+            LENGTHS.to_vec(),
+        ).with_function("singly linked list", |b, &len| {
+            remove_random(b, len, new_singly_linked_list)
+        }),
+    );
+
+    c.bench(
+        "free list pop and push",
+        ParameterizedBenchmark::new("vec", |b, &len| pop_push(b, len, new_vec), LENGTHS.to_vec())
+            .with_function("singly linked list", |b, &len| {
+                pop_push(b, len, new_singly_linked_list)
+            }),
+    );
+}
+
+criterion_group!(benches, free_lists);
+criterion_main!(benches);
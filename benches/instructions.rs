@@ -0,0 +1,136 @@
+//! Instruction counts of the basic operations of each allocator, measured under Cachegrind by iai.
+//!
+//! The criterion benches measure wall clock time, which is what matters in the end but varies
+//! between runs on a busy or shared machine. The counts here are the same on every run, so they
+//! show small changes which criterion's noise would hide, but say nothing about caches or branch
+//! prediction. A change should look good in both. These benches need valgrind, so without it
+//! name the criterion benches to run instead of running every bench:
+//!
+//! ```text
+//! cargo bench --bench instructions
+//! ```
+//!
+//! iai counts the whole of each function, so every operation is paired with a `*_setup` bench
+//! which only builds the state it starts from. The setup is deterministic, so the cost of the
+//! operation itself is the difference between the two counts.
+
+extern crate buddy_allocator_workshop;
+#[macro_use]
+extern crate iai;
+
+use buddy_allocator_workshop::buddy_allocator_bitmap::Tree;
+use buddy_allocator_workshop::buddy_allocator_lists::{self, Block as ListsBlock};
+use buddy_allocator_workshop::buddy_allocator_tree;
+use buddy_allocator_workshop::{BuddyAllocatorApi, MAX_ORDER};
+use iai::black_box;
+
+type ListsAllocator = buddy_allocator_lists::BuddyAllocator<Vec<ListsBlock>>;
+type TreeAllocator = buddy_allocator_tree::BuddyAllocator<Vec<*const buddy_allocator_tree::Block>>;
+
+/// A tree with the lower half allocated, so that allocating descends into the right half
+fn bitmap_half_full() -> Tree {
+    let mut tree = Tree::new();
+    tree.alloc_exact(MAX_ORDER - 1).unwrap();
+    tree
+}
+
+fn bitmap_fresh_setup() -> Tree {
+    Tree::new()
+}
+
+fn bitmap_alloc_fresh() -> Option<*const u8> {
+    let mut tree = Tree::new();
+    tree.alloc_exact(black_box(0))
+}
+
+fn bitmap_half_full_setup() -> Tree {
+    bitmap_half_full()
+}
+
+fn bitmap_alloc_half_full() -> Option<*const u8> {
+    let mut tree = bitmap_half_full();
+    tree.alloc_exact(black_box(0))
+}
+
+fn bitmap_dealloc_setup() -> Tree {
+    let mut tree = Tree::new();
+    tree.alloc_exact(0).unwrap();
+    tree
+}
+
+fn bitmap_dealloc() -> bool {
+    let mut tree = Tree::new();
+    let addr = tree.alloc_exact(0).unwrap();
+    tree.dealloc_exact(black_box(addr), 0)
+}
+
+fn lists() -> ListsAllocator {
+    let mut allocator = ListsAllocator::new();
+    allocator.create_top_level(0).unwrap();
+    allocator
+}
+
+fn lists_setup() -> ListsAllocator {
+    lists()
+}
+
+fn lists_alloc() -> bool {
+    let mut allocator = lists();
+    allocator.allocate_exact(black_box(0)).is_ok()
+}
+
+fn lists_dealloc_setup() -> ListsAllocator {
+    let mut allocator = lists();
+    BuddyAllocatorApi::allocate(&mut allocator, 0).unwrap();
+    allocator
+}
+
+fn lists_dealloc() -> bool {
+    let mut allocator = lists();
+    let addr = BuddyAllocatorApi::allocate(&mut allocator, 0).unwrap();
+    allocator.deallocate(black_box(addr), 0).is_ok()
+}
+
+fn rb_tree() -> TreeAllocator {
+    let mut allocator = TreeAllocator::new();
+    allocator.create_top_level(0).unwrap();
+    allocator
+}
+
+fn rb_tree_setup() -> TreeAllocator {
+    rb_tree()
+}
+
+fn rb_tree_alloc() -> bool {
+    let mut allocator = rb_tree();
+    allocator.allocate_exact(black_box(0)).is_ok()
+}
+
+fn rb_tree_dealloc_setup() -> TreeAllocator {
+    let mut allocator = rb_tree();
+    allocator.allocate_exact(0).unwrap();
+    allocator
+}
+
+fn rb_tree_dealloc() -> bool {
+    let mut allocator = rb_tree();
+    let addr = allocator.allocate_exact(0).unwrap();
+    allocator.deallocate(black_box(addr)).is_ok()
+}
+
+main!(
+    bitmap_fresh_setup,
+    bitmap_alloc_fresh,
+    bitmap_half_full_setup,
+    bitmap_alloc_half_full,
+    bitmap_dealloc_setup,
+    bitmap_dealloc,
+    lists_setup,
+    lists_alloc,
+    lists_dealloc_setup,
+    lists_dealloc,
+    rb_tree_setup,
+    rb_tree_alloc,
+    rb_tree_dealloc_setup,
+    rb_tree_dealloc
+);
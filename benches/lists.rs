@@ -0,0 +1,18 @@
+#[macro_use]
+extern crate criterion;
+extern crate buddy_allocator_workshop;
+
+use criterion::Criterion;
+
+/// The vecs demo allocating 100k blocks of order 0, which splits a top level block for nearly
+/// every allocation and so removes from the front of the longest lists.
+fn lists_vecs_demo(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_lists::demo_vecs;
+
+    c.bench_function("lists_vecs demo 100k blocks", |b| {
+        b.iter(|| demo_vecs(false, 100_000, 0).unwrap());
+    });
+}
+
+criterion_group!(benches, lists_vecs_demo);
+criterion_main!(benches);
@@ -1,16 +1,17 @@
 #[macro_use]
 extern crate criterion;
 extern crate buddy_allocator_workshop;
+extern crate intrusive_collections;
 
 use criterion::Criterion;
 
 fn rb_tree_vecs(c: &mut Criterion) {
     use buddy_allocator_workshop::buddy_allocator_tree::*;
-    use buddy_allocator_workshop::{MAX_ORDER, BASE_ORDER};
+    use buddy_allocator_workshop::geometry::region_bytes;
 
     let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
-    allocator.create_top_level(0);
-    allocator.create_top_level(2usize.pow((BASE_ORDER + MAX_ORDER) as u32));
+    allocator.create_top_level(0).unwrap();
+    allocator.create_top_level(region_bytes().unwrap()).unwrap();
 
     let mut blocks_created_top_level = 1;
 
@@ -19,8 +20,8 @@ fn rb_tree_vecs(c: &mut Criterion) {
             match allocator.allocate_exact(0) {
                 Ok(_) => (),
                 Err(BlockAllocateError::NoBlocksAvailable) => {
-                    let size_of_block = 2usize.pow((BASE_ORDER + MAX_ORDER) as u32);
-                    allocator.create_top_level(size_of_block * blocks_created_top_level);
+                    let size_of_block = region_bytes().unwrap();
+                    allocator.create_top_level(size_of_block * blocks_created_top_level).unwrap();
                     blocks_created_top_level += 1;
                 }
                 Err(e) => panic!("Error: {:?}", e),
@@ -29,5 +30,99 @@ fn rb_tree_vecs(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, rb_tree_vecs);
+/// Allocate and free a block with up to 100k blocks already allocated. With only free blocks in the
+/// tree, the time taken should stay flat as more blocks are allocated.
+fn rb_tree_outstanding_allocations(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_tree::*;
+
+    c.bench_function_over_inputs(
+        "rb_tree_vecs allocate and free with outstanding allocations",
+        |b, &outstanding| {
+            let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+            allocator.create_top_level(0).unwrap();
+            for _ in 0..outstanding {
+                allocator.allocate_exact(0).unwrap();
+            }
+
+            b.iter(|| {
+                let address = allocator.allocate_exact(0).unwrap();
+                allocator.deallocate(address).unwrap();
+            });
+        },
+        vec![0, 1_000, 10_000, 50_000, 100_000],
+    );
+}
+
+/// Allocate a block of order 0 from a whole top level block and free it again, which splits it
+/// all the way down and merges it all the way back up. With the linked list free lists, each of
+/// those pushes and pops a node.
+fn rb_tree_linked_lists_split_merge(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_tree::*;
+    use intrusive_collections::SinglyLinkedList;
+
+    let mut allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+    allocator.create_top_level(0).unwrap();
+
+    c.bench_function("rb_tree_linked_lists split and merge", move |b| {
+        b.iter(|| {
+            let address = allocator.allocate_exact(0).unwrap();
+            allocator.deallocate(address).unwrap();
+        });
+    });
+}
+
+/// Allocate and free a whole top level block, with the free list of every smaller order empty.
+fn rb_tree_vecs_top_level(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_tree::*;
+    use buddy_allocator_workshop::MAX_ORDER;
+
+    let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+    allocator.create_top_level(0).unwrap();
+
+    c.bench_function("rb_tree_vecs allocate and free a top level block", move |b| {
+        b.iter(|| {
+            let address = allocator.allocate_exact(MAX_ORDER).unwrap();
+            allocator.deallocate(address).unwrap();
+        });
+    });
+}
+
+/// Free 10k blocks of order 0 whose free buddies were listed long before, in the order they were
+/// listed, so that each buddy is deep in its free list when it is merged with.
+fn rb_tree_vecs_merge_old_buddies(c: &mut Criterion) {
+    use buddy_allocator_workshop::buddy_allocator_tree::*;
+
+    c.bench_function("rb_tree_vecs merge with 10k old buddies", |b| {
+        b.iter_with_setup(
+            || {
+                let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+                allocator.create_top_level(0).unwrap();
+
+                let mut addresses: Vec<usize> = (0..20_000)
+                    .map(|_| allocator.allocate_exact(0).unwrap())
+                    .collect();
+                addresses.sort();
+                for pair in addresses.chunks(2) {
+                    allocator.deallocate(pair[0]).unwrap();
+                }
+
+                (allocator, addresses)
+            },
+            |(mut allocator, addresses)| {
+                for pair in addresses.chunks(2) {
+                    allocator.deallocate(pair[1]).unwrap();
+                }
+            },
+        );
+    });
+}
+
+criterion_group!(
+    benches,
+    rb_tree_vecs,
+    rb_tree_outstanding_allocations,
+    rb_tree_linked_lists_split_merge,
+    rb_tree_vecs_top_level,
+    rb_tree_vecs_merge_old_buddies
+);
 criterion_main!(benches);
@@ -0,0 +1,57 @@
+/* C interface to the bitmap buddy allocator, built with the `ffi` feature. Matches src/ffi.rs. */
+
+#ifndef BUDDY_ALLOCATOR_H
+#define BUDDY_ALLOCATOR_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Returned by buddy_alloc when no block could be allocated */
+#define BUDDY_ALLOC_FAILED UINT64_MAX
+
+#define BUDDY_OK 0
+/* The handle was null or not a live handle */
+#define BUDDY_BAD_HANDLE (-1)
+/* No used block of the given order begins at the given address */
+#define BUDDY_NOT_ALLOCATED (-2)
+/* A pointer argument other than the handle was null */
+#define BUDDY_NULL_POINTER (-3)
+
+/* An allocator. Only ever handled through a pointer. */
+typedef struct BuddyHandle BuddyHandle;
+
+/* A snapshot of an allocator's usage, filled in by buddy_stats */
+typedef struct CStats {
+    uint64_t managed_bytes;
+    uint64_t used_bytes;
+    uint64_t outstanding_allocations;
+    uint64_t peak_used_bytes;
+    uint64_t peak_outstanding_allocations;
+} CStats;
+
+/* Create an allocator managing the `len` bytes beginning at `base`, shrunk inward to whole blocks
+ * of order 0. Returns null if that leaves no memory to manage. */
+BuddyHandle *buddy_create(uint64_t base, uint64_t len);
+
+/* Allocate a block of the given order, returning its address or BUDDY_ALLOC_FAILED. Blocks of
+ * order 0 are 4 KiB unless BASE_ORDER was configured otherwise. */
+uint64_t buddy_alloc(BuddyHandle *handle, uint8_t order);
+
+/* Free the block of the given order beginning at `addr`. Returns BUDDY_OK on success. */
+int32_t buddy_free(BuddyHandle *handle, uint64_t addr, uint8_t order);
+
+/* Write the allocator's usage to `stats`. Returns BUDDY_OK on success. */
+int32_t buddy_stats(BuddyHandle *handle, CStats *stats);
+
+/* Destroy an allocator, after which its handle is rejected. Does nothing if the handle is not a
+ * live handle. */
+void buddy_destroy(BuddyHandle *handle);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BUDDY_ALLOCATOR_H */
@@ -0,0 +1,343 @@
+//! A bitmap tree which threads allocate from without taking a lock. Each node holds the free order
+//! of a block as in [Tree](buddy_allocator_bitmap::Tree), and a block is claimed by a compare and
+//! swap of its node for [USED], after which the claiming thread walks up fixing every ancestor.
+//!
+//! Two threads can claim a block and a block above or below it before either has fixed the
+//! ancestors of its own. Whichever walks up into the other's used node gives its block back and
+//! descends again, so only one of them keeps its block.
+//!
+//! Every node also holds a tag, which each write bumps. An ancestor is fixed from its children with
+//! a compare and swap, and the tag makes that fail if another thread wrote the ancestor since its
+//! children were read, even with the same order. With only the order, a fix computed from stale
+//! children could go through and mark a node completely free above a used block.
+
+use std::cmp;
+use std::sync::atomic::{AtomicU32, Ordering};
+use buddy_allocator_bitmap::block_size;
+use {LEVEL_COUNT, MAX_ORDER};
+
+/// The free order of a node which is itself allocated, rather than out of free blocks below it
+const USED: u8 = 0xff;
+
+/// The value of a node: its free order in the low byte and its tag in the rest.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Node(u32);
+
+impl Node {
+    fn order_free(self) -> u8 {
+        self.0 as u8
+    }
+
+    /// The free order, counting a used node as having nothing free
+    fn order_free_below(self) -> u8 {
+        match self.order_free() {
+            USED => 0,
+            order_free => order_free,
+        }
+    }
+
+    /// The next value of this node, which has the given free order and a bumped tag
+    fn with(self, order_free: u8) -> Node {
+        Node((self.0 & !0xff).wrapping_add(0x100) | u32::from(order_free))
+    }
+}
+
+/// A tree of one block of [MAX_ORDER] which any number of threads allocate from at once.
+///
+/// An allocation only fails when no block of the order is free, except while another thread which
+/// raced it for an overlapping block of a different order is giving its block back.
+pub struct AtomicTree {
+    /// 1 indexed, so that the children of node `n` are `2n` and `2n + 1`. Node 0 is unused.
+    nodes: Box<[AtomicU32]>,
+    base_address: usize,
+}
+
+impl AtomicTree {
+    /// A tree with every block free, managing the block of [MAX_ORDER] beginning at
+    /// `base_address`.
+    pub fn new_at(base_address: usize) -> AtomicTree {
+        let nodes = (0..1usize << LEVEL_COUNT)
+            .map(|index| AtomicU32::new(u32::from(AtomicTree::order(index.max(1)) + 1)))
+            .collect::<Vec<_>>();
+
+        AtomicTree {
+            nodes: nodes.into_boxed_slice(),
+            base_address,
+        }
+    }
+
+    /// The order of the blocks at the level of a node
+    fn order(index: usize) -> u8 {
+        let depth = (::std::mem::size_of::<usize>() * 8 - 1) as u8 - index.leading_zeros() as u8;
+        MAX_ORDER - depth
+    }
+
+    fn load(&self, index: usize) -> Node {
+        Node(self.nodes[index].load(Ordering::SeqCst))
+    }
+
+    fn compare_and_swap(&self, index: usize, current: Node, new: Node) -> bool {
+        self.nodes[index]
+            .compare_exchange(current.0, new.0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Allocate a block of the given order, returning `None` if no block of it is free.
+    pub fn alloc_exact(&self, desired_order: u8) -> Option<*const u8> {
+        if desired_order > MAX_ORDER {
+            return None;
+        }
+
+        loop {
+            if self.load(1).order_free_below() <= desired_order {
+                return None;
+            }
+
+            let index = match self.claim(desired_order) {
+                Some(index) => index,
+                // A race changed a node on the way down
+                None => continue,
+            };
+
+            if self.fix_ancestors(index).is_ok() {
+                let first_index = 1 << (MAX_ORDER - desired_order);
+                let offset = (index - first_index) * block_size(desired_order);
+                return Some((self.base_address + offset) as *const u8);
+            }
+
+            // Another thread claimed a block above this one first, so give this one back
+            let released = self.free_node(index, desired_order);
+            debug_assert!(released, "Only this thread can free the block it claimed!");
+        }
+    }
+
+    /// Descend to a completely free node of the given order and claim it, returning its index.
+    /// Returns `None` if the nodes changed so that the descent found no block.
+    fn claim(&self, desired_order: u8) -> Option<usize> {
+        // A node with a free block of the order has `order_free` of at least desired_order + 1
+        let has_free = |index| self.load(index).order_free_below() > desired_order;
+
+        let mut index = 1;
+        for _ in desired_order..MAX_ORDER {
+            index = if has_free(2 * index) {
+                2 * index
+            } else if has_free(2 * index + 1) {
+                2 * index + 1
+            } else {
+                return None;
+            };
+        }
+
+        let node = self.load(index);
+        let free = node.order_free() == desired_order + 1;
+        if free && self.compare_and_swap(index, node, node.with(USED)) {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Fix each ancestor of the node from its children, from its parent up to the root. Stops at
+    /// and returns the first ancestor which is itself used.
+    fn fix_ancestors(&self, mut index: usize) -> Result<(), usize> {
+        while index > 1 {
+            index /= 2;
+            let order = AtomicTree::order(index);
+
+            loop {
+                let node = self.load(index);
+                if node.order_free() == USED {
+                    return Err(index);
+                }
+
+                // A completely free child has `order_free` of (order - 1) + 1
+                let (left, right) = (self.load(2 * index), self.load(2 * index + 1));
+                let order_free = if left.order_free() == order && right.order_free() == order {
+                    order + 1
+                } else {
+                    cmp::max(left.order_free_below(), right.order_free_below())
+                };
+
+                if self.compare_and_swap(index, node, node.with(order_free)) {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Free the block of the given order beginning at `addr`. Returns `false` if no block of the
+    /// order was allocated there.
+    pub fn dealloc_exact(&self, addr: *const u8, order: u8) -> bool {
+        if order > MAX_ORDER || (addr as usize) < self.base_address {
+            return false;
+        }
+
+        let offset = addr as usize - self.base_address;
+        if offset >= block_size(MAX_ORDER) || offset % block_size(order) != 0 {
+            return false;
+        }
+
+        self.free_node((1 << (MAX_ORDER - order)) + offset / block_size(order), order)
+    }
+
+    /// Mark the used node of the given order completely free, then fix its ancestors. Returns
+    /// `false` if the node is not used.
+    fn free_node(&self, index: usize, order: u8) -> bool {
+        loop {
+            let node = self.load(index);
+            if node.order_free() != USED {
+                return false;
+            }
+            if self.compare_and_swap(index, node, node.with(order + 1)) {
+                break;
+            }
+        }
+
+        // An ancestor can only be used if it was claimed after this node was freed, by a thread
+        // which fixes the nodes above it itself
+        let _ = self.fix_ancestors(index);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use testing::{BlockSet, XorShift};
+    use MAX_ORDER_SIZE;
+
+    #[test]
+    fn test_alloc_and_dealloc() {
+        let base_address = 1 << MAX_ORDER_SIZE;
+        let tree = AtomicTree::new_at(base_address);
+
+        let first = tree.alloc_exact(0).unwrap();
+        let second = tree.alloc_exact(2).unwrap();
+        assert_eq!(first as usize, base_address);
+        assert_eq!(second as usize, base_address + block_size(2));
+        assert_eq!(tree.alloc_exact(MAX_ORDER), None);
+        assert_eq!(tree.alloc_exact(MAX_ORDER + 1), None);
+
+        // Only the block allocated at an address, with its own order, can be freed
+        assert!(!tree.dealloc_exact(first, 1));
+        assert!(!tree.dealloc_exact((base_address + 1) as *const u8, 0));
+        assert!(!tree.dealloc_exact(0 as *const u8, 0));
+        assert!(!tree.dealloc_exact((base_address + block_size(MAX_ORDER)) as *const u8, 0));
+        assert!(tree.dealloc_exact(first, 0));
+        assert!(!tree.dealloc_exact(first, 0));
+        assert!(tree.dealloc_exact(second, 2));
+
+        // Every block merged back
+        assert_eq!(tree.alloc_exact(MAX_ORDER), Some(base_address as *const u8));
+    }
+
+    #[test]
+    fn test_racing_claims() {
+        let tree = AtomicTree::new_at(0);
+
+        // Two descents both found the tree empty, and claimed a block and one above it before
+        // either fixed its ancestors
+        let small = tree.claim(0).unwrap();
+        let large = tree.claim(3).unwrap();
+        assert_eq!(large, small >> 3);
+
+        // The block above was fixed first, so the block below it is given back
+        assert_eq!(tree.fix_ancestors(large), Ok(()));
+        assert_eq!(tree.fix_ancestors(small), Err(large));
+        assert!(tree.free_node(small, 0));
+        assert!(!tree.dealloc_exact(0 as *const u8, 0));
+
+        // Once the block below is fixed first, the block above it cannot be claimed at all
+        assert!(tree.dealloc_exact(0 as *const u8, 3));
+        let small = tree.claim(0).unwrap();
+        assert_eq!(tree.fix_ancestors(small), Ok(()));
+        let next = tree.claim(3).unwrap();
+        assert_eq!(next, large + 1);
+        assert_eq!(tree.fix_ancestors(next), Ok(()));
+
+        assert!(tree.dealloc_exact(0 as *const u8, 0));
+        assert!(tree.dealloc_exact(block_size(3) as *const u8, 3));
+        assert_eq!(tree.alloc_exact(MAX_ORDER), Some(0 as *const u8));
+    }
+
+    #[test]
+    fn test_exhaustion_across_threads() {
+        let tree = Arc::new(AtomicTree::new_at(0));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let tree = tree.clone();
+                thread::spawn(move || {
+                    let mut addresses = Vec::new();
+                    while let Some(addr) = tree.alloc_exact(0) {
+                        addresses.push(addr as usize);
+                    }
+                    addresses
+                })
+            })
+            .collect();
+
+        let mut unique = HashSet::new();
+        let mut allocations = 0;
+        for thread in threads {
+            for addr in thread.join().unwrap() {
+                allocations += 1;
+                assert!(unique.insert(addr), "{:#x} handed out twice", addr);
+            }
+        }
+
+        // Every block of order 0 in the tree was handed out exactly once
+        assert_eq!(allocations, 1 << MAX_ORDER);
+        assert_eq!(unique.len(), 1 << MAX_ORDER);
+
+        for &addr in &unique {
+            assert!(tree.dealloc_exact(addr as *const u8, 0));
+        }
+        assert_eq!(tree.alloc_exact(MAX_ORDER), Some(0 as *const u8));
+    }
+
+    /// Threads allocating and freeing blocks of different orders race for blocks above and below
+    /// each other, so claims are given back
+    #[test]
+    fn test_mixed_orders_across_threads() {
+        let tree = Arc::new(AtomicTree::new_at(0));
+        let blocks = Arc::new(Mutex::new(BlockSet::new()));
+
+        let threads: Vec<_> = (0..8)
+            .map(|thread_number| {
+                let (tree, blocks) = (tree.clone(), blocks.clone());
+                thread::spawn(move || {
+                    let mut rng = XorShift::new(525 + thread_number);
+                    let mut live = Vec::new();
+                    for _ in 0..5000 {
+                        if !live.is_empty() && rng.below(2) == 0 {
+                            let nth = rng.below(live.len() as u64) as usize;
+                            let (addr, order) = live.swap_remove(nth);
+                            assert!(blocks.lock().unwrap().remove(addr));
+                            assert!(tree.dealloc_exact(addr as *const u8, order));
+                        } else {
+                            let order = rng.below(6) as u8;
+                            let addr = tree.alloc_exact(order).unwrap() as usize;
+                            let inserted = blocks.lock().unwrap().insert(addr, block_size(order));
+                            assert!(inserted, "{:#x} of order {} overlaps a block", addr, order);
+                            live.push((addr, order));
+                        }
+                    }
+                    live
+                })
+            })
+            .collect();
+
+        // Only free the blocks left once no thread can allocate them again
+        let live: Vec<_> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+        for (addr, order) in live {
+            assert!(tree.dealloc_exact(addr as *const u8, order));
+        }
+        assert_eq!(tree.alloc_exact(MAX_ORDER), Some(0 as *const u8));
+    }
+}
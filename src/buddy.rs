@@ -0,0 +1,192 @@
+//! Finding the buddy of a block, shared by the allocators which merge blocks by address.
+//!
+//! A block's buddy is found by flipping the bit of the block's size in its address, but only in
+//! its offset into its region, as blocks are aligned to their size relative to the region's base
+//! rather than absolutely. A region whose base is aligned to the size of a top level block gives
+//! the same buddies either way.
+
+use geometry::size_of_order;
+use super::MAX_ORDER;
+
+/// The address of the buddy of the block of the given order beginning at `addr`, in the region
+/// beginning at `region_base`.
+///
+/// # Panicking
+///
+/// In debug builds, panics if the order is not less than [MAX_ORDER], as top level blocks have no
+/// buddies, or if the block is not aligned to its size within the region.
+pub fn buddy_of(addr: usize, order: u8, region_base: usize) -> usize {
+    let offset = offset_in_region(addr, order, region_base);
+    region_base + (offset ^ size_of_order(order))
+}
+
+/// The address of the block of the order above which the block of the given order beginning at
+/// `addr` and its buddy merge into, in the region beginning at `region_base`. This is whichever of
+/// the two is lower.
+///
+/// # Panicking
+///
+/// Panics in debug builds in the same cases as [buddy_of].
+pub fn parent_of(addr: usize, order: u8, region_base: usize) -> usize {
+    let offset = offset_in_region(addr, order, region_base);
+    region_base + (offset & !size_of_order(order))
+}
+
+fn offset_in_region(addr: usize, order: u8, region_base: usize) -> usize {
+    debug_assert!(order < MAX_ORDER, "Top level blocks of order {} have no buddies!", order);
+
+    let offset = addr.wrapping_sub(region_base);
+    debug_assert!(
+        offset < size_of_order(MAX_ORDER),
+        "Block {:#x} is not in the region at {:#x}!",
+        addr,
+        region_base
+    );
+    debug_assert_eq!(
+        offset & (size_of_order(order) - 1),
+        0,
+        "Block {:#x} of order {} is not aligned within the region at {:#x}!",
+        addr,
+        order,
+        region_base
+    );
+
+    offset
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buddy_allocator_lists::{self, BuddyAllocator as ListsAllocator};
+    use buddy_allocator_tree::{self, BuddyAllocator as TreeAllocator};
+    use stats::AllocatorStats;
+    use std::cmp;
+    use testing::XorShift;
+    use {BuddyAllocatorApi, BASE_ORDER, LEVEL_COUNT, MAX_ORDER_SIZE};
+
+    const TOP_LEVEL_SIZE: usize = 1 << MAX_ORDER_SIZE;
+
+    /// Aligned and unaligned bases, including regions ending at the top of the address space
+    fn region_bases() -> Vec<usize> {
+        vec![
+            0,
+            TOP_LEVEL_SIZE,
+            TOP_LEVEL_SIZE * 5,
+            0x1000,
+            TOP_LEVEL_SIZE * 3 + 0x5000,
+            usize::max_value() - TOP_LEVEL_SIZE + 1,
+            usize::max_value() - TOP_LEVEL_SIZE - 0x2fff,
+        ]
+    }
+
+    #[test]
+    fn test_buddies_pair_up_within_region() {
+        let mut rng = XorShift::new(482);
+
+        for base in region_bases() {
+            for order in 0..MAX_ORDER {
+                let size = 1usize << (order + BASE_ORDER);
+                let blocks = TOP_LEVEL_SIZE / size;
+
+                // The first and last blocks of the region and some between
+                let picks = (0..16).map(|_| rng.below(blocks as u64) as usize);
+                for n in vec![0, 1, blocks - 2, blocks - 1].into_iter().chain(picks) {
+                    let addr = base + n * size;
+                    let buddy = buddy_of(addr, order, base);
+
+                    assert_ne!(buddy, addr);
+                    assert_eq!(buddy_of(buddy, order, base), addr);
+                    assert!(buddy.wrapping_sub(base) < TOP_LEVEL_SIZE, "{:#x} left region", buddy);
+
+                    // The pair merges into the lower of the two, which is aligned to its own size
+                    let parent = parent_of(addr, order, base);
+                    assert_eq!(parent, cmp::min(addr, buddy));
+                    assert_eq!(parent_of(buddy, order, base), parent);
+                    assert_eq!((parent - base) % (size * 2), 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_aligned_region_matches_xor() {
+        for &base in &[0, TOP_LEVEL_SIZE, TOP_LEVEL_SIZE * 7] {
+            for order in 0..MAX_ORDER {
+                let addr = base + (3usize << (order + BASE_ORDER + 1));
+                if addr - base >= TOP_LEVEL_SIZE {
+                    continue;
+                }
+                assert_eq!(buddy_of(addr, order, base), addr ^ (1 << (order + BASE_ORDER)));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(debug_assertions)]
+    fn test_top_level_has_no_buddy() {
+        buddy_of(0, MAX_ORDER, 0);
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(debug_assertions)]
+    fn test_misaligned_block() {
+        buddy_of(0x1000, 1, 0);
+    }
+
+    /// Fill every region with blocks of random orders, free them in a random order, and check that
+    /// every region merged back into a single free top level block.
+    fn check_merges_back<A: BuddyAllocatorApi + AllocatorStats>(mut allocator: A, bases: &[usize]) {
+        for &base in bases {
+            allocator.create_top_level(base);
+        }
+
+        // A few small blocks, so that some merges go all the way up from order 0, and then only
+        // large blocks so that the regions fill up quickly
+        let mut live: Vec<_> = (0..8)
+            .map(|order| (allocator.allocate(order).unwrap(), order))
+            .collect();
+
+        let mut rng = XorShift::new(482);
+        let smallest = MAX_ORDER - 4;
+        loop {
+            let order = smallest + rng.below(4) as u8;
+            match allocator.allocate(order) {
+                Some(addr) => live.push((addr, order)),
+                // Whatever is left is smaller than the smallest order
+                None if order == smallest => break,
+                None => {}
+            }
+        }
+
+        while !live.is_empty() {
+            let (addr, order) = live.swap_remove(rng.below(live.len() as u64) as usize);
+            assert!(allocator.deallocate(addr, order), "{:#x} could not be freed", addr);
+        }
+
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = bases.len();
+        assert_eq!(allocator.free_histogram(), expected);
+    }
+
+    #[test]
+    fn test_lists_merge_within_regions() {
+        // Unaligned regions only merge back if buddies are found relative to their bases
+        let bases = [0x1000, TOP_LEVEL_SIZE * 3 + 0x5000];
+        check_merges_back(ListsAllocator::<Vec<buddy_allocator_lists::Block>>::new(), &bases);
+    }
+
+    #[test]
+    fn test_rb_tree_merges_within_regions() {
+        let bases = [TOP_LEVEL_SIZE, TOP_LEVEL_SIZE * 3];
+        check_merges_back(TreeAllocator::<Vec<*const buddy_allocator_tree::Block>>::new(), &bases);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rb_tree_rejects_unaligned_region() {
+        let mut allocator = TreeAllocator::<Vec<*const buddy_allocator_tree::Block>>::new();
+        allocator.create_top_level(0x1000).unwrap();
+    }
+}
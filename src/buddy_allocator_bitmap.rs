@@ -1,10 +1,32 @@
 ///! A modified buddy bitmap allocator
 use std::cmp;
-use std::mem;
+use std::iter;
 use std::time::{Duration, Instant};
-use super::{BASE_ORDER, LEVEL_COUNT, MAX_ORDER, MAX_ORDER_SIZE};
+use mem_map::{usable_ranges, MemRegion};
+use metrics::{Latencies, OpTimer};
+#[cfg(feature = "metrics")]
+use metrics::LatencySummary;
+use observer::{AllocEvent, AllocObserver, ObserverSlot};
+use snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use stats::{AllocatorStats, BlockInfo, OpCounters, Usage};
+use steady_state;
+use std::marker::PhantomData;
+use std::mem;
+use std::slice;
+use testing::RegionTracker;
+use self::flat_tree::NodeIndex;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use config::BuddyConfig;
+use geometry;
+use super::{
+    AllocationPolicy, BuddyAllocatorApi, DemoError, DemoReport, DurationReport, PageAllocError,
+    PageSize, PhysicalAllocator, RegionBusy, BASE_ORDER, LEVEL_COUNT, MAX_ORDER,
+};
 
-/// A block in the bitmap
+/// A block in the bitmap. Transparent so that external storage can be given as bytes.
+#[derive(Debug, Copy, Clone)]
+#[repr(transparent)]
 struct Block {
     /// The order of the biggest block under this block - 1. 0 denotes used
     order_free: u8,
@@ -19,229 +41,4908 @@ impl Block {
     }
 }
 
-/// A tree of blocks. Contains the flat representation of the tree as a flat array
-// TODO i might have a *few* cache misses here, eh?
-pub struct Tree {
-    /// Flat array representation of tree. Used with the help of the `flat_tree` crate.
-    flat_blocks: Box<[Block; Tree::blocks_in_tree(LEVEL_COUNT)]>,
+// `order_free` stores the order + 1, so the maximum order must be at most `u8::MAX - 1`
+const_assert!(__bitmap_order_free_fits_u8; (MAX_ORDER as usize) < ::std::u8::MAX as usize);
+
+/// A block allocated from a [Tree], identified by its node in the tree rather than by its address,
+/// so that it can be freed without mapping the address back to a node. Fits in 4 bytes, e.g. to be
+/// kept in an array of frame metadata, with the 1 indexed node index in the low 27 bits and the
+/// order in the high 5.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BlockHandle(u32);
+
+/// How far the order is shifted up in a [BlockHandle]
+const HANDLE_ORDER_SHIFT: u8 = 27;
+
+// Every node index of a tree must fit below the order of a handle, and every order above it
+const_assert!(__bitmap_handle_index_fits; (LEVEL_COUNT as usize) <= HANDLE_ORDER_SHIFT as usize);
+const_assert!(__bitmap_handle_order_fits; (MAX_ORDER as usize) < 1 << (32 - HANDLE_ORDER_SHIFT));
+
+impl BlockHandle {
+    fn new(node_index: usize, order: u8) -> Self {
+        BlockHandle(node_index as u32 | u32::from(order) << HANDLE_ORDER_SHIFT)
+    }
+
+    /// A handle from what [BlockHandle::to_raw] returned. Any number is accepted, as handles are
+    /// checked against the tree whenever they are used.
+    pub fn from_raw(raw: u32) -> Self {
+        BlockHandle(raw)
+    }
+
+    pub fn to_raw(self) -> u32 {
+        self.0
+    }
+
+    /// The 1 indexed index of the block's node in the flat tree
+    pub fn node_index(self) -> usize {
+        (self.0 & ((1 << HANDLE_ORDER_SHIFT) - 1)) as usize
+    }
+
+    pub fn order(self) -> u8 {
+        (self.0 >> HANDLE_ORDER_SHIFT) as u8
+    }
 }
 
-impl Tree {
-    const fn blocks_in_tree(levels: u8) -> usize {
-        ((1 << levels) - 1) as usize
+/// The base order of a tree: a block of order `k` is `2^(k + BASE_ORDER)` bytes. Implemented by
+/// marker types so that trees with different sizes of smallest block, e.g. 4 KiB pages of RAM and
+/// 64 byte blocks of a small SRAM pool, can be used side by side.
+///
+/// The largest block, of size `2^(MAX_ORDER + BASE_ORDER)`, must fit in a `usize`.
+pub trait BaseOrder {
+    const BASE_ORDER: u8;
+}
+
+/// The crate wide [BASE_ORDER](::BASE_ORDER), which trees have unless given another.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct DefaultBaseOrder;
+
+impl BaseOrder for DefaultBaseOrder {
+    const BASE_ORDER: u8 = BASE_ORDER;
+}
+
+/// The size in bytes of a block of the given order.
+#[inline]
+pub fn block_size(order: u8) -> usize {
+    geometry::size_of_order(order)
+}
+
+/// The size in bytes of a block of the given order in a tree with the base order `B`.
+#[inline]
+pub fn block_size_in<B: BaseOrder>(order: u8) -> usize {
+    debug_assert!(order <= MAX_ORDER, "Order {} larger than max of {}!", order, MAX_ORDER);
+    geometry::size_of_order_in(order, B::BASE_ORDER)
+}
+
+/// Begins every snapshot of a tree
+const SNAPSHOT_MAGIC: &[u8; 4] = b"BSBT";
+
+/// The number of levels at the top of every tree which are kept in [HotBlocks]. Every descent
+/// passes through all of them, and 7 levels of 1 byte blocks fit in two cache lines.
+const HOT_LEVELS: u8 = 7;
+const HOT_BLOCKS: usize = (1 << HOT_LEVELS) - 1;
+
+/// How many blocks each task fills when the levels of a tree are filled in parallel
+#[cfg(feature = "rayon")]
+const PARALLEL_FILL_CHUNK: usize = 1 << 16;
+
+/// The blocks of the top [HOT_LEVELS] levels of a tree, aligned so that they take as few cache
+/// lines as possible.
+#[derive(Copy, Clone)]
+#[repr(C, align(64))]
+struct HotBlocks([Block; HOT_BLOCKS]);
+
+/// What the big array of a [BlockStorage] is made of: a block each, or with the `packed_bitmap`
+/// feature, a byte holding a pair of blocks.
+#[cfg(not(feature = "packed_bitmap"))]
+type ColdCell = Block;
+#[cfg(feature = "packed_bitmap")]
+type ColdCell = u8;
+
+// Packed blocks keep their `order_free` in a nibble, so every order below the hot levels must fit
+#[cfg(feature = "packed_bitmap")]
+const_assert!(__bitmap_packed_order_free_fits; (LEVEL_COUNT - HOT_LEVELS) as usize <= 0xf);
+
+/// The blocks of a tree in two tiers: the top levels, which are touched by every operation, are
+/// kept together inside the tree, and the rest in a big array. Both tiers are indexed by the 0
+/// indexed position of the block in the flat tree, so the first [HOT_BLOCKS] blocks of the big
+/// array are left unused rather than shifting every index of the lower levels. The flat tree keeps
+/// each level contiguous, as [flat_tree::flat_level] gives, so every level is one slice.
+///
+/// Levels do not get arrays of their own, indexed as `levels[level][offset]`: those would hold the
+/// blocks in exactly the order the flat tree already does, so a descent would touch the same cache
+/// lines and only the index math would change.
+///
+/// With the `packed_bitmap` feature, two blocks of the big array share each byte, the one with
+/// the even index in the low nibble, so that twice as many blocks fit in each cache line.
+struct BlockStorage {
+    hot: HotBlocks,
+    /// Either leaked from a box owned by the tree or external storage given to [Tree::init_in]
+    cold: &'static mut [ColdCell],
+    /// The number of blocks in the tree
+    len: usize,
+}
+
+impl BlockStorage {
+    const fn empty() -> Self {
+        BlockStorage {
+            hot: HotBlocks([Block { order_free: 0 }; HOT_BLOCKS]),
+            cold: &mut [],
+            len: 0,
+        }
     }
 
-    pub fn new() -> Tree {
-        const BLOCKS_IN_TREE: usize = Tree::blocks_in_tree(LEVEL_COUNT);
-        let mut flat_blocks: Box<[Block; BLOCKS_IN_TREE]> = box unsafe { mem::uninitialized() };
+    /// Storage for the blocks of a tree with the given number of levels, leaked from a box.
+    fn on_heap(levels: u8) -> Self {
+        let cold = vec![Self::UNFILLED; Self::cells(levels)];
+        BlockStorage {
+            cold: Box::leak(cold.into_boxed_slice()),
+            len: Tree::blocks_in_tree(levels),
+            ..Self::empty()
+        }
+    }
 
-        let mut start: usize = 0;
-        for level in 0..LEVEL_COUNT {
-            let order = MAX_ORDER - level;
-            let size = 1 << (level as usize);
-            for block in start..(start + size) {
-                flat_blocks[block] = Block::new_free(order);
-            }
-            start += size;
+    /// Storage for the blocks of a tree with the given number of levels in the first
+    /// [BlockStorage::storage_len] bytes of external storage.
+    fn in_storage(storage: &'static mut [u8], levels: u8) -> Self {
+        // Cells are a byte or transparent wrappers around one, so any bytes are valid cells
+        let cells = storage.as_mut_ptr() as *mut ColdCell;
+        BlockStorage {
+            cold: unsafe { slice::from_raw_parts_mut(cells, Self::cells(levels)) },
+            len: Tree::blocks_in_tree(levels),
+            ..Self::empty()
         }
+    }
+
+    /// How many bytes of storage the blocks of a tree with the given number of levels take
+    const fn storage_len(levels: u8) -> usize {
+        Self::cells(levels) * mem::size_of::<ColdCell>()
+    }
+
+    #[cfg(not(feature = "packed_bitmap"))]
+    const UNFILLED: ColdCell = Block { order_free: 0 };
+    #[cfg(feature = "packed_bitmap")]
+    const UNFILLED: ColdCell = 0;
+
+    #[cfg(not(feature = "packed_bitmap"))]
+    const fn cells(levels: u8) -> usize {
+        Tree::blocks_in_tree(levels)
+    }
+
+    #[cfg(feature = "packed_bitmap")]
+    const fn cells(levels: u8) -> usize {
+        Self::cell_of(Tree::blocks_in_tree(levels) + 1)
+    }
+
+    /// The index of the cell holding the block at `index`
+    #[cfg(not(feature = "packed_bitmap"))]
+    const fn cell_of(index: usize) -> usize {
+        index
+    }
 
-        Tree { flat_blocks }
+    #[cfg(feature = "packed_bitmap")]
+    const fn cell_of(index: usize) -> usize {
+        index / 2
     }
 
-    pub const fn blocks_in_level(order: u8) -> usize {
-        (1 << (BASE_ORDER + order) as usize) / (1 << (BASE_ORDER as usize))
+    /// The number of blocks in the tree
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The `order_free` of the block at `index`, panicking if it is outside of the tree
+    fn get(&self, index: usize) -> u8 {
+        assert!(index < self.len(), "Block {} is outside of the tree!", index);
+        unsafe { self.get_unchecked(index) }
+    }
+
+    /// Set the `order_free` of the block at `index`, panicking if it is outside of the tree
+    fn set(&mut self, index: usize, order_free: u8) {
+        assert!(index < self.len(), "Block {} is outside of the tree!", index);
+        unsafe { self.set_unchecked(index, order_free) }
     }
 
     #[inline]
-    unsafe fn block_mut(&mut self, index: usize) -> &mut Block {
-        debug_assert!(index < Tree::blocks_in_tree(LEVEL_COUNT));
-        self.flat_blocks.get_unchecked_mut(index)
+    unsafe fn get_unchecked(&self, index: usize) -> u8 {
+        if index < HOT_BLOCKS {
+            self.hot.0.get_unchecked(index).order_free
+        } else {
+            Self::get_cold(self.cold, index)
+        }
     }
 
     #[inline]
-    unsafe fn block(&self, index: usize) -> &Block {
-        debug_assert!(index < Tree::blocks_in_tree(LEVEL_COUNT));
-        self.flat_blocks.get_unchecked(index)
+    unsafe fn set_unchecked(&mut self, index: usize, order_free: u8) {
+        if index < HOT_BLOCKS {
+            self.hot.0.get_unchecked_mut(index).order_free = order_free;
+        } else {
+            Self::set_cold(self.cold, index, order_free);
+        }
     }
 
-    pub fn alloc_exact(&mut self, desired_order: u8) -> Option<*const u8> {
-        let root = unsafe { self.block_mut(0) };
+    #[cfg(not(feature = "packed_bitmap"))]
+    #[inline]
+    unsafe fn get_cold(cold: &[ColdCell], index: usize) -> u8 {
+        cold.get_unchecked(Self::cell_of(index)).order_free
+    }
 
-        // If the root node has no orders free, or if it does not have the desired order free
-        if root.order_free == 0 || (root.order_free - 1) < desired_order {
-            return None;
+    #[cfg(not(feature = "packed_bitmap"))]
+    #[inline]
+    unsafe fn set_cold(cold: &mut [ColdCell], index: usize, order_free: u8) {
+        cold.get_unchecked_mut(Self::cell_of(index)).order_free = order_free;
+    }
+
+    #[cfg(feature = "packed_bitmap")]
+    #[inline]
+    unsafe fn get_cold(cold: &[ColdCell], index: usize) -> u8 {
+        (cold.get_unchecked(Self::cell_of(index)) >> ((index & 1) * 4)) & 0xf
+    }
+
+    #[cfg(feature = "packed_bitmap")]
+    #[inline]
+    unsafe fn set_cold(cold: &mut [ColdCell], index: usize, order_free: u8) {
+        debug_assert!(order_free <= 0xf, "order_free {} does not fit in a nibble!", order_free);
+        let shift = (index & 1) * 4;
+        let cell = cold.get_unchecked_mut(Self::cell_of(index));
+        *cell = (*cell & !(0xf << shift)) | (order_free << shift);
+    }
+
+    /// A pointer to the block at `index`, or to the byte holding it, which may be outside of the
+    /// tree as long as it is not dereferenced.
+    #[inline]
+    fn ptr(&self, index: usize) -> *const u8 {
+        if index < HOT_BLOCKS {
+            self.hot.0.as_ptr().wrapping_add(index) as *const u8
+        } else {
+            self.cold.as_ptr().wrapping_add(Self::cell_of(index)).cast()
         }
+    }
 
-        let mut addr: u32 = 0;
-        let mut node_index = 1;
+    /// The `order_free` of every block, in order
+    fn order_frees<'a>(&'a self) -> impl Iterator<Item = u8> + 'a {
+        (0..self.len()).map(move |index| self.get(index))
+    }
 
-        let max_level = MAX_ORDER - desired_order;
+    /// Set every block of the given level to `order_free`.
+    fn fill_level(&mut self, level: u8, order_free: u8) {
+        if level < HOT_LEVELS {
+            self.hot.0[flat_tree::flat_level(level)].fill(Block { order_free });
+        } else {
+            let (cells, cell) = self.cold_level(level, order_free);
+            cells.fill(cell);
+        }
+    }
 
-        for level in 0..max_level {
-            let left_child_index = flat_tree::left_child(node_index);
-            let left_child = unsafe { self.block(left_child_index - 1) };
+    /// Set every block of the given level, which must be below the hot levels, to `order_free`
+    /// like [BlockStorage::fill_level], in parallel.
+    #[cfg(feature = "rayon")]
+    fn fill_level_parallel(&mut self, level: u8, order_free: u8) {
+        let (cells, cell) = self.cold_level(level, order_free);
+        cells.par_chunks_mut(PARALLEL_FILL_CHUNK).for_each(|chunk| {
+            chunk.fill(cell);
+        });
+    }
 
-            let o = left_child.order_free;
-            // If the child is not used (o!=0) or (desired_order in o-1)
-            // Due to the +1 offset, we need to subtract 1 from 0:
-            // However, (o - 1) >= desired_order can be simplified to o > desired_order
-            node_index = if o != 0 && o > desired_order {
-                left_child_index
-            } else {
-                // Move over to the right: if the parent had a free order and the left didn't, the right must, or the parent is invalid and does not uphold invariants
-                // Since the address is moving from the left hand side, we need to increase it
-                // Block size in bytes = 2^(BASE_ORDER + order)
-                // We also only want to allocate on the order of the child, hence subtracting 1
-                addr += 1 << ((MAX_ORDER_SIZE - level - 1) as u32);
-                left_child_index + 1
-            };
+    /// The cells of a level below the hot levels, and the cell which gives each of its blocks
+    /// the given `order_free`.
+    #[cfg(not(feature = "packed_bitmap"))]
+    fn cold_level(&mut self, level: u8, order_free: u8) -> (&mut [ColdCell], ColdCell) {
+        (&mut self.cold[flat_tree::flat_level(level)], Block { order_free })
+    }
+
+    /// The cells of a level below the hot levels, and the cell which gives each of its blocks
+    /// the given `order_free`. A level begins at an odd index, so its first block shares a cell
+    /// with the level above and its last one with the level below: those two are set here.
+    #[cfg(feature = "packed_bitmap")]
+    fn cold_level(&mut self, level: u8, order_free: u8) -> (&mut [ColdCell], ColdCell) {
+        let blocks = flat_tree::flat_level(level);
+        let (first, last) = (blocks.start, blocks.end - 1);
+        unsafe {
+            Self::set_cold(self.cold, first, order_free);
+            Self::set_cold(self.cold, last, order_free);
         }
+        let cells = Self::cell_of(first + 1)..Self::cell_of(last);
+        (&mut self.cold[cells], order_free * 0x11)
+    }
 
-        let block = unsafe { self.block_mut(node_index - 1) };
-        block.order_free = 0;
+    /// The bytes of the whole big array, including its unused start
+    #[cfg(any(feature = "paranoid", test))]
+    fn cold_bytes(&self) -> &[u8] {
+        // Safe because cells are a byte or transparent wrappers around one
+        let bytes = mem::size_of_val(self.cold);
+        unsafe { slice::from_raw_parts(self.cold.as_ptr().cast(), bytes) }
+    }
 
-        // Iterate upwards and set parents accordingly
-        for _ in 0..max_level {
-            // Treat as right index because we need to be 0 indexed here!
-            // If we exclude the last bit, we'll always get an even number (the left node while 1 indexed)
-            let right_index = node_index & !1;
-            node_index = flat_tree::parent(node_index);
+    /// The bytes holding every block, as the hot blocks which are in the tree followed by the
+    /// cells of the big array after them.
+    #[cfg(feature = "paranoid")]
+    fn as_bytes(&self) -> (&[u8], &[u8]) {
+        let hot = &self.hot.0[..cmp::min(self.len(), HOT_BLOCKS)];
+        let cold = self.cold_bytes().get(Self::cell_of(HOT_BLOCKS)..).unwrap_or(&[]);
 
-            let left = unsafe { self.block(right_index - 1) }.order_free;
-            let right = unsafe { self.block(right_index) }.order_free;
+        // Safe because blocks are transparent wrappers around a byte
+        let hot = unsafe { slice::from_raw_parts(hot.as_ptr() as *const u8, hot.len()) };
+        (hot, cold)
+    }
 
-            unsafe { self.block_mut(node_index - 1) }.order_free = cmp::max(left, right);
+    /// The block which differs between bytes given by [BlockStorage::as_bytes], given the
+    /// position of the first differing byte, as its 0 indexed index and expected and actual
+    /// `order_free`.
+    #[cfg(feature = "paranoid")]
+    fn changed_block(&self, position: usize, expected: u8, actual: u8) -> (usize, u8, u8) {
+        let hot_len = cmp::min(self.len(), HOT_BLOCKS);
+        if position < hot_len || !cfg!(feature = "packed_bitmap") {
+            return (position, expected, actual);
         }
 
-        Some(addr as *const u8)
+        // The byte holds two blocks, the first of which is the first to differ
+        let index = (Self::cell_of(HOT_BLOCKS) + position - hot_len) * 2;
+        let shift = if (expected ^ actual) & 0xf != 0 { 0 } else { 4 };
+        let nibble = |byte: u8| (byte >> shift) & 0xf;
+        (index + shift / 4, nibble(expected), nibble(actual))
     }
 }
 
-/// Flat tree things.
-///
-/// # Note
-/// **1 INDEXED!**
-mod flat_tree {
+/// One bit for every block of order 0, grouped in words, which is set if the block can be
+/// allocated: neither it nor any block above it is used. A second level of bits records which
+/// words have any bit set. With the `leaf_bitmap` feature, order 0 allocations find the lowest
+/// free block with `trailing_zeros` on both levels rather than descending the tree. With the
+/// feature disabled it is zero sized and does nothing, so the tree can keep it up to date
+/// unconditionally.
+#[cfg(feature = "leaf_bitmap")]
+struct LeafBitmap {
+    /// One bit for every word of `words`, set if the word has any bit set
+    summary: &'static mut [u64],
+    words: &'static mut [u64],
+    /// Whether `summary` and `words`, which are contiguous, are a leaked box which must be freed
+    /// when the bitmap is dropped, rather than part of the external storage given to
+    /// [Tree::init_in]
+    owns_words: bool,
+    /// No word of `summary` before this one has a bit set
+    first_summary: usize,
+}
+
+#[cfg(not(feature = "leaf_bitmap"))]
+struct LeafBitmap;
+
+#[cfg(feature = "leaf_bitmap")]
+impl LeafBitmap {
+    const fn empty() -> Self {
+        LeafBitmap {
+            summary: &mut [],
+            words: &mut [],
+            owns_words: false,
+            first_summary: 0,
+        }
+    }
+
+    const fn words(levels: u8) -> usize {
+        ((1usize << (levels - 1)) + 63) / 64
+    }
+
+    const fn summary_words(levels: u8) -> usize {
+        (LeafBitmap::words(levels) + 63) / 64
+    }
+
+    /// How many bytes of external storage the bitmap of a tree needs, including enough to align
+    /// the words.
+    const fn storage_len(levels: u8) -> usize {
+        (LeafBitmap::summary_words(levels) + LeafBitmap::words(levels)) * mem::size_of::<u64>()
+            + mem::align_of::<u64>()
+            - 1
+    }
+
+    /// Split contiguous words into the two levels. Any bits are valid, and they are cleared by
+    /// [LeafBitmap::reset] before being read.
+    fn from_words(words: &'static mut [u64], levels: u8, owns_words: bool) -> Self {
+        let (summary, words) = words.split_at_mut(LeafBitmap::summary_words(levels));
+        LeafBitmap {
+            summary,
+            words,
+            owns_words,
+            first_summary: 0,
+        }
+    }
+
+    fn on_heap(levels: u8) -> Self {
+        let words = vec![0; LeafBitmap::summary_words(levels) + LeafBitmap::words(levels)];
+        LeafBitmap::from_words(Box::leak(words.into_boxed_slice()), levels, true)
+    }
+
+    /// A bitmap in the first [LeafBitmap::storage_len] bytes of `storage`, which the caller must
+    /// have checked are there.
+    fn in_storage(storage: &'static mut [u8], levels: u8) -> Self {
+        let align = mem::align_of::<u64>();
+        let skip = (align - storage.as_ptr() as usize % align) % align;
+        let len = LeafBitmap::summary_words(levels) + LeafBitmap::words(levels);
+        let words = storage[skip..].as_mut_ptr() as *mut u64;
+        LeafBitmap::from_words(unsafe { slice::from_raw_parts_mut(words, len) }, levels, false)
+    }
+
+    /// Mark the first `leaves` blocks as free and the rest of the words as not being blocks.
+    fn reset(&mut self, leaves: usize) {
+        for word in self.summary.iter_mut().chain(self.words.iter_mut()) {
+            *word = 0;
+        }
+        self.mark(0, leaves, true);
+    }
+
+    /// Mark the `count` blocks of order 0 beginning at the block `first` as free or not. `count`
+    /// must be a power of two which `first` is aligned to, as it is for the blocks of any order.
     #[inline]
-    pub fn left_child(index: usize) -> usize {
-        index << 1
+    fn mark(&mut self, first: usize, count: usize, free: bool) {
+        if count >= 64 {
+            for index in first / 64..(first + count) / 64 {
+                self.words[index] = if free { !0 } else { 0 };
+                self.mark_summary(index, free);
+            }
+        } else {
+            let index = first / 64;
+            let mask = ((1u64 << count) - 1) << (first % 64);
+            if free {
+                self.words[index] |= mask;
+            } else {
+                self.words[index] &= !mask;
+            }
+
+            let any_free = self.words[index] != 0;
+            self.mark_summary(index, any_free);
+        }
+
+        if free {
+            self.first_summary = cmp::min(self.first_summary, first / (64 * 64));
+        }
     }
 
     #[inline]
-    pub fn parent(index: usize) -> usize {
-        index >> 1
+    fn mark_summary(&mut self, index: usize, any_free: bool) {
+        let bit = 1 << (index % 64);
+        if any_free {
+            self.summary[index / 64] |= bit;
+        } else {
+            self.summary[index / 64] &= !bit;
+        }
     }
-}
 
-pub fn demo(print_addresses: bool, blocks: u32, order: u8) -> Duration {
-    let num_trees = ((blocks as f32) / (Tree::blocks_in_level(MAX_ORDER - order) as f32)).ceil() as usize;
+    /// The lowest block of order 0 which is free.
+    #[inline]
+    fn first_free(&mut self) -> Option<usize> {
+        while let Some(&summary) = self.summary.get(self.first_summary) {
+            if summary != 0 {
+                let index = self.first_summary * 64 + summary.trailing_zeros() as usize;
+                return Some(index * 64 + self.words[index].trailing_zeros() as usize);
+            }
+            self.first_summary += 1;
+        }
 
-    let mut trees = Vec::with_capacity(num_trees);
-    for _ in 0..num_trees {
-        trees.push(Tree::new());
+        None
     }
 
-    let start = Instant::now();
-    let mut current_tree = 0;
+    fn is_free(&self, leaf: usize) -> bool {
+        self.words[leaf / 64] & (1 << (leaf % 64)) != 0
+    }
 
-    for _ in 0..blocks {
-        let addr = match trees[current_tree].alloc_exact(order) {
-            Some(addr) => addr,
-            None => {
-                current_tree += 1;
-                trees[current_tree].alloc_exact(order).unwrap()
-            }
-        };
+    fn metadata_bytes(&self) -> usize {
+        (self.summary.len() + self.words.len()) * mem::size_of::<u64>()
+    }
+}
 
-        if print_addresses {
-            println!("Address: {:#x}", addr as usize);
+#[cfg(feature = "leaf_bitmap")]
+impl Drop for LeafBitmap {
+    fn drop(&mut self) {
+        if self.owns_words {
+            let len = self.summary.len() + self.words.len();
+            let words = mem::replace(&mut self.summary, &mut []).as_mut_ptr();
+            drop(unsafe { Box::from_raw(slice::from_raw_parts_mut(words, len)) });
         }
     }
+}
+
+#[cfg(not(feature = "leaf_bitmap"))]
+impl LeafBitmap {
+    const fn empty() -> Self {
+        LeafBitmap
+    }
+
+    const fn storage_len(_levels: u8) -> usize {
+        0
+    }
+
+    fn on_heap(_levels: u8) -> Self {
+        LeafBitmap
+    }
+
+    fn in_storage(_storage: &'static mut [u8], _levels: u8) -> Self {
+        LeafBitmap
+    }
+
+    fn reset(&mut self, _leaves: usize) {}
+
+    #[inline(always)]
+    fn mark(&mut self, _first: usize, _count: usize, _free: bool) {}
+
+    #[inline(always)]
+    fn first_free(&mut self) -> Option<usize> {
+        None
+    }
 
-    start.elapsed()
+    fn metadata_bytes(&self) -> usize {
+        0
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use std::collections::BTreeSet;
-    use super::*;
+/// A copy of the blocks of a tree, taken at the end of every public call which changes them and
+/// compared against them at the start of the next, so that a stray write into the tree is caught
+/// by the next call rather than handing out memory which is in use. Comparing against a copy is as
+/// fast as recomputing a checksum, as both read every block, and it gives the value the corrupted
+/// block should have. With the `paranoid` feature disabled it is zero sized and does nothing.
+#[cfg(feature = "paranoid")]
+struct Seal {
+    /// The `order_free` of every block when the tree was sealed, hot blocks first
+    expected: Vec<u8>,
+}
 
-    #[test]
-    fn test_flat_tree_fns() {
-        use super::flat_tree::*;
-        //    1
-        //  2   3
-        // 4 5 6 7
-        assert_eq!(left_child(1), 2);
-        assert_eq!(parent(2), 1);
+#[cfg(not(feature = "paranoid"))]
+struct Seal;
+
+#[cfg(feature = "paranoid")]
+impl Seal {
+    const fn empty() -> Self {
+        Seal {
+            expected: Vec::new(),
+        }
     }
 
-    #[test]
-    fn test_blocks_in_tree() {
-        assert_eq!(Tree::blocks_in_tree(3), 1 + 2 + 4);
-        assert_eq!(Tree::blocks_in_tree(1), 1);
+    fn seal(&mut self, blocks: &BlockStorage) {
+        let (hot, cold) = blocks.as_bytes();
+        self.expected.clear();
+        self.expected.extend_from_slice(hot);
+        self.expected.extend_from_slice(cold);
     }
 
-    #[test]
-    fn test_tree_runs_out_of_blocks() {
-        let mut tree = Tree::new();
-        let max_blocks = Tree::blocks_in_level(MAX_ORDER);
-        for _ in 0..max_blocks {
-            assert_ne!(tree.alloc_exact(0), None);
+    /// # Panicking
+    ///
+    /// Panics with the 1 indexed node index and the expected and actual `order_free` of the first
+    /// block which has changed since the tree was sealed.
+    fn verify(&self, blocks: &BlockStorage) {
+        let (hot, cold) = blocks.as_bytes();
+        let (expected_hot, expected_cold) = self.expected.split_at(hot.len());
+        if expected_hot == hot && expected_cold == cold {
+            return;
         }
 
-        assert_eq!(tree.alloc_exact(0), None);
+        let (index, expected, actual) = self.expected
+            .iter()
+            .zip(hot.iter().chain(cold))
+            .enumerate()
+            .find(|&(_, (expected, actual))| expected != actual)
+            .map(|(position, (&expected, &actual))| {
+                blocks.changed_block(position, expected, actual)
+            })
+            .expect("The tree changed size after being sealed!");
+
+        panic!(
+            "Tree metadata corrupted at node {}: expected order_free {}, found {}!",
+            index + 1,
+            expected,
+            actual
+        );
     }
 
-    #[test]
-    fn test_init_tree() {
-        let tree = Tree::new();
+    fn metadata_bytes(&self) -> usize {
+        self.expected.capacity()
+    }
+}
 
-        // Highest level has 1 block, next has 2, next 4
-        assert_eq!(tree.flat_blocks[0].order_free, 19);
+#[cfg(not(feature = "paranoid"))]
+impl Seal {
+    const fn empty() -> Self {
+        Seal
+    }
 
-        assert_eq!(tree.flat_blocks[1].order_free, 18);
-        assert_eq!(tree.flat_blocks[2].order_free, 18);
+    #[inline(always)]
+    fn seal(&mut self, _blocks: &BlockStorage) {}
 
-        assert_eq!(tree.flat_blocks[3].order_free, 17);
-        assert_eq!(tree.flat_blocks[4].order_free, 17);
-        assert_eq!(tree.flat_blocks[5].order_free, 17);
-        assert_eq!(tree.flat_blocks[6].order_free, 17);
+    #[inline(always)]
+    fn verify(&self, _blocks: &BlockStorage) {}
+
+    fn metadata_bytes(&self) -> usize {
+        0
     }
+}
 
-    #[test]
-    fn test_alloc_exact() {
-        let mut tree = Tree::new();
-        tree.alloc_exact(3).unwrap();
+/// A tree of blocks. Contains the flat representation of the tree as a flat array
+pub struct Tree<B: BaseOrder = DefaultBaseOrder> {
+    /// Flat array representation of tree. Used with the help of the `flat_tree` crate.
+    flat_blocks: BlockStorage,
+    /// Whether `flat_blocks.cold` is a leaked box which must be freed when the tree is dropped
+    owns_blocks: bool,
+    /// Which blocks of order 0 can be allocated, kept in step with `flat_blocks` by every operation
+    leaves: LeafBitmap,
+    /// Checked at the start of every public call which changes the blocks, and taken again at the
+    /// end
+    seal: Seal,
+    /// The number of levels the tree was configured with, at most [LEVEL_COUNT], or 0 if the tree
+    /// has not been initialized.
+    levels: u8,
+    /// The address of the first byte of the tree. Addresses returned by the tree are offset by it
+    /// so that several trees can be used together without handing out the same block twice.
+    base_address: usize,
+    usage: Usage,
+    /// The 1 indexed node indices of the blocks which were reserved rather than allocated, in
+    /// ascending order. They look exactly like allocated blocks, so frees of them are checked here.
+    reserved: Vec<usize>,
+    counters: OpCounters,
+    observer: ObserverSlot,
+    latencies: Latencies,
+    base_order: PhantomData<B>,
+}
 
-        tree = Tree::new();
-        assert_eq!(tree.alloc_exact(MAX_ORDER - 1), Some(0x0 as *const u8));
-        assert_eq!(
-            tree.alloc_exact(MAX_ORDER - 1),
-            Some((2usize.pow(MAX_ORDER_SIZE as u32) / 2) as *const u8)
-        );
-        assert_eq!(tree.alloc_exact(0), None);
-        assert_eq!(tree.alloc_exact(MAX_ORDER - 1), None);
+impl Tree {
+    const fn blocks_in_tree(levels: u8) -> usize {
+        ((1 << levels) - 1) as usize
+    }
 
-        tree = Tree::new();
-        assert_eq!(tree.alloc_exact(MAX_ORDER), Some(0x0 as *const u8));
-        assert_eq!(tree.alloc_exact(MAX_ORDER), None);
+    pub fn new() -> Tree {
+        Tree::new_at(0)
     }
 
-    #[test]
-    fn test_alloc_unique_addresses() {
-        let max_blocks = Tree::blocks_in_level(MAX_ORDER);
-        let mut seen = BTreeSet::new();
-        let mut tree = Tree::new();
+    /// Create a tree whose blocks begin at `base_address`, which must be aligned to the size of a
+    /// block of [MAX_ORDER].
+    pub fn new_at(base_address: usize) -> Tree {
+        Tree::with_base_order_at(base_address)
+    }
 
-        for _ in 0..max_blocks {
-            let addr = tree.alloc_exact(0).unwrap();
+    /// Create a tree like [Tree::new] whose blocks are kept in external storage rather than on the
+    /// heap, e.g. a static array in a kernel which has no heap yet. See [Tree::init_in].
+    pub fn new_in(storage: &'static mut [u8]) -> Result<Tree, TreeInitError> {
+        let mut tree = Tree::empty();
+        tree.init_in(storage, 0, LEVEL_COUNT)?;
+        Ok(tree)
+    }
 
-            if seen.contains(&addr) {
-                panic!("Allocator must return addresses never been allocated before!");
-            } else {
-                seen.insert(addr);
+    /// Create a tree with `levels` levels of blocks rather than [LEVEL_COUNT], beginning at address
+    /// 0, e.g. to compare smaller trees with the full size or to check every address of a toy
+    /// tree. Orders above `levels - 1` are never allocated.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if `levels` is 0 or more than [LEVEL_COUNT].
+    pub fn with_levels(levels: u8) -> Tree {
+        Tree::with_levels_at(levels, 0)
+    }
+
+    /// A tree with no storage, which can be built in a `static` and given its storage later with
+    /// [Tree::init] or [Tree::init_in]. Until then, allocating from the tree fails and freeing to
+    /// it frees nothing.
+    pub const fn empty() -> Tree {
+        Tree::empty_with_base_order()
+    }
+
+    /// How many bytes of storage [Tree::init_in] needs for a tree with the given number of levels,
+    /// which includes the leaf bitmap with the `leaf_bitmap` feature.
+    pub const fn storage_len(levels: u8) -> usize {
+        BlockStorage::storage_len(levels) + LeafBitmap::storage_len(levels)
+    }
+
+    /// How many blocks of the base order (order 0) fit in a single block of the given order. Returns
+    /// `None` if the order is larger than [MAX_ORDER].
+    pub fn base_blocks_per_block(order: u8) -> Option<usize> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        1usize.checked_shl(u32::from(order))
+    }
+
+    /// How many blocks of the given order a whole tree is made up of. Returns `None` if the order is
+    /// larger than [MAX_ORDER].
+    pub fn blocks_of_order_in_tree(order: u8) -> Option<usize> {
+        1usize.checked_shl(u32::from(MAX_ORDER.checked_sub(order)?))
+    }
+
+    /// Save the blocks, reserved memory and usage of the tree. Counters, latencies and the observer
+    /// are not saved.
+    pub fn to_snapshot(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new(SNAPSHOT_MAGIC);
+        writer.u8(self.levels);
+        writer.u64(self.base_address as u64);
+        writer.usage(&self.usage);
+        writer.u64(self.reserved.len() as u64);
+        for &node_index in &self.reserved {
+            writer.u64(node_index as u64);
+        }
+
+        let blocks: Vec<u8> = self.flat_blocks.order_frees().collect();
+        writer.bytes(&blocks);
+        writer.finish()
+    }
+
+    /// Restore a tree saved by [to_snapshot]. Every block is checked to agree with its children,
+    /// and the usage to agree with the used blocks.
+    pub fn from_snapshot(bytes: &[u8]) -> Result<Tree, SnapshotError> {
+        let mut reader = SnapshotReader::new(bytes, SNAPSHOT_MAGIC)?;
+
+        let levels = reader.u8()?;
+        if levels == 0 || levels > LEVEL_COUNT {
+            return Err(SnapshotError::InvalidField { field: "levels" });
+        }
+
+        let size = block_size(levels - 1);
+        let base_address = reader.usize("base address")?;
+        if base_address & (size - 1) != 0 || base_address.checked_add(size).is_none() {
+            return Err(SnapshotError::InvalidField { field: "base address" });
+        }
+
+        let usage = reader.usage()?;
+        let reserved_count = reader.usize("reserved blocks")?;
+        let mut reserved = Vec::new();
+        for _ in 0..reserved_count {
+            reserved.push(reader.usize("reserved blocks")?);
+        }
+        let encoded = reader.bytes(Tree::blocks_in_tree(levels))?;
+        reader.finish()?;
+
+        // No block can have more free than its own order, which also keeps every block small
+        // enough for packed storage
+        let mut tree = Tree::with_levels_at(levels, base_address);
+        for (index, &order_free) in encoded.iter().enumerate() {
+            if order_free > tree.node_order(index + 1) + 1 {
+                return Err(SnapshotError::InvalidBlock { index });
             }
+            tree.flat_blocks.set(index, order_free);
         }
+        tree.rebuild_leaves();
+        tree.check_blocks()
+            .map_err(|index| SnapshotError::InvalidBlock { index })?;
+
+        let mut used_bytes = 0;
+        tree.for_each_block(&mut |block| {
+            if block.used {
+                used_bytes += block.size();
+            }
+        });
+
+        // Reserved blocks must be distinct blocks which look allocated
+        let in_order = reserved.windows(2).all(|pair| pair[0] < pair[1]);
+        let valid = |&node_index: &usize| {
+            node_index != 0
+                && node_index <= Tree::blocks_in_tree(levels)
+                && tree.is_allocated(node_index, tree.node_order(node_index))
+        };
+        if !in_order || !reserved.iter().all(valid) {
+            return Err(SnapshotError::InvalidField { field: "reserved blocks" });
+        }
+
+        tree.reserved = reserved;
+        if tree.reserved_bytes().checked_add(usage.used_bytes()) != Some(used_bytes) {
+            return Err(SnapshotError::InvalidField { field: "used bytes" });
+        }
+
+        tree.usage = usage;
+        tree.seal.seal(&tree.flat_blocks);
+        Ok(tree)
+    }
+
+    /// The tree as bytes, e.g. to checkpoint it between the phases of a benchmark. The same as
+    /// [to_snapshot].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_snapshot()
+    }
+
+    /// Restore a tree saved by [to_bytes], rejecting any block which does not agree with its
+    /// children as [from_snapshot] does.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Tree, SnapshotError> {
+        Tree::from_snapshot(bytes)
+    }
+}
+
+impl<B: BaseOrder> Tree<B> {
+    /// Create a tree with the base order `B` whose blocks begin at `base_address`, which must be
+    /// aligned to the size of its blocks of [MAX_ORDER].
+    pub fn with_base_order_at(base_address: usize) -> Self {
+        Self::with_levels_at(LEVEL_COUNT, base_address)
+    }
+
+    fn with_levels_at(levels: u8, base_address: usize) -> Self {
+        let mut tree = Self::empty_with_base_order();
+        if let Err(err) = tree.init(base_address, levels) {
+            panic!("Could not create tree: {:?}", err);
+        }
+        tree
+    }
+
+    /// A tree with the base order `B` and no storage, like [Tree::empty].
+    pub const fn empty_with_base_order() -> Self {
+        Tree {
+            flat_blocks: BlockStorage::empty(),
+            owns_blocks: false,
+            leaves: LeafBitmap::empty(),
+            seal: Seal::empty(),
+            levels: 0,
+            base_address: 0,
+            usage: Usage::new(),
+            reserved: Vec::new(),
+            counters: OpCounters::new(),
+            observer: ObserverSlot::new(),
+            latencies: Latencies::new(),
+            base_order: PhantomData,
+        }
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.levels != 0
+    }
+
+    /// Whether a block of the given order could be allocated from the tree
+    fn has_free(&self, order: u8) -> bool {
+        // The root's order_free is 1 more than the largest free order, and 0 if none is free
+        self.is_initialized() && unsafe { self.order_free(0) } > order
+    }
+
+    /// Give an empty tree storage on the heap, with `levels` levels of blocks beginning at
+    /// `base_address`.
+    pub fn init(&mut self, base_address: usize, levels: u8) -> Result<(), TreeInitError> {
+        self.init_unfilled(base_address, levels)?;
+        self.fill(base_address, levels);
+        Ok(())
+    }
+
+    /// Give an empty tree storage on the heap like [Tree::init], without filling its blocks.
+    fn init_unfilled(&mut self, base_address: usize, levels: u8) -> Result<(), TreeInitError> {
+        self.check_init(base_address, levels)?;
+
+        self.flat_blocks = BlockStorage::on_heap(levels);
+        self.owns_blocks = true;
+        self.leaves = LeafBitmap::on_heap(levels);
+        Ok(())
+    }
+
+    /// Give an empty tree external storage, e.g. a static array in a kernel which has no heap yet,
+    /// with `levels` levels of blocks beginning at `base_address`. Only the first
+    /// [Tree::storage_len] bytes of the storage are used.
+    pub fn init_in(
+        &mut self,
+        storage: &'static mut [u8],
+        base_address: usize,
+        levels: u8,
+    ) -> Result<(), TreeInitError> {
+        self.check_init(base_address, levels)?;
+
+        let needed = Tree::storage_len(levels);
+        if storage.len() < needed {
+            return Err(TreeInitError::StorageTooSmall { needed });
+        }
+
+        let (blocks, leaves) = storage.split_at_mut(BlockStorage::storage_len(levels));
+        self.flat_blocks = BlockStorage::in_storage(blocks, levels);
+        self.owns_blocks = false;
+        self.leaves = LeafBitmap::in_storage(leaves, levels);
+        self.fill(base_address, levels);
+        Ok(())
+    }
+
+    fn check_init(&self, base_address: usize, levels: u8) -> Result<(), TreeInitError> {
+        if self.is_initialized() {
+            return Err(TreeInitError::AlreadyInitialized);
+        }
+
+        // Addresses are computed in a usize, so the size of the top block must fit in one
+        let usize_bits = mem::size_of::<usize>() * 8;
+        let fits = |levels: u8| ((levels - 1 + B::BASE_ORDER) as usize) < usize_bits;
+        if levels == 0 || levels > LEVEL_COUNT || !fits(levels) {
+            return Err(TreeInitError::InvalidLevels { levels });
+        }
+
+        Self::check_base_address(base_address, levels)
+    }
+
+    fn check_base_address(base_address: usize, levels: u8) -> Result<(), TreeInitError> {
+        let size = block_size_in::<B>(levels - 1);
+        if base_address & (size - 1) != 0 || base_address.checked_add(size).is_none() {
+            return Err(TreeInitError::InvalidBaseAddress { base_address });
+        }
+
+        Ok(())
+    }
+
+    /// Whether the whole tree is one free block, with nothing in it allocated or reserved.
+    pub fn is_completely_free(&self) -> bool {
+        self.is_initialized() && unsafe { self.order_free(0) } == self.levels
+    }
+
+    /// Whether `addr` lies in the top block of the tree.
+    pub fn contains(&self, addr: *const u8) -> bool {
+        self.is_initialized()
+            && (addr as usize)
+                .checked_sub(self.base_address)
+                .is_some_and(|offset| offset < self.top_level_size())
+    }
+
+    /// The size of the top block of the tree, or 0 if it has not been initialized.
+    fn top_level_size(&self) -> usize {
+        if self.is_initialized() {
+            block_size_in::<B>(self.levels - 1)
+        } else {
+            0
+        }
+    }
+
+    /// Move a completely free tree so that its blocks begin at `base_address`, which must be
+    /// aligned to the size of its top block, e.g. to give a tree removed from one forest to
+    /// another at a different address.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the tree is not completely free, as the blocks handed out would move with it.
+    pub fn rebase(&mut self, base_address: usize) -> Result<(), TreeInitError> {
+        assert!(self.is_completely_free(), "Only a completely free tree can be rebased!");
+        Self::check_base_address(base_address, self.levels)?;
+        self.base_address = base_address;
+        Ok(())
+    }
+
+    pub fn base_address(&self) -> usize {
+        self.base_address
+    }
+
+    /// Mark every block of the storage as completely free.
+    fn fill(&mut self, base_address: usize, levels: u8) {
+        #[cfg(not(feature = "rayon"))]
+        self.fill_levels(levels);
+        #[cfg(feature = "rayon")]
+        self.fill_levels_parallel(levels);
+
+        self.finish_fill(base_address, levels);
+    }
+
+    /// Mark the top level of the storage as completely free, leaving the rest for a [LazyTree] to
+    /// fill.
+    fn fill_top(&mut self, base_address: usize, levels: u8) {
+        self.flat_blocks.fill_level(0, levels);
+        self.finish_fill(base_address, levels);
+    }
+
+    fn finish_fill(&mut self, base_address: usize, levels: u8) {
+        self.leaves.reset(1 << (levels - 1));
+        self.levels = levels;
+        self.base_address = base_address;
+        self.seal.seal(&self.flat_blocks);
+    }
+
+    /// Set every block of the first `levels` levels to completely free. Every block of a level is
+    /// the same, so each level is filled as one slice rather than a block at a time.
+    #[cfg(any(not(feature = "rayon"), test))]
+    fn fill_levels(&mut self, levels: u8) {
+        for level in 0..levels {
+            self.flat_blocks.fill_level(level, levels - level);
+        }
+    }
+
+    /// Set every block of the first `levels` levels to completely free like [Tree::fill_levels],
+    /// but with the levels below the hot blocks split into chunks which are filled in parallel.
+    #[cfg(feature = "rayon")]
+    fn fill_levels_parallel(&mut self, levels: u8) {
+        let hot_levels = cmp::min(levels, HOT_LEVELS);
+        for level in 0..hot_levels {
+            self.flat_blocks.fill_level(level, levels - level);
+        }
+
+        for level in hot_levels..levels {
+            self.flat_blocks.fill_level_parallel(level, levels - level);
+        }
+    }
+
+    /// Set the `order_free` of the block at `index` (0 indexed).
+    #[inline]
+    unsafe fn set_order_free(&mut self, index: usize, order_free: u8) {
+        debug_assert!(index < self.flat_blocks.len());
+        self.flat_blocks.set_unchecked(index, order_free)
+    }
+
+    /// The `order_free` of the block at `index` (0 indexed).
+    #[inline]
+    unsafe fn order_free(&self, index: usize) -> u8 {
+        debug_assert!(index < self.flat_blocks.len());
+        self.flat_blocks.get_unchecked(index)
+    }
+
+    #[inline]
+    unsafe fn set_node_order_free(&mut self, node: NodeIndex, order_free: u8) {
+        self.set_order_free(node.flat(), order_free)
+    }
+
+    #[inline]
+    unsafe fn node_order_free(&self, node: NodeIndex) -> u8 {
+        self.order_free(node.flat())
+    }
+
+    /// Hint to the CPU that the block at `index` (0 indexed) is about to be read, so that its cache
+    /// line can be loaded while the descent works on the level above. Only does anything with the
+    /// `prefetch` feature on x86 CPUs with SSE, and never faults, even outside of the tree.
+    #[inline(always)]
+    fn prefetch(&self, index: usize) {
+        #[cfg(all(
+            feature = "prefetch",
+            any(target_arch = "x86", target_arch = "x86_64"),
+            target_feature = "sse"
+        ))]
+        {
+            #[cfg(target_arch = "x86")]
+            use std::arch::x86::{_mm_prefetch, _MM_HINT_T0};
+            #[cfg(target_arch = "x86_64")]
+            use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+
+            // Safe because a prefetch is only a hint, and does not dereference the pointer
+            unsafe { _mm_prefetch(self.flat_blocks.ptr(index) as *const i8, _MM_HINT_T0) };
+        }
+
+        #[cfg(not(all(
+            feature = "prefetch",
+            any(target_arch = "x86", target_arch = "x86_64"),
+            target_feature = "sse"
+        )))]
+        let _ = index;
+    }
+
+    /// Walk down from the root to a free block of the desired order, which the root must have.
+    /// Returns the block's (1 indexed) node index and address, and the first level at which the
+    /// block on the way down was completely free, or the level of the block if none was.
+    ///
+    /// Which child to move to depends on the state of the tree, so it is chosen without a branch:
+    /// a fragmented tree makes it impossible to predict.
+    #[inline]
+    fn descend(&self, desired_order: u8) -> (usize, usize, u8) {
+        let top_order = self.levels - 1;
+        let max_level = top_order - desired_order;
+
+        let mut addr = self.base_address;
+        let mut node = NodeIndex::ROOT;
+        let mut splitting = false;
+        let mut split_levels = 0;
+
+        for level in 0..max_level {
+            let order = top_order - level;
+            let order_free = unsafe { self.node_order_free(node) };
+            splitting |= order_free == order + 1;
+            split_levels += splitting as u8;
+
+            // Due to the +1 offset, the left child has the desired order free if o - 1 >=
+            // desired_order, i.e o > desired_order. If it does not, the right child must, or the
+            // parent does not uphold the invariants.
+            let left_child = node.left_child();
+            let o = unsafe { self.node_order_free(left_child) };
+            let go_right = o <= desired_order;
+
+            // Moving right from the left child increases the address by the size of the left child,
+            // which is one order below the block at this level
+            node = NodeIndex::new(left_child.get() ^ go_right as usize);
+            addr += go_right as usize * block_size_in::<B>(order - 1);
+
+            // The children of the new node are already in the line being read, but its
+            // grandchildren, read at the level after next, are usually in another one
+            self.prefetch(node.left_child().left_child().flat());
+        }
+
+        (node.get(), addr, max_level - split_levels)
+    }
+
+    /// Find the lowest free block of order 0 in the leaf bitmap rather than descending the tree,
+    /// returning the same as [Tree::descend]. Returns `None` for any other order, and always
+    /// without the `leaf_bitmap` feature.
+    #[inline]
+    fn free_leaf(&mut self, desired_order: u8) -> Option<(usize, usize, u8)> {
+        if desired_order != 0 {
+            return None;
+        }
+
+        let leaf = self.leaves.first_free()?;
+        let top_order = self.levels - 1;
+        let node = NodeIndex::at(top_order, leaf);
+        debug_assert!(self.path_is_free(node.get()), "Leaf {} is not free in the tree!", leaf);
+
+        // The blocks on the way down which are split are the completely free ones, which are all
+        // directly above the leaf
+        let mut split_levels = 0;
+        let mut ancestor = node;
+        for order in 1..=top_order {
+            ancestor = ancestor.parent();
+            if unsafe { self.node_order_free(ancestor) } != order + 1 {
+                break;
+            }
+            split_levels += 1;
+        }
+
+        let addr = self.base_address + leaf * block_size_in::<B>(0);
+        Some((node.get(), addr, top_order - split_levels))
+    }
+
+    /// Whether neither the block at the given 1 indexed node index nor any block above it is used.
+    fn path_is_free(&self, mut node_index: usize) -> bool {
+        while node_index != 0 {
+            if unsafe { self.order_free(node_index - 1) } == 0 {
+                return false;
+            }
+            node_index = flat_tree::parent(node_index);
+        }
+
+        true
+    }
+
+    /// The index in the leaf bitmap of the block of order 0 beginning at `addr`.
+    #[inline]
+    fn leaf_of(&self, addr: usize) -> usize {
+        (addr - self.base_address) >> B::BASE_ORDER
+    }
+
+    /// Does nothing, as allocating from a pristine tree descends the same levels as any other
+    /// allocation and so has no splits to be paid for ahead of time. It is here so that the tree
+    /// can be warmed up like the other allocators.
+    pub fn warm_up(&mut self, _order: u8, _count: usize) {}
+
+    pub fn alloc_exact(&mut self, desired_order: u8) -> Option<*const u8> {
+        self.seal.verify(&self.flat_blocks);
+        let timer = OpTimer::start();
+        let addr = self.alloc_exact_untimed(desired_order).map(|(_, addr)| addr as *const u8);
+        self.latencies.record(timer);
+        self.seal.seal(&self.flat_blocks);
+        addr
+    }
+
+    /// Allocate a block of exactly the given order like [Tree::alloc_exact], but return a handle
+    /// to its node rather than its address. The address can be found with [Tree::handle_address].
+    pub fn alloc_exact_handle(&mut self, desired_order: u8) -> Option<BlockHandle> {
+        self.seal.verify(&self.flat_blocks);
+        let timer = OpTimer::start();
+        let handle = self.alloc_exact_untimed(desired_order)
+            .map(|(node_index, _)| BlockHandle::new(node_index, desired_order));
+        self.latencies.record(timer);
+        self.seal.seal(&self.flat_blocks);
+        handle
+    }
+
+    /// Allocate a block of exactly the given order, returning its 1 indexed node index and address.
+    fn alloc_exact_untimed(&mut self, desired_order: u8) -> Option<(usize, usize)> {
+        if !self.is_initialized() {
+            return None;
+        }
+
+        let root = unsafe { self.order_free(0) };
+
+        // If the root node has no orders free, or if it does not have the desired order free
+        if root == 0 || (root - 1) < desired_order {
+            return None;
+        }
+
+        let top_order = self.levels - 1;
+        if desired_order == top_order {
+            return Some(self.alloc_root());
+        }
+
+        let max_level = top_order - desired_order;
+        let (node_index, addr, first_split) = match self.free_leaf(desired_order) {
+            Some(found) => found,
+            None => self.descend(desired_order),
+        };
+
+        // The first completely free block on the way down is split down to the desired order, and
+        // every block below it is on its left hand side, so all of them begin at the same address
+        for level in first_split..max_level {
+            self.counters.splits[(top_order - level) as usize] += 1;
+            self.observer.notify(AllocEvent::Split {
+                addr,
+                order: top_order - level,
+            });
+        }
+
+        let target = NodeIndex::new(node_index);
+        unsafe { self.set_node_order_free(target, 0) };
+        let leaf = self.leaf_of(addr);
+        self.leaves.mark(leaf, 1 << desired_order, false);
+
+        // Iterate upwards and set parents accordingly
+        let mut node = target;
+        for _ in 0..max_level {
+            node = node.parent();
+
+            let left = unsafe { self.node_order_free(node.left_child()) };
+            let right = unsafe { self.node_order_free(node.right_child()) };
+
+            unsafe { self.set_node_order_free(node, cmp::max(left, right)) };
+        }
+
+        self.usage.allocated_bytes(block_size_in::<B>(desired_order));
+        self.counters.allocations[desired_order as usize] += 1;
+        self.observer.notify(AllocEvent::Alloc {
+            addr,
+            order: desired_order,
+        });
+        Some((target.get(), addr))
+    }
+
+    /// Allocate the whole tree, which must be completely free, returning the root's 1 indexed node
+    /// index and address. Only the root is marked used, as nothing descends past a used block, so
+    /// the blocks below it are left completely free for when the root is freed again.
+    fn alloc_root(&mut self) -> (usize, usize) {
+        let top_order = self.levels - 1;
+        let addr = self.base_address;
+
+        unsafe { self.set_order_free(0, 0) };
+        self.leaves.mark(0, 1 << top_order, false);
+
+        self.usage.allocated_bytes(block_size_in::<B>(top_order));
+        self.counters.allocations[top_order as usize] += 1;
+        self.observer.notify(AllocEvent::Alloc {
+            addr,
+            order: top_order,
+        });
+        (1, addr)
+    }
+
+    /// Allocate a block of the given order which lies entirely below `limit`, e.g. for a device
+    /// which can only address the low 16 MiB. The lowest such block is chosen.
+    pub fn alloc_below(&mut self, order: u8, limit: usize) -> Result<usize, BlockAllocateError> {
+        self.seal.verify(&self.flat_blocks);
+        let timer = OpTimer::start();
+        let addr = self.alloc_in_range_untimed(order, 0, limit);
+        self.latencies.record(timer);
+        self.seal.seal(&self.flat_blocks);
+        addr
+    }
+
+    /// Allocate a block of exactly the given order which lies entirely below `limit`, like
+    /// [Tree::alloc_below] but returning its address in the same way as [Tree::alloc_exact].
+    /// Returns `None` if there is no such block, leaving the tree untouched.
+    pub fn alloc_exact_below(&mut self, order: u8, limit: usize) -> Option<*const u8> {
+        self.alloc_below(order, limit).ok().map(|addr| addr as *const u8)
+    }
+
+    /// Allocate a block of exactly the given order which lies entirely inside `[low, high)`, e.g.
+    /// for a device window. Free blocks straddling either end of the range are never chosen. The
+    /// lowest such block is chosen, and `None` returned if there is none.
+    pub fn alloc_exact_in_range(
+        &mut self,
+        order: u8,
+        low: usize,
+        high: usize,
+    ) -> Option<*const u8> {
+        self.seal.verify(&self.flat_blocks);
+        let timer = OpTimer::start();
+        let addr = self.alloc_in_range_untimed(order, low, high);
+        self.latencies.record(timer);
+        self.seal.seal(&self.flat_blocks);
+        addr.ok().map(|addr| addr as *const u8)
+    }
+
+    fn alloc_in_range_untimed(
+        &mut self,
+        order: u8,
+        low: usize,
+        high: usize,
+    ) -> Result<usize, BlockAllocateError> {
+        if order > MAX_ORDER || !self.is_initialized() || order > self.levels - 1 {
+            return Err(BlockAllocateError::OrderTooLarge {
+                order,
+                max_order: cmp::min(MAX_ORDER, self.levels.saturating_sub(1)),
+            });
+        }
+
+        let (node_index, addr) = self
+            .find_in_range(order, low, high)
+            .ok_or(BlockAllocateError::NoBlocksAvailable)?;
+        self.take(node_index, addr, order);
+        Ok(addr)
+    }
+
+    /// Search for the lowest free block of the given order which lies inside `[low, high)`,
+    /// returning its 1 indexed node index and address. Subtrees with no block of the order inside
+    /// the range are never entered.
+    fn find_in_range(&self, order: u8, low: usize, high: usize) -> Option<(usize, usize)> {
+        let size = block_size_in::<B>(order);
+        let first_addr = low.checked_add(size - 1)? & !(size - 1);
+        let last_addr = high.checked_sub(size)?;
+        if first_addr > last_addr {
+            return None;
+        }
+
+        // Depth first, left first. At most one right sibling is pending per level, plus the node
+        // being visited. (node index, order, address)
+        let mut stack = [(0, 0, 0); LEVEL_COUNT as usize + 1];
+        stack[0] = (1, self.levels - 1, self.base_address);
+        let mut len = 1;
+
+        while len > 0 {
+            len -= 1;
+            let (node_index, node_order, addr) = stack[len];
+            let order_free = unsafe { self.order_free(node_index - 1) };
+
+            // `order_free` is the largest free order + 1
+            let last_in_node = addr + (block_size_in::<B>(node_order) - size);
+            if order_free <= order || addr > last_addr || last_in_node < first_addr {
+                continue;
+            }
+
+            if node_order == order {
+                return Some((node_index, addr));
+            }
+
+            let left_child_index = flat_tree::left_child(node_index);
+            let child_order = node_order - 1;
+            let right_addr = addr + block_size_in::<B>(child_order);
+            stack[len] = (left_child_index + 1, child_order, right_addr);
+            stack[len + 1] = (left_child_index, child_order, addr);
+            len += 2;
+        }
+
+        None
+    }
+
+    /// Allocate the block of the given order beginning at `addr`, e.g. to keep a kernel loaded at a
+    /// fixed physical address. Free blocks above it are split as [Tree::alloc_exact] would, so the
+    /// rest of their memory can still be allocated.
+    pub fn alloc_at(&mut self, addr: usize, order: u8) -> Result<(), AllocAtError> {
+        self.seal.verify(&self.flat_blocks);
+        let timer = OpTimer::start();
+        let result = self.alloc_at_untimed(addr, order);
+        self.latencies.record(timer);
+        self.seal.seal(&self.flat_blocks);
+        result
+    }
+
+    fn alloc_at_untimed(&mut self, addr: usize, order: u8) -> Result<(), AllocAtError> {
+        if !self.is_initialized() || order > self.levels - 1 {
+            return Err(AllocAtError::OrderTooLarge {
+                order,
+                max_order: self.levels.saturating_sub(1),
+            });
+        }
+
+        let top_order = self.levels - 1;
+        let offset = match addr.checked_sub(self.base_address) {
+            Some(offset) if offset < block_size_in::<B>(top_order) => offset,
+            _ => return Err(AllocAtError::OutOfRange { addr }),
+        };
+
+        if offset & (block_size_in::<B>(order) - 1) != 0 {
+            return Err(AllocAtError::Misaligned { addr, order });
+        }
+
+        // Walk down the path to the block. Below a completely free block every block is free.
+        let level = top_order - order;
+        let target = flat_tree::index_of(level, offset >> (order + B::BASE_ORDER));
+        let mut free = false;
+        for node_level in 0..=level {
+            let node_index = target >> (level - node_level);
+            let node_order = top_order - node_level;
+
+            if self.is_allocated(node_index, node_order) {
+                let size = block_size_in::<B>(node_order);
+                return Err(AllocAtError::Used {
+                    addr: self.base_address + (offset & !(size - 1)),
+                    order: node_order,
+                });
+            }
+
+            if unsafe { self.order_free(node_index - 1) } == node_order + 1 {
+                free = true;
+                break;
+            }
+        }
+
+        if !free {
+            return Err(AllocAtError::PartlyUsed { addr, order });
+        }
+
+        self.take(target, addr, order);
+        Ok(())
+    }
+
+    /// Allocate the free block at the given 1 indexed node index, splitting the free blocks above
+    /// it and updating its ancestors.
+    fn take(&mut self, target: usize, addr: usize, order: u8) {
+        let top_order = self.levels - 1;
+        let max_level = top_order - order;
+
+        // Once a completely free block is reached every block below it on the way down is split
+        let mut splitting = false;
+        for level in 0..max_level {
+            let node_index = target >> (max_level - level);
+            let node_order = top_order - level;
+
+            if !splitting {
+                splitting = unsafe { self.order_free(node_index - 1) } == node_order + 1;
+            }
+
+            if splitting {
+                self.counters.splits[node_order as usize] += 1;
+                self.observer.notify(AllocEvent::Split {
+                    addr: addr & !(block_size_in::<B>(node_order) - 1),
+                    order: node_order,
+                });
+            }
+        }
+
+        unsafe { self.set_order_free(target - 1, 0) };
+        let leaf = self.leaf_of(addr);
+        self.leaves.mark(leaf, 1 << order, false);
+
+        let mut node_index = target;
+        for _ in 0..max_level {
+            let right_index = node_index & !1;
+            node_index = flat_tree::parent(node_index);
+
+            let left = unsafe { self.order_free(right_index - 1) };
+            let right = unsafe { self.order_free(right_index) };
+
+            unsafe { self.set_order_free(node_index - 1, cmp::max(left, right)) };
+        }
+
+        self.usage.allocated_bytes(block_size_in::<B>(order));
+        self.counters.allocations[order as usize] += 1;
+        self.observer.notify(AllocEvent::Alloc { addr, order });
+    }
+
+    /// Free the block of the given order beginning at `addr`, merging it with its buddy for as long
+    /// as the buddy is also completely free. Returns `false` and frees nothing if the address is
+    /// outside of the tree, is not aligned to the order, or the block there is not used.
+    pub fn dealloc_exact(&mut self, addr: *const u8, order: u8) -> bool {
+        self.seal.verify(&self.flat_blocks);
+        let timer = OpTimer::start();
+        let freed = self.dealloc_exact_untimed(addr, order);
+        self.latencies.record(timer);
+        self.seal.seal(&self.flat_blocks);
+        freed
+    }
+
+    fn dealloc_exact_untimed(&mut self, addr: *const u8, order: u8) -> bool {
+        if !self.is_initialized() {
+            return false;
+        }
+
+        let top_order = self.levels - 1;
+        if order > top_order {
+            return false;
+        }
+
+        let offset = match (addr as usize).checked_sub(self.base_address) {
+            Some(offset) if offset < block_size_in::<B>(top_order) => offset,
+            _ => return false,
+        };
+
+        if offset & (block_size_in::<B>(order) - 1) != 0 {
+            return false;
+        }
+
+        let level = top_order - order;
+        let node_index = flat_tree::index_of(level, offset >> (order + B::BASE_ORDER));
+
+        if !self.is_allocated(node_index, order) || self.is_reserved(node_index) {
+            return false;
+        }
+
+        self.free_node(node_index, offset, order);
+        true
+    }
+
+    /// Free a block allocated by [Tree::alloc_exact_handle], merging it like [Tree::dealloc_exact].
+    /// Returns `false` and frees nothing if the handle's node is not in the tree, is not on the
+    /// level of the handle's order, or is not a used block, e.g. because the handle is stale.
+    pub fn dealloc_handle(&mut self, handle: BlockHandle) -> bool {
+        self.seal.verify(&self.flat_blocks);
+        let timer = OpTimer::start();
+        let freed = self.dealloc_handle_untimed(handle);
+        self.latencies.record(timer);
+        self.seal.seal(&self.flat_blocks);
+        freed
+    }
+
+    fn dealloc_handle_untimed(&mut self, handle: BlockHandle) -> bool {
+        let (node_index, order) = (handle.node_index(), handle.order());
+        let offset = match self.handle_offset(handle) {
+            Some(offset) => offset,
+            None => return false,
+        };
+
+        if !self.is_allocated(node_index, order) || self.is_reserved(node_index) {
+            return false;
+        }
+
+        self.free_node(node_index, offset, order);
+        true
+    }
+
+    /// Free the used block beginning at `addr` without being told its order, merging it like
+    /// [Tree::dealloc_exact]. The order is found by descending from the root towards the address
+    /// until the block which was allocated there.
+    pub fn dealloc(&mut self, addr: *const u8) -> Result<(), FreeError> {
+        self.seal.verify(&self.flat_blocks);
+        let timer = OpTimer::start();
+        let result = self.dealloc_untimed(addr);
+        self.latencies.record(timer);
+        self.seal.seal(&self.flat_blocks);
+        result
+    }
+
+    fn dealloc_untimed(&mut self, addr: *const u8) -> Result<(), FreeError> {
+        let (node_index, offset, order) = self.find_allocated(addr)?;
+        self.free_node(node_index, offset, order);
+        Ok(())
+    }
+
+    /// Free the block of the given order beginning at `addr` like [Tree::dealloc_exact], but say
+    /// why nothing was freed, including when a block of another order was allocated there.
+    pub fn dealloc_exact_checked(&mut self, addr: *const u8, order: u8) -> Result<(), FreeError> {
+        self.seal.verify(&self.flat_blocks);
+        let timer = OpTimer::start();
+        let result = self.dealloc_exact_checked_untimed(addr, order);
+        self.latencies.record(timer);
+        self.seal.seal(&self.flat_blocks);
+        result
+    }
+
+    fn dealloc_exact_checked_untimed(
+        &mut self,
+        addr: *const u8,
+        order: u8,
+    ) -> Result<(), FreeError> {
+        if self.dealloc_exact_untimed(addr, order) {
+            return Ok(());
+        }
+
+        // Only a failure needs the slower search for the block at the address
+        let (_, _, allocated_order) = self.find_allocated(addr)?;
+        Err(FreeError::WrongOrder {
+            addr: addr as usize,
+            order: allocated_order,
+        })
+    }
+
+    /// Find the allocated block beginning at `addr` by descending from the root towards it,
+    /// returning its 1 indexed node index, offset and order.
+    fn find_allocated(&self, addr: *const u8) -> Result<(usize, usize, u8), FreeError> {
+        if !self.is_initialized() {
+            return Err(FreeError::OutOfRange { addr: addr as usize });
+        }
+
+        let top_order = self.levels - 1;
+        let offset = match (addr as usize).checked_sub(self.base_address) {
+            Some(offset) if offset < block_size_in::<B>(top_order) => offset,
+            _ => return Err(FreeError::OutOfRange { addr: addr as usize }),
+        };
+        let not_allocated = Err(FreeError::NotAllocated { addr: addr as usize });
+
+        let (mut node_index, mut order) = (1, top_order);
+        loop {
+            let order_free = unsafe { self.order_free(node_index - 1) };
+            if order_free == order + 1 {
+                return not_allocated;
+            }
+
+            if self.is_allocated(node_index, order) {
+                let aligned = offset & (block_size_in::<B>(order) - 1) == 0;
+                if !aligned || self.is_reserved(node_index) {
+                    return not_allocated;
+                }
+
+                return Ok((node_index, offset, order));
+            }
+
+            // Into whichever child holds the address
+            order -= 1;
+            let right = (offset >> (order + B::BASE_ORDER)) & 1;
+            node_index = flat_tree::left_child(node_index) + right;
+        }
+    }
+
+    /// Whether the node at the given 1 indexed node index, on the level of `order`, is a block
+    /// which was allocated. A node is also marked used when both of its children are, but then it
+    /// is not a block which was allocated. Below an allocated block every block is completely free.
+    fn is_allocated(&self, node_index: usize, order: u8) -> bool {
+        let used = unsafe { self.order_free(node_index - 1) } == 0;
+        used && (order == 0
+            || unsafe { self.order_free(flat_tree::left_child(node_index) - 1) } != 0)
+    }
+
+    /// Whether the node at the given 1 indexed node index is a block which was reserved
+    fn is_reserved(&self, node_index: usize) -> bool {
+        self.reserved.binary_search(&node_index).is_ok()
+    }
+
+    /// The order of the blocks on the level of the given 1 indexed node index
+    fn node_order(&self, node_index: usize) -> u8 {
+        self.levels - 1 - flat_tree::level_of(node_index)
+    }
+
+    /// The address of the block of a handle, or `None` if the handle's node is not in the tree or
+    /// is not on the level of its order. Whether the block is used is not checked.
+    pub fn handle_address(&self, handle: BlockHandle) -> Option<*const u8> {
+        self.handle_offset(handle)
+            .map(|offset| (self.base_address + offset) as *const u8)
+    }
+
+    /// The offset into the tree of the block of a handle, if its node is on the level of its order.
+    fn handle_offset(&self, handle: BlockHandle) -> Option<usize> {
+        if !self.is_initialized() || handle.order() > self.levels - 1 {
+            return None;
+        }
+
+        let order = handle.order();
+        if handle.node_index() == 0 {
+            return None;
+        }
+
+        let node = NodeIndex::new(handle.node_index());
+        if node.level() != self.levels - 1 - order {
+            return None;
+        }
+
+        Some(node.offset_in_level() * block_size_in::<B>(order))
+    }
+
+    /// Free the used block of the given order at the given 1 indexed node index, which begins at
+    /// `offset` bytes into the tree, and merge it with its buddies.
+    fn free_node(&mut self, node_index: usize, offset: usize, order: u8) {
+        let top_order = self.levels - 1;
+        let mut node = NodeIndex::new(node_index);
+
+        unsafe { self.set_node_order_free(node, order + 1) };
+        self.leaves.mark(offset >> B::BASE_ORDER, 1 << order, true);
+        self.observer.notify(AllocEvent::Dealloc {
+            addr: self.base_address + offset,
+            order,
+        });
+
+        // Iterate upwards and set parents accordingly. A parent whose children, the node and its
+        // buddy, are both completely free is itself a completely free block of its order.
+        for parent_order in order + 1..=top_order {
+            let own = unsafe { self.node_order_free(node) };
+            let buddy = unsafe { self.node_order_free(node.sibling()) };
+            node = node.parent();
+
+            // A completely free child has `order_free` of (parent_order - 1) + 1
+            let order_free = if own == parent_order && buddy == parent_order {
+                self.counters.merges[parent_order as usize] += 1;
+                self.observer.notify(AllocEvent::Merge {
+                    addr: self.base_address + (offset & !(block_size_in::<B>(parent_order) - 1)),
+                    order: parent_order,
+                });
+                parent_order + 1
+            } else {
+                cmp::max(own, buddy)
+            };
+
+            unsafe { self.set_node_order_free(node, order_free) };
+        }
+
+        self.usage.freed_bytes(block_size_in::<B>(order));
+        self.counters.frees[order as usize] += 1;
+    }
+
+    /// Mark the free block of the given order beginning at `offset` bytes into the tree as used
+    /// without allocating it, so it is never handed out. Returns `false` if the block is not free.
+    fn reserve_block(&mut self, offset: usize, order: u8) -> bool {
+        let top_order = self.levels - 1;
+        let level = top_order - order;
+        let target = flat_tree::index_of(level, offset >> (order + B::BASE_ORDER));
+
+        // Walk down to the block. Below a completely free block every block is completely free.
+        for ancestor_level in 0..level {
+            let node_index = target >> (level - ancestor_level);
+            match unsafe { self.order_free(node_index - 1) } {
+                0 => return false,
+                order_free if order_free == top_order - ancestor_level + 1 => break,
+                _ => {}
+            }
+        }
+
+        if unsafe { self.order_free(target - 1) } != order + 1 {
+            return false;
+        }
+        unsafe { self.set_order_free(target - 1, 0) };
+        self.leaves.mark(offset >> B::BASE_ORDER, 1 << order, false);
+        if let Err(position) = self.reserved.binary_search(&target) {
+            self.reserved.insert(position, target);
+        }
+
+        let mut node_index = target;
+        for _ in 0..level {
+            let right_index = node_index & !1;
+            node_index = flat_tree::parent(node_index);
+
+            let left = unsafe { self.order_free(right_index - 1) };
+            let right = unsafe { self.order_free(right_index) };
+
+            unsafe { self.set_order_free(node_index - 1, cmp::max(left, right)) };
+        }
+
+        true
+    }
+
+    /// Reserve the memory in `[begin, end)` so that it is never handed out, e.g. because it is a
+    /// hole in the physical memory map. Both addresses must be aligned to the size of a block of
+    /// order 0, and parts outside of the tree are ignored. Reserved memory is not counted as
+    /// allocated or managed, but is reported as used by [AllocatorStats::for_each_block].
+    ///
+    /// # Panicking
+    ///
+    /// Panics if any of the memory is not free, or if the tree has not been initialized.
+    pub fn reserve_range(&mut self, begin: usize, end: usize) {
+        assert!(self.is_initialized(), "Cannot reserve memory in an uninitialized tree!");
+        self.seal.verify(&self.flat_blocks);
+        debug_assert_eq!(begin & (block_size_in::<B>(0) - 1), 0, "Reserved range must be aligned!");
+        debug_assert_eq!(end & (block_size_in::<B>(0) - 1), 0, "Reserved range must be aligned!");
+
+        let top_order = self.levels - 1;
+        let tree_end = self.base_address + block_size_in::<B>(top_order);
+        let mut offset = cmp::max(begin, self.base_address) - self.base_address;
+        let end = cmp::min(end, tree_end) - self.base_address;
+
+        // Reserve the largest aligned block which fits each time, so that as few blocks are used
+        while offset < end {
+            let fits = |&order: &u8| {
+                let size = block_size_in::<B>(order);
+                offset & (size - 1) == 0 && offset + size <= end
+            };
+            let order = (0..=top_order).rev().find(fits).unwrap();
+
+            assert!(
+                self.reserve_block(offset, order),
+                "Reserved memory at {:#x} is not free!",
+                self.base_address + offset
+            );
+            offset += block_size_in::<B>(order);
+        }
+
+        self.seal.seal(&self.flat_blocks);
+    }
+
+    /// Reserve the memory in `[start, end)` like [Tree::reserve_range], but without it having to be
+    /// aligned: every block of order 0 which overlaps the range is reserved, e.g. the whole page
+    /// an MMIO register or the end of a kernel image lies in.
+    ///
+    /// # Panicking
+    ///
+    /// Panics in the same cases as [Tree::reserve_range].
+    pub fn reserve(&mut self, start: usize, end: usize) {
+        let page = block_size_in::<B>(0);
+        let end = end.checked_add(page - 1).map_or(!(page - 1), |end| end & !(page - 1));
+        self.reserve_range(start & !(page - 1), end);
+    }
+
+    /// How many bytes were reserved by [Tree::reserve_range]
+    fn reserved_bytes(&self) -> usize {
+        self.reserved
+            .iter()
+            .map(|&node_index| block_size_in::<B>(self.node_order(node_index)))
+            .sum()
+    }
+
+    /// Mark the memory of every used block as not free in the leaf bitmap, whose every block is
+    /// free when the tree is created.
+    fn rebuild_leaves(&mut self) {
+        let mut used = Vec::new();
+        self.visit_blocks(&mut |block| {
+            if block.used {
+                used.push(block);
+            }
+        });
+
+        for block in used {
+            let leaf = self.leaf_of(block.addr);
+            self.leaves.mark(leaf, 1 << block.order, false);
+        }
+    }
+
+    /// Check that the free order of every block agrees with its children, returning the (0 indexed)
+    /// index of a block which does not, as [Tree::check_invariants] does. With the `leaf_bitmap`
+    /// feature, the bitmap is then checked to agree with the blocks.
+    fn check_blocks(&self) -> Result<(), usize> {
+        self.check_invariants()
+            .map_err(|violation| violation.node_index - 1)?;
+        self.check_leaves()
+    }
+
+    /// Check that the free order of every block agrees with its children: it is the larger of
+    /// theirs, or if both are completely free, its own order + 1 (or 0 if it is used). Blocks are
+    /// checked from the leaves up so that the block reported is the lowest one which is
+    /// inconsistent.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        if !self.is_initialized() {
+            return Ok(());
+        }
+
+        let top_order = self.levels - 1;
+
+        for level in (0..self.levels).rev() {
+            let order = top_order - level;
+
+            for node_index in (1 << level)..(1 << (level + 1)) {
+                let order_free = unsafe { self.order_free(node_index - 1) };
+
+                let (valid, children) = if order == 0 {
+                    (order_free <= 1, None)
+                } else {
+                    let left_child_index = flat_tree::left_child(node_index);
+                    let left = unsafe { self.order_free(left_child_index - 1) };
+                    let right = unsafe { self.order_free(left_child_index) };
+
+                    // Completely free children make a completely free block unless it is used
+                    let valid = if left == order && right == order {
+                        order_free == order + 1 || order_free == 0
+                    } else {
+                        order_free == cmp::max(left, right)
+                    };
+                    (valid, Some((left, right)))
+                };
+
+                if !valid {
+                    return Err(InvariantViolation {
+                        node_index,
+                        order_free,
+                        children,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that exactly the blocks of order 0 which can be allocated are marked as free in the
+    /// leaf bitmap, that the summary agrees with the words and that no free block is before the
+    /// first summary word, returning the (0 indexed) index of the lowest block of order 0 which is
+    /// marked wrongly, or the first of those in a word whose summary bit is wrong.
+    #[cfg(feature = "leaf_bitmap")]
+    fn check_leaves(&self) -> Result<(), usize> {
+        let mut blocks = Vec::new();
+        self.visit_blocks(&mut |block| blocks.push(block));
+        blocks.sort_unstable_by_key(|block| block.addr);
+
+        let first_leaf_index = Tree::blocks_in_tree(self.levels - 1);
+        for block in blocks {
+            let first = self.leaf_of(block.addr);
+            for leaf in first..first + (1 << block.order) {
+                let before_hint = leaf / (64 * 64) < self.leaves.first_summary;
+                if self.leaves.is_free(leaf) == block.used || (before_hint && !block.used) {
+                    return Err(first_leaf_index + leaf);
+                }
+            }
+        }
+
+        for (index, &word) in self.leaves.words.iter().enumerate() {
+            let any_free = self.leaves.summary[index / 64] & (1 << (index % 64)) != 0;
+            if any_free != (word != 0) {
+                return Err(first_leaf_index + index * 64);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "leaf_bitmap"))]
+    fn check_leaves(&self) -> Result<(), usize> {
+        Ok(())
+    }
+
+    /// Set the observer which is told about every operation, returning the previous one.
+    pub fn set_observer(&mut self, obs: Box<dyn AllocObserver>) -> Option<Box<dyn AllocObserver>> {
+        self.observer.set(obs)
+    }
+
+    /// Remove the observer, returning it if one was set.
+    pub fn take_observer(&mut self) -> Option<Box<dyn AllocObserver>> {
+        self.observer.take()
+    }
+
+    /// The addresses of the maximal free blocks of exactly the given order, lowest first. These are
+    /// the blocks [Tree::alloc_exact] can return for the order without splitting a larger one, so
+    /// the halves of a larger free block are not included.
+    pub fn free_blocks<'a>(&'a self, order: u8) -> impl Iterator<Item = usize> + 'a {
+        // Only blocks which are neither completely free nor out of free blocks of the order are
+        // entered. (1 indexed node index, order, address)
+        let mut stack = Vec::new();
+        if self.is_initialized() && order < self.levels {
+            stack.push((1, self.levels - 1, self.base_address));
+        }
+
+        iter::from_fn(move || {
+            while let Some((node_index, node_order, addr)) = stack.pop() {
+                let order_free = unsafe { self.order_free(node_index - 1) };
+
+                if node_order == order {
+                    if order_free == order + 1 {
+                        return Some(addr);
+                    }
+                } else if order_free > order && order_free != node_order + 1 {
+                    let left_child_index = flat_tree::left_child(node_index);
+                    let right_addr = addr + block_size_in::<B>(node_order - 1);
+                    stack.push((left_child_index + 1, node_order - 1, right_addr));
+                    stack.push((left_child_index, node_order - 1, addr));
+                }
+            }
+
+            None
+        })
+    }
+
+    /// Call `f` with every maximal free block and every used block, as reported by
+    /// [AllocatorStats::for_each_block] for trees of the crate wide base order.
+    fn visit_blocks(&self, f: &mut dyn FnMut(BlockInfo)) {
+        if !self.is_initialized() {
+            return;
+        }
+
+        // 1 indexed (node index, order, address) triples
+        let mut stack = vec![(1, self.levels - 1, self.base_address)];
+        while let Some((node_index, order, addr)) = stack.pop() {
+            let order_free = unsafe { self.order_free(node_index - 1) };
+
+            if order_free == order + 1 {
+                f(BlockInfo { addr, order, used: false });
+                continue;
+            }
+
+            let left_child_index = flat_tree::left_child(node_index);
+            let descend = order > 0 && (order_free != 0 || {
+                // A used block leaves its children as they were, which is completely free, while a
+                // block whose children are both used has no free order either
+                let left = unsafe { self.order_free(left_child_index - 1) };
+                let right = unsafe { self.order_free(left_child_index) };
+                left == 0 && right == 0
+            });
+
+            if descend {
+                stack.push((left_child_index, order - 1, addr));
+                stack.push((left_child_index + 1, order - 1, addr + block_size_in::<B>(order - 1)));
+            } else {
+                f(BlockInfo { addr, order, used: true });
+            }
+        }
+    }
+}
+
+// Only trees of the crate wide base order report statistics, as [BlockInfo] and [Usage] size
+// blocks by it
+impl AllocatorStats for Tree {
+    fn free_histogram(&self) -> [usize; LEVEL_COUNT as usize] {
+        let mut histogram = [0; LEVEL_COUNT as usize];
+        if !self.is_initialized() {
+            return histogram;
+        }
+
+        // Walk down from the root, stopping at fully free blocks (which are maximal as their parent
+        // was not fully free) and at used blocks. 1 indexed (node index, order) pairs.
+        let mut stack = vec![(1, self.levels - 1)];
+        while let Some((node_index, order)) = stack.pop() {
+            let order_free = unsafe { self.order_free(node_index - 1) };
+
+            if order_free == order + 1 {
+                histogram[order as usize] += 1;
+            } else if order_free != 0 {
+                // Partially used, so it cannot be a leaf
+                let left_child_index = flat_tree::left_child(node_index);
+                stack.push((left_child_index, order - 1));
+                stack.push((left_child_index + 1, order - 1));
+            }
+        }
+
+        histogram
+    }
+
+    fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    fn reset_peaks(&mut self) {
+        self.usage.reset_peaks();
+    }
+
+    fn op_counters(&self) -> OpCounters {
+        self.counters
+    }
+
+    fn reset_op_counters(&mut self) {
+        self.counters = OpCounters::new();
+    }
+
+    #[cfg(feature = "metrics")]
+    fn latency_summary(&self) -> LatencySummary {
+        self.latencies.summary()
+    }
+
+    fn metadata_bytes(&self) -> usize {
+        BlockStorage::storage_len(self.levels)
+            + self.leaves.metadata_bytes()
+            + self.seal.metadata_bytes()
+    }
+
+    fn managed_bytes(&self) -> usize {
+        if !self.is_initialized() {
+            return 0;
+        }
+
+        block_size(self.levels - 1) - self.reserved_bytes()
+    }
+
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockInfo)) {
+        self.visit_blocks(f)
+    }
+}
+
+impl PhysicalAllocator for Tree {
+    /// The configuration of the tree's levels, or the default configuration, which
+    /// [Tree::new] builds, if the tree has not been initialized.
+    fn config(&self) -> BuddyConfig {
+        BuddyConfig::new(BASE_ORDER, self.levels).unwrap_or_default()
+    }
+
+    fn alloc(&mut self, size: PageSize) -> Result<*const u8, PageAllocError> {
+        let order = self.config().order_for(size)?;
+        self.alloc_exact(order).ok_or(PageAllocError::OutOfMemory { size })
+    }
+
+    fn dealloc(&mut self, addr: *const u8) -> Result<(), FreeError> {
+        Tree::dealloc(self, addr)
+    }
+}
+
+impl<B: BaseOrder> Drop for Tree<B> {
+    fn drop(&mut self) {
+        if self.owns_blocks {
+            let blocks = mem::replace(&mut self.flat_blocks.cold, &mut []);
+            drop(unsafe { Box::from_raw(blocks as *mut [ColdCell]) });
+        }
+    }
+}
+
+/// A tree whose levels are only filled when an allocation first reaches them, so that creating one
+/// touches almost none of its storage. It hands out the same blocks as a [Tree].
+///
+/// Every level below the filled ones is untouched, so all of its blocks are completely free. An
+/// allocation fills down to one level below the block it allocates, so that a used block always
+/// has filled children which show whether it was allocated or split.
+pub struct LazyTree {
+    tree: Tree,
+    /// The number of levels from the top which have been filled
+    filled_levels: u8,
+}
+
+impl LazyTree {
+    /// Create a lazy tree with the same blocks as [Tree::new].
+    pub fn new() -> LazyTree {
+        LazyTree::new_at(0)
+    }
+
+    /// Create a lazy tree with the same blocks as [Tree::new_at].
+    pub fn new_at(base_address: usize) -> LazyTree {
+        let mut tree = Tree::empty();
+        if let Err(err) = tree.init_unfilled(base_address, LEVEL_COUNT) {
+            panic!("Could not create tree: {:?}", err);
+        }
+        tree.fill_top(base_address, LEVEL_COUNT);
+
+        LazyTree {
+            tree,
+            filled_levels: 1,
+        }
+    }
+
+    /// The number of levels from the top which have been filled so far
+    pub fn filled_levels(&self) -> u8 {
+        self.filled_levels
+    }
+
+    /// Allocate a block like [Tree::alloc_exact], first filling the levels it reaches.
+    pub fn alloc_exact(&mut self, desired_order: u8) -> Option<*const u8> {
+        self.fill_for(desired_order);
+        self.tree.alloc_exact(desired_order)
+    }
+
+    /// Free a block like [Tree::dealloc_exact], first filling the levels it reaches so that a
+    /// block which was never allocated is not mistaken for a used one.
+    pub fn dealloc_exact(&mut self, addr: *const u8, order: u8) -> bool {
+        self.fill_for(order);
+        self.tree.dealloc_exact(addr, order)
+    }
+
+    /// Fill every level which is not yet filled, returning the tree.
+    pub fn into_tree(mut self) -> Tree {
+        let levels = self.tree.levels;
+        self.fill_to(levels);
+        self.tree
+    }
+
+    /// Fill down to the children of the blocks of the given order
+    fn fill_for(&mut self, order: u8) {
+        let levels = self.tree.levels.saturating_sub(order) + 1;
+        self.fill_to(levels);
+    }
+
+    fn fill_to(&mut self, levels: u8) {
+        let levels = cmp::min(levels, self.tree.levels);
+        if levels <= self.filled_levels {
+            return;
+        }
+
+        let top_order = self.tree.levels - 1;
+        for level in self.filled_levels..levels {
+            self.tree.flat_blocks.fill_level(level, top_order - level + 1);
+        }
+        self.filled_levels = levels;
+        self.tree.seal.seal(&self.tree.flat_blocks);
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BlockAllocateError {
+    NoBlocksAvailable,
+    OrderTooLarge { order: u8, max_order: u8 },
+}
+
+/// Why the block at a given address could not be allocated by [Tree::alloc_at].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AllocAtError {
+    /// The tree has no blocks of the order, or has not been initialized
+    OrderTooLarge { order: u8, max_order: u8 },
+    /// The address is not inside the tree
+    OutOfRange { addr: usize },
+    /// The address is not aligned to the size of a block of the order
+    Misaligned { addr: usize, order: u8 },
+    /// The block is, or lies inside, the allocated or reserved block of `order` beginning at
+    /// `addr`
+    Used { addr: usize, order: u8 },
+    /// The block has been split and some of the blocks it was split into are used
+    PartlyUsed { addr: usize, order: u8 },
+}
+
+/// A block whose free order does not agree with its children, found by [Tree::check_invariants].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InvariantViolation {
+    /// The 1 indexed node index of the block
+    pub node_index: usize,
+    /// The free order + 1 of the block, or 0 if it has none
+    pub order_free: u8,
+    /// The free orders of its left and right children, or `None` if it is of order 0
+    pub children: Option<(u8, u8)>,
+}
+
+/// Why a block could not be freed by its address alone.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FreeError {
+    /// The address is not inside the memory given to the allocator
+    OutOfRange { addr: usize },
+    /// No used block begins at the address: the memory there is free, or the address is inside a
+    /// used block rather than at its beginning
+    NotAllocated { addr: usize },
+    /// The block beginning at the address was allocated with this order rather than the one it
+    /// was freed with
+    WrongOrder { addr: usize, order: u8 },
+}
+
+/// Why an empty tree could not be initialized.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TreeInitError {
+    /// The tree has already been initialized
+    AlreadyInitialized,
+    /// A tree must have between 1 and [LEVEL_COUNT] levels, and few enough that the size of its
+    /// top block fits in a `usize`
+    InvalidLevels { levels: u8 },
+    /// The base address is not aligned to the size of the top block, or the tree would wrap around
+    /// the address space
+    InvalidBaseAddress { base_address: usize },
+    /// External storage must be at least `needed` bytes long
+    StorageTooSmall { needed: usize },
+}
+
+/// How a [Forest] adds a tree once none of its trees has a free block of an order.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Growth {
+    /// The number of levels of each tree added
+    pub levels: u8,
+    /// The most bytes the top blocks of all the trees may add up to, or `None` for no limit
+    pub max_bytes: Option<usize>,
+}
+
+/// Several trees, each managing one top level block, which together behave as a single allocator.
+#[derive(Default)]
+pub struct Forest {
+    trees: Vec<Tree>,
+    usage: Usage,
+    /// Lent to a tree for the duration of each operation on it
+    observer: ObserverSlot,
+    latencies: Latencies,
+    /// Which tree is allocated from when several have a free block
+    policy: AllocationPolicy,
+    /// Whether a tree is added when every tree is out of blocks
+    growth: Option<Growth>,
+}
+
+impl Forest {
+    pub fn new() -> Forest {
+        Forest {
+            trees: Vec::new(),
+            usage: Usage::new(),
+            observer: ObserverSlot::new(),
+            latencies: Latencies::new(),
+            policy: AllocationPolicy::default(),
+            growth: None,
+        }
+    }
+
+    /// Set the observer which is told about every operation, returning the previous one.
+    pub fn set_observer(&mut self, obs: Box<dyn AllocObserver>) -> Option<Box<dyn AllocObserver>> {
+        self.observer.set(obs)
+    }
+
+    /// Remove the observer, returning it if one was set.
+    pub fn take_observer(&mut self) -> Option<Box<dyn AllocObserver>> {
+        self.observer.take()
+    }
+
+    /// Set how the tree to allocate from is chosen, returning the previous policy. Each tree always
+    /// allocates its lowest free block, so [AllocationPolicy::Deterministic] only has to choose the
+    /// lowest tree with a block free rather than the first added.
+    pub fn set_policy(&mut self, policy: AllocationPolicy) -> AllocationPolicy {
+        mem::replace(&mut self.policy, policy)
+    }
+
+    pub fn policy(&self) -> AllocationPolicy {
+        self.policy
+    }
+
+    /// Set whether and how a tree is added when no tree has a free block of the order allocated,
+    /// returning the previous growth. Each tree added begins at the first address after the end
+    /// of every tree in the forest which is aligned to the size of its top block.
+    pub fn set_growth(&mut self, growth: Option<Growth>) -> Option<Growth> {
+        mem::replace(&mut self.growth, growth)
+    }
+
+    pub fn growth(&self) -> Option<Growth> {
+        self.growth
+    }
+
+    /// Add a tree with a block of the given order after every other tree, if the growth allows.
+    /// Returns whether a tree was added.
+    fn grow(&mut self, order: u8) -> bool {
+        let growth = match self.growth {
+            Some(growth) if order < growth.levels && growth.levels <= LEVEL_COUNT => growth,
+            _ => return false,
+        };
+
+        let top_level_size = block_size(growth.levels - 1);
+        let managed: usize = self.trees.iter().map(Tree::top_level_size).sum();
+        if growth.max_bytes.is_some_and(|max| managed.saturating_add(top_level_size) > max) {
+            return false;
+        }
+
+        let end = self.trees
+            .iter()
+            .map(|tree| tree.base_address + tree.top_level_size())
+            .max()
+            .unwrap_or(0);
+        let base_address = match end.checked_add(top_level_size - 1) {
+            Some(end) => end & !(top_level_size - 1),
+            None => return false,
+        };
+
+        // Fails if the tree would wrap around the address space
+        let mut tree = Tree::empty();
+        if tree.init(base_address, growth.levels).is_err() {
+            return false;
+        }
+
+        self.trees.push(tree);
+        true
+    }
+
+    /// Run `f` on a tree with the forest's observer lent to it.
+    fn observed<R, F>(tree: &mut Tree, observer: &mut ObserverSlot, f: F) -> R
+    where
+        F: FnOnce(&mut Tree) -> R,
+    {
+        mem::swap(&mut tree.observer, observer);
+        let result = f(tree);
+        mem::swap(&mut tree.observer, observer);
+        result
+    }
+
+    /// Add a tree managing the block of [MAX_ORDER] beginning at `begin_address`.
+    pub fn create_top_level(&mut self, begin_address: usize) {
+        self.trees.push(Tree::new_at(begin_address));
+    }
+
+    /// Build a forest of `count` trees, the `i`th of which manages the block of [MAX_ORDER]
+    /// beginning at `i * block_size(MAX_ORDER)`.
+    pub fn with_trees(count: usize) -> Forest {
+        let mut forest = Forest::new();
+        for tree_number in 0..count {
+            forest.create_top_level(tree_number * block_size(MAX_ORDER));
+        }
+        forest
+    }
+
+    /// Add a tree which was built elsewhere, e.g. one removed from another forest. It is allocated
+    /// from after the trees already in the forest.
+    pub fn add_tree(&mut self, tree: Tree) {
+        self.trees.push(tree);
+    }
+
+    /// Detach the tree beginning at `base` so that its memory can be given back. Only a tree which
+    /// is completely free can be removed, so nothing the forest handed out is still in it and
+    /// freeing an address in it afterwards is rejected.
+    pub fn remove_tree(&mut self, base: usize) -> Result<Tree, RegionBusy> {
+        let position = self.trees
+            .iter()
+            .position(|tree| tree.base_address == base && tree.is_completely_free())
+            .ok_or(RegionBusy { begin_address: base })?;
+
+        // Later trees keep their order, as the first tree with a free block is allocated from
+        Ok(self.trees.remove(position))
+    }
+
+    /// Build a forest with a tree for each of the top level blocks beginning at `bases`, in that
+    /// order, like calling [Forest::create_top_level] with each but constructing the trees in
+    /// parallel.
+    #[cfg(feature = "rayon")]
+    pub fn new_parallel(bases: &[usize]) -> Forest {
+        Forest {
+            trees: bases.par_iter().map(|&base| Tree::new_at(base)).collect(),
+            ..Forest::new()
+        }
+    }
+
+    /// Build a forest managing the usable memory in `regions`. A tree is created for every top level
+    /// block which contains usable memory, with the rest of the block reserved.
+    pub fn from_regions(regions: &[MemRegion]) -> Forest {
+        let ranges = usable_ranges(regions);
+        let top_level_size = block_size(MAX_ORDER);
+        let mut forest = Forest::new();
+
+        for &(begin, end) in &ranges {
+            let first = begin & !(top_level_size - 1);
+            let last = (end - 1) & !(top_level_size - 1);
+
+            // Adjacent ranges may share a top level block, which is only created once
+            let first = match forest.trees.last() {
+                Some(tree) if tree.base_address == first => first + top_level_size,
+                _ => first,
+            };
+
+            for base in (first..=last).step_by(top_level_size) {
+                forest.create_top_level(base);
+            }
+        }
+
+        // Reserve everything in each tree which is not in a usable range
+        for tree in &mut forest.trees {
+            let tree_end = tree.base_address + top_level_size;
+            let mut free_from = tree.base_address;
+
+            for &(begin, end) in &ranges {
+                if end <= tree.base_address || begin >= tree_end {
+                    continue;
+                }
+
+                if begin > free_from {
+                    tree.reserve_range(free_from, begin);
+                }
+                free_from = cmp::min(end, tree_end);
+            }
+
+            tree.reserve_range(free_from, tree_end);
+        }
+
+        forest
+    }
+
+    /// Allocate a block of the given order from the first tree which has one free, or the lowest
+    /// with [AllocationPolicy::Deterministic].
+    pub fn alloc_exact(&mut self, desired_order: u8) -> Option<*const u8> {
+        let timer = OpTimer::start();
+        let addr = self.alloc_exact_untimed(desired_order);
+        self.latencies.record(timer);
+        addr
+    }
+
+    fn alloc_exact_untimed(&mut self, desired_order: u8) -> Option<*const u8> {
+        if desired_order > MAX_ORDER {
+            return None;
+        }
+
+        let addr = match self.alloc_from_trees(desired_order) {
+            Some(addr) => addr,
+            None if self.grow(desired_order) => {
+                let tree = self.trees.last_mut().expect("A tree was just added!");
+                Forest::observed(tree, &mut self.observer, |tree| tree.alloc_exact(desired_order))?
+            }
+            None => return None,
+        };
+
+        self.usage.allocated(desired_order);
+        Some(addr)
+    }
+
+    fn alloc_from_trees(&mut self, desired_order: u8) -> Option<*const u8> {
+        let observer = &mut self.observer;
+        match self.policy {
+            AllocationPolicy::FirstFound => self.trees
+                .iter_mut()
+                .filter_map(|tree| {
+                    Forest::observed(tree, observer, |tree| tree.alloc_exact(desired_order))
+                })
+                .next(),
+            AllocationPolicy::Deterministic => {
+                let tree = self.trees
+                    .iter_mut()
+                    .filter(|tree| tree.has_free(desired_order))
+                    .min_by_key(|tree| tree.base_address)?;
+                Forest::observed(tree, observer, |tree| tree.alloc_exact(desired_order))
+            }
+        }
+    }
+
+    /// Allocate a block of the given order like [Forest::alloc_exact], but say why none was
+    /// allocated.
+    pub fn try_alloc_exact(&mut self, order: u8) -> Result<*const u8, BlockAllocateError> {
+        if order > MAX_ORDER {
+            return Err(BlockAllocateError::OrderTooLarge {
+                order,
+                max_order: MAX_ORDER,
+            });
+        }
+
+        self.alloc_exact(order).ok_or(BlockAllocateError::NoBlocksAvailable)
+    }
+
+    /// Allocate a block of the given order which lies entirely below `limit` from the first tree
+    /// which has one.
+    pub fn alloc_below(&mut self, order: u8, limit: usize) -> Result<usize, BlockAllocateError> {
+        let timer = OpTimer::start();
+        let addr = self.alloc_below_untimed(order, limit);
+        self.latencies.record(timer);
+        addr
+    }
+
+    fn alloc_below_untimed(
+        &mut self,
+        order: u8,
+        limit: usize,
+    ) -> Result<usize, BlockAllocateError> {
+        if order > MAX_ORDER {
+            return Err(BlockAllocateError::OrderTooLarge {
+                order,
+                max_order: MAX_ORDER,
+            });
+        }
+
+        let observer = &mut self.observer;
+        let addr = self
+            .trees
+            .iter_mut()
+            .filter(|tree| tree.base_address < limit)
+            .filter_map(|tree| {
+                Forest::observed(tree, observer, |tree| tree.alloc_below(order, limit)).ok()
+            })
+            .next()
+            .ok_or(BlockAllocateError::NoBlocksAvailable)?;
+
+        self.usage.allocated(order);
+        Ok(addr)
+    }
+
+    /// Free the block of the given order beginning at `addr` in the tree whose top block contains
+    /// it. Returns `false` if no tree has a used block of that order there.
+    pub fn dealloc_exact(&mut self, addr: *const u8, order: u8) -> bool {
+        let timer = OpTimer::start();
+        let freed = self.dealloc_exact_untimed(addr, order);
+        self.latencies.record(timer);
+        freed
+    }
+
+    fn dealloc_exact_untimed(&mut self, addr: *const u8, order: u8) -> bool {
+        let tree = match self.trees.iter_mut().find(|tree| tree.contains(addr)) {
+            Some(tree) => tree,
+            None => return false,
+        };
+
+        let freed =
+            Forest::observed(tree, &mut self.observer, |tree| tree.dealloc_exact(addr, order));
+
+        if freed {
+            self.usage.freed(order);
+        }
+
+        freed
+    }
+
+    /// Free the used block beginning at `addr` without being told its order, in the tree whose top
+    /// block contains it, like [Tree::dealloc].
+    pub fn dealloc(&mut self, addr: *const u8) -> Result<(), FreeError> {
+        let timer = OpTimer::start();
+        let result = self.dealloc_untimed(addr);
+        self.latencies.record(timer);
+        result
+    }
+
+    fn dealloc_untimed(&mut self, addr: *const u8) -> Result<(), FreeError> {
+        let tree = self.trees
+            .iter_mut()
+            .find(|tree| tree.contains(addr))
+            .ok_or(FreeError::OutOfRange { addr: addr as usize })?;
+
+        // The order freed is only known to the tree, so find it first to update the usage
+        let (_, _, order) = tree.find_allocated(addr)?;
+        Forest::observed(tree, &mut self.observer, |tree| tree.dealloc(addr))?;
+        self.usage.freed(order);
+        Ok(())
+    }
+
+    /// The tree whose top block contains `addr`, e.g. to look at the statistics of only that tree.
+    pub fn tree_containing(&self, addr: *const u8) -> Option<&Tree> {
+        self.trees.iter().find(|tree| tree.contains(addr))
+    }
+}
+
+impl BuddyAllocatorApi for Forest {
+    fn create_top_level(&mut self, begin_address: usize) {
+        Forest::create_top_level(self, begin_address)
+    }
+
+    fn allocate(&mut self, order: u8) -> Option<usize> {
+        self.alloc_exact(order).map(|addr| addr as usize)
+    }
+
+    fn deallocate(&mut self, address: usize, order: u8) -> bool {
+        self.dealloc_exact(address as *const u8, order)
+    }
+
+    fn warm_up(&mut self, order: u8, count: usize) {
+        for tree in &mut self.trees {
+            tree.warm_up(order, count);
+        }
+    }
+}
+
+impl AllocatorStats for Forest {
+    fn free_histogram(&self) -> [usize; LEVEL_COUNT as usize] {
+        let mut histogram = [0; LEVEL_COUNT as usize];
+
+        for tree in &self.trees {
+            for (total, count) in histogram.iter_mut().zip(tree.free_histogram().iter()) {
+                *total += count;
+            }
+        }
+
+        histogram
+    }
+
+    fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    fn reset_peaks(&mut self) {
+        self.usage.reset_peaks();
+    }
+
+    fn op_counters(&self) -> OpCounters {
+        let mut counters = OpCounters::new();
+        for tree in &self.trees {
+            counters.add(&tree.counters);
+        }
+
+        counters
+    }
+
+    fn reset_op_counters(&mut self) {
+        for tree in &mut self.trees {
+            tree.reset_op_counters();
+        }
+    }
+
+    fn metadata_bytes(&self) -> usize {
+        let trees = self.trees.capacity() * mem::size_of::<Tree>();
+        trees + self.trees.iter().map(Tree::metadata_bytes).sum::<usize>()
+    }
+
+    fn managed_bytes(&self) -> usize {
+        self.trees.iter().map(Tree::managed_bytes).sum()
+    }
+
+    #[cfg(feature = "metrics")]
+    fn latency_summary(&self) -> LatencySummary {
+        self.latencies.summary()
+    }
+
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockInfo)) {
+        for tree in &self.trees {
+            tree.for_each_block(f);
+        }
+    }
+}
+
+/// Flat tree things.
+///
+/// # Note
+/// **1 INDEXED!**
+mod flat_tree {
+    use std::mem;
+    use std::ops::Range;
+
+    #[inline]
+    pub fn left_child(index: usize) -> usize {
+        index << 1
+    }
+
+    #[inline]
+    pub fn right_child(index: usize) -> usize {
+        (index << 1) | 1
+    }
+
+    #[inline]
+    pub fn parent(index: usize) -> usize {
+        index >> 1
+    }
+
+    /// The other child of the node's parent. The root has no sibling.
+    #[inline]
+    pub fn sibling(index: usize) -> usize {
+        index ^ 1
+    }
+
+    /// The level of the node, counting down from the root at level 0
+    #[inline]
+    pub fn level_of(index: usize) -> u8 {
+        (mem::size_of::<usize>() * 8 - 1) as u8 - index.leading_zeros() as u8
+    }
+
+    /// How many nodes are to the left of the node on its level
+    #[inline]
+    pub fn offset_in_level(index: usize) -> usize {
+        index - (1 << level_of(index))
+    }
+
+    /// The node `offset` nodes from the left of `level`
+    #[inline]
+    pub fn index_of(level: u8, offset: usize) -> usize {
+        (1 << level) + offset
+    }
+
+    /// The positions of the nodes of `level` in the array of blocks, which is 0 indexed. Each level
+    /// follows the one above it, so the nodes of a level are a contiguous slice of the array.
+    #[inline]
+    pub fn flat_level(level: u8) -> Range<usize> {
+        index_of(level, 0) - 1..index_of(level + 1, 0) - 1
+    }
+
+    /// A node index, which keeps the 1 indexed convention of this module to itself. Use
+    /// [NodeIndex::flat] to index the array of blocks.
+    #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+    pub struct NodeIndex(usize);
+
+    impl NodeIndex {
+        pub const ROOT: NodeIndex = NodeIndex(1);
+
+        /// The node with the given 1 indexed index, which must not be 0
+        #[inline]
+        pub fn new(index: usize) -> Self {
+            debug_assert_ne!(index, 0, "Node indices are 1 indexed!");
+            NodeIndex(index)
+        }
+
+        /// The node `offset` nodes from the left of `level`
+        #[inline]
+        pub fn at(level: u8, offset: usize) -> Self {
+            NodeIndex(index_of(level, offset))
+        }
+
+        /// The 1 indexed index of the node
+        #[inline]
+        pub fn get(self) -> usize {
+            self.0
+        }
+
+        /// The index of the node's block in the array of blocks, which is 0 indexed
+        #[inline]
+        pub fn flat(self) -> usize {
+            self.0 - 1
+        }
+
+        #[inline]
+        pub fn left_child(self) -> Self {
+            NodeIndex(left_child(self.0))
+        }
+
+        #[inline]
+        pub fn right_child(self) -> Self {
+            NodeIndex(right_child(self.0))
+        }
+
+        #[inline]
+        pub fn parent(self) -> Self {
+            NodeIndex::new(parent(self.0))
+        }
+
+        #[inline]
+        pub fn sibling(self) -> Self {
+            NodeIndex::new(sibling(self.0))
+        }
+
+        #[inline]
+        pub fn level(self) -> u8 {
+            level_of(self.0)
+        }
+
+        #[inline]
+        pub fn offset_in_level(self) -> usize {
+            offset_in_level(self.0)
+        }
+    }
+}
+
+pub fn demo(print_addresses: bool, blocks: u32, order: u8) -> Result<Duration, DemoError> {
+    demo_with_config(&BuddyConfig::default(), print_addresses, blocks, order)
+        .map(|report| report.duration)
+}
+
+/// Run the demo on trees with the number of levels given by `config`. The trees only support the
+/// crate wide base order, so any other base order is rejected.
+pub fn demo_with_config(
+    config: &BuddyConfig,
+    print_addresses: bool,
+    blocks: u32,
+    order: u8,
+) -> Result<DemoReport, DemoError> {
+    demo_with_max_bytes(config, print_addresses, blocks, order, None)
+}
+
+/// Run the demo like [demo_with_config], beginning with one tree and adding another each time the
+/// trees run out of blocks, until they would manage more than `max_bytes` between them.
+pub fn demo_with_max_bytes(
+    config: &BuddyConfig,
+    print_addresses: bool,
+    blocks: u32,
+    order: u8,
+    max_bytes: Option<usize>,
+) -> Result<DemoReport, DemoError> {
+    if config.base_order() != BASE_ORDER {
+        return Err(DemoError::UnsupportedBaseOrder {
+            base_order: config.base_order(),
+        });
+    }
+
+    if order > config.max_order() {
+        return Err(DemoError::OrderTooLarge {
+            order,
+            max_order: config.max_order(),
+        });
+    }
+
+    let mut forest = Forest::new();
+    let mut tree = Tree::empty();
+    tree.init(0, config.levels())
+        .expect("The configuration has already been validated!");
+    forest.add_tree(tree);
+    forest.set_growth(Some(Growth {
+        levels: config.levels(),
+        max_bytes,
+    }));
+
+    let mut regions = RegionTracker::new();
+    let mut trees_tracked = 0;
+    let start = Instant::now();
+
+    for allocation in 0..blocks {
+        let addr = forest
+            .try_alloc_exact(order)
+            .map_err(|err| match err {
+                BlockAllocateError::NoBlocksAvailable => DemoError::OutOfBlocks { allocation },
+                BlockAllocateError::OrderTooLarge { order, max_order } => {
+                    DemoError::OrderTooLarge { order, max_order }
+                }
+            })?;
+
+        if cfg!(debug_assertions) {
+            // Every tree the forest grew by is a region the blocks may lie in
+            for tree in &forest.trees[trees_tracked..] {
+                regions.add(tree.base_address, config.top_level_size());
+            }
+            trees_tracked = forest.trees.len();
+            regions.assert_valid(addr as usize, config.block_size(order));
+        }
+
+        if print_addresses {
+            println!("Address: {:#x}", addr as usize);
+        }
+    }
+
+    let duration = start.elapsed();
+
+    Ok(DemoReport {
+        duration,
+        allocated_bytes: forest.usage().used_bytes(),
+        managed_bytes: forest.managed_bytes(),
+    })
+}
+
+pub fn demo_steady_state(
+    print_addresses: bool,
+    blocks: u32,
+    order: u8,
+    runs: usize,
+) -> Result<Vec<Duration>, DemoError> {
+    steady_state::demo(Forest::new(), print_addresses, blocks, order, runs)
+}
+
+pub fn demo_for_duration(
+    print_addresses: bool,
+    blocks: u32,
+    order: u8,
+    budget: Duration,
+) -> Result<DurationReport, DemoError> {
+    steady_state::demo_for(Forest::new(), print_addresses, blocks, order, budget)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use testing::{check_unique_addresses, BlockSet, RecordingObserver, XorShift};
+    use super::*;
+    use {AllocError, PageSize};
+    use geometry::{block_bytes, region_bytes};
+
+    #[test]
+    fn test_flat_tree_fns() {
+        use super::flat_tree::*;
+        //    1
+        //  2   3
+        // 4 5 6 7
+        assert_eq!(left_child(1), 2);
+        assert_eq!(parent(2), 1);
+
+        let children = [(1, 2, 3), (2, 4, 5), (3, 6, 7)];
+        for &(index, left, right) in &children {
+            assert_eq!(left_child(index), left);
+            assert_eq!(right_child(index), right);
+            assert_eq!(parent(left), index);
+            assert_eq!(parent(right), index);
+            assert_eq!(sibling(left), right);
+            assert_eq!(sibling(right), left);
+        }
+
+        let levels = [(1, 0, 0), (2, 1, 0), (3, 1, 1), (4, 2, 0), (5, 2, 1), (6, 2, 2), (7, 2, 3)];
+        for &(index, level, offset) in &levels {
+            assert_eq!(level_of(index), level);
+            assert_eq!(offset_in_level(index), offset);
+            assert_eq!(index_of(level, offset), index);
+
+            let node = NodeIndex::at(level, offset);
+            assert_eq!(node, NodeIndex::new(index));
+            assert_eq!((node.get(), node.flat()), (index, index - 1));
+            assert_eq!((node.level(), node.offset_in_level()), (level, offset));
+        }
+
+        let node = NodeIndex::new(5);
+        assert_eq!(node.parent(), NodeIndex::new(2));
+        assert_eq!(node.sibling(), NodeIndex::new(4));
+        assert_eq!(NodeIndex::ROOT.left_child(), NodeIndex::new(2));
+        assert_eq!(NodeIndex::ROOT.right_child(), NodeIndex::new(3));
+        assert_eq!(level_of(1 << 20), 20);
+
+        // Each level directly follows the one above it in the array of blocks
+        assert_eq!(flat_level(0), 0..1);
+        assert_eq!(flat_level(2), 3..7);
+        for level in 0..LEVEL_COUNT {
+            let blocks = flat_level(level);
+            assert_eq!(blocks.len(), 1 << level);
+            assert_eq!(flat_level(level + 1).start, blocks.end);
+            assert_eq!(NodeIndex::at(level, 0).flat(), blocks.start);
+            assert_eq!(NodeIndex::at(level, blocks.len() - 1).flat(), blocks.end - 1);
+        }
+    }
+
+    #[test]
+    fn test_alloc_exact_sequence_toy_tree() {
+        // The addresses and nodes handed out, worked out by hand on the 4 level tree
+        let mut tree = Tree::with_levels(4);
+        let allocations = [(0, 0x0, 8), (1, 0x2000, 5), (0, 0x1000, 9), (2, 0x4000, 3)];
+        for &(order, addr, node_index) in &allocations {
+            let handle = tree.alloc_exact_handle(order).unwrap();
+            assert_eq!(handle.node_index(), node_index);
+            assert_eq!(tree.handle_address(handle), Some(addr as *const u8));
+        }
+        assert_eq!(tree.alloc_exact(0), None);
+
+        assert!(tree.dealloc_exact(0x1000 as *const u8, 0));
+        assert_eq!(tree.alloc_exact(1), None);
+        assert_eq!(tree.alloc_exact(0), Some(0x1000 as *const u8));
+        assert_eq!(tree.check_blocks(), Ok(()));
+    }
+
+    #[test]
+    fn test_blocks_in_tree() {
+        assert_eq!(Tree::blocks_in_tree(3), 1 + 2 + 4);
+        assert_eq!(Tree::blocks_in_tree(1), 1);
+    }
+
+    #[test]
+    fn test_base_blocks_per_block() {
+        for order in 0..LEVEL_COUNT {
+            assert_eq!(Tree::base_blocks_per_block(order), Some(1 << order));
+        }
+
+        assert_eq!(Tree::base_blocks_per_block(0), Some(1));
+        assert_eq!(Tree::base_blocks_per_block(LEVEL_COUNT), None);
+        assert_eq!(Tree::base_blocks_per_block(u8::max_value()), None);
+    }
+
+    #[test]
+    fn test_blocks_of_order_in_tree() {
+        for order in 0..LEVEL_COUNT {
+            assert_eq!(
+                Tree::blocks_of_order_in_tree(order),
+                Some(1 << (MAX_ORDER - order))
+            );
+            // Every order covers the whole tree
+            assert_eq!(
+                Tree::blocks_of_order_in_tree(order).unwrap() * Tree::base_blocks_per_block(order).unwrap(),
+                Tree::blocks_of_order_in_tree(0).unwrap()
+            );
+        }
+
+        assert_eq!(Tree::blocks_of_order_in_tree(MAX_ORDER), Some(1));
+        assert_eq!(Tree::blocks_of_order_in_tree(LEVEL_COUNT), None);
+        assert_eq!(Tree::blocks_of_order_in_tree(u8::max_value()), None);
+    }
+
+    #[test]
+    fn test_tree_runs_out_of_blocks() {
+        let mut tree = Tree::new();
+        let max_blocks = Tree::blocks_of_order_in_tree(0).unwrap();
+        for _ in 0..max_blocks {
+            assert_ne!(tree.alloc_exact(0), None);
+        }
+
+        assert_eq!(tree.alloc_exact(0), None);
+    }
+
+    #[test]
+    fn test_lazy_tree_matches_eager() {
+        let mut rng = XorShift::new(521);
+        let mut eager = Tree::new();
+        let mut lazy = LazyTree::new();
+        let mut live = Vec::new();
+
+        // Start with large blocks, so that the lower levels are still unfilled when the first
+        // small ones are allocated
+        for op in 0..10_000 {
+            if live.is_empty() || rng.below(3) != 0 {
+                let order = if op < 100 {
+                    MAX_ORDER - rng.below(4) as u8
+                } else {
+                    rng.below(8) as u8
+                };
+                let addr = eager.alloc_exact(order);
+                assert_eq!(lazy.alloc_exact(order), addr, "Allocation {} differs", op);
+                if let Some(addr) = addr {
+                    live.push((addr, order));
+                }
+            } else {
+                let (addr, order) = live.swap_remove(rng.below(live.len() as u64) as usize);
+                assert!(eager.dealloc_exact(addr, order));
+                assert!(lazy.dealloc_exact(addr, order));
+            }
+        }
+
+        let lazy = lazy.into_tree();
+        assert_eq!(lazy.check_blocks(), Ok(()));
+        assert_eq!(lazy.to_snapshot(), eager.to_snapshot());
+    }
+
+    #[test]
+    fn test_lazy_tree_fills_on_demand() {
+        let mut tree = LazyTree::new();
+        assert_eq!(tree.filled_levels(), 1);
+
+        // Down to the level of the block and its children
+        assert_eq!(tree.alloc_exact(MAX_ORDER - 2), Some(0x0 as *const u8));
+        assert_eq!(tree.filled_levels(), 4);
+        assert_eq!(tree.alloc_exact(MAX_ORDER - 1), Some(block_size(MAX_ORDER - 1) as *const u8));
+        assert_eq!(tree.filled_levels(), 4);
+
+        // A block which was never allocated is not freed, even on a level which was not filled
+        assert!(!tree.dealloc_exact(block_size(MAX_ORDER - 2) as *const u8, 0));
+        assert_eq!(tree.filled_levels(), LEVEL_COUNT);
+        assert!(tree.dealloc_exact(0x0 as *const u8, MAX_ORDER - 2));
+        assert_eq!(tree.into_tree().check_blocks(), Ok(()));
+    }
+
+    #[test]
+    fn test_block_storage_get_set() {
+        let mut tree = Tree::with_levels(10);
+        let len = tree.flat_blocks.len();
+
+        // With packed blocks, neighbours share a byte, so setting one must leave the other alone
+        for index in 0..len {
+            tree.flat_blocks.set(index, (index % 13) as u8);
+        }
+        for index in 0..len {
+            assert_eq!(tree.flat_blocks.get(index), (index % 13) as u8, "Block {}", index);
+        }
+
+        // Both ends of a level share a byte with the levels either side of it
+        tree.flat_blocks.fill_level(8, 2);
+        for index in 0..len {
+            let expected = if index >= 255 && index < 511 { 2 } else { (index % 13) as u8 };
+            assert_eq!(tree.flat_blocks.get(index), expected, "Block {}", index);
+        }
+    }
+
+    #[test]
+    fn test_init_tree() {
+        let tree = Tree::new();
+
+        // Highest level has 1 block, next has 2, next 4
+        assert_eq!(tree.flat_blocks.get(0), LEVEL_COUNT);
+
+        assert_eq!(tree.flat_blocks.get(1), LEVEL_COUNT - 1);
+        assert_eq!(tree.flat_blocks.get(2), LEVEL_COUNT - 1);
+
+        assert_eq!(tree.flat_blocks.get(3), LEVEL_COUNT - 2);
+        assert_eq!(tree.flat_blocks.get(4), LEVEL_COUNT - 2);
+        assert_eq!(tree.flat_blocks.get(5), LEVEL_COUNT - 2);
+        assert_eq!(tree.flat_blocks.get(6), LEVEL_COUNT - 2);
+    }
+
+    #[test]
+    fn test_alloc_exact() {
+        let mut tree = Tree::new();
+        tree.alloc_exact(3).unwrap();
+
+        tree = Tree::new();
+        assert_eq!(tree.alloc_exact(MAX_ORDER - 1), Some(0x0 as *const u8));
+        assert_eq!(
+            tree.alloc_exact(MAX_ORDER - 1),
+            Some((region_bytes().unwrap() / 2) as *const u8)
+        );
+        assert_eq!(tree.alloc_exact(0), None);
+        assert_eq!(tree.alloc_exact(MAX_ORDER - 1), None);
+
+        tree = Tree::new();
+        assert_eq!(tree.alloc_exact(MAX_ORDER), Some(0x0 as *const u8));
+        assert_eq!(tree.alloc_exact(MAX_ORDER), None);
+    }
+
+    #[test]
+    fn test_alloc_root() {
+        let mut tree = Tree::new();
+        let children = (tree.flat_blocks.get(1), tree.flat_blocks.get(2));
+
+        // From a pristine tree, only the root is marked used
+        assert_eq!(tree.alloc_exact(MAX_ORDER), Some(0 as *const u8));
+        assert_eq!(tree.flat_blocks.get(0), 0);
+        assert_eq!((tree.flat_blocks.get(1), tree.flat_blocks.get(2)), children);
+        assert_eq!(tree.alloc_exact(0), None);
+        assert_eq!(tree.free_histogram(), [0; LEVEL_COUNT as usize]);
+        assert_eq!(tree.op_counters().splits, [0; LEVEL_COUNT as usize]);
+
+        // Only the root itself can be freed, after which the tree is pristine again
+        assert!(!tree.dealloc_exact(0 as *const u8, MAX_ORDER - 1));
+        assert!(tree.dealloc_exact(0 as *const u8, MAX_ORDER));
+        assert_eq!(tree.flat_blocks.get(0), MAX_ORDER + 1);
+        assert_eq!(tree.alloc_exact(MAX_ORDER), Some(0 as *const u8));
+        assert!(tree.dealloc_exact(0 as *const u8, MAX_ORDER));
+
+        // Not while any block is allocated, but again once everything has been freed
+        let addrs: Vec<_> = (0..4).map(|_| tree.alloc_exact(0).unwrap()).collect();
+        assert_eq!(tree.alloc_exact(MAX_ORDER), None);
+        for (n, &addr) in addrs.iter().enumerate() {
+            assert!(tree.dealloc_exact(addr, 0));
+            let expected = if n == addrs.len() - 1 { Some(0 as *const u8) } else { None };
+            assert_eq!(tree.alloc_exact(MAX_ORDER), expected);
+        }
+        assert_eq!(tree.check_blocks(), Ok(()));
+    }
+
+    #[test]
+    fn test_alloc_root_toy_tree() {
+        let mut tree = Tree::with_levels(4);
+        assert_eq!(tree.alloc_exact(3), Some(0 as *const u8));
+        assert!((1..15).all(|n| tree.flat_blocks.get(n) != 0));
+        assert_eq!(tree.alloc_exact(3), None);
+        assert!(tree.dealloc_exact(0 as *const u8, 3));
+
+        // Blocks of every order are handed out below the root as if it had never been allocated
+        assert_eq!(tree.alloc_exact(1), Some(0 as *const u8));
+        assert_eq!(tree.alloc_exact(3), None);
+        assert!(tree.dealloc_exact(0 as *const u8, 1));
+        assert_eq!(tree.alloc_exact(3), Some(0 as *const u8));
+        assert_eq!(tree.check_blocks(), Ok(()));
+    }
+
+    #[test]
+    fn test_alloc_exact_toy_tree() {
+        // 4 levels: one order 3 block at the top, eight order 0 blocks at the bottom
+        let mut tree = Tree::with_levels(4);
+        for n in 0..8 {
+            assert_eq!(tree.alloc_exact(0), Some((n * block_size(0)) as *const u8));
+        }
+        assert_eq!(tree.alloc_exact(0), None);
+
+        let mut tree = Tree::with_levels(4);
+        assert_eq!(tree.alloc_exact(1), Some(0 as *const u8));
+        assert_eq!(tree.alloc_exact(0), Some(block_size(1) as *const u8));
+        assert_eq!(tree.alloc_exact(2), Some(block_size(2) as *const u8));
+        assert_eq!(tree.alloc_exact(0), Some((block_size(1) + block_size(0)) as *const u8));
+        assert_eq!(tree.alloc_exact(0), None);
+
+        let mut tree = Tree::with_levels(4);
+        assert_eq!(tree.alloc_exact(3), Some(0 as *const u8));
+        assert_eq!(tree.alloc_exact(0), None);
+        assert_eq!(tree.alloc_exact(4), None);
+    }
+
+    #[test]
+    fn test_with_levels() {
+        // 3 levels: one order 2 block, four order 0 blocks
+        let mut tree = Tree::with_levels(3);
+        assert_eq!(tree.alloc_exact(3), None);
+        assert_eq!(tree.alloc_exact(MAX_ORDER), None);
+        for n in 0..4 {
+            assert_eq!(tree.alloc_exact(0), Some((n * block_size(0)) as *const u8));
+        }
+        assert_eq!(tree.alloc_exact(0), None);
+        assert_eq!(tree.managed_bytes(), 4 * block_size(0));
+
+        // The full number of levels behaves exactly like the default tree
+        let mut full = Tree::with_levels(LEVEL_COUNT);
+        let mut default = Tree::new();
+        for &order in &[0, MAX_ORDER - 1, 3, 0, MAX_ORDER, 1, MAX_ORDER - 1, 0] {
+            assert_eq!(full.alloc_exact(order), default.alloc_exact(order));
+        }
+        assert_eq!(full.to_snapshot(), default.to_snapshot());
+    }
+
+    #[test]
+    #[should_panic(expected = "InvalidLevels")]
+    fn test_with_levels_too_many() {
+        Tree::with_levels(LEVEL_COUNT + 1);
+    }
+
+    #[test]
+    fn test_alloc_exact_toy_tree_matches_model() {
+        // The tree always hands out the lowest free block which is aligned to its size, so check
+        // it against a brute force model of the eight order 0 blocks
+        let mut rng = XorShift::new(427);
+
+        for _ in 0..256 {
+            let mut tree = Tree::with_levels(4);
+            let mut used = [false; 8];
+
+            for _ in 0..8 {
+                let order = rng.below(4) as u8;
+                let width = 1 << order;
+                let expected = (0..8)
+                    .step_by(width)
+                    .find(|&start| used[start..start + width].iter().all(|u| !u));
+
+                if let Some(start) = expected {
+                    for block in &mut used[start..start + width] {
+                        *block = true;
+                    }
+                }
+
+                assert_eq!(
+                    tree.alloc_exact(order),
+                    expected.map(|start| (start * block_size(0)) as *const u8)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_block_order_free_limit() {
+        assert_eq!(Block::new_free(::std::u8::MAX - 1).order_free, ::std::u8::MAX);
+    }
+
+    #[cfg(feature = "large_config")]
+    #[test]
+    fn test_large_config_addresses() {
+        let mut tree = Tree::new();
+        assert_eq!(tree.alloc_exact(MAX_ORDER - 1), Some(0 as *const u8));
+
+        let second = tree.alloc_exact(MAX_ORDER - 1).unwrap() as usize;
+        assert_eq!(second, block_size(MAX_ORDER - 1));
+        assert!(second as u64 > u64::from(::std::u32::MAX));
+    }
+
+    #[test]
+    fn test_block_size() {
+        assert_eq!(block_size(0), block_bytes(0).unwrap());
+        assert_eq!(block_size(MAX_ORDER), region_bytes().unwrap());
+    }
+
+    #[test]
+    fn test_forest_unique_addresses() {
+        check_unique_addresses(&mut Forest::new(), 2, &[0, 3, 1, 0, 5, 2], 1000);
+    }
+
+    #[test]
+    fn test_free_histogram() {
+        let mut forest = Forest::new();
+        assert_eq!(forest.free_histogram(), [0; LEVEL_COUNT as usize]);
+
+        // Pristine: one top level block per region
+        forest.create_top_level(0);
+        forest.create_top_level(block_size(MAX_ORDER));
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 2;
+        assert_eq!(forest.free_histogram(), expected);
+
+        // Allocating down to order 0 leaves one free buddy on every order below the top
+        forest.alloc_exact(0).unwrap();
+        let mut expected = [1; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 1;
+        assert_eq!(forest.free_histogram(), expected);
+    }
+
+    #[test]
+    fn test_free_histogram_toy_tree() {
+        let mut tree = Tree::with_levels(4);
+        tree.alloc_exact(1).unwrap();
+        tree.alloc_exact(0).unwrap();
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[0] = 1;
+        expected[2] = 1;
+        assert_eq!(tree.free_histogram(), expected);
+
+        // Fully fragmented: every other leaf is used, so parents are only partially free
+        let mut tree = Tree::with_levels(4);
+        for leaf in 0..8 {
+            tree.flat_blocks.set(7 + leaf, if leaf % 2 == 0 { 0 } else { 1 });
+        }
+        for node in (0..7).rev() {
+            let left = tree.flat_blocks.get(node * 2 + 1);
+            let right = tree.flat_blocks.get(node * 2 + 2);
+            tree.flat_blocks.set(node, cmp::max(left, right));
+        }
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[0] = 4;
+        assert_eq!(tree.free_histogram(), expected);
+
+        // Completely used
+        let mut tree = Tree::with_levels(4);
+        tree.alloc_exact(3).unwrap();
+        assert_eq!(tree.free_histogram(), [0; LEVEL_COUNT as usize]);
+    }
+
+    #[test]
+    fn test_dealloc_exact_toy_tree() {
+        let mut tree = Tree::with_levels(4);
+        let first = tree.alloc_exact(0).unwrap();
+        let second = tree.alloc_exact(0).unwrap();
+
+        // Freeing one half of a pair must not make the parent completely free
+        assert!(tree.dealloc_exact(first, 0));
+        assert_eq!(tree.alloc_exact(1), Some(block_size(1) as *const u8));
+        assert!(tree.dealloc_exact(block_size(1) as *const u8, 1));
+
+        // Both halves free merge back into the whole tree
+        assert!(tree.dealloc_exact(second, 0));
+        assert_eq!(tree.alloc_exact(3), Some(0 as *const u8));
+        assert!(tree.dealloc_exact(0 as *const u8, 3));
+
+        // Not used, misaligned or outside of the tree
+        assert!(!tree.dealloc_exact(0 as *const u8, 0));
+        assert!(!tree.dealloc_exact(block_size(0) as *const u8, 1));
+        assert!(!tree.dealloc_exact(block_size(3) as *const u8, 0));
+        assert!(!tree.dealloc_exact(0 as *const u8, 4));
+    }
+
+    #[test]
+    fn test_dealloc_exact_round_trip() {
+        let mut rng = XorShift::new(501);
+
+        // A small tree filled with blocks of order 0, and a full size one with larger blocks so
+        // that filling it stays quick
+        for &(levels, order) in &[(12, 0), (LEVEL_COUNT, MAX_ORDER - 8)] {
+            let top_order = levels - 1;
+            let mut tree = Tree::with_levels(levels);
+
+            let mut addresses = Vec::new();
+            while let Some(addr) = tree.alloc_exact(order) {
+                addresses.push(addr);
+            }
+            assert_eq!(addresses.len(), 1 << (top_order - order));
+
+            // Freed in an arbitrary order, the tree is only whole again after the last block
+            while !addresses.is_empty() {
+                assert_eq!(tree.alloc_exact(top_order), None);
+                let addr = addresses.swap_remove(rng.below(addresses.len() as u64) as usize);
+                assert!(tree.dealloc_exact(addr, order));
+            }
+
+            assert_eq!(tree.check_blocks(), Ok(()));
+            assert_eq!(tree.alloc_exact(top_order), Some(0 as *const u8));
+        }
+    }
+
+    #[test]
+    fn test_dealloc_exact_wrong_order() {
+        let mut tree = Tree::with_levels(4);
+        let first = tree.alloc_exact(0).unwrap();
+        let second = tree.alloc_exact(0).unwrap();
+
+        // The parent of the two blocks is marked used too, but it was never allocated
+        assert!(!tree.dealloc_exact(first, 1));
+        assert_eq!(tree.alloc_exact(1), Some(block_size(1) as *const u8));
+        assert_eq!(tree.usage().outstanding_allocations(), 3);
+
+        // Nor was the root, which is marked used once every block below it is
+        tree.alloc_exact(2).unwrap();
+        assert_eq!(tree.alloc_exact(0), None);
+        assert!(!tree.dealloc_exact(0 as *const u8, 3));
+        assert!(!tree.dealloc_exact(0 as *const u8, 2));
+
+        assert!(tree.dealloc_exact(first, 0));
+        assert!(tree.dealloc_exact(second, 0));
+        assert_eq!(tree.check_blocks(), Ok(()));
+    }
+
+    #[test]
+    fn test_dealloc_exact_new_at() {
+        let base = block_size(MAX_ORDER);
+        let mut tree = Tree::new_at(base);
+        let addr = tree.alloc_exact(0).unwrap();
+        assert!(!tree.dealloc_exact(0 as *const u8, 0));
+        assert!(tree.dealloc_exact(addr, 0));
+        assert_eq!(tree.alloc_exact(MAX_ORDER), Some(base as *const u8));
+    }
+
+    #[test]
+    fn test_dealloc_adjacent_blocks() {
+        let mut tree = Tree::with_levels(4);
+        let first = tree.alloc_exact(0).unwrap();
+        let second = tree.alloc_exact(0).unwrap();
+        assert_eq!(second as usize, first as usize + block_size(0));
+
+        // Only the first is freed, so the pair is not free as a whole
+        assert_eq!(tree.dealloc(first), Ok(()));
+        let error = Err(FreeError::NotAllocated { addr: first as usize });
+        assert_eq!(tree.dealloc(first), error);
+        assert_eq!(tree.alloc_exact(1), Some(block_size(1) as *const u8));
+        assert_eq!(tree.dealloc(block_size(1) as *const u8), Ok(()));
+        assert_eq!(tree.alloc_exact(3), None);
+
+        assert_eq!(tree.dealloc(second), Ok(()));
+        assert_eq!(tree.alloc_exact(3), Some(0 as *const u8));
+        assert_eq!(tree.check_blocks(), Ok(()));
+    }
+
+    #[test]
+    fn test_dealloc_finds_order() {
+        let mut tree = Tree::new();
+        let orders = [3, 0, MAX_ORDER - 1, 5, 0, 1];
+        let addresses: Vec<_> = orders
+            .iter()
+            .map(|&order| tree.alloc_exact(order).unwrap())
+            .collect();
+
+        for (&addr, &order) in addresses.iter().zip(&orders).rev() {
+            let frees = tree.op_counters().frees[order as usize];
+            assert_eq!(tree.dealloc(addr), Ok(()));
+            assert_eq!(tree.op_counters().frees[order as usize], frees + 1);
+        }
+
+        assert_eq!(tree.alloc_exact(MAX_ORDER), Some(0 as *const u8));
+    }
+
+    #[test]
+    fn test_dealloc_inside_block_rejected() {
+        // A 2 MiB block, freed by an address in its middle rather than by its beginning
+        let order = PageSize::Mib2.power_of_two() - BASE_ORDER;
+        let mut tree = Tree::new_at(block_size(MAX_ORDER));
+        tree.alloc_exact(0).unwrap();
+        let addr = tree.alloc_exact(order).unwrap() as usize;
+
+        for &inside in &[addr + block_size(order) / 2, addr + block_size(0)] {
+            let error = Err(FreeError::NotAllocated { addr: inside });
+            assert_eq!(tree.dealloc(inside as *const u8), error);
+        }
+        assert_eq!(tree.usage().outstanding_allocations(), 2);
+
+        assert_eq!(tree.dealloc(addr as *const u8), Ok(()));
+        assert_eq!(tree.alloc_exact(order), Some(addr as *const u8));
+    }
+
+    /// Overwrite the free order of the block at the 1 indexed node index, bypassing every check
+    fn corrupt_node(tree: &mut Tree, node_index: usize, order_free: u8) {
+        tree.flat_blocks.set(node_index - 1, order_free);
+    }
+
+    #[test]
+    fn test_check_invariants_reports_corruption() {
+        let mut tree = Tree::with_levels(4);
+        tree.alloc_exact(0).unwrap();
+        tree.alloc_exact(2).unwrap();
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        // The root claims a free block of order 2, but its children have none
+        corrupt_node(&mut tree, 1, 3);
+        let violation = InvariantViolation {
+            node_index: 1,
+            order_free: 3,
+            children: Some((2, 0)),
+        };
+        assert_eq!(tree.check_invariants(), Err(violation));
+        assert_eq!(tree.check_blocks(), Err(0));
+        corrupt_node(&mut tree, 1, 2);
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        // The lowest inconsistent block is reported, so the leaf rather than its parent
+        corrupt_node(&mut tree, 9, 3);
+        let violation = InvariantViolation {
+            node_index: 9,
+            order_free: 3,
+            children: None,
+        };
+        assert_eq!(tree.check_invariants(), Err(violation));
+    }
+
+    #[test]
+    fn test_double_free_detected() {
+        let mut tree = Tree::new_at(0);
+        let addr = tree.alloc_exact(3).unwrap();
+        let not_allocated = Err(FreeError::NotAllocated { addr: addr as usize });
+
+        assert_eq!(tree.dealloc(addr), Ok(()));
+        assert_eq!(tree.dealloc(addr), not_allocated);
+        assert_eq!(tree.dealloc_exact_checked(addr, 3), not_allocated);
+        assert!(tree.is_completely_free());
+        assert_eq!(tree.check_blocks(), Ok(()));
+
+        // Never allocated at all
+        let never = (addr as usize + block_size(5)) as *const u8;
+        let error = Err(FreeError::NotAllocated { addr: never as usize });
+        assert_eq!(tree.dealloc_exact_checked(never, 0), error);
+    }
+
+    #[test]
+    fn test_dealloc_exact_checked_wrong_order() {
+        let mut tree = Tree::new_at(0);
+        let addr = tree.alloc_exact(2).unwrap();
+        tree.alloc_exact(0).unwrap();
+
+        for &order in &[0, 1, 3, MAX_ORDER, MAX_ORDER + 1] {
+            let error = Err(FreeError::WrongOrder { addr: addr as usize, order: 2 });
+            assert_eq!(tree.dealloc_exact_checked(addr, order), error);
+        }
+
+        // Inside the block rather than at its beginning
+        let inside = (addr as usize + block_size(1)) as *const u8;
+        let error = Err(FreeError::NotAllocated { addr: inside as usize });
+        assert_eq!(tree.dealloc_exact_checked(inside, 1), error);
+        assert_eq!(tree.usage().outstanding_allocations(), 2);
+
+        assert_eq!(tree.dealloc_exact_checked(addr, 2), Ok(()));
+        assert_eq!(tree.check_blocks(), Ok(()));
+        let out_of_range = block_size(MAX_ORDER) as *const u8;
+        let error = Err(FreeError::OutOfRange { addr: block_size(MAX_ORDER) });
+        assert_eq!(tree.dealloc_exact_checked(out_of_range, 0), error);
+    }
+
+    #[test]
+    fn test_dealloc_reserved_rejected() {
+        let mut tree = Tree::with_levels(4);
+        tree.reserve_range(0x0, 0x1000);
+        tree.reserve_range(0x4000, 0x8000);
+        let addr = tree.alloc_exact(0).unwrap();
+
+        for &reserved in &[0x0, 0x4000, 0x6000] {
+            let error = Err(FreeError::NotAllocated { addr: reserved });
+            assert_eq!(tree.dealloc(reserved as *const u8), error);
+        }
+        assert_eq!(tree.dealloc(addr), Ok(()));
+        assert_eq!(tree.usage().used_bytes(), 0);
+        assert_eq!(tree.alloc_exact(1), Some(0x2000 as *const u8));
+    }
+
+    #[test]
+    fn test_dealloc_out_of_range() {
+        let base = block_size(MAX_ORDER);
+        let mut tree = Tree::new_at(base);
+        tree.alloc_exact(MAX_ORDER).unwrap();
+
+        for &addr in &[0, base - block_size(0), base * 2, usize::max_value()] {
+            assert_eq!(tree.dealloc(addr as *const u8), Err(FreeError::OutOfRange { addr }));
+        }
+        assert_eq!(tree.dealloc(base as *const u8), Ok(()));
+
+        let mut empty = Tree::empty();
+        assert_eq!(empty.dealloc(0 as *const u8), Err(FreeError::OutOfRange { addr: 0 }));
+    }
+
+    #[test]
+    fn test_physical_alloc_page_sizes() {
+        // A page of 1 GiB can take the whole tree, so each size is allocated from a fresh tree
+        for &size in &[PageSize::Kib4, PageSize::Mib2, PageSize::Gib1] {
+            let mut tree = Tree::new_at(3 * block_size(MAX_ORDER));
+            let bytes = 1usize << size.power_of_two();
+
+            let first = PhysicalAllocator::alloc(&mut tree, size).unwrap();
+            assert_eq!(first as usize % bytes, 0, "{:?} page not aligned", size);
+            assert_eq!(tree.usage().used_bytes(), bytes);
+
+            // Unless the page took the whole tree, the next page is its buddy
+            if size.power_of_two() - BASE_ORDER == MAX_ORDER {
+                assert_eq!(
+                    PhysicalAllocator::alloc(&mut tree, size),
+                    Err(PageAllocError::OutOfMemory { size })
+                );
+            } else {
+                let second = PhysicalAllocator::alloc(&mut tree, size).unwrap();
+                assert_eq!(second as usize, first as usize + bytes);
+                assert_eq!(PhysicalAllocator::dealloc(&mut tree, second), Ok(()));
+            }
+
+            assert_eq!(PhysicalAllocator::dealloc(&mut tree, first), Ok(()));
+            assert_eq!(tree.usage().used_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn test_physical_alloc_config() {
+        let mut tree = Tree::with_levels(4);
+        assert_eq!(tree.config(), BuddyConfig::new(BASE_ORDER, 4).unwrap());
+        assert_eq!(
+            PhysicalAllocator::alloc(&mut tree, PageSize::Mib2),
+            Err(PageAllocError::Unsupported { size: PageSize::Mib2 })
+        );
+        assert!(PhysicalAllocator::alloc(&mut tree, PageSize::Kib4).is_ok());
+
+        // Nothing can be allocated before the tree is given storage
+        let mut empty = Tree::empty();
+        assert_eq!(empty.config(), BuddyConfig::default());
+        assert_eq!(
+            PhysicalAllocator::alloc(&mut empty, PageSize::Kib4),
+            Err(PageAllocError::OutOfMemory { size: PageSize::Kib4 })
+        );
+    }
+
+    #[test]
+    fn test_physical_dealloc_unallocated() {
+        let mut tree = Tree::new();
+        let addr = block_size(0);
+        assert_eq!(
+            PhysicalAllocator::dealloc(&mut tree, addr as *const u8),
+            Err(FreeError::NotAllocated { addr })
+        );
+    }
+
+    #[test]
+    fn test_block_handles_toy_tree() {
+        let mut tree = Tree::with_levels(4);
+        let first = tree.alloc_exact_handle(0).unwrap();
+        let second = tree.alloc_exact_handle(0).unwrap();
+        assert_eq!((first.node_index(), first.order()), (8, 0));
+        assert_eq!(BlockHandle::from_raw(second.to_raw()), second);
+        assert_eq!(tree.handle_address(first), Some(0 as *const u8));
+        assert_eq!(tree.handle_address(second), Some(block_size(0) as *const u8));
+
+        // The parent of two used blocks is marked used, but was never allocated
+        let parent = BlockHandle::new(4, 1);
+        assert!(!tree.dealloc_handle(parent));
+
+        // Nodes on another level than their order, or outside of the tree
+        assert!(!tree.dealloc_handle(BlockHandle::new(8, 1)));
+        assert!(!tree.dealloc_handle(BlockHandle::new(0, 3)));
+        assert!(!tree.dealloc_handle(BlockHandle::new(16, 0)));
+        assert!(!tree.dealloc_handle(BlockHandle::new(1, 4)));
+        assert_eq!(tree.handle_address(BlockHandle::new(16, 0)), None);
+
+        // A handle is stale once its block is freed
+        assert!(tree.dealloc_handle(first));
+        assert!(!tree.dealloc_handle(first));
+        assert!(tree.dealloc_handle(second));
+        assert_eq!(tree.alloc_exact(3), Some(0 as *const u8));
+    }
+
+    #[test]
+    fn test_op_counters_toy_tree() {
+        let mut tree = Tree::with_levels(4);
+
+        // Splits the order 3, 2 and 1 blocks on the way down
+        tree.alloc_exact(0).unwrap();
+        // The buddies are already free, so nothing is split
+        tree.alloc_exact(0).unwrap();
+        tree.alloc_exact(1).unwrap();
+        // Splits the order 2 block to the right
+        tree.alloc_exact(1).unwrap();
+
+        let mut expected = OpCounters::new();
+        expected.allocations[0] = 2;
+        expected.allocations[1] = 2;
+        expected.splits[3] = 1;
+        expected.splits[2] = 2;
+        expected.splits[1] = 1;
+        assert_eq!(tree.op_counters(), expected);
+
+        // The order 1 block merges back into the order 2 block, but not further
+        assert!(tree.dealloc_exact(block_size(2) as *const u8, 1));
+        expected.frees[1] = 1;
+        expected.merges[2] = 1;
+        assert_eq!(tree.op_counters(), expected);
+    }
+
+    #[test]
+    fn test_observer_events() {
+        let mut forest = Forest::new();
+        let recorder = RecordingObserver::new();
+        assert!(forest.set_observer(Box::new(recorder.clone())).is_none());
+        forest.create_top_level(block_size(MAX_ORDER));
+
+        let base = block_size(MAX_ORDER);
+        let order = MAX_ORDER - 2;
+        let first = forest.alloc_exact(order).unwrap();
+        let second = forest.alloc_exact(order).unwrap();
+        assert!(forest.dealloc_exact(first, order));
+        assert!(forest.dealloc_exact(second, order));
+
+        assert_eq!(
+            recorder.take_events(),
+            vec![
+                AllocEvent::Split { addr: base, order: MAX_ORDER },
+                AllocEvent::Split { addr: base, order: MAX_ORDER - 1 },
+                AllocEvent::Alloc { addr: base, order },
+                AllocEvent::Alloc { addr: base + block_size(order), order },
+                AllocEvent::Dealloc { addr: base, order },
+                AllocEvent::Dealloc { addr: base + block_size(order), order },
+                AllocEvent::Merge { addr: base, order: MAX_ORDER - 1 },
+                AllocEvent::Merge { addr: base, order: MAX_ORDER },
+            ]
+        );
+
+        // Nothing is recorded once the observer is removed
+        assert!(forest.take_observer().is_some());
+        forest.alloc_exact(0).unwrap();
+        assert_eq!(recorder.take_events(), vec![]);
+    }
+
+    #[test]
+    fn test_forest_from_regions() {
+        let top_level_size = block_size(MAX_ORDER) as u64;
+        let regions = [
+            MemRegion { start: 0x1000, len: 0x1f000, usable: true },
+            MemRegion { start: 0x8000, len: 0x1000, usable: false },
+            MemRegion { start: top_level_size + 0x4000, len: 0x2000, usable: true },
+        ];
+        let ranges = usable_ranges(&regions);
+        let mut forest = Forest::from_regions(&regions);
+
+        assert_eq!(forest.trees.len(), 2);
+        assert_eq!(forest.managed_bytes(), 0x7000 + 0x17000 + 0x2000);
+        assert_eq!(forest.largest_free_extent(), Some((0x9000, 0x17000)));
+
+        // Only the usable memory is ever handed out, and reserving it is not allocating it
+        let mut allocated = 0;
+        while let Some(addr) = forest.alloc_exact(0) {
+            let addr = addr as usize;
+            assert!(
+                ranges.iter().any(|&(begin, end)| addr >= begin && addr < end),
+                "Block {:#x} is not in usable memory!",
+                addr
+            );
+            allocated += 1;
+        }
+
+        assert_eq!(allocated, 0x7 + 0x17 + 0x2);
+        assert_eq!(forest.usage().outstanding_allocations(), allocated);
+        assert_eq!(forest.largest_free_extent(), None);
+    }
+
+    /// The descent of [Tree::descend] as it was written with a branch, to check it against.
+    fn descend_branching(tree: &Tree, desired_order: u8) -> (usize, usize, u8) {
+        let top_order = tree.levels - 1;
+        let max_level = top_order - desired_order;
+        let mut addr = tree.base_address;
+        let mut node_index = 1;
+        let mut first_split = max_level;
+
+        for level in 0..max_level {
+            let order_free = tree.flat_blocks.get(node_index - 1);
+            if first_split == max_level && order_free == top_order - level + 1 {
+                first_split = level;
+            }
+
+            let left_child_index = flat_tree::left_child(node_index);
+            let o = tree.flat_blocks.get(left_child_index - 1);
+            node_index = if o != 0 && o > desired_order {
+                left_child_index
+            } else {
+                addr += block_size(top_order - level - 1);
+                left_child_index + 1
+            };
+        }
+
+        (node_index, addr, first_split)
+    }
+
+    #[test]
+    fn test_descend_matches_branching() {
+        let mut rng = XorShift::new(457);
+        let mut compared = 0;
+
+        for &levels in &[6, 9, LEVEL_COUNT] {
+            let mut tree = Tree::with_levels_at(levels, block_size(levels - 1));
+            let mut live = Vec::new();
+
+            for _ in 0..3000 {
+                let order = rng.below(u64::from(cmp::min(levels, 5))) as u8;
+                if live.is_empty() || rng.below(3) != 0 {
+                    if let Some(addr) = tree.alloc_exact(order) {
+                        live.push((addr, order));
+                    }
+                } else {
+                    let (addr, order) = live.swap_remove(rng.below(live.len() as u64) as usize);
+                    assert!(tree.dealloc_exact(addr, order));
+                }
+
+                let root_order_free = tree.flat_blocks.get(0);
+                for order in (0..levels).filter(|&order| order < root_order_free) {
+                    assert_eq!(tree.descend(order), descend_branching(&tree, order));
+                    compared += 1;
+                }
+            }
+        }
+
+        assert!(compared > 10_000, "Only {} descents were compared", compared);
+    }
+
+    #[test]
+    fn test_leaf_bitmap_matches_tree() {
+        // Order 0 allocations are mostly found in the leaf bitmap, which must pick the block the
+        // descent would and stay in step with the tree through every kind of operation
+        let mut rng = XorShift::new(461);
+
+        for &levels in &[4, 8, 12] {
+            let mut tree = Tree::with_levels_at(levels, block_size(levels - 1));
+            let top = block_size(levels - 1);
+            tree.reserve_range(top + block_size(1), top + block_size(2));
+            let mut live = Vec::new();
+
+            for step in 0..4000 {
+                let order = match rng.below(4) {
+                    0 => rng.below(u64::from(cmp::min(levels, 4))) as u8,
+                    _ => 0,
+                };
+
+                if live.is_empty() || rng.below(5) < 3 {
+                    let root_order_free = tree.flat_blocks.get(0);
+                    let expected = Some(order)
+                        .filter(|&order| order < root_order_free)
+                        .map(|order| tree.descend(order).1 as *const u8);
+
+                    let addr = if rng.below(4) == 0 {
+                        tree.alloc_below(order, usize::max_value()).ok().map(|a| a as *const u8)
+                    } else {
+                        tree.alloc_exact(order)
+                    };
+                    assert_eq!(addr, expected);
+                    live.extend(addr.map(|addr| (addr, order)));
+                } else {
+                    let (addr, order) = live.swap_remove(rng.below(live.len() as u64) as usize);
+                    assert!(tree.dealloc_exact(addr, order));
+                }
+
+                if step % 50 == 0 {
+                    assert_eq!(tree.check_blocks(), Ok(()));
+                }
+            }
+
+            assert_eq!(tree.check_blocks(), Ok(()));
+            let restored = Tree::from_snapshot(&tree.to_snapshot()).unwrap();
+            assert_eq!(restored.check_blocks(), Ok(()));
+        }
+    }
+
+    #[cfg(feature = "leaf_bitmap")]
+    #[test]
+    fn test_check_leaves_finds_wrong_bits() {
+        let mut tree = Tree::with_levels(4);
+        let first_leaf_index = Tree::blocks_in_tree(3);
+        let addr = tree.alloc_exact(1).unwrap();
+        assert_eq!(addr as usize, 0);
+        assert_eq!(tree.check_blocks(), Ok(()));
+
+        // A used block marked as free
+        tree.leaves.mark(1, 1, true);
+        assert_eq!(tree.check_blocks(), Err(first_leaf_index + 1));
+        tree.leaves.mark(1, 1, false);
+
+        // A free block marked as used
+        tree.leaves.mark(6, 1, false);
+        assert_eq!(tree.check_blocks(), Err(first_leaf_index + 6));
+        tree.leaves.mark(6, 1, true);
+
+        // A word with free blocks which the summary says has none
+        tree.leaves.summary[0] = 0;
+        assert_eq!(tree.check_blocks(), Err(first_leaf_index));
+        tree.leaves.summary[0] = 1;
+        assert_eq!(tree.check_blocks(), Ok(()));
+
+        // A free block hidden before the first summary word
+        tree.leaves.first_summary = 1;
+        assert_eq!(tree.check_blocks(), Err(first_leaf_index + 2));
+    }
+
+    /// Allocate the lowest block of order 0 of a tree of 9 levels, whose blocks from node 128 down
+    /// are cold, so that overwriting a block afterwards is caught by the next call.
+    #[cfg(feature = "paranoid")]
+    fn sealed_tree() -> (Tree, *const u8) {
+        let mut tree = Tree::with_levels(9);
+        let addr = tree.alloc_exact(0).unwrap();
+        (tree, addr)
+    }
+
+    #[cfg(feature = "paranoid")]
+    #[test]
+    #[should_panic(expected = "corrupted at node 300: expected order_free 1, found 9")]
+    fn test_paranoid_detects_cold_corruption() {
+        let (mut tree, addr) = sealed_tree();
+        let order_free = tree.flat_blocks.get(299);
+        tree.flat_blocks.set(299, order_free ^ 0x8);
+        tree.dealloc_exact(addr, 0);
+    }
+
+    #[cfg(feature = "paranoid")]
+    #[test]
+    #[should_panic(expected = "corrupted at node 3: expected order_free 8, found 2")]
+    fn test_paranoid_detects_hot_corruption() {
+        let (mut tree, _) = sealed_tree();
+        tree.flat_blocks.set(2, 2);
+        tree.alloc_exact(1);
+    }
+
+    #[cfg(feature = "paranoid")]
+    #[test]
+    fn test_paranoid_ignores_restored_block() {
+        let (mut tree, addr) = sealed_tree();
+        tree.flat_blocks.set(299, 0);
+        tree.flat_blocks.set(299, 1);
+        assert!(tree.dealloc_exact(addr, 0));
+        assert!(tree.is_completely_free());
+    }
+
+    #[test]
+    fn test_alloc_below_toy_tree() {
+        let mut tree = Tree::with_levels(4);
+
+        // Fill the low half, after which only high memory is free
+        for block in 0..4 {
+            assert_eq!(tree.alloc_below(0, 0x4000), Ok(block * 0x1000));
+        }
+        assert_eq!(tree.alloc_below(0, 0x4000), Err(BlockAllocateError::NoBlocksAvailable));
+        assert_eq!(tree.alloc_below(1, 0x5000), Err(BlockAllocateError::NoBlocksAvailable));
+        assert_eq!(tree.alloc_below(0, 0x5000), Ok(0x4000));
+        assert_eq!(tree.alloc_below(1, 0x8000), Ok(0x6000));
+        assert_eq!(
+            tree.alloc_below(4, 0x8000),
+            Err(BlockAllocateError::OrderTooLarge { order: 4, max_order: 3 })
+        );
+        assert_eq!(tree.check_blocks(), Ok(()));
+        assert_eq!(tree.usage().used_bytes(), 0x7000);
+
+        assert!(tree.dealloc_exact(0x2000 as *const u8, 0));
+        assert_eq!(tree.alloc_below(0, 0x8000), Ok(0x2000));
+        assert_eq!(tree.alloc_below(0, 0x8000), Ok(0x5000));
+        assert_eq!(tree.alloc_below(0, 0x8000), Err(BlockAllocateError::NoBlocksAvailable));
+    }
+
+    #[test]
+    fn test_alloc_at_2mib() {
+        let order = PageSize::Mib2.power_of_two() - BASE_ORDER;
+        let mut tree = Tree::new_at(0);
+
+        // The block is taken out of the top level block, leaving the rest of it allocatable
+        assert_eq!(tree.alloc_at(0x200000, order), Ok(()));
+        assert_eq!(tree.alloc_exact(order), Some(0x0 as *const u8));
+        assert_eq!(tree.alloc_exact(order), Some(0x400000 as *const u8));
+        assert_eq!(tree.check_blocks(), Ok(()));
+        assert_eq!(tree.usage().used_bytes(), 3 * block_size(order));
+
+        assert!(tree.dealloc_exact(0x200000 as *const u8, order));
+        assert_eq!(tree.alloc_at(0x200000, order), Ok(()));
+    }
+
+    #[test]
+    fn test_alloc_at_toy_tree() {
+        let mut tree = Tree::with_levels(4);
+
+        assert_eq!(tree.alloc_at(0x5000, 0), Ok(()));
+        assert_eq!(tree.alloc_at(0x5000, 0), Err(AllocAtError::Used { addr: 0x5000, order: 0 }));
+        assert_eq!(
+            tree.alloc_at(0x4000, 1),
+            Err(AllocAtError::PartlyUsed { addr: 0x4000, order: 1 })
+        );
+        assert_eq!(tree.alloc_at(0x4000, 0), Ok(()));
+        assert_eq!(
+            tree.alloc_at(0x1000, 1),
+            Err(AllocAtError::Misaligned { addr: 0x1000, order: 1 })
+        );
+        assert_eq!(tree.alloc_at(0x8000, 0), Err(AllocAtError::OutOfRange { addr: 0x8000 }));
+        assert_eq!(
+            tree.alloc_at(0x0, 4),
+            Err(AllocAtError::OrderTooLarge { order: 4, max_order: 3 })
+        );
+
+        // Blocks inside an allocated block report the block containing them
+        assert_eq!(tree.alloc_exact(1), Some(0x0 as *const u8));
+        assert_eq!(tree.alloc_at(0x1000, 0), Err(AllocAtError::Used { addr: 0x0, order: 1 }));
+        assert_eq!(tree.alloc_at(0x6000, 1), Ok(()));
+        assert_eq!(tree.alloc_exact(1), Some(0x2000 as *const u8));
+        assert_eq!(tree.alloc_exact(0), None);
+        assert_eq!(tree.check_blocks(), Ok(()));
+    }
+
+    #[test]
+    fn test_alloc_at_reserved() {
+        let mut tree = Tree::with_levels(4);
+        tree.reserve(0x2000, 0x4000);
+        assert_eq!(tree.alloc_at(0x2000, 0), Err(AllocAtError::Used { addr: 0x2000, order: 1 }));
+        assert_eq!(tree.alloc_at(0x0, 1), Ok(()));
+    }
+
+    #[test]
+    fn test_alloc_exact_below_dma_zone() {
+        let limit = 16 << 20;
+        let mut tree = Tree::new_at(0);
+
+        let mut blocks = BTreeSet::new();
+        for _ in 0..limit / block_size(0) {
+            let addr = tree.alloc_exact_below(0, limit).expect("Ran out of blocks below 16 MiB");
+            assert!((addr as usize) < limit);
+            assert!(blocks.insert(addr as usize));
+        }
+
+        // Failing leaves the tree as it was, so the rest of memory is still allocatable
+        let free = tree.free_histogram();
+        assert_eq!(tree.alloc_exact_below(0, limit), None);
+        assert_eq!(tree.free_histogram(), free);
+        assert_eq!(tree.check_blocks(), Ok(()));
+        assert_eq!(tree.alloc_exact(0), Some(limit as *const u8));
+    }
+
+    #[test]
+    fn test_alloc_exact_in_range_window() {
+        let mib2 = PageSize::Mib2.power_of_two() - BASE_ORDER;
+        let (low, high) = (0x400000, 0x600000);
+        let mut tree = Tree::new_at(0);
+        tree.alloc_exact(0).unwrap();
+
+        let mut blocks = BTreeSet::new();
+        for _ in 0..block_size(mib2) / block_size(0) {
+            let addr = tree.alloc_exact_in_range(0, low, high).expect("Window ran out early");
+            let addr = addr as usize;
+            assert!(low <= addr && addr + block_size(0) <= high);
+            assert!(blocks.insert(addr));
+        }
+
+        assert_eq!(tree.alloc_exact_in_range(0, low, high), None);
+        assert_eq!(tree.check_blocks(), Ok(()));
+        assert_eq!(tree.alloc_exact(0), Some(0x1000 as *const u8));
+    }
+
+    #[test]
+    fn test_alloc_exact_in_range_straddling() {
+        let mib2 = PageSize::Mib2.power_of_two() - BASE_ORDER;
+        let mut tree = Tree::new_at(0);
+
+        // The free 2 MiB blocks at 0x200000 and 0x600000 each straddle an end of the range
+        assert_eq!(
+            tree.alloc_exact_in_range(mib2, 0x300000, 0x700000),
+            Some(0x400000 as *const u8)
+        );
+        assert_eq!(tree.alloc_exact_in_range(mib2, 0x300000, 0x700000), None);
+        assert_eq!(tree.alloc_exact_in_range(0, 0x300000, 0x300000), None);
+        assert_eq!(tree.alloc_exact_in_range(0, 0x3ff001, 0x401000), None);
+        assert_eq!(tree.alloc_exact_in_range(0, 0x3fe001, 0x401000), Some(0x3ff000 as *const u8));
+        assert_eq!(tree.check_blocks(), Ok(()));
+    }
+
+    #[test]
+    fn test_free_blocks_fresh_tree() {
+        let tree = Tree::new_at(0);
+        assert_eq!(tree.free_blocks(MAX_ORDER).collect::<Vec<_>>(), [0]);
+        for order in 0..MAX_ORDER {
+            assert_eq!(tree.free_blocks(order).next(), None);
+        }
+        assert_eq!(Tree::empty().free_blocks(0).next(), None);
+        assert_eq!(tree.free_blocks(MAX_ORDER + 1).next(), None);
+    }
+
+    #[test]
+    fn test_free_blocks_after_one_alloc() {
+        let mut tree = Tree::new_at(0);
+        tree.alloc_exact(0).unwrap();
+
+        // Splitting the top level block leaves the right buddy free at every order below it
+        assert_eq!(tree.free_blocks(MAX_ORDER).next(), None);
+        for order in 0..MAX_ORDER {
+            assert_eq!(tree.free_blocks(order).collect::<Vec<_>>(), [block_size(order)]);
+        }
+
+        let free = tree.free_histogram();
+        for order in 0..LEVEL_COUNT {
+            assert_eq!(tree.free_blocks(order).count(), free[order as usize]);
+        }
+    }
+
+    #[test]
+    fn test_free_blocks_lowest_first() {
+        let mut tree = Tree::with_levels(4);
+        for addr in &[0x0, 0x2000, 0x5000] {
+            assert_eq!(tree.alloc_at(*addr, 0), Ok(()));
+        }
+        assert_eq!(tree.free_blocks(0).collect::<Vec<_>>(), [0x1000, 0x3000, 0x4000]);
+        assert_eq!(tree.free_blocks(1).collect::<Vec<_>>(), [0x6000]);
+    }
+
+    #[test]
+    fn test_alloc_below_matches_alloc_exact() {
+        // With no limit, both choose the lowest free block and report the same events
+        let mut below = Tree::new_at(block_size(MAX_ORDER));
+        let mut exact = Tree::new_at(block_size(MAX_ORDER));
+        let below_recorder = RecordingObserver::new();
+        let exact_recorder = RecordingObserver::new();
+        below.set_observer(Box::new(below_recorder.clone()));
+        exact.set_observer(Box::new(exact_recorder.clone()));
+
+        let mut rng = XorShift::new(453);
+        let mut allocated = Vec::new();
+        for _ in 0..200 {
+            if allocated.is_empty() || rng.below(3) != 0 {
+                let order = rng.below(8) as u8;
+                let addr = below.alloc_below(order, usize::max_value()).unwrap();
+                assert_eq!(exact.alloc_exact(order), Some(addr as *const u8));
+                allocated.push((addr, order));
+            } else {
+                let index = rng.below(allocated.len() as u64) as usize;
+                let (addr, order) = allocated.swap_remove(index);
+                assert!(below.dealloc_exact(addr as *const u8, order));
+                assert!(exact.dealloc_exact(addr as *const u8, order));
+            }
+        }
+
+        assert_eq!(below_recorder.take_events(), exact_recorder.take_events());
+        assert_eq!(below.op_counters(), exact.op_counters());
+        assert_eq!(below.to_snapshot(), exact.to_snapshot());
+    }
+
+    #[test]
+    fn test_forest_alloc_below_limit() {
+        let top_level_size = block_size(MAX_ORDER);
+        let limit = 16 << 20;
+        let mut forest = Forest::new();
+        forest.create_top_level(top_level_size);
+        forest.create_top_level(0);
+
+        // Low memory runs out while the rest of the first tree and all of the second are free
+        let mut allocated = BlockSet::new();
+        let mut rng = XorShift::new(16);
+        loop {
+            let order = rng.below(9) as u8;
+            match forest.alloc_below(order, limit) {
+                Ok(addr) => {
+                    assert!(addr + block_size(order) <= limit, "{:#x} straddles the limit", addr);
+                    assert!(allocated.insert(addr, block_size(order)));
+                }
+                Err(BlockAllocateError::NoBlocksAvailable) if order == 0 => break,
+                Err(err) => assert_eq!(err, BlockAllocateError::NoBlocksAvailable),
+            }
+        }
+
+        assert_eq!(forest.usage().used_bytes(), limit);
+        assert_eq!(forest.alloc_below(0, limit), Err(BlockAllocateError::NoBlocksAvailable));
+        assert!(forest.alloc_exact(MAX_ORDER).is_some());
+        assert_eq!(forest.alloc_exact(0), Some(limit as *const u8));
+    }
+
+    #[test]
+    fn test_empty_tree_init_in() {
+        let mut tree = Tree::empty();
+        assert_eq!(tree.alloc_exact(0), None);
+        assert!(!tree.dealloc_exact(0 as *const u8, 0));
+        assert_eq!(tree.free_histogram(), [0; LEVEL_COUNT as usize]);
+        assert_eq!(tree.largest_free_extent(), None);
+
+        let storage: &'static mut [u8] = Box::leak(vec![0xff; 128].into_boxed_slice());
+        let needed = Tree::storage_len(4);
+        #[cfg(not(feature = "leaf_bitmap"))]
+        assert_eq!(needed, if cfg!(feature = "packed_bitmap") { 8 } else { 15 });
+        assert_eq!(
+            tree.init(0, LEVEL_COUNT + 1),
+            Err(TreeInitError::InvalidLevels { levels: LEVEL_COUNT + 1 })
+        );
+        assert_eq!(
+            tree.init(0x1000, 4),
+            Err(TreeInitError::InvalidBaseAddress { base_address: 0x1000 })
+        );
+
+        let (small, storage) = storage.split_at_mut(needed - 1);
+        assert_eq!(
+            tree.init_in(small, 0, 4),
+            Err(TreeInitError::StorageTooSmall { needed })
+        );
+        assert_eq!(tree.init_in(storage, 0x8000, 4), Ok(()));
+        assert_eq!(tree.managed_bytes(), 0x8000);
+
+        let addresses: Vec<_> = (0..8).map(|_| tree.alloc_exact(0).unwrap() as usize).collect();
+        assert_eq!(addresses, (0..8).map(|block| 0x8000 + block * 0x1000).collect::<Vec<_>>());
+        assert_eq!(tree.alloc_exact(0), None);
+        assert_eq!(tree.init(0, 4), Err(TreeInitError::AlreadyInitialized));
+    }
+
+    #[test]
+    fn test_new_in_matches_boxed() {
+        let needed = Tree::storage_len(LEVEL_COUNT);
+        let small: &'static mut [u8] = Box::leak(vec![0; needed - 1].into_boxed_slice());
+        assert_eq!(Tree::new_in(small).err(), Some(TreeInitError::StorageTooSmall { needed }));
+
+        let storage: &'static mut [u8] = Box::leak(vec![0xff; needed].into_boxed_slice());
+        let mut borrowed = Tree::new_in(storage).unwrap();
+        let mut boxed = Tree::new();
+
+        let mut rng = XorShift::new(505);
+        let mut live = Vec::new();
+        for _ in 0..2000 {
+            if !live.is_empty() && rng.below(3) == 0 {
+                let (addr, order) = live.swap_remove(rng.below(live.len() as u64) as usize);
+                assert!(borrowed.dealloc_exact(addr, order));
+                assert!(boxed.dealloc_exact(addr, order));
+            } else {
+                let order = rng.below(6) as u8;
+                let addr = borrowed.alloc_exact(order);
+                assert_eq!(addr, boxed.alloc_exact(order));
+                live.extend(addr.map(|addr| (addr, order)));
+            }
+        }
+
+        assert_eq!(borrowed.usage(), boxed.usage());
+        assert_eq!(borrowed.to_snapshot(), boxed.to_snapshot());
+    }
+
+    #[test]
+    fn test_reserve_range_toy_tree() {
+        let mut tree = Tree::with_levels(4);
+        tree.reserve_range(0x1000, 0x7000);
+        assert_eq!(tree.usage().used_bytes(), 0);
+        assert_eq!(tree.managed_bytes(), 0x2000);
+
+        assert_eq!(tree.alloc_exact(0), Some(0 as *const u8));
+        assert_eq!(tree.alloc_exact(0), Some(0x7000 as *const u8));
+        assert_eq!(tree.alloc_exact(0), None);
+    }
+
+    #[test]
+    fn test_reserve_rounds_outward() {
+        let mut tree = Tree::with_levels(8);
+        let pages = 1 << 7;
+        // 6 KiB in the middle of the tree, beginning half way through a page
+        let (start, end) = (pages / 2 * block_size(0) - 0x800, pages / 2 * block_size(0) + 0x1000);
+        tree.reserve(start, end);
+        assert_eq!(tree.managed_bytes(), (pages - 2) * block_size(0));
+
+        // The two pages the range overlaps
+        let reserved = start - 0x800..end;
+        let mut allocated = 0;
+        while let Some(addr) = tree.alloc_exact(0) {
+            assert!(!reserved.contains(&(addr as usize)), "{:?} is reserved", addr);
+            allocated += 1;
+        }
+        assert_eq!(allocated, pages - 2);
+
+        // Ranges which are already aligned are reserved as they are
+        let mut tree = Tree::with_levels(4);
+        tree.reserve(0x1000, 0x3000);
+        assert_eq!(tree.managed_bytes(), 6 * block_size(0));
+        assert_eq!(tree.alloc_exact(0), Some(0 as *const u8));
+        assert_eq!(tree.alloc_exact(0), Some(0x3000 as *const u8));
+    }
+
+    #[test]
+    fn test_reserved_blocks_not_freed() {
+        let mut tree = Tree::with_levels(4);
+        tree.reserve_range(0x0, 0x1000);
+        tree.reserve_range(0x4000, 0x8000);
+
+        assert!(!tree.dealloc_exact(0x0 as *const u8, 0));
+        assert!(!tree.dealloc_exact(0x4000 as *const u8, 2));
+        assert!(!tree.dealloc_exact(0x6000 as *const u8, 1));
+        let handle = BlockHandle::new(1 << 3, 0);
+        assert_eq!(tree.handle_address(handle), Some(0x0 as *const u8));
+        assert!(!tree.dealloc_handle(handle));
+
+        // The holes are still never handed out
+        assert_eq!(tree.managed_bytes(), 0x3000);
+        let addresses: Vec<_> = (0..3).map(|_| tree.alloc_exact(0).unwrap() as usize).collect();
+        assert_eq!(addresses, vec![0x1000, 0x2000, 0x3000]);
+        assert_eq!(tree.alloc_exact(0), None);
+        assert_eq!(tree.usage().used_bytes(), 0x3000);
+    }
+
+    #[test]
+    #[should_panic(expected = "not free")]
+    fn test_reserve_range_used() {
+        let mut tree = Tree::with_levels(4);
+        tree.alloc_exact(1).unwrap();
+        tree.reserve_range(0x1000, 0x2000);
+    }
+
+    /// A tree at a non zero base with a mixture of used, free and reserved blocks
+    fn fragmented_toy_tree() -> Tree {
+        let mut tree = Tree::with_levels_at(5, block_size(4) * 3);
+        tree.reserve_range(tree.base_address + 0xc000, tree.base_address + 0xe000);
+
+        let addresses: Vec<_> = (0..6).map(|_| tree.alloc_exact(0).unwrap()).collect();
+        tree.alloc_exact(2).unwrap();
+        for &addr in addresses.iter().step_by(2) {
+            assert!(tree.dealloc_exact(addr, 0));
+        }
+
+        tree
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut original = fragmented_toy_tree();
+        let mut restored = Tree::from_snapshot(&original.to_snapshot()).unwrap();
+
+        let blocks = |tree: &Tree| {
+            let mut blocks = Vec::new();
+            tree.for_each_block(&mut |block| blocks.push(block));
+            blocks
+        };
+        assert_eq!(blocks(&restored), blocks(&original));
+        assert_eq!(restored.usage(), original.usage());
+        assert_eq!(restored.managed_bytes(), original.managed_bytes());
+        assert_eq!(restored.to_snapshot(), original.to_snapshot());
+
+        // The restored tree hands out the same blocks from here on
+        for &order in &[0, 1, 0, 0, 2, 0, 1] {
+            assert_eq!(restored.alloc_exact(order), original.alloc_exact(order));
+        }
+        assert!(restored.dealloc_exact(restored.base_address as *const u8, 0));
+        assert!(original.dealloc_exact(original.base_address as *const u8, 0));
+        assert_eq!(restored.alloc_exact(0), original.alloc_exact(0));
+    }
+
+    #[test]
+    fn test_bytes_continue_sequence() {
+        let mut rng = XorShift::new(517);
+        let mut original = Tree::new_at(0);
+        let mut live = Vec::new();
+        let step = |tree: &mut Tree, live: &mut Vec<(*const u8, u8)>, rng: &mut XorShift| {
+            if live.is_empty() || rng.below(3) != 0 {
+                let order = rng.below(6) as u8;
+                if let Some(addr) = tree.alloc_exact(order) {
+                    live.push((addr, order));
+                }
+            } else {
+                let (addr, order) = live.swap_remove(rng.below(live.len() as u64) as usize);
+                assert!(tree.dealloc_exact(addr, order));
+            }
+        };
+
+        for _ in 0..500 {
+            step(&mut original, &mut live, &mut rng);
+        }
+
+        let mut restored = Tree::from_bytes(&original.to_bytes()).unwrap();
+        let (mut restored_live, mut restored_rng) = (live.clone(), rng.clone());
+        for _ in 0..500 {
+            step(&mut original, &mut live, &mut rng);
+            step(&mut restored, &mut restored_live, &mut restored_rng);
+            assert_eq!(restored_live, live);
+        }
+        assert_eq!(restored.to_bytes(), original.to_bytes());
+
+        // A block claiming more free memory than its children have is rejected
+        let mut bytes = original.to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] = 0;
+        assert!(Tree::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_rejects_corrupt_input() {
+        let bytes = fragmented_toy_tree().to_snapshot();
+        // Offsets of the fields after the 5 byte header. The tree has one reserved block.
+        let (levels, used_bytes, reserved, blocks) = (5, 14, 54, 62);
+
+        let corrupt = |offset: usize, value: u8| {
+            let mut corrupted = bytes.clone();
+            corrupted[offset] = value;
+            Tree::from_snapshot(&corrupted).err()
+        };
+
+        assert_eq!(corrupt(0, b'X'), Some(SnapshotError::BadHeader));
+        assert_eq!(corrupt(levels, 0), Some(SnapshotError::InvalidField { field: "levels" }));
+        assert_eq!(
+            corrupt(levels, LEVEL_COUNT + 1),
+            Some(SnapshotError::InvalidField { field: "levels" })
+        );
+        // The root claiming to be completely free while its children are not
+        assert_eq!(corrupt(blocks, 5), Some(SnapshotError::InvalidBlock { index: 0 }));
+        // A leaf with a free order only a larger block could have
+        assert_eq!(corrupt(blocks + 15, 2), Some(SnapshotError::InvalidBlock { index: 15 }));
+        // Usage which does not agree with the used blocks
+        assert_eq!(
+            corrupt(used_bytes, bytes[used_bytes] + 1),
+            Some(SnapshotError::InvalidField { field: "used bytes" })
+        );
+        // Reserved blocks which are not in the tree or do not look allocated
+        for &node_index in &[0, 1, 15, 32] {
+            assert_eq!(
+                corrupt(reserved, node_index),
+                Some(SnapshotError::InvalidField { field: "reserved blocks" })
+            );
+        }
+
+        assert_eq!(
+            Tree::from_snapshot(&bytes[..bytes.len() - 1]).err(),
+            Some(SnapshotError::Truncated)
+        );
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Tree::from_snapshot(&trailing).err(),
+            Some(SnapshotError::TrailingBytes { count: 1 })
+        );
+    }
+
+    #[test]
+    fn test_render_map_toy_tree() {
+        let expected = |strip: &str, char_size: usize| {
+            format!(
+                "{}\n0x0..0x8000, 1 char = {:#x} bytes: \
+                 '.' free, '#' used, '+' partly used, '-' reserved\n",
+                strip, char_size
+            )
+        };
+
+        let mut tree = Tree::with_levels(4);
+        assert_eq!(tree.render_map(8), expected("........", 0x1000));
+
+        tree.alloc_exact(1).unwrap();
+        tree.alloc_exact(0).unwrap();
+        assert_eq!(tree.render_map(8), expected("###.....", 0x1000));
+        assert_eq!(tree.render_map(4), expected("#+..", 0x2000));
+        // Never more columns than there are blocks of order 0
+        assert_eq!(tree.render_map(100), tree.render_map(8));
+
+        tree.alloc_exact(0).unwrap();
+        tree.alloc_exact(2).unwrap();
+        assert_eq!(tree.render_map(3), expected("##", 0x4000));
+    }
+
+    #[test]
+    fn test_export_usage_toy_tree() {
+        let mut tree = Tree::with_levels(4);
+        assert_eq!(tree.export_usage(0x2000), vec![0.0; 4]);
+
+        tree.alloc_exact(0).unwrap();
+        assert_eq!(tree.export_usage(0x2000), vec![0.5, 0.0, 0.0, 0.0]);
+        // Buckets which do not line up with blocks
+        assert_eq!(
+            tree.export_usage(0x1800),
+            vec![4096.0 / 6144.0, 0.0, 0.0, 0.0, 0.0, 0.0]
+        );
+
+        tree.alloc_exact(0).unwrap();
+        tree.alloc_exact(0).unwrap();
+        assert_eq!(tree.export_usage(0x1800), vec![1.0, 1.0, 0.0, 0.0, 0.0, 0.0]);
+        // The last bucket only covers the last 0x2000 bytes of the tree
+        tree.alloc_exact(2).unwrap();
+        assert_eq!(tree.export_usage(0x3000), vec![1.0, 2.0 / 3.0, 1.0]);
+        assert_eq!(tree.export_usage(0x8000), vec![7.0 / 8.0]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_export_usage_rejects_small_buckets() {
+        Tree::with_levels(4).export_usage(0x800);
+    }
+
+    #[test]
+    fn test_for_each_block_toy_tree() {
+        let mut tree = Tree::with_levels(3);
+        tree.alloc_exact(0).unwrap();
+        tree.alloc_exact(0).unwrap();
+        tree.alloc_exact(1).unwrap();
+        assert!(tree.dealloc_exact(0 as *const u8, 0));
+
+        let mut blocks = Vec::new();
+        tree.for_each_block(&mut |block| blocks.push(block));
+        blocks.sort_by_key(|block| block.addr);
+        assert_eq!(
+            blocks,
+            vec![
+                BlockInfo { addr: 0, order: 0, used: false },
+                BlockInfo { addr: block_size(0), order: 0, used: true },
+                BlockInfo { addr: block_size(1), order: 1, used: true },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_peaks() {
+        let mut forest = Forest::new();
+        forest.create_top_level(0);
+
+        let addresses: Vec<_> = (0..100).map(|_| forest.alloc_exact(0).unwrap()).collect();
+        for &addr in &addresses[..50] {
+            assert!(forest.dealloc_exact(addr, 0));
+        }
+        for _ in 0..25 {
+            forest.alloc_exact(0).unwrap();
+        }
+
+        assert_eq!(forest.usage().outstanding_allocations(), 75);
+        assert_eq!(forest.peak_outstanding_allocations(), 100);
+        assert_eq!(forest.peak_used_bytes(), 100 * block_size(0));
+        assert_eq!(forest.trees[0].peak_used_bytes(), 100 * block_size(0));
+
+        forest.reset_peaks();
+        assert_eq!(forest.peak_outstanding_allocations(), 75);
+        assert_eq!(forest.peak_used_bytes(), 75 * block_size(0));
+    }
+
+    #[test]
+    fn test_forest_spills_into_next_tree() {
+        let mut forest = Forest::new();
+        forest.create_top_level(0);
+        forest.create_top_level(block_size(MAX_ORDER));
+
+        assert_eq!(forest.alloc_exact(MAX_ORDER), Some(0 as *const u8));
+        assert_eq!(forest.alloc_exact(0), Some(block_size(MAX_ORDER) as *const u8));
+        assert_eq!(forest.alloc_exact(MAX_ORDER), None);
+        assert_eq!(forest.alloc_exact(MAX_ORDER + 1), None);
+    }
+
+    #[test]
+    fn test_forest_alloc_pages_rolls_back() {
+        let mut forest = Forest::new();
+        forest.create_top_level(0);
+        for order in (4..MAX_ORDER).rev() {
+            forest.alloc_exact(order).unwrap();
+        }
+        let free = forest.free_histogram();
+
+        // The pages allocated before running out are freed again and merge back
+        let err = forest.alloc_pages(17).map(|_| ()).unwrap_err();
+        assert_eq!(err, AllocError { requested: 17, available: 16 });
+        assert_eq!(forest.free_histogram(), free);
+        assert_eq!(forest.usage().outstanding_allocations(), MAX_ORDER as usize - 4);
+
+        let pages: BTreeSet<usize> = forest.alloc_pages(16).unwrap().collect();
+        assert_eq!(pages.len(), 16);
+        assert_eq!(BuddyAllocatorApi::allocate(&mut forest, 0), None);
+    }
+
+    #[test]
+    fn test_forest_remove_tree() {
+        let size = block_size(MAX_ORDER);
+        let mut forest = Forest::new();
+        forest.create_top_level(0);
+        forest.create_top_level(size);
+
+        let addr = forest.alloc_exact(MAX_ORDER).unwrap();
+        let second = forest.alloc_exact(0).unwrap();
+        assert_eq!(second, size as *const u8);
+
+        // Both trees are in use, and no tree begins in the middle of one
+        let busy = |begin_address| Some(RegionBusy { begin_address });
+        assert_eq!(forest.remove_tree(0).err(), busy(0));
+        assert_eq!(forest.remove_tree(size).err(), busy(size));
+        assert_eq!(forest.remove_tree(size / 2).err(), busy(size / 2));
+
+        assert!(forest.dealloc_exact(second, 0));
+        let tree = forest.remove_tree(size).unwrap();
+        assert_eq!(tree.base_address(), size);
+        assert!(tree.is_completely_free());
+        assert_eq!(forest.managed_bytes(), size);
+        assert_eq!(forest.usage().outstanding_allocations(), 1);
+
+        // A stale address from the removed tree is not routed to its neighbour, and nothing is
+        // allocated from it any more
+        assert!(!forest.dealloc_exact(second, 0));
+        assert_eq!(forest.alloc_exact(0), None);
+        assert!(forest.remove_tree(size).is_err());
+
+        // The neighbour is still in use
+        assert!(forest.remove_tree(0).is_err());
+        assert!(forest.dealloc_exact(addr, MAX_ORDER));
+        assert!(forest.remove_tree(0).is_ok());
+        assert_eq!(forest.managed_bytes(), 0);
+    }
+
+    #[test]
+    fn test_forest_re_add_removed_tree() {
+        let size = block_size(MAX_ORDER);
+        let mut forest = Forest::new();
+        forest.create_top_level(0);
+        let addr = forest.alloc_exact(0).unwrap();
+        assert!(forest.dealloc_exact(addr, 0));
+
+        let mut tree = forest.remove_tree(0).unwrap();
+        assert_eq!(
+            tree.rebase(size / 2),
+            Err(TreeInitError::InvalidBaseAddress { base_address: size / 2 })
+        );
+        tree.rebase(size * 3).unwrap();
+        forest.add_tree(tree);
+
+        assert_eq!(forest.alloc_exact(1), Some((size * 3) as *const u8));
+        assert!(!forest.dealloc_exact(0 as *const u8, 1));
+        assert!(forest.dealloc_exact((size * 3) as *const u8, 1));
+        assert!(forest.remove_tree(size * 3).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "completely free")]
+    fn test_rebase_used_tree() {
+        let mut tree = Tree::new();
+        tree.alloc_exact(0).unwrap();
+        let _ = tree.rebase(block_size(MAX_ORDER));
+    }
+
+    /// Every byte of both tiers of a tree's blocks, including the unused start of the cold tier
+    #[cfg(feature = "rayon")]
+    fn raw_blocks(tree: &Tree) -> (Vec<u8>, Vec<u8>) {
+        let hot = tree.flat_blocks.hot.0.iter().map(|block| block.order_free).collect();
+        let cold = tree.flat_blocks.cold_bytes().to_vec();
+        (hot, cold)
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_fill_matches_serial() {
+        for &levels in &[1, HOT_LEVELS - 1, HOT_LEVELS, HOT_LEVELS + 1, 12, LEVEL_COUNT] {
+            let parallel = Tree::with_levels(levels);
+
+            let mut serial = Tree::with_levels(levels);
+            for index in 0..serial.flat_blocks.len() {
+                serial.flat_blocks.set(index, 0);
+            }
+            serial.fill_levels(levels);
+
+            assert!(raw_blocks(&parallel) == raw_blocks(&serial), "{} levels differ", levels);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_forest_new_parallel_matches_serial() {
+        let bases: Vec<usize> = (0..8).map(|tree| tree * block_size(MAX_ORDER)).collect();
+        let parallel = Forest::new_parallel(&bases);
+
+        let mut serial = Forest::new();
+        for &base in &bases {
+            serial.create_top_level(base);
+        }
+
+        assert_eq!(parallel.trees.len(), bases.len());
+        for (parallel, serial) in parallel.trees.iter().zip(&serial.trees) {
+            assert_eq!(parallel.base_address, serial.base_address);
+            assert!(raw_blocks(parallel) == raw_blocks(serial));
+        }
+    }
+
+    #[test]
+    fn test_forest_with_trees() {
+        let size = block_size(MAX_ORDER);
+        let mut forest = Forest::with_trees(3);
+        assert_eq!(forest.managed_bytes(), 3 * size);
+
+        for tree_number in 0..3 {
+            assert_eq!(forest.alloc_exact(MAX_ORDER), Some((tree_number * size) as *const u8));
+        }
+        assert_eq!(forest.alloc_exact(0), None);
+    }
+
+    #[test]
+    fn test_forest_grows_on_demand() {
+        let size = block_size(3);
+        let mut forest = Forest::new();
+        forest.add_tree(Tree::with_levels_at(4, 0));
+        let growth = Growth { levels: 4, max_bytes: None };
+        assert_eq!(forest.set_growth(Some(growth)), None);
+
+        // One more block than the first tree holds is allocated from a tree added after it
+        for block in 0..8 {
+            assert_eq!(forest.try_alloc_exact(0), Ok((block * block_size(0)) as *const u8));
+        }
+        assert_eq!(forest.try_alloc_exact(0), Ok(size as *const u8));
+        assert_eq!(forest.managed_bytes(), 2 * size);
+        assert_eq!(forest.usage().used_bytes(), 9 * block_size(0));
+
+        // A block too large for a tree of the growth is not allocated, and adds no tree
+        assert_eq!(forest.alloc_exact(4), None);
+        assert_eq!(forest.managed_bytes(), 2 * size);
+        assert_eq!(
+            forest.try_alloc_exact(MAX_ORDER + 1),
+            Err(BlockAllocateError::OrderTooLarge { order: MAX_ORDER + 1, max_order: MAX_ORDER })
+        );
+
+        // The first address after every tree aligned to the new tree's top block
+        forest.set_growth(Some(Growth { levels: 5, max_bytes: None }));
+        assert_eq!(forest.alloc_exact(4), Some((2 * size) as *const u8));
+    }
+
+    #[test]
+    fn test_forest_growth_limit() {
+        let size = block_size(3);
+        let mut forest = Forest::new();
+        forest.set_growth(Some(Growth { levels: 4, max_bytes: Some(2 * size + 1) }));
+
+        assert_eq!(forest.try_alloc_exact(3), Ok(0 as *const u8));
+        assert_eq!(forest.try_alloc_exact(3), Ok(size as *const u8));
+        assert_eq!(forest.try_alloc_exact(3), Err(BlockAllocateError::NoBlocksAvailable));
+        assert_eq!(forest.managed_bytes(), 2 * size);
+
+        // Without growth the forest keeps the trees it has
+        forest.set_growth(None);
+        forest.dealloc(0 as *const u8).unwrap();
+        assert_eq!(forest.try_alloc_exact(0), Ok(0 as *const u8));
+        assert_eq!(forest.growth(), None);
+    }
+
+    #[test]
+    fn test_forest_dealloc_across_boundary() {
+        let size = block_size(3);
+        let mut forest = Forest::new();
+        forest.add_tree(Tree::with_levels_at(4, 0));
+        forest.add_tree(Tree::with_levels_at(4, size));
+
+        // The ninth block of order 0 is the first in the second tree
+        let blocks: Vec<_> = (0..9).map(|_| forest.alloc_exact(0).unwrap()).collect();
+        assert_eq!(blocks[7], (size - block_size(0)) as *const u8);
+        assert_eq!(blocks[8], size as *const u8);
+        assert_eq!(forest.tree_containing(blocks[8]).unwrap().usage().used_bytes(), block_size(0));
+
+        // Freeing into the first tree makes it the one allocated from again
+        forest.dealloc(blocks[2]).unwrap();
+        assert_eq!(forest.usage().used_bytes(), 8 * block_size(0));
+        let first_tree = forest.tree_containing(blocks[2]).unwrap();
+        assert_eq!(first_tree.usage().used_bytes(), 7 * block_size(0));
+        assert_eq!(forest.alloc_exact(0), Some(blocks[2]));
+
+        assert_eq!(forest.dealloc(blocks[2]), Ok(()));
+        assert_eq!(
+            forest.dealloc(blocks[2]),
+            Err(FreeError::NotAllocated { addr: blocks[2] as usize })
+        );
+        assert_eq!(
+            forest.dealloc((2 * size) as *const u8),
+            Err(FreeError::OutOfRange { addr: 2 * size })
+        );
+        assert!(forest.tree_containing((2 * size) as *const u8).is_none());
+        assert!(!forest.dealloc_exact((2 * size) as *const u8, 0));
+
+        // With the order given, only the tree containing the block frees it
+        assert!(forest.dealloc_exact(blocks[8], 0));
+        assert!(!forest.dealloc_exact(blocks[8], 0));
+
+        for &addr in blocks.iter().filter(|&&addr| addr != blocks[2] && addr != blocks[8]) {
+            forest.dealloc(addr).unwrap();
+        }
+        assert_eq!(forest.usage().used_bytes(), 0);
+        assert_eq!(forest.alloc_exact(3), Some(0 as *const u8));
+        assert_eq!(forest.alloc_exact(3), Some(size as *const u8));
+    }
+
+    #[test]
+    fn test_alloc_exact_new_at() {
+        let base = block_size(MAX_ORDER) * 3;
+        let mut tree = Tree::new_at(base);
+        assert_eq!(tree.alloc_exact(MAX_ORDER - 1), Some(base as *const u8));
+        assert_eq!(
+            tree.alloc_exact(MAX_ORDER - 1),
+            Some((base + block_size(MAX_ORDER - 1)) as *const u8)
+        );
+        assert_eq!(tree.alloc_exact(0), None);
+    }
+
+    #[test]
+    fn test_new_at_8gib_disjoint() {
+        let high_base = 8 << 30;
+        let mut low = Tree::new_at(0);
+        let mut high = Tree::new_at(high_base);
+        let mut blocks = BlockSet::new();
+
+        // Both trees are exhausted, alternating between them
+        let order = MAX_ORDER - 6;
+        for _ in 0..1 << 6 {
+            for tree in &mut [&mut low, &mut high] {
+                let addr = tree.alloc_exact(order).unwrap() as usize;
+                assert!(blocks.insert(addr, block_size(order)), "{:#x} overlaps", addr);
+            }
+        }
+        assert_eq!(low.alloc_exact(0), None);
+        assert_eq!(high.alloc_exact(0), None);
+
+        // Neither tree frees the other's blocks
+        let error = Err(FreeError::OutOfRange { addr: high_base });
+        assert_eq!(low.dealloc(high_base as *const u8), error);
+        assert_eq!(high.dealloc(0 as *const u8), Err(FreeError::OutOfRange { addr: 0 }));
+
+        let mut fresh = Tree::new_at(high_base);
+        assert_eq!(fresh.alloc_exact(MAX_ORDER), Some(high_base as *const u8));
+    }
+
+    #[test]
+    fn test_demo_with_config() {
+        // Three trees of 16 blocks of order 0 are needed for 40 blocks
+        let config = BuddyConfig::new(12, 5).unwrap();
+        let report = demo_with_config(&config, false, 40, 0).unwrap();
+        assert_eq!(report.allocated_bytes, 40 * 0x1000);
+        assert_eq!(report.managed_bytes, 3 * 16 * 0x1000);
+
+        let report = demo_with_config(&config, false, 3, 4).unwrap();
+        assert_eq!(report.allocated_bytes, 3 * 16 * 0x1000);
+        assert_eq!(report.managed_bytes, report.allocated_bytes);
+
+        assert_eq!(
+            demo_with_config(&config, false, 1, 5),
+            Err(DemoError::OrderTooLarge { order: 5, max_order: 4 })
+        );
+        assert_eq!(
+            demo_with_config(&BuddyConfig::new(13, 5).unwrap(), false, 1, 0),
+            Err(DemoError::UnsupportedBaseOrder { base_order: 13 })
+        );
+    }
+
+    /// One more block than a tree holds used to index past the last tree
+    #[test]
+    fn test_demo_one_past_a_tree() {
+        let config = BuddyConfig::new(12, 5).unwrap();
+        let tree_bytes = 16 * 0x1000;
+
+        // The demo begins with one tree, so the seventeenth block is from a tree it grew by
+        let report = demo_with_config(&config, false, 16, 0).unwrap();
+        assert_eq!(report.managed_bytes, tree_bytes);
+        let report = demo_with_config(&config, false, 17, 0).unwrap();
+        assert_eq!(report.allocated_bytes, 17 * 0x1000);
+        assert_eq!(report.managed_bytes, 2 * tree_bytes);
+
+        // Once another tree would go over the limit, the demo runs out of blocks
+        assert_eq!(
+            demo_with_max_bytes(&config, false, 17, 0, Some(tree_bytes)),
+            Err(DemoError::OutOfBlocks { allocation: 16 })
+        );
+        let report = demo_with_max_bytes(&config, false, 17, 0, Some(2 * tree_bytes)).unwrap();
+        assert_eq!(report.managed_bytes, 2 * tree_bytes);
+    }
+
+    /// Blocks of 64 bytes, as in a small SRAM pool
+    struct Bytes64;
+
+    impl BaseOrder for Bytes64 {
+        const BASE_ORDER: u8 = 6;
+    }
+
+    /// Blocks of 64 KiB, so that a tree of the default number of levels is larger than 4 GiB
+    struct Kib64;
+
+    impl BaseOrder for Kib64 {
+        const BASE_ORDER: u8 = 16;
+    }
+
+    /// Blocks so large that a tree of the default number of levels does not fit in a `usize`
+    struct Huge;
+
+    impl BaseOrder for Huge {
+        const BASE_ORDER: u8 = 50;
+    }
+
+    #[test]
+    fn test_addresses_above_4gib() {
+        let mut tree: Tree<Kib64> = Tree::with_base_order_at(0);
+        assert_eq!(tree.alloc_exact(MAX_ORDER - 1), Some(0 as *const u8));
+
+        let second = tree.alloc_exact(MAX_ORDER - 1).unwrap() as usize;
+        assert_eq!(second, block_size_in::<Kib64>(MAX_ORDER - 1));
+        assert!(second as u64 > u64::from(::std::u32::MAX));
+        assert!(tree.dealloc_exact(second as *const u8, MAX_ORDER - 1));
+        assert_eq!(tree.alloc_exact(MAX_ORDER - 2), Some(second as *const u8));
+    }
+
+    #[test]
+    fn test_init_rejects_oversized_tree() {
+        let mut tree = Tree::<Huge>::empty_with_base_order();
+        let levels = (mem::size_of::<usize>() * 8) as u8 - Huge::BASE_ORDER;
+        let error = Err(TreeInitError::InvalidLevels { levels: levels + 1 });
+        assert_eq!(tree.init(0, levels + 1), error);
+        assert_eq!(tree.init(0, levels), Ok(()));
+        assert_eq!(tree.alloc_exact(levels - 1), Some(0 as *const u8));
+    }
+
+    #[test]
+    fn test_base_orders_side_by_side() {
+        assert_eq!(block_size_in::<Bytes64>(0), 64);
+        assert_eq!(block_size_in::<Bytes64>(3), 512);
+        assert_eq!(block_size_in::<DefaultBaseOrder>(3), block_size(3));
+
+        let ram_base = block_size(MAX_ORDER);
+        let sram_base = block_size_in::<Bytes64>(MAX_ORDER) * 5;
+        let mut ram = Tree::new_at(ram_base);
+        let mut sram: Tree<Bytes64> = Tree::with_base_order_at(sram_base);
+
+        // The same orders are a page or 64 bytes apart
+        let step = 1 << BASE_ORDER;
+        assert_eq!(ram.alloc_exact(0), Some(ram_base as *const u8));
+        assert_eq!(ram.alloc_exact(0), Some((ram_base + step) as *const u8));
+        assert_eq!(ram.alloc_exact(2), Some((ram_base + 4 * step) as *const u8));
+        assert_eq!(sram.alloc_exact(0), Some(sram_base as *const u8));
+        assert_eq!(sram.alloc_exact(0), Some((sram_base + 64) as *const u8));
+        assert_eq!(sram.alloc_exact(2), Some((sram_base + 256) as *const u8));
+
+        // Addresses are checked against the block size of each tree
+        assert!(!sram.dealloc_exact((sram_base + 32) as *const u8, 0));
+        assert!(sram.dealloc_exact((sram_base + 64) as *const u8, 0));
+        assert!(!ram.dealloc_exact((ram_base + 64) as *const u8, 0));
+        assert!(ram.dealloc_exact((ram_base + step) as *const u8, 0));
+
+        // A whole SRAM tree is far smaller, so it is aligned to less
+        let mut empty_ram = Tree::empty();
+        assert_eq!(
+            empty_ram.init(sram_base, LEVEL_COUNT),
+            Err(TreeInitError::InvalidBaseAddress { base_address: sram_base })
+        );
+        let mut empty_sram = Tree::<Bytes64>::empty_with_base_order();
+        assert_eq!(
+            empty_sram.init(64, LEVEL_COUNT),
+            Err(TreeInitError::InvalidBaseAddress { base_address: 64 })
+        );
+        assert_eq!(empty_sram.init(sram_base, LEVEL_COUNT), Ok(()));
+    }
+
+    #[test]
+    fn test_alloc_unique_addresses_multi_tree() {
+        const TREES: usize = 3;
+        let mut seen = BTreeSet::new();
+        let mut rng = XorShift::new(432);
+
+        for tree_number in 0..TREES {
+            let base = block_size(MAX_ORDER) * tree_number;
+            let mut tree = Tree::new_at(base);
+            let mut regions = RegionTracker::new();
+            regions.add(base, block_size(MAX_ORDER));
+
+            // Some small blocks of mixed orders, then fill the rest of the tree with large ones
+            let orders = (0..256)
+                .map(|_| rng.below(4) as u8)
+                .chain(::std::iter::repeat(MAX_ORDER - 4));
+
+            for order in orders {
+                let addr = match tree.alloc_exact(order) {
+                    Some(addr) => addr as usize,
+                    None if order == MAX_ORDER - 4 => break,
+                    None => panic!("Tree ran out of small blocks!"),
+                };
+
+                // Walking the whole tree after every one of the small blocks would make the test
+                // slow, so only every 16th is checked
+                let checked = order == MAX_ORDER - 4 || seen.len() % 16 == 0;
+                if cfg!(debug_assertions) && checked {
+                    assert_eq!(tree.check_invariants(), Ok(()));
+                }
+
+                // Every address of a tree must lie in that tree's own range
+                regions.assert_valid(addr, block_size(order));
+                assert!(seen.insert(addr), "Address {:#x} was allocated twice!", addr);
+            }
+        }
+
+        // The large blocks fill the trees up, so the addresses from every tree were checked
+        assert!(seen.iter().any(|&addr| addr >= block_size(MAX_ORDER) * (TREES - 1)));
     }
 }
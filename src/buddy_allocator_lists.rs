@@ -1,21 +1,195 @@
-use super::{top_level_blocks, PageSize, PhysicalAllocator, MAX_ORDER, BASE_ORDER, LEVEL_COUNT};
+use super::{top_level_blocks, AllocError, AllocationPolicy, BuddyAllocatorApi, DemoError, DurationReport, PageAllocError, PageSize, PhysicalAllocator, MAX_ORDER, BASE_ORDER, LEVEL_COUNT, PageIter, RegionBusy};
+use buddy::{buddy_of, parent_of};
+use buddy_allocator_bitmap::FreeError;
+use geometry::{block_bytes, find_overlap, region_bytes, size_of_order};
+#[cfg(feature = "compact-blocks")]
+use geometry::{compact_frame, compact_frame_address, COMPACT_FRAME_BITS};
+#[cfg(feature = "compact-blocks")]
+use bit_field::BitField;
+#[cfg(feature = "compact-blocks")]
+use super::MAX_ORDER_SIZE;
+use config::BuddyConfig;
 use array_init;
+use metrics::{Latencies, OpTimer};
+#[cfg(feature = "metrics")]
+use metrics::LatencySummary;
+use observer::{AllocEvent, AllocObserver, ObserverSlot};
+use snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use stats::{AllocatorStats, BlockInfo, OpCounters, Usage};
+use steady_state;
+use testing::{BlockSet, RegionTracker};
 #[cfg(feature = "flame_profile")]
 use flame;
 
-use std::collections::LinkedList;
+use std::mem;
+#[cfg(feature = "compact-blocks")]
+use std::fmt;
+use std::collections::{BTreeMap, HashSet, LinkedList};
 use std::vec::Vec;
 use std::time::{Instant, Duration};
 
-#[derive(Debug, Eq, PartialEq)]
+// Block sizes are computed from `order + BASE_ORDER` as a u8
+const_assert!(__lists_block_size_exponent_fits_u8;
+    (BASE_ORDER as usize) + (MAX_ORDER as usize) <= ::std::u8::MAX as usize);
+
+// Every order needs a bit in the mask of orders with free blocks
+const_assert!(__lists_orders_fit_mask; (LEVEL_COUNT as usize) <= 32);
+
+/// Begins every snapshot of a lists allocator
+const SNAPSHOT_MAGIC: &[u8; 4] = b"BSBL";
+
+// A compact block has 5 bits for its order
+#[cfg(feature = "compact-blocks")]
+const_assert!(__lists_compact_order_fits; (MAX_ORDER as usize) < 1 << 5);
+
+// A compact block must be able to address at least one top level block
+#[cfg(feature = "compact-blocks")]
+const_assert!(__lists_compact_top_level_fits;
+    (MAX_ORDER_SIZE as usize) <= (COMPACT_FRAME_BITS as usize) + (BASE_ORDER as usize));
+
+/// The largest id a region can have
+#[cfg(not(feature = "compact-blocks"))]
+const MAX_REGION_ID: u16 = ::std::u16::MAX;
+/// The largest id a region can have, as compact blocks have 6 bits for it
+#[cfg(feature = "compact-blocks")]
+const MAX_REGION_ID: u16 = (1 << 6) - 1;
+
+#[cfg(not(feature = "compact-blocks"))]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Block {
     begin_address: usize,
     order: u8,
     state: BlockState,
+    /// The id of the region the block was split from, so that it is only ever merged with blocks
+    /// of the same region
+    region: u16,
+}
+
+/// A block packed into 32 bits for small address spaces: the state in bit 0, the order in bits
+/// 1 to 5, the region id in bits 6 to 11 and the frame number of the address in the top
+/// [COMPACT_FRAME_BITS]. Only blocks below 4 GiB by default, in at most 64 regions, fit.
+#[cfg(feature = "compact-blocks")]
+#[derive(Clone, Eq, PartialEq)]
+pub struct Block {
+    bit_field: u32,
+}
+
+#[cfg(not(feature = "compact-blocks"))]
+impl Block {
+    fn new(begin_address: usize, order: u8, state: BlockState, region: u16) -> Self {
+        Block {
+            begin_address,
+            order,
+            state,
+            region,
+        }
+    }
+
+    #[inline]
+    fn begin_address(&self) -> usize {
+        self.begin_address
+    }
+
+    #[inline]
+    fn order(&self) -> u8 {
+        self.order
+    }
+
+    #[inline]
+    fn state(&self) -> BlockState {
+        self.state
+    }
+
+    /// The id of the region the block was split from, so that it is only ever merged with blocks
+    /// of the same region
+    #[inline]
+    fn region(&self) -> u16 {
+        self.region
+    }
+
+    /// Set the state of the block, returning the previous state
+    #[inline]
+    fn set_state(&mut self, state: BlockState) -> BlockState {
+        mem::replace(&mut self.state, state)
+    }
+
+    #[cfg(test)]
+    fn set_region(&mut self, region: u16) {
+        self.region = region;
+    }
+}
+
+#[cfg(feature = "compact-blocks")]
+impl Block {
+    fn new(begin_address: usize, order: u8, state: BlockState, region: u16) -> Self {
+        let frame = compact_frame(begin_address).unwrap_or_else(|| {
+            panic!("Block {:#x} does not fit in a compact block!", begin_address)
+        });
+        debug_assert!(region <= MAX_REGION_ID, "Region id {} does not fit!", region);
+
+        let mut bit_field = 0u32;
+        bit_field.set_bit(0, state == BlockState::Used);
+        bit_field.set_bits(1..6, u32::from(order));
+        bit_field.set_bits(6..12, u32::from(region));
+        bit_field.set_bits(12..32, frame);
+
+        Block { bit_field }
+    }
+
+    #[inline]
+    fn begin_address(&self) -> usize {
+        compact_frame_address(self.bit_field.get_bits(12..32))
+    }
+
+    #[inline]
+    fn order(&self) -> u8 {
+        self.bit_field.get_bits(1..6) as u8
+    }
+
+    #[inline]
+    fn state(&self) -> BlockState {
+        if self.bit_field.get_bit(0) {
+            BlockState::Used
+        } else {
+            BlockState::Free
+        }
+    }
+
+    /// The id of the region the block was split from, so that it is only ever merged with blocks
+    /// of the same region
+    #[inline]
+    fn region(&self) -> u16 {
+        self.bit_field.get_bits(6..12) as u16
+    }
+
+    /// Set the state of the block, returning the previous state
+    #[inline]
+    fn set_state(&mut self, state: BlockState) -> BlockState {
+        let previous = self.state();
+        self.bit_field.set_bit(0, state == BlockState::Used);
+        previous
+    }
+
+    #[cfg(test)]
+    fn set_region(&mut self, region: u16) {
+        self.bit_field.set_bits(6..12, u32::from(region));
+    }
+}
+
+#[cfg(feature = "compact-blocks")]
+impl fmt::Debug for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Block")
+            .field("begin_address", &self.begin_address())
+            .field("order", &self.order())
+            .field("state", &self.state())
+            .field("region", &self.region())
+            .finish()
+    }
 }
 
 #[repr(u8)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum BlockState {
     Used,
     Free,
@@ -28,6 +202,13 @@ pub trait BlockList {
     fn get(&self, index: usize) -> Option<&Block>;
     fn get_mut(&mut self, index: usize) -> Option<&mut Block>;
     fn remove(&mut self, index: usize);
+    /// Remove the block at `index` without keeping the order of the other blocks, which may be
+    /// cheaper than [BlockList::remove]. Like any removal, it changes the indices of other blocks.
+    fn remove_unordered(&mut self, index: usize);
+    /// Call `f` with every block in the list, in order
+    fn for_each<F: FnMut(&Block)>(&self, f: F);
+    /// How many bytes of heap memory the list uses
+    fn metadata_bytes(&self) -> usize;
 }
 
 impl BlockList for LinkedList<Block> {
@@ -45,7 +226,7 @@ impl BlockList for LinkedList<Block> {
 
     fn get(&self, index: usize) -> Option<&Block> {
         let len = self.len();
-        if len == 0 {
+        if index >= len {
             return None;
         }
 
@@ -58,7 +239,7 @@ impl BlockList for LinkedList<Block> {
 
     fn get_mut(&mut self, index: usize) -> Option<&mut Block> {
         let len = self.len();
-        if len == 0 {
+        if index >= len {
             return None;
         }
 
@@ -69,10 +250,27 @@ impl BlockList for LinkedList<Block> {
         }
     }
     fn remove(&mut self, index: usize) {
+        let len = self.len();
+        assert!(index < len, "Index {} out of bounds of list of length {}", index, len);
+
         let mut second_part = self.split_off(index);
         second_part.pop_front();
         self.append(&mut second_part);
     }
+
+    fn remove_unordered(&mut self, index: usize) {
+        // Nothing is moved by removing from a linked list anyway
+        BlockList::remove(self, index);
+    }
+
+    fn for_each<F: FnMut(&Block)>(&self, f: F) {
+        self.iter().for_each(f)
+    }
+
+    fn metadata_bytes(&self) -> usize {
+        // Every node holds a pointer to the next and previous nodes as well as the block
+        LinkedList::len(self) * (mem::size_of::<Block>() + 2 * mem::size_of::<usize>())
+    }
 }
 
 impl BlockList for Vec<Block> {
@@ -106,24 +304,87 @@ impl BlockList for Vec<Block> {
     fn remove(&mut self, index: usize) {
         self.remove(index);
     }
+
+    fn remove_unordered(&mut self, index: usize) {
+        // Moves the last block into the gap rather than shifting every block after it
+        self.swap_remove(index);
+    }
+
+    fn for_each<F: FnMut(&Block)>(&self, f: F) {
+        self.iter().for_each(f)
+    }
+
+    fn metadata_bytes(&self) -> usize {
+        self.capacity() * mem::size_of::<Block>()
+    }
 }
 
 pub struct BuddyAllocator<L: BlockList> {
     lists: [L; LEVEL_COUNT as usize],
+    /// Incremented every time a block is removed from the list of that order, which shifts the
+    /// indices of the blocks after it.
+    generations: [u64; LEVEL_COUNT as usize],
+    /// How many free blocks are in the list of each order
+    free_blocks: [usize; LEVEL_COUNT as usize],
+    /// Bit k is set if the list of order k has any free blocks, so that searching for a block to
+    /// split can skip the orders with none
+    free_orders: u32,
+    /// How a free block is chosen to allocate from
+    policy: AllocationPolicy,
+    /// First byte address of every region given to the allocator mapped to its last byte address
+    /// and id, so that overlapping regions can be rejected and blocks checked against their region.
+    regions: BTreeMap<usize, Region>,
+    usage: Usage,
+    counters: OpCounters,
+    observer: ObserverSlot,
+    latencies: Latencies,
 }
 
-/// A very temporary block index. Is not to be trusted to remain pointing to the same block. Use at
-/// own risk!
+/// A region of memory given to the allocator
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Region {
+    /// The address of the last byte of the region
+    last: usize,
+    /// Stored in every block of the region. Ids are reused once their region is removed.
+    id: u16,
+}
+
+/// A very temporary block index. It is invalidated as soon as any block is removed from the list of
+/// its order, which is detected by comparing the generation it was created in against the list's.
 #[derive(Debug, Copy, Clone)]
-struct BlockIndex {
+pub struct BlockIndex {
     order: u8,
     index: usize,
+    generation: u64,
+}
+
+/// The list a [BlockIndex] points into has changed since the index was created.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct StaleIndex;
+
+/// An owned copy of the regions and blocks of an allocator, taken by [BuddyAllocator::snapshot].
+/// It answers the same queries as the allocator it was taken from, as they were when it was taken,
+/// so that another thread can inspect it while the allocator carries on.
+pub struct AllocatorSnapshot {
+    /// A copy of the allocator with every list copied into a vector. It is never allocated from.
+    allocator: BuddyAllocator<Vec<Block>>,
+    /// The heap memory used by the lists of the original allocator, which depends on their kind
+    metadata_bytes: usize,
 }
 
 impl BuddyAllocator<LinkedList<Block>> {
     pub fn new() -> Self {
         BuddyAllocator {
             lists: array_init::array_init(|_| LinkedList::new()),
+            generations: [0; LEVEL_COUNT as usize],
+            free_blocks: [0; LEVEL_COUNT as usize],
+            free_orders: 0,
+            policy: AllocationPolicy::default(),
+            regions: BTreeMap::new(),
+            usage: Usage::new(),
+            counters: OpCounters::new(),
+            observer: ObserverSlot::new(),
+            latencies: Latencies::new(),
         }
     }
 }
@@ -132,49 +393,287 @@ impl BuddyAllocator<Vec<Block>> {
     pub fn new() -> Self {
         BuddyAllocator {
             lists: array_init::array_init(|_| Vec::new()),
+            generations: [0; LEVEL_COUNT as usize],
+            free_blocks: [0; LEVEL_COUNT as usize],
+            free_orders: 0,
+            policy: AllocationPolicy::default(),
+            regions: BTreeMap::new(),
+            usage: Usage::new(),
+            counters: OpCounters::new(),
+            observer: ObserverSlot::new(),
+            latencies: Latencies::new(),
         }
     }
 }
 
 impl<L: BlockList> BuddyAllocator<L> {
-    /// Get a block by its index.
+    /// Create an index into the list of the given order which is valid until the list next has a
+    /// block removed from it.
+    fn index(&self, order: u8, index: usize) -> BlockIndex {
+        BlockIndex {
+            order,
+            index,
+            generation: self.generations[order as usize],
+        }
+    }
+
+    /// Whether the list the index points into has had a block removed since it was created.
+    fn is_stale(&self, index: &BlockIndex) -> bool {
+        self.generations[index.order as usize] != index.generation
+    }
+
+    /// Get a block by its index. Returns `None` if the index is stale.
     ///
     /// # Panicking
     ///
     /// Panics if the order is larger than maximum. This indicates a programming error.
     fn get(&self, block: &BlockIndex) -> Option<&Block> {
+        if self.is_stale(block) {
+            return None;
+        }
+
         let list = &self.lists[block.order as usize];
         list.get(block.index)
     }
 
-    /// Get a block by its index mutably.
+    /// Get a block by its index mutably. Returns `None` if the index is stale.
     ///
     /// # Panicking
     ///
     /// Panics if the order is larger than maximum. This indicates a programming error.
     fn get_mut(&mut self, block: &BlockIndex) -> Option<&mut Block> {
+        if self.is_stale(block) {
+            return None;
+        }
+
         let list = &mut self.lists[block.order as usize];
         list.get_mut(block.index)
     }
 
-    /// Modify a block by setting its state to a new one. This will not merge blocks if set to free,
-    /// it will just mark the block as freed.
-    ///
-    /// # Panicking
-    ///
-    /// This function will panic if the index is incorrect
-    fn modify(&mut self, index: &mut BlockIndex, new_state: BlockState) {
-        let block = self.get_mut(index).unwrap();
-        block.state = new_state;
+    /// Set the state of a block. This will not merge blocks if set to free, it will just mark the
+    /// block as freed. Does not invalidate any indices.
+    fn set_state(&mut self, index: &BlockIndex, state: BlockState) -> Result<(), StaleIndex> {
+        let block = self.get_mut(index).ok_or(StaleIndex)?;
+        let previous = block.set_state(state);
+
+        match (previous, state) {
+            (BlockState::Used, BlockState::Free) => self.count_free(index.order, true),
+            (BlockState::Free, BlockState::Used) => self.count_free(index.order, false),
+            _ => {}
+        }
+        Ok(())
     }
 
-    /// Create a top level block
-    pub fn create_top_level(&mut self, begin_address: usize) {
-        self.lists[MAX_ORDER as usize].push(Block {
-            begin_address,
-            order: MAX_ORDER,
-            state: BlockState::Free,
+    /// Record that a free block was added to or taken from the list of the given order.
+    #[inline]
+    fn count_free(&mut self, order: u8, added: bool) {
+        let count = &mut self.free_blocks[order as usize];
+        if added {
+            *count += 1;
+        } else {
+            *count -= 1;
+        }
+
+        if *count == 0 {
+            self.free_orders &= !(1 << order);
+        } else {
+            self.free_orders |= 1 << order;
+        }
+    }
+
+    /// Push a block to the list of its order.
+    fn push(&mut self, block: Block) {
+        if block.state() == BlockState::Free {
+            self.count_free(block.order(), true);
+        }
+        self.lists[block.order() as usize].push(block);
+    }
+
+    /// Remove a free block from its list, invalidating all indices into that list.
+    fn remove(&mut self, index: BlockIndex) {
+        debug_assert_eq!(
+            self.lists[index.order as usize].get(index.index).map(|block| block.state()),
+            Some(BlockState::Free)
+        );
+        self.lists[index.order as usize].remove(index.index);
+        self.count_free(index.order, false);
+        let generation = &mut self.generations[index.order as usize];
+        *generation = generation.wrapping_add(1);
+    }
+
+    /// Remove a free block from its list like [BuddyAllocator::remove], but without keeping the
+    /// order of the rest of the list.
+    fn remove_unordered(&mut self, index: BlockIndex) {
+        debug_assert_eq!(
+            self.lists[index.order as usize].get(index.index).map(|block| block.state()),
+            Some(BlockState::Free)
+        );
+        self.lists[index.order as usize].remove_unordered(index.index);
+        self.count_free(index.order, false);
+        let generation = &mut self.generations[index.order as usize];
+        *generation = generation.wrapping_add(1);
+    }
+
+    /// Set the observer which is told about every operation, returning the previous one.
+    pub fn set_observer(&mut self, obs: Box<dyn AllocObserver>) -> Option<Box<dyn AllocObserver>> {
+        self.observer.set(obs)
+    }
+
+    /// Remove the observer, returning it if one was set.
+    pub fn take_observer(&mut self) -> Option<Box<dyn AllocObserver>> {
+        self.observer.take()
+    }
+
+    /// Set how free blocks are chosen to allocate from, returning the previous policy.
+    pub fn set_policy(&mut self, policy: AllocationPolicy) -> AllocationPolicy {
+        mem::replace(&mut self.policy, policy)
+    }
+
+    pub fn policy(&self) -> AllocationPolicy {
+        self.policy
+    }
+
+    /// Save the blocks, regions and usage of the allocator. Counters, latencies and the observer
+    /// are not saved.
+    pub fn to_snapshot(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new(SNAPSHOT_MAGIC);
+        writer.u8(LEVEL_COUNT);
+
+        writer.u64(self.regions.len() as u64);
+        for &begin_address in self.regions.keys() {
+            writer.u64(begin_address as u64);
+        }
+
+        // Blocks are saved in list order, as that decides which free block is used first
+        for list in self.lists.iter() {
+            writer.u64(list.len() as u64);
+            list.for_each(|block| {
+                writer.u64(block.begin_address() as u64);
+                writer.u8(block.state() as u8);
+            });
+        }
+
+        writer.usage(&self.usage);
+        writer.finish()
+    }
+
+    /// Copy the regions and blocks of the allocator, with its usage, counters and latencies, into
+    /// a [AllocatorSnapshot] which is unaffected by anything the allocator does afterwards. The
+    /// observer is not copied. Unlike [BuddyAllocator::to_snapshot], nothing is serialized.
+    pub fn snapshot(&self) -> AllocatorSnapshot {
+        let lists = array_init::array_init(|order| {
+            let list = &self.lists[order];
+            let mut copy = Vec::with_capacity(list.len());
+            list.for_each(|block| copy.push(block.clone()));
+            copy
+        });
+
+        AllocatorSnapshot {
+            allocator: BuddyAllocator {
+                lists,
+                generations: self.generations,
+                free_blocks: self.free_blocks,
+                free_orders: self.free_orders,
+                policy: self.policy,
+                regions: self.regions.clone(),
+                usage: self.usage,
+                counters: self.counters,
+                observer: ObserverSlot::new(),
+                latencies: self.latencies.clone(),
+            },
+            metadata_bytes: self.metadata_bytes(),
+        }
+    }
+
+    /// Record that the region `[begin_address, begin_address + size)` is managed by the allocator,
+    /// returning the id of the region. Returns an error and records nothing if it overlaps a
+    /// region which is already managed, or if every id is taken.
+    fn add_region_range(&mut self, begin_address: usize, size: usize) -> Result<u16, RegionError> {
+        debug_assert_ne!(size, 0, "Region must not be empty!");
+        let last = begin_address
+            .checked_add(size - 1)
+            .ok_or(RegionError::WrapsAround { begin_address })?;
+
+        let overlap = find_overlap(&self.regions, begin_address, last, |region| region.last);
+        if let Some(existing_address) = overlap {
+            return Err(RegionError::Overlapping {
+                begin_address,
+                existing_address,
+            });
+        }
+
+        // The lowest id which is not in use. There are rarely more than a few regions.
+        let id = (0..=MAX_REGION_ID)
+            .find(|&id| self.regions.values().all(|region| region.id != id))
+            .ok_or(RegionError::OutOfIds { begin_address })?;
+
+        self.regions.insert(begin_address, Region { last, id });
+        Ok(id)
+    }
+
+    /// The base address and record of the region containing `address`, if any.
+    fn region_of(&self, address: usize) -> Option<(usize, Region)> {
+        self.regions
+            .range(..=address)
+            .next_back()
+            .filter(|&(_, region)| region.last >= address)
+            .map(|(&base, &region)| (base, region))
+    }
+
+    /// Create a top level block. Returns an error if it overlaps memory already given to the
+    /// allocator, as overlapping blocks would be handed out twice.
+    pub fn create_top_level(&mut self, begin_address: usize) -> Result<(), RegionError> {
+        let region = self.add_region_range(begin_address, size_of_order(MAX_ORDER))?;
+        self.push(Block::new(begin_address, MAX_ORDER, BlockState::Free, region));
+        Ok(())
+    }
+
+    /// Give back the region created at `base`, which must have merged back into a single free
+    /// top level block. Its block and its record are removed, so nothing in it is allocated again
+    /// and the memory may be given to the allocator again later.
+    pub fn remove_region(&mut self, base: usize) -> Result<(), RegionBusy> {
+        let busy = RegionBusy { begin_address: base };
+        if !self.regions.contains_key(&base) {
+            return Err(busy);
+        }
+
+        let position = self.lists[MAX_ORDER as usize]
+            .position(|block| block.begin_address() == base && block.state() == BlockState::Free)
+            .ok_or(busy)?;
+        let index = self.index(MAX_ORDER, position);
+
+        self.remove(index);
+        self.regions.remove(&base);
+        Ok(())
+    }
+
+    /// The base addresses of the regions which [BuddyAllocator::remove_region] would remove, in
+    /// ascending order.
+    pub fn removable_regions(&self) -> Vec<usize> {
+        let mut regions = Vec::new();
+        self.lists[MAX_ORDER as usize].for_each(|block| {
+            if block.state() == BlockState::Free {
+                regions.push(block.begin_address());
+            }
         });
+
+        regions.sort();
+        regions
+    }
+
+    /// How many blocks of the given order are in the given state. Every list holds both its used
+    /// and free blocks, and the free blocks of each order are counted as they change, so this does
+    /// not scan the list.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the order is larger than [MAX_ORDER].
+    pub fn count_by_state(&self, order: u8, state: BlockState) -> usize {
+        let free = self.free_blocks[order as usize];
+        match state {
+            BlockState::Free => free,
+            BlockState::Used => self.lists[order as usize].len() - free,
+        }
     }
 
     /// Splits a block in place. Index will be invalidated. Returns index of first buddy
@@ -185,91 +684,275 @@ impl<L: BlockList> BuddyAllocator<L> {
     /// 2. Attempt to split used block
     /// 3. List state bad (order x in list order of y != x)
     fn split(&mut self, index: BlockIndex) -> Result<BlockIndex, BlockSplitError> {
-        let block = self.get(&index).unwrap();
+        let block = self.get(&index).expect("Attempted to split a stale or invalid index");
 
-        if block.state == BlockState::Used {
+        if block.state() == BlockState::Used {
             panic!("Attempted to split used block at index {:?}", index);
         }
 
         debug_assert_eq!(
-            block.order, index.order,
+            block.order(), index.order,
             "Index should have order equal to block!"
         );
 
-        let original_order = block.order;
+        let original_order = block.order();
         let order = original_order - 1;
 
         if index.order == 0 {
             return Err(BlockSplitError::BlockSmallestPossible);
         }
 
-        let buddies: [Block; 2] = array_init::array_init(|n| Block {
-            begin_address: if n == 0 {
-                block.begin_address
-            } else {
-                block.begin_address + 2usize.pow(u32::from(order + BASE_ORDER))
-            },
-            order,
-            state: BlockState::Free,
+        let buddies: [Block; 2] = array_init::array_init(|n| {
+            let begin_address = block.begin_address() + n * block_bytes(order).unwrap();
+            Block::new(begin_address, order, BlockState::Free, block.region())
         });
 
-        self.lists[original_order as usize].remove(index.index);
+        // Nothing relies on the order of a list, so the block is swapped out rather than shifting
+        // the blocks after it. The buddies go in the list below, so their index is unaffected
+        self.remove_unordered(index);
+        self.counters.splits[original_order as usize] += 1;
+        self.observer.notify(AllocEvent::Split {
+            addr: buddies[0].begin_address(),
+            order: original_order,
+        });
 
         let [first, second] = buddies;
-        self.lists[order as usize].push(first);
-        self.lists[order as usize].push(second);
+        self.push(first);
+        self.push(second);
 
-        Ok(BlockIndex {
-            order,
-            index: self.lists[order as usize].len() - 2,
-        })
+        let first_index = self.lists[order as usize].len() - 2;
+        Ok(self.index(order, first_index))
+    }
+
+    pub fn allocate_exact(&mut self, order: u8) -> Result<BlockIndex, BlockAllocateError> {
+        #[cfg(feature = "flame_profile")]
+        let _span = flame::start_guard("allocate_exact");
+
+        let timer = OpTimer::start();
+        let result = self.allocate_exact_untimed(order);
+        self.latencies.record(timer);
+        result
     }
 
-    #[cfg_attr(feature = "flame_profile", flame)]
-    fn allocate_exact(&mut self, order: u8) -> Result<BlockIndex, BlockAllocateError> {
+    fn allocate_exact_untimed(&mut self, order: u8) -> Result<BlockIndex, BlockAllocateError> {
         if order > MAX_ORDER {
-            return Err(BlockAllocateError::OrderTooLarge(order));
+            return Err(BlockAllocateError::OrderTooLarge {
+                order,
+                max_order: MAX_ORDER,
+            });
         }
 
         #[cfg(feature = "flame_profile")]
         flame::note("allocate begin", None);
 
-        let mut index = self.find_or_split(order)?;
+        let index = self.find_or_split(order)?;
+
+        self.set_state(&index, BlockState::Used)
+            .expect("find_or_split must return a fresh index");
+        self.usage.allocated(order);
+        self.counters.allocations[order as usize] += 1;
 
-        self.modify(&mut index, BlockState::Used);
+        let addr = self.get(&index).unwrap().begin_address();
+        self.observer.notify(AllocEvent::Alloc { addr, order });
         Ok(index)
     }
 
-    /// Find a frame of a given order or splits other frames recursively until one is made. Does not
-    /// set state to used.
+    /// Split free blocks ahead of time until at least `count` blocks of `order` are free, so that
+    /// allocating them later does not pay for the splits. Nothing is marked as used, and fewer
+    /// blocks are split if there are no larger free blocks left.
     ///
-    /// # Panicking
+    /// # Note
     ///
-    /// Panics if the order is greater than max or if a programming error is encountered such as
-    /// attempting to split a block of the smallest possible size.
-    fn find_or_split(&mut self, order: u8) -> Result<BlockIndex, BlockAllocateError> {
+    /// The buddies split here are free without being merged, so a snapshot or dump taken before
+    /// they are allocated will not load.
+    pub fn warm_up(&mut self, order: u8, count: usize) {
+        if order >= MAX_ORDER {
+            return;
+        }
+
+        // Split the smallest larger free block once each time, so that blocks of the order above
+        // are split before any higher ones
+        while self.free_blocks[order as usize] < count {
+            match self.smallest_free(order + 1) {
+                Some(index) => self.split(index).unwrap(),
+                None => return,
+            };
+        }
+    }
+
+    /// Allocate `n` blocks of order 0 together and return their addresses. The free pages are
+    /// counted from the free blocks of each order first, so if fewer than `n` are free nothing is
+    /// split or allocated at all, rather than being rolled back.
+    pub fn alloc_pages(&mut self, n: usize) -> Result<PageIter, AllocError> {
+        let available = self.free_blocks
+            .iter()
+            .enumerate()
+            .map(|(order, &free)| free << order)
+            .sum();
+
+        if available < n {
+            return Err(AllocError { requested: n, available });
+        }
+
+        let pages = (0..n)
+            .map(|_| {
+                let index = self.allocate_exact(0).expect("Enough pages were counted as free");
+                self.get(&index).unwrap().begin_address()
+            })
+            .collect();
+
+        Ok(PageIter::new(pages))
+    }
+
+    /// Free the used block of the given order beginning at `address`, merging it with its buddy
+    /// for as long as the buddy is also free. Buddies are found relative to the base of the
+    /// block's region, so regions need not be aligned to the size of a top level block.
+    pub fn deallocate(&mut self, address: usize, order: u8) -> Result<(), BlockDeallocateError> {
+        let timer = OpTimer::start();
+        let result = self.deallocate_untimed(address, order);
+        self.latencies.record(timer);
+        result
+    }
+
+    fn deallocate_untimed(
+        &mut self,
+        address: usize,
+        order: u8,
+    ) -> Result<(), BlockDeallocateError> {
         if order > MAX_ORDER {
-            panic!("Order {} larger than max of {}!", order, MAX_ORDER);
+            return Err(BlockDeallocateError::NoBlockAtAddress);
         }
 
-        let opt: Option<BlockIndex> = self.lists[order as usize]
-            .position(|block| block.state == BlockState::Free)
-            .map(|index| BlockIndex { order, index });
+        let position = self.lists[order as usize]
+            .position(|block| block.begin_address() == address)
+            .ok_or(BlockDeallocateError::NoBlockAtAddress)?;
+        let index = self.index(order, position);
 
-        let block = match opt {
-            Some(thing) => Ok(thing),
-            None => {
-                if order >= MAX_ORDER {
-                    Err(BlockAllocateError::NoBlocksAvailable)
-                } else {
-                    let block_index = self.find_or_split(order + 1)?;
-                    let first = self.split(block_index).unwrap();
-                    Ok(first)
-                }
+        let block = self.get(&index).unwrap();
+        if block.state() != BlockState::Used {
+            return Err(BlockDeallocateError::BlockNotUsed);
+        }
+
+        // The block must lie entirely within the region it was split from, or merging it would
+        // hand out memory of another region or memory which was never given to the allocator
+        let region_id = block.region();
+        let last = address.wrapping_add(size_of_order(order) - 1);
+        let region_base = match self.region_of(address) {
+            Some((base, region))
+                if region.id == region_id && last >= address && last <= region.last =>
+            {
+                base
+            }
+            _ => return Err(BlockDeallocateError::OutsideRegion),
+        };
+
+        self.set_state(&index, BlockState::Free).unwrap();
+        self.usage.freed(order);
+        self.counters.frees[order as usize] += 1;
+        self.observer.notify(AllocEvent::Dealloc { addr: address, order });
+
+        let mut index = index;
+        let mut address = address;
+        let mut order = order;
+
+        // Merges never cross into another region, even one which is physically adjacent
+        while order < MAX_ORDER {
+            let buddy_address = buddy_of(address, order, region_base);
+            let buddy_position = self.lists[order as usize].position(|block| {
+                block.begin_address() == buddy_address
+                    && block.state() == BlockState::Free
+                    && block.region() == region_id
+            });
+
+            let buddy_position = match buddy_position {
+                Some(position) => position,
+                None => break,
+            };
+
+            // Remove the later block first so that the earlier index is not shifted
+            let buddy = self.index(order, buddy_position);
+            if buddy_position > index.index {
+                self.remove(buddy);
+                self.remove(index);
+            } else {
+                self.remove(index);
+                self.remove(buddy);
+            }
+
+            address = parent_of(address, order, region_base);
+            order += 1;
+            self.counters.merges[order as usize] += 1;
+            self.observer.notify(AllocEvent::Merge { addr: address, order });
+
+            self.push(Block::new(address, order, BlockState::Free, region_id));
+            index = self.index(order, self.lists[order as usize].len() - 1);
+        }
+
+        Ok(())
+    }
+
+    /// A free block of the lowest order no lower than `order` which has one, found without
+    /// searching the lists of the orders which have no free blocks.
+    fn smallest_free(&mut self, order: u8) -> Option<BlockIndex> {
+        let orders = self.free_orders & (!0 << order);
+        if orders == 0 {
+            return None;
+        }
+
+        let order = orders.trailing_zeros() as u8;
+        let position = self.lists[order as usize]
+            .position(|block| block.state() == BlockState::Free)
+            .expect("The mask of free orders disagrees with the lists!");
+        Some(self.index(order, position))
+    }
+
+    /// The free block of an order no lower than `order` at the lowest address. Splitting it down
+    /// keeps its address, so this is where the lowest block of `order` which can be allocated is.
+    fn lowest_free(&mut self, order: u8) -> Option<BlockIndex> {
+        // The address, order and position of the lowest free block so far
+        let mut lowest: Option<(usize, u8, usize)> = None;
+
+        for candidate_order in order..=MAX_ORDER {
+            if self.free_orders & (1 << candidate_order) == 0 {
+                continue;
             }
-        }?;
 
-        Ok(block)
+            let mut position = 0;
+            self.lists[candidate_order as usize].for_each(|block| {
+                let lower = lowest.map_or(true, |(addr, _, _)| block.begin_address() < addr);
+                if block.state() == BlockState::Free && lower {
+                    lowest = Some((block.begin_address(), candidate_order, position));
+                }
+                position += 1;
+            });
+        }
+
+        lowest.map(|(_, order, position)| self.index(order, position))
+    }
+
+    /// Find a frame of a given order, or split a larger free frame down until one is made: the
+    /// smallest, or the lowest with [AllocationPolicy::Deterministic]. Does not set state to used.
+    ///
+    /// The order must have already been checked to be no greater than [MAX_ORDER] by the caller.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if a programming error is encountered such as attempting to split a block of the
+    /// smallest possible size.
+    fn find_or_split(&mut self, order: u8) -> Result<BlockIndex, BlockAllocateError> {
+        debug_assert!(order <= MAX_ORDER, "Order {} larger than max of {}!", order, MAX_ORDER);
+
+        let found = match self.policy {
+            AllocationPolicy::FirstFound => self.smallest_free(order),
+            AllocationPolicy::Deterministic => self.lowest_free(order),
+        };
+        let mut index = found.ok_or(BlockAllocateError::NoBlocksAvailable)?;
+
+        while index.order > order {
+            index = self.split(index).unwrap();
+        }
+
+        Ok(index)
     }
 }
 
@@ -278,84 +961,414 @@ pub enum BlockSplitError {
     BlockSmallestPossible,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum BlockAllocateError {
     NoBlocksAvailable,
-    OrderTooLarge(u8),
-}
-
-impl<L: BlockList> PhysicalAllocator for BuddyAllocator<L> {
-    fn alloc(&mut self, size: PageSize) -> *const u8 {
-        let index = self.allocate_exact(size.power_of_two() - BASE_ORDER)
-            .unwrap();
-        let block = self.get(&index).unwrap();
-        block.begin_address as *const u8
-    }
-
-    fn dealloc(&mut self, _frame: *const u8) {
-        unimplemented!()
-    }
+    OrderTooLarge { order: u8, max_order: u8 },
 }
 
-pub fn demo_linked_lists(print_addresses: bool, blocks: u32, block_size: u8) -> Duration {
-    let allocator = BuddyAllocator::<LinkedList<Block>>::new();
-    demo(allocator, print_addresses, blocks, block_size)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BlockDeallocateError {
+    /// No block of the given order begins at the given address
+    NoBlockAtAddress,
+    /// The block at the given address is already free
+    BlockNotUsed,
+    /// The block does not lie entirely within the region it was split from
+    OutsideRegion,
 }
 
-pub fn demo_vecs(print_addresses: bool, blocks: u32, block_size: u8) -> Duration {
-    let allocator = BuddyAllocator::<Vec<Block>>::new();
-    demo(allocator, print_addresses, blocks, block_size)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RegionError {
+    /// The region overlaps the region beginning at `existing_address`, which was added earlier
+    Overlapping {
+        begin_address: usize,
+        existing_address: usize,
+    },
+    /// The region would wrap around the end of the address space
+    WrapsAround { begin_address: usize },
+    /// Every region id is taken by another region
+    OutOfIds { begin_address: usize },
 }
 
-fn demo<L: BlockList>(
-    mut allocator: BuddyAllocator<L>,
-    print_addresses: bool,
-    blocks: u32,
-    block_size: u8,
-) -> Duration {
-    let top_level_blocks = top_level_blocks(blocks, block_size);
+impl<L: BlockList + Default> BuddyAllocator<L> {
+    /// Restore an allocator saved by [to_snapshot]. The snapshot is checked to describe blocks
+    /// which cover every region exactly once without any free buddies left unmerged, and usage
+    /// which agrees with the used blocks.
+    pub fn from_snapshot(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut reader = SnapshotReader::new(bytes, SNAPSHOT_MAGIC)?;
+        if reader.u8()? != LEVEL_COUNT {
+            return Err(SnapshotError::InvalidField { field: "level count" });
+        }
 
-    for block_number in 0..top_level_blocks {
-        allocator
-            .create_top_level(2usize.pow(u32::from(MAX_ORDER + BASE_ORDER)) * block_number as usize);
-    }
+        let mut allocator = BuddyAllocator {
+            lists: array_init::array_init(|_| L::default()),
+            generations: [0; LEVEL_COUNT as usize],
+            free_blocks: [0; LEVEL_COUNT as usize],
+            free_orders: 0,
+            policy: AllocationPolicy::default(),
+            regions: BTreeMap::new(),
+            usage: Usage::new(),
+            counters: OpCounters::new(),
+            observer: ObserverSlot::new(),
+            latencies: Latencies::new(),
+        };
 
-    let start = Instant::now();
+        let region_count = reader.usize("region count")?;
+        for _ in 0..region_count {
+            let begin_address = reader.usize("region address")?;
+            allocator
+                .add_region_range(begin_address, size_of_order(MAX_ORDER))
+                .map_err(|_| SnapshotError::InvalidField { field: "region address" })?;
+        }
 
-    for _ in 0..blocks {
-        let index = allocator.allocate_exact(block_size).unwrap();
-        let addr = allocator.get(&index).unwrap().begin_address;
+        let mut covered = BlockSet::new();
+        let mut free = HashSet::new();
+        let (mut used_bytes, mut used_blocks) = (0, 0);
+        let mut index = 0;
+
+        for order in 0..=MAX_ORDER {
+            let size = size_of_order(order);
+
+            for _ in 0..reader.usize("block count")? {
+                let begin_address = reader.usize("block address")?;
+                let state = match reader.u8()? {
+                    state if state == BlockState::Used as u8 => BlockState::Used,
+                    state if state == BlockState::Free as u8 => BlockState::Free,
+                    _ => return Err(SnapshotError::InvalidBlock { index }),
+                };
+
+                // Region ids are not saved, as blocks take the id of the region they lie in
+                let region = allocator
+                    .region_of(begin_address)
+                    .filter(|&(first, region)| {
+                        (begin_address - first) % size == 0
+                            && region.last - begin_address >= size - 1
+                    })
+                    .map(|(_, region)| region.id);
+
+                let region = match region {
+                    Some(region) if covered.insert(begin_address, size) => region,
+                    _ => return Err(SnapshotError::InvalidBlock { index }),
+                };
+
+                if state == BlockState::Used {
+                    used_bytes += size;
+                    used_blocks += 1;
+                } else {
+                    free.insert((begin_address, order));
+                }
 
-        if print_addresses {
-            println!("Address: {:#x}", addr);
+                allocator.push(Block::new(begin_address, order, state, region));
+                index += 1;
+            }
         }
-    }
 
-    start.elapsed()
-}
+        let usage = reader.usage()?;
+        reader.finish()?;
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use ::MAX_ORDER_SIZE;
+        // Blocks do not overlap, so they cover every region if their sizes add up
+        let covered_bytes: usize = (0..=MAX_ORDER)
+            .map(|order| allocator.lists[order as usize].len() * size_of_order(order))
+            .sum();
+        if covered_bytes != region_count * size_of_order(MAX_ORDER) {
+            return Err(SnapshotError::InvalidField { field: "block count" });
+        }
 
-    #[test]
-    fn test_create_top_level() {
-        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
-        allocator.create_top_level(0);
-        allocator.create_top_level(2usize.pow(MAX_ORDER_SIZE as u32));
+        // Buddies which are both free are always merged when the second one is freed
+        let unmerged = free.iter().any(|&(address, order)| {
+            let (region_base, _) = allocator.region_of(address).unwrap();
+            order < MAX_ORDER && free.contains(&(buddy_of(address, order, region_base), order))
+        });
+        if unmerged {
+            return Err(SnapshotError::InvalidField { field: "free blocks" });
+        }
+
+        if usage.used_bytes() != used_bytes || usage.outstanding_allocations() != used_blocks {
+            return Err(SnapshotError::InvalidField { field: "used bytes" });
+        }
+
+        allocator.usage = usage;
+        Ok(allocator)
+    }
+}
+
+impl<L: BlockList> BuddyAllocatorApi for BuddyAllocator<L> {
+    fn create_top_level(&mut self, begin_address: usize) {
+        if let Err(err) = BuddyAllocator::create_top_level(self, begin_address) {
+            panic!("Could not create top level block: {:?}", err);
+        }
+    }
+
+    fn allocate(&mut self, order: u8) -> Option<usize> {
+        let index = self.allocate_exact(order).ok()?;
+        self.get(&index).map(|block| block.begin_address())
+    }
+
+    fn deallocate(&mut self, address: usize, order: u8) -> bool {
+        BuddyAllocator::deallocate(self, address, order).is_ok()
+    }
+
+    fn warm_up(&mut self, order: u8, count: usize) {
+        BuddyAllocator::warm_up(self, order, count)
+    }
+
+    fn alloc_pages(&mut self, n: usize) -> Result<PageIter, AllocError> {
+        BuddyAllocator::alloc_pages(self, n)
+    }
+}
+
+impl<L: BlockList> AllocatorStats for BuddyAllocator<L> {
+    fn free_histogram(&self) -> [usize; LEVEL_COUNT as usize] {
+        // Buddies are merged on deallocation, so every free block is maximal but for those split
+        // ahead of time by warm_up
+        let mut histogram = [0; LEVEL_COUNT as usize];
+        for order in 0..LEVEL_COUNT {
+            histogram[order as usize] = self.count_by_state(order, BlockState::Free);
+        }
+
+        histogram
+    }
+
+    fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    fn reset_peaks(&mut self) {
+        self.usage.reset_peaks();
+    }
+
+    fn op_counters(&self) -> OpCounters {
+        self.counters
+    }
+
+    fn reset_op_counters(&mut self) {
+        self.counters = OpCounters::new();
+    }
+
+    #[cfg(feature = "metrics")]
+    fn latency_summary(&self) -> LatencySummary {
+        self.latencies.summary()
+    }
+
+    fn metadata_bytes(&self) -> usize {
+        self.lists.iter().map(BlockList::metadata_bytes).sum()
+    }
+
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockInfo)) {
+        for list in self.lists.iter() {
+            list.for_each(|block| {
+                f(BlockInfo {
+                    addr: block.begin_address(),
+                    order: block.order(),
+                    used: block.state() == BlockState::Used,
+                })
+            });
+        }
+    }
+}
+
+impl AllocatorSnapshot {
+    /// See [BuddyAllocator::removable_regions].
+    pub fn removable_regions(&self) -> Vec<usize> {
+        self.allocator.removable_regions()
+    }
+
+    /// See [BuddyAllocator::count_by_state].
+    pub fn count_by_state(&self, order: u8, state: BlockState) -> usize {
+        self.allocator.count_by_state(order, state)
+    }
+}
+
+/// The statistics of the allocator when the snapshot was taken. Resetting the peaks or counters
+/// only resets those of the snapshot.
+impl AllocatorStats for AllocatorSnapshot {
+    fn free_histogram(&self) -> [usize; LEVEL_COUNT as usize] {
+        self.allocator.free_histogram()
+    }
+
+    fn usage(&self) -> &Usage {
+        self.allocator.usage()
+    }
+
+    fn reset_peaks(&mut self) {
+        self.allocator.reset_peaks();
+    }
+
+    fn op_counters(&self) -> OpCounters {
+        self.allocator.op_counters()
+    }
+
+    fn reset_op_counters(&mut self) {
+        self.allocator.reset_op_counters();
+    }
+
+    #[cfg(feature = "metrics")]
+    fn latency_summary(&self) -> LatencySummary {
+        self.allocator.latency_summary()
+    }
+
+    fn metadata_bytes(&self) -> usize {
+        self.metadata_bytes
+    }
+
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockInfo)) {
+        self.allocator.for_each_block(f)
+    }
+}
+
+impl<L: BlockList> PhysicalAllocator for BuddyAllocator<L> {
+    /// Always the default configuration, as the orders of this allocator are fixed by the crate
+    /// wide constants.
+    fn config(&self) -> BuddyConfig {
+        BuddyConfig::default()
+    }
+
+    fn alloc(&mut self, size: PageSize) -> Result<*const u8, PageAllocError> {
+        let order = self.config().order_for(size)?;
+        // The order fits the configuration, so only running out of blocks fails
+        let index = self.allocate_exact(order)
+            .map_err(|_| PageAllocError::OutOfMemory { size })?;
+        let block = self.get(&index).unwrap();
+        Ok(block.begin_address() as *const u8)
+    }
+
+    fn dealloc(&mut self, addr: *const u8) -> Result<(), FreeError> {
+        let addr = addr as usize;
+        if self.region_of(addr).is_none() {
+            return Err(FreeError::OutOfRange { addr });
+        }
+
+        // Only one used block can begin at an address, so its order is the only one with one there
+        let order = (0..=MAX_ORDER)
+            .find(|&order| {
+                self.lists[order as usize]
+                    .position(|block| {
+                        block.begin_address() == addr && block.state() == BlockState::Used
+                    })
+                    .is_some()
+            })
+            .ok_or(FreeError::NotAllocated { addr })?;
+
+        self.deallocate(addr, order).map_err(|err| match err {
+            BlockDeallocateError::OutsideRegion => FreeError::OutOfRange { addr },
+            _ => FreeError::NotAllocated { addr },
+        })
+    }
+}
+
+pub fn demo_linked_lists(print_addresses: bool, blocks: u32, block_size: u8) -> Result<Duration, DemoError> {
+    let allocator = BuddyAllocator::<LinkedList<Block>>::new();
+    let top_level_blocks = top_level_blocks(blocks, block_size);
+    demo(allocator, top_level_blocks, print_addresses, blocks, block_size)
+}
+
+pub fn demo_vecs(print_addresses: bool, blocks: u32, block_size: u8) -> Result<Duration, DemoError> {
+    let allocator = BuddyAllocator::<Vec<Block>>::new();
+    let top_level_blocks = top_level_blocks(blocks, block_size);
+    demo(allocator, top_level_blocks, print_addresses, blocks, block_size)
+}
+
+pub fn demo_linked_lists_steady_state(
+    print_addresses: bool,
+    blocks: u32,
+    block_size: u8,
+    runs: usize,
+) -> Result<Vec<Duration>, DemoError> {
+    let allocator = BuddyAllocator::<LinkedList<Block>>::new();
+    steady_state::demo(allocator, print_addresses, blocks, block_size, runs)
+}
+
+pub fn demo_vecs_steady_state(
+    print_addresses: bool,
+    blocks: u32,
+    block_size: u8,
+    runs: usize,
+) -> Result<Vec<Duration>, DemoError> {
+    let allocator = BuddyAllocator::<Vec<Block>>::new();
+    steady_state::demo(allocator, print_addresses, blocks, block_size, runs)
+}
+
+pub fn demo_linked_lists_for_duration(
+    print_addresses: bool,
+    blocks: u32,
+    block_size: u8,
+    budget: Duration,
+) -> Result<DurationReport, DemoError> {
+    let allocator = BuddyAllocator::<LinkedList<Block>>::new();
+    steady_state::demo_for(allocator, print_addresses, blocks, block_size, budget)
+}
+
+pub fn demo_vecs_for_duration(
+    print_addresses: bool,
+    blocks: u32,
+    block_size: u8,
+    budget: Duration,
+) -> Result<DurationReport, DemoError> {
+    let allocator = BuddyAllocator::<Vec<Block>>::new();
+    steady_state::demo_for(allocator, print_addresses, blocks, block_size, budget)
+}
+
+fn demo<L: BlockList>(
+    mut allocator: BuddyAllocator<L>,
+    top_level_blocks: u64,
+    print_addresses: bool,
+    blocks: u32,
+    block_size: u8,
+) -> Result<Duration, DemoError> {
+    let mut regions = RegionTracker::new();
+
+    for block_number in 0..top_level_blocks {
+        let begin_address = region_bytes().unwrap() * block_number as usize;
+        allocator
+            .create_top_level(begin_address)
+            .expect("Demo top level blocks must not overlap!");
+        regions.add(begin_address, region_bytes().unwrap());
+    }
+
+    let start = Instant::now();
+
+    for allocation in 0..blocks {
+        let index = allocator
+            .allocate_exact(block_size)
+            .map_err(|err| match err {
+                BlockAllocateError::NoBlocksAvailable => DemoError::OutOfBlocks { allocation },
+                BlockAllocateError::OrderTooLarge { order, max_order } => {
+                    DemoError::OrderTooLarge { order, max_order }
+                }
+            })?;
+        let addr = allocator.get(&index).unwrap().begin_address();
+
+        if cfg!(debug_assertions) {
+            regions.assert_valid(addr, block_bytes(block_size).unwrap());
+        }
+
+        if print_addresses {
+            println!("Address: {:#x}", addr);
+        }
+    }
+
+    Ok(start.elapsed())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dump::AllocatorState;
+    use std::thread;
+    use testing::{check_unique_addresses, RecordingObserver, XorShift};
+    use workload::{self, WorkloadSpec};
+    #[cfg(not(feature = "compact-blocks"))]
+    use MAX_ORDER_SIZE;
+
+    #[test]
+    fn test_create_top_level() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(region_bytes().unwrap()).unwrap();
 
         let expected = vec![
-            Block {
-                begin_address: 0,
-                order: MAX_ORDER,
-                state: BlockState::Free,
-            },
-            Block {
-                begin_address: 2usize.pow(MAX_ORDER_SIZE as u32),
-                order: MAX_ORDER,
-                state: BlockState::Free,
-            },
+            Block::new(0, MAX_ORDER, BlockState::Free, 0),
+            Block::new(region_bytes().unwrap(), MAX_ORDER, BlockState::Free, 1),
         ];
 
         assert_eq!(allocator.lists[MAX_ORDER as usize - 1].len(), 0);
@@ -363,27 +1376,512 @@ mod test {
     }
 
     #[test]
-    fn test_split() {
+    fn test_create_top_level_overlapping() {
+        let size = region_bytes().unwrap();
         let mut allocator = BuddyAllocator::<Vec<Block>>::new();
-        allocator.create_top_level(0);
-        allocator
-            .split(BlockIndex {
-                index: 0,
-                order: MAX_ORDER,
+        allocator.create_top_level(size).unwrap();
+
+        // Exact duplicate
+        assert_eq!(
+            allocator.create_top_level(size),
+            Err(RegionError::Overlapping {
+                begin_address: size,
+                existing_address: size,
+            })
+        );
+
+        // Partial overlaps from above and below
+        assert_eq!(
+            allocator.create_top_level(size + 0x1000),
+            Err(RegionError::Overlapping {
+                begin_address: size + 0x1000,
+                existing_address: size,
+            })
+        );
+        assert_eq!(
+            allocator.create_top_level(0x1000),
+            Err(RegionError::Overlapping {
+                begin_address: 0x1000,
+                existing_address: size,
+            })
+        );
+
+        assert_eq!(
+            allocator.create_top_level(usize::max_value() - 0xfff),
+            Err(RegionError::WrapsAround {
+                begin_address: usize::max_value() - 0xfff,
             })
+        );
+
+        // Rejected regions must not have added any blocks
+        assert_eq!(allocator.lists[MAX_ORDER as usize].len(), 1);
+
+        // Adjacent regions on either side are fine
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(size * 2).unwrap();
+        assert_eq!(allocator.lists[MAX_ORDER as usize].len(), 3);
+    }
+
+    #[test]
+    fn test_remove_region() {
+        let size = 1usize << MAX_ORDER_SIZE;
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(size).unwrap();
+        assert_eq!(allocator.removable_regions(), vec![0, size]);
+
+        // Fill the first region and put one block in the second
+        let order = MAX_ORDER - 2;
+        let mut addresses: Vec<_> = (0..5)
+            .map(|_| BuddyAllocatorApi::allocate(&mut allocator, order).unwrap())
+            .collect();
+        addresses.sort();
+        assert_eq!(addresses[4], size);
+        assert_eq!(allocator.removable_regions(), Vec::<usize>::new());
+
+        let busy = |begin_address| Err(RegionBusy { begin_address });
+        assert_eq!(allocator.remove_region(size), busy(size));
+        assert_eq!(allocator.remove_region(size * 2), busy(size * 2));
+        assert_eq!(allocator.remove_region(0x1000), busy(0x1000));
+
+        for &addr in &addresses {
+            allocator.deallocate(addr, order).unwrap();
+        }
+        assert_eq!(allocator.removable_regions(), vec![0, size]);
+        assert_eq!(allocator.remove_region(size), Ok(()));
+        assert_eq!(allocator.removable_regions(), vec![0]);
+
+        // Removing it twice is rejected, as it is no longer a region
+        assert_eq!(allocator.remove_region(size), busy(size));
+
+        // Only the remaining region is allocated from, until it runs out
+        for _ in 0..4 {
+            let addr = BuddyAllocatorApi::allocate(&mut allocator, order).unwrap();
+            assert!(addr < size, "Block {:#x} is in the removed region!", addr);
+        }
+        assert_eq!(BuddyAllocatorApi::allocate(&mut allocator, order), None);
+
+        // The memory of a removed region can be given to the allocator again
+        allocator.create_top_level(size).unwrap();
+        assert_eq!(BuddyAllocatorApi::allocate(&mut allocator, order), Some(size));
+    }
+
+    #[test]
+    fn test_block_metadata() {
+        // A compact block is a quarter of the size of a full one
+        let expected = if cfg!(feature = "compact-blocks") {
+            4
+        } else {
+            2 * mem::size_of::<usize>()
+        };
+        assert_eq!(mem::size_of::<Block>(), expected);
+
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        for _ in 0..100 {
+            BuddyAllocatorApi::allocate(&mut allocator, 0).unwrap();
+        }
+
+        let capacity: usize = allocator.lists.iter().map(Vec::capacity).sum();
+        assert_eq!(allocator.metadata_bytes(), capacity * expected);
+    }
+
+    #[test]
+    fn test_region_ids() {
+        let size = 1usize << MAX_ORDER_SIZE;
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        for n in 0..3 {
+            allocator.create_top_level(size * n).unwrap();
+        }
+
+        let ids: Vec<_> = allocator.regions.values().map(|region| region.id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+
+        // The id of a removed region is given to the next region added
+        allocator.remove_region(size).unwrap();
+        allocator.create_top_level(size * 3).unwrap();
+        assert_eq!(allocator.regions[&(size * 3)].id, 1);
+
+        // Split blocks keep the id of their region
+        let addr = BuddyAllocatorApi::allocate(&mut allocator, 0).unwrap();
+        let region = allocator.region_of(addr).unwrap().1.id;
+        for list in allocator.lists.iter() {
+            list.for_each(|block| {
+                if block.begin_address() >= addr && block.begin_address() < addr + size {
+                    assert_eq!(block.region(), region);
+                }
+            });
+        }
+    }
+
+    /// The index of the block of the given order beginning at `address`
+    fn index_at<L: BlockList>(
+        allocator: &mut BuddyAllocator<L>,
+        address: usize,
+        order: u8,
+    ) -> BlockIndex {
+        let position = allocator.lists[order as usize]
+            .position(|block| block.begin_address() == address)
             .unwrap();
+        allocator.index(order, position)
+    }
+
+    #[test]
+    fn test_no_merge_across_adjacent_regions() {
+        let size = 1usize << MAX_ORDER_SIZE;
+        let order = MAX_ORDER - 1;
+
+        // The seam between the regions is in the middle of the block of twice the size which is
+        // aligned absolutely, so the halves on either side would merge if regions were ignored
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(size / 2).unwrap();
+        allocator.create_top_level(size / 2 + size).unwrap();
+        for _ in 0..4 {
+            BuddyAllocatorApi::allocate(&mut allocator, order).unwrap();
+        }
+
+        allocator.deallocate(size, order).unwrap();
+        allocator.deallocate(size + size / 2, order).unwrap();
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[order as usize] = 2;
+        assert_eq!(allocator.free_histogram(), expected);
+
+        // Each merges with its buddy in its own region
+        allocator.deallocate(size / 2, order).unwrap();
+        allocator.deallocate(size * 2, order).unwrap();
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 2;
+        assert_eq!(allocator.free_histogram(), expected);
+    }
+
+    #[test]
+    fn test_region_checked_on_deallocate() {
+        let size = 1usize << MAX_ORDER_SIZE;
+        let order = MAX_ORDER - 1;
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(size).unwrap();
+        BuddyAllocatorApi::allocate(&mut allocator, order).unwrap();
+        BuddyAllocatorApi::allocate(&mut allocator, order).unwrap();
+
+        // A block recorded as being from another region is not freed
+        let index = index_at(&mut allocator, 0, order);
+        allocator.get_mut(&index).unwrap().set_region(1);
+        assert_eq!(allocator.deallocate(0, order), Err(BlockDeallocateError::OutsideRegion));
+        let index = index_at(&mut allocator, 0, order);
+        assert_eq!(allocator.get(&index).unwrap().state(), BlockState::Used);
+
+        // Nor is a free buddy recorded as being from another region merged with
+        allocator.get_mut(&index).unwrap().set_region(0);
+        allocator.deallocate(0, order).unwrap();
+        let index = index_at(&mut allocator, 0, order);
+        allocator.get_mut(&index).unwrap().set_region(1);
+        allocator.deallocate(size / 2, order).unwrap();
+
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[order as usize] = 2;
+        expected[MAX_ORDER as usize] = 1;
+        assert_eq!(allocator.free_histogram(), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "Overlapping")]
+    fn test_api_create_top_level_overlapping_panics() {
+        let mut allocator = BuddyAllocator::<LinkedList<Block>>::new();
+        BuddyAllocatorApi::create_top_level(&mut allocator, 0);
+        BuddyAllocatorApi::create_top_level(&mut allocator, 0);
+    }
+
+    #[test]
+    fn test_free_histogram() {
+        let mut allocator = BuddyAllocator::<LinkedList<Block>>::new();
+        assert_eq!(allocator.free_histogram(), [0; LEVEL_COUNT as usize]);
+
+        // Pristine: one top level block per region
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(region_bytes().unwrap()).unwrap();
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 2;
+        assert_eq!(allocator.free_histogram(), expected);
+
+        // Splitting down to order 0 leaves one free buddy on every order below the top
+        allocator.allocate_exact(0).unwrap();
+        let mut expected = [1; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 1;
+        assert_eq!(allocator.free_histogram(), expected);
+    }
+
+    #[test]
+    fn test_free_histogram_fragmented() {
+        // Every other order 0 block is used, so no free blocks of a higher order exist
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        for n in 0..16 {
+            allocator.push(Block::new(
+                n * block_bytes(0).unwrap(),
+                0,
+                if n % 2 == 0 { BlockState::Used } else { BlockState::Free },
+                0,
+            ));
+        }
+
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[0] = 8;
+        assert_eq!(allocator.free_histogram(), expected);
+    }
+
+    #[test]
+    fn test_deallocate_merges_buddies() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+
+        let first = allocator.allocate(0).unwrap();
+        let second = allocator.allocate(0).unwrap();
+        assert_eq!(
+            allocator.deallocate(first, 1),
+            Err(BlockDeallocateError::NoBlockAtAddress)
+        );
+
+        allocator.deallocate(first, 0).unwrap();
+        assert_eq!(
+            allocator.deallocate(first, 0),
+            Err(BlockDeallocateError::BlockNotUsed)
+        );
+
+        allocator.deallocate(second, 0).unwrap();
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 1;
+        assert_eq!(allocator.free_histogram(), expected);
+        assert_eq!(allocator.allocate(MAX_ORDER), Some(0));
+    }
+
+    #[test]
+    fn test_observer_events() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        let recorder = RecordingObserver::new();
+        assert!(allocator.set_observer(Box::new(recorder.clone())).is_none());
+        allocator.create_top_level(0).unwrap();
+
+        let order = MAX_ORDER - 2;
+        let size = block_bytes(order).unwrap();
+        let first = allocator.allocate(order).unwrap();
+        let second = allocator.allocate(order).unwrap();
+        allocator.deallocate(first, order).unwrap();
+        allocator.deallocate(second, order).unwrap();
+
+        assert_eq!(
+            recorder.take_events(),
+            vec![
+                AllocEvent::Split { addr: 0, order: MAX_ORDER },
+                AllocEvent::Split { addr: 0, order: MAX_ORDER - 1 },
+                AllocEvent::Alloc { addr: 0, order },
+                AllocEvent::Alloc { addr: size, order },
+                AllocEvent::Dealloc { addr: 0, order },
+                AllocEvent::Dealloc { addr: size, order },
+                AllocEvent::Merge { addr: 0, order: MAX_ORDER - 1 },
+                AllocEvent::Merge { addr: 0, order: MAX_ORDER },
+            ]
+        );
+
+        // Nothing is recorded once the observer is removed
+        assert!(allocator.take_observer().is_some());
+        allocator.allocate(0).unwrap();
+        assert_eq!(recorder.take_events(), vec![]);
+    }
+
+    /// Two top level blocks after a random workload of allocations and frees
+    fn fragmented<L: BlockList>(mut allocator: BuddyAllocator<L>) -> BuddyAllocator<L> {
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(1 << MAX_ORDER_SIZE).unwrap();
+
+        let mut rng = XorShift::new(448);
+        let mut allocated = Vec::new();
+        for _ in 0..300 {
+            if allocated.is_empty() || rng.below(3) != 0 {
+                let order = rng.below(6) as u8;
+                let addr = BuddyAllocatorApi::allocate(&mut allocator, order).unwrap();
+                allocated.push((addr, order));
+            } else {
+                let index = rng.below(allocated.len() as u64) as usize;
+                let (addr, order) = allocated.swap_remove(index);
+                allocator.deallocate(addr, order).unwrap();
+            }
+        }
+
+        allocator
+    }
+
+    fn blocks_of<L: BlockList>(allocator: &BuddyAllocator<L>) -> Vec<(usize, u8, BlockState)> {
+        let mut blocks = Vec::new();
+        for list in allocator.lists.iter() {
+            list.for_each(|block| {
+                blocks.push((block.begin_address(), block.order(), block.state()))
+            });
+        }
+        blocks
+    }
+
+    fn check_snapshot_round_trip<L: BlockList + Default>(mut original: BuddyAllocator<L>) {
+        let mut restored = BuddyAllocator::<L>::from_snapshot(&original.to_snapshot()).unwrap();
+        assert_eq!(blocks_of(&restored), blocks_of(&original));
+        assert_eq!(restored.usage(), original.usage());
+        assert_eq!(restored.to_snapshot(), original.to_snapshot());
+
+        assert_eq!(restored.regions, original.regions);
+
+        // The restored allocator hands out the same blocks from here on
+        for order in (0..8).chain(0..8) {
+            assert_eq!(
+                BuddyAllocatorApi::allocate(&mut restored, order),
+                BuddyAllocatorApi::allocate(&mut original, order)
+            );
+        }
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        check_snapshot_round_trip(fragmented(BuddyAllocator::<Vec<Block>>::new()));
+        check_snapshot_round_trip(fragmented(BuddyAllocator::<LinkedList<Block>>::new()));
+        check_snapshot_round_trip(BuddyAllocator::<Vec<Block>>::new());
+    }
+
+    /// A snapshot of the given regions and `(address, order, used)` blocks, which must be sorted by
+    /// order, with usage matching the used blocks
+    fn snapshot_of(regions: &[usize], blocks: &[(usize, u8, u8)]) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new(SNAPSHOT_MAGIC);
+        writer.u8(LEVEL_COUNT);
+        writer.u64(regions.len() as u64);
+        for &region in regions {
+            writer.u64(region as u64);
+        }
+
+        let mut usage = Usage::new();
+        for order in 0..=MAX_ORDER {
+            let in_list: Vec<_> = blocks.iter().filter(|block| block.1 == order).collect();
+            writer.u64(in_list.len() as u64);
+            for &&(address, _, state) in &in_list {
+                writer.u64(address as u64);
+                writer.u8(state);
+                if state == BlockState::Used as u8 {
+                    usage.allocated(order);
+                }
+            }
+        }
+
+        writer.usage(&usage);
+        writer.finish()
+    }
+
+    #[test]
+    fn test_snapshot_rejects_corrupt_input() {
+        let restore = |bytes: &[u8]| BuddyAllocator::<Vec<Block>>::from_snapshot(bytes).err();
+        let (used, free) = (BlockState::Used as u8, BlockState::Free as u8);
+        let half = 1 << (MAX_ORDER_SIZE - 1);
+
+        // A valid snapshot to begin with
+        let valid = snapshot_of(&[0], &[(0, MAX_ORDER - 1, used), (half, MAX_ORDER - 1, free)]);
+        assert_eq!(restore(&valid), None);
+
+        assert_eq!(
+            restore(&snapshot_of(&[0], &[(0, MAX_ORDER - 1, used), (half, MAX_ORDER - 1, 7)])),
+            Some(SnapshotError::InvalidBlock { index: 1 })
+        );
+        // Overlapping blocks, a block outside of every region, and a misaligned block
+        assert_eq!(
+            restore(&snapshot_of(&[0], &[(0, 0, used), (0, MAX_ORDER, free)])),
+            Some(SnapshotError::InvalidBlock { index: 1 })
+        );
+        assert_eq!(
+            restore(&snapshot_of(&[0], &[(half * 2, MAX_ORDER, free)])),
+            Some(SnapshotError::InvalidBlock { index: 0 })
+        );
+        assert_eq!(
+            restore(&snapshot_of(&[0], &[(0x1000, 1, used)])),
+            Some(SnapshotError::InvalidBlock { index: 0 })
+        );
+        // Memory of a region which no block covers
+        assert_eq!(
+            restore(&snapshot_of(&[0], &[(0, MAX_ORDER - 1, used)])),
+            Some(SnapshotError::InvalidField { field: "block count" })
+        );
+        // Free buddies which should have been merged
+        assert_eq!(
+            restore(&snapshot_of(&[0], &[(0, MAX_ORDER - 1, free), (half, MAX_ORDER - 1, free)])),
+            Some(SnapshotError::InvalidField { field: "free blocks" })
+        );
+        // Overlapping regions
+        assert_eq!(
+            restore(&snapshot_of(&[0, half], &[])),
+            Some(SnapshotError::InvalidField { field: "region address" })
+        );
+
+        // Usage which does not match the used blocks
+        let mut corrupted = valid.clone();
+        let outstanding_offset = corrupted.len() - 24;
+        corrupted[outstanding_offset] = 0;
+        assert_eq!(restore(&corrupted), Some(SnapshotError::InvalidField { field: "used bytes" }));
+
+        let mut corrupted = valid.clone();
+        corrupted[5] = LEVEL_COUNT + 1;
+        assert_eq!(restore(&corrupted), Some(SnapshotError::InvalidField { field: "level count" }));
+        assert_eq!(restore(&valid[..valid.len() - 1]), Some(SnapshotError::Truncated));
+        assert_eq!(restore(&valid[1..]), Some(SnapshotError::BadHeader));
+    }
+
+    #[test]
+    fn test_render_map() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        assert_eq!(allocator.render_map(8), "(no memory managed)\n");
+
+        let size = region_bytes().unwrap();
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(size * 2).unwrap();
+        allocator.allocate(MAX_ORDER - 2).unwrap();
+
+        // The gap between the regions is reserved
+        assert_eq!(
+            allocator.render_map(12),
+            format!(
+                "#...----....\n0x0..{:#x}, 1 char = {:#x} bytes: \
+                 '.' free, '#' used, '+' partly used, '-' reserved\n",
+                size * 3,
+                size / 4
+            )
+        );
+        assert!(allocator.render_map(3).starts_with("+-.\n"));
+    }
+
+    #[test]
+    fn test_peaks() {
+        let mut allocator = BuddyAllocator::<LinkedList<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+
+        let addresses: Vec<_> = (0..100).map(|_| allocator.allocate(0).unwrap()).collect();
+        for &addr in &addresses[..50] {
+            allocator.deallocate(addr, 0).unwrap();
+        }
+        for _ in 0..25 {
+            allocator.allocate(0).unwrap();
+        }
+
+        assert_eq!(allocator.usage().outstanding_allocations(), 75);
+        assert_eq!(allocator.peak_outstanding_allocations(), 100);
+        assert_eq!(allocator.peak_used_bytes(), 100 * block_bytes(0).unwrap());
+
+        allocator.reset_peaks();
+        assert_eq!(allocator.peak_outstanding_allocations(), 75);
+        assert_eq!(allocator.peak_used_bytes(), 75 * block_bytes(0).unwrap());
+    }
+
+    #[test]
+    fn test_split() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        let index = allocator.index(MAX_ORDER, 0);
+        allocator.split(index).unwrap();
 
         let expected_blocks = [
-            Block {
-                begin_address: 0,
-                order: MAX_ORDER - 1,
-                state: BlockState::Free,
-            },
-            Block {
-                begin_address: 2usize.pow(MAX_ORDER_SIZE as u32 - 1),
-                order: MAX_ORDER - 1,
-                state: BlockState::Free,
-            },
+            Block::new(0, MAX_ORDER - 1, BlockState::Free, 0),
+            Block::new(block_bytes(MAX_ORDER - 1).unwrap(), MAX_ORDER - 1, BlockState::Free, 0),
         ];
 
         assert_eq!(allocator.lists[MAX_ORDER as usize - 1].len(), 2);
@@ -398,31 +1896,24 @@ mod test {
     #[test]
     fn test_get_linked_list() {
         let mut allocator = BuddyAllocator::<LinkedList<Block>>::new();
-        allocator.create_top_level(0);
-        allocator.create_top_level(2usize.pow((MAX_ORDER + BASE_ORDER) as u32) as usize);
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(region_bytes().unwrap()).unwrap();
 
         let mut indices: [BlockIndex; 2] = array_init::array_init(|_| {
-            allocator
-                .split(BlockIndex {
-                    index: 0,
-                    order: MAX_ORDER,
-                })
-                .unwrap()
+            let index = allocator.index(MAX_ORDER, 0);
+            allocator.split(index).unwrap()
         });
 
         indices[1].index += 1; // Make sure we iterate from back too
 
         let expected_blocks = [
-            Block {
-                begin_address: 0,
-                order: MAX_ORDER - 1,
-                state: BlockState::Free,
-            },
-            Block {
-                begin_address: 2usize.pow(MAX_ORDER_SIZE as u32 - 1) * indices[1].index,
-                order: MAX_ORDER - 1,
-                state: BlockState::Free,
-            },
+            Block::new(0, MAX_ORDER - 1, BlockState::Free, 0),
+            Block::new(
+                block_bytes(MAX_ORDER - 1).unwrap() * indices[1].index,
+                MAX_ORDER - 1,
+                BlockState::Free,
+                1,
+            ),
         ];
 
         for (index, expected) in indices.iter().zip(expected_blocks.iter()) {
@@ -434,31 +1925,24 @@ mod test {
     #[test]
     fn test_get_mut_linked_list() {
         let mut allocator = BuddyAllocator::<LinkedList<Block>>::new();
-        allocator.create_top_level(0);
-        allocator.create_top_level(1024 * 1024 * 1024);
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(region_bytes().unwrap()).unwrap();
 
         let mut indices: [BlockIndex; 2] = array_init::array_init(|_| {
-            allocator
-                .split(BlockIndex {
-                    index: 0,
-                    order: MAX_ORDER,
-                })
-                .unwrap()
+            let index = allocator.index(MAX_ORDER, 0);
+            allocator.split(index).unwrap()
         });
 
         indices[1].index += 1; // Make sure we iterate from back too
 
         let expected_blocks = [
-            Block {
-                begin_address: 0,
-                order: MAX_ORDER - 1,
-                state: BlockState::Free,
-            },
-            Block {
-                begin_address: 2usize.pow((MAX_ORDER_SIZE - 1) as u32) * indices[1].index,
-                order: MAX_ORDER - 1,
-                state: BlockState::Free,
-            },
+            Block::new(0, MAX_ORDER - 1, BlockState::Free, 0),
+            Block::new(
+                block_bytes(MAX_ORDER - 1).unwrap() * indices[1].index,
+                MAX_ORDER - 1,
+                BlockState::Free,
+                1,
+            ),
         ];
 
         for (index, expected) in indices.iter().zip(expected_blocks.iter()) {
@@ -467,77 +1951,471 @@ mod test {
         }
     }
 
+    fn linked_list_of(addresses: &[usize]) -> LinkedList<Block> {
+        addresses
+            .iter()
+            .map(|&begin_address| Block::new(begin_address, 0, BlockState::Free, 0))
+            .collect()
+    }
+
+    /// Generates random list lengths, always including the small edge cases
+    fn random_lengths() -> Vec<usize> {
+        let mut rng = XorShift::new(0x2545_f491_4f6c_dd1d);
+        let mut lengths: Vec<usize> = (0..8).collect();
+        lengths.extend((0..64).map(|_| rng.below(256) as usize));
+        lengths
+    }
+
+    #[test]
+    fn test_get_linked_list_matches_vec() {
+        for len in random_lengths() {
+            let reference: Vec<usize> = (0..len).map(|n| n * 0x1000).collect();
+            let mut list = linked_list_of(&reference);
+
+            for index in 0..len + 2 {
+                let expected = reference.get(index);
+
+                assert_eq!(
+                    BlockList::get(&list, index).map(|b| b.begin_address()),
+                    expected.cloned(),
+                    "get({}) wrong for list of length {}",
+                    index,
+                    len
+                );
+                assert_eq!(
+                    BlockList::get_mut(&mut list, index).map(|b| b.begin_address()),
+                    expected.cloned(),
+                    "get_mut({}) wrong for list of length {}",
+                    index,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove_linked_list_matches_vec() {
+        for len in random_lengths() {
+            let reference: Vec<usize> = (0..len).map(|n| n * 0x1000).collect();
+
+            for index in 0..len {
+                let mut list = linked_list_of(&reference);
+                BlockList::remove(&mut list, index);
+
+                let mut expected = reference.clone();
+                expected.remove(index);
+
+                assert_eq!(
+                    list.iter().map(|b| b.begin_address()).collect::<Vec<_>>(),
+                    expected,
+                    "remove({}) wrong for list of length {}",
+                    index,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_remove_linked_list_out_of_bounds() {
+        let mut list = linked_list_of(&[0, 0x1000]);
+        BlockList::remove(&mut list, 2);
+    }
+
+    #[test]
+    fn test_stale_index() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        for n in 0..3 {
+            allocator.create_top_level(region_bytes().unwrap() * n).unwrap();
+        }
+
+        let second = allocator.index(MAX_ORDER, 1);
+        assert_eq!(
+            allocator.get(&second).unwrap().begin_address(),
+            region_bytes().unwrap()
+        );
+
+        // Removes the first block from the list by moving the third block into its place, so
+        // `second` is stale and a fresh index 0 points to the third block
+        let first = allocator.index(MAX_ORDER, 0);
+        allocator.split(first).unwrap();
+
+        assert!(allocator.get(&second).is_none());
+        assert!(allocator.get_mut(&second).is_none());
+        assert_eq!(allocator.set_state(&second, BlockState::Used), Err(StaleIndex));
+
+        let fresh = allocator.index(MAX_ORDER, 0);
+        assert_eq!(allocator.set_state(&fresh, BlockState::Used), Ok(()));
+        assert_eq!(
+            *allocator.get(&fresh).unwrap(),
+            Block::new(region_bytes().unwrap() * 2, MAX_ORDER, BlockState::Used, 2)
+        );
+    }
+
+    #[test]
+    fn test_split_unordered_keeps_other_blocks() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        for n in 0..3 {
+            allocator.create_top_level(region_bytes().unwrap() * n).unwrap();
+        }
+
+        let middle = allocator.index(MAX_ORDER, 1);
+        let first_buddy = allocator.split(middle).unwrap();
+        assert_eq!(
+            allocator.get(&first_buddy).unwrap().begin_address(),
+            region_bytes().unwrap()
+        );
+
+        let mut top_level: Vec<usize> = allocator.lists[MAX_ORDER as usize]
+            .iter()
+            .map(|block| block.begin_address())
+            .collect();
+        top_level.sort();
+        assert_eq!(top_level, vec![0, region_bytes().unwrap() * 2]);
+    }
+
+    fn check_free_orders<L: BlockList>(mut allocator: BuddyAllocator<L>) {
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(region_bytes().unwrap()).unwrap();
+
+        let spec = WorkloadSpec::uniform(2000, MAX_ORDER, 463);
+        workload::run_with(&mut allocator, &spec, |allocator, _| {
+            for order in 0..=MAX_ORDER {
+                let mut free = 0;
+                allocator.lists[order as usize].for_each(|block| {
+                    if block.state() == BlockState::Free {
+                        free += 1;
+                    }
+                });
+
+                assert_eq!(allocator.free_blocks[order as usize], free);
+                assert_eq!(allocator.free_orders & (1 << order) != 0, free != 0);
+            }
+        });
+    }
+
+    fn check_count_by_state<L: BlockList>(mut allocator: BuddyAllocator<L>) {
+        allocator.create_top_level(0).unwrap();
+
+        for k in 1..=40 {
+            allocator.allocate_exact(0).unwrap();
+
+            // The free blocks left after the first k blocks are the binary digits of what remains
+            let remaining = (1usize << MAX_ORDER) - k;
+            for order in 0..=MAX_ORDER {
+                let used = if order == 0 { k } else { 0 };
+                let free = if order < MAX_ORDER { (remaining >> order) & 1 } else { 0 };
+                assert_eq!(allocator.count_by_state(order, BlockState::Used), used);
+                assert_eq!(allocator.count_by_state(order, BlockState::Free), free);
+            }
+        }
+    }
+
+    #[test]
+    fn test_count_by_state() {
+        check_count_by_state(BuddyAllocator::<Vec<Block>>::new());
+        check_count_by_state(BuddyAllocator::<LinkedList<Block>>::new());
+    }
+
+    /// Everything a snapshot answers: the blocks, usage, counters, metadata, free and used counts
+    /// of each order and removable regions
+    type Inspection = (AllocatorState, Usage, OpCounters, usize, Vec<(usize, usize)>, Vec<usize>);
+
+    fn inspect<S: AllocatorStats>(
+        stats: &S,
+        count_by_state: &dyn Fn(u8, BlockState) -> usize,
+        removable_regions: Vec<usize>,
+    ) -> Inspection {
+        let counts = (0..LEVEL_COUNT)
+            .map(|order| {
+                let free = count_by_state(order, BlockState::Free);
+                (free, count_by_state(order, BlockState::Used))
+            })
+            .collect();
+
+        (
+            AllocatorState::of(stats),
+            *stats.usage(),
+            stats.op_counters(),
+            stats.metadata_bytes(),
+            counts,
+            removable_regions,
+        )
+    }
+
+    fn inspect_live<L: BlockList>(allocator: &BuddyAllocator<L>) -> Inspection {
+        let count = |order, state| allocator.count_by_state(order, state);
+        inspect(allocator, &count, allocator.removable_regions())
+    }
+
+    fn inspect_snapshot(snapshot: &AllocatorSnapshot) -> Inspection {
+        let count = |order, state| snapshot.count_by_state(order, state);
+        inspect(snapshot, &count, snapshot.removable_regions())
+    }
+
+    fn check_snapshot_unaffected<L: BlockList>(mut allocator: BuddyAllocator<L>) {
+        let size = 1usize << MAX_ORDER_SIZE;
+        for n in 0..3 {
+            allocator.create_top_level(size * n).unwrap();
+        }
+
+        let mut rng = XorShift::new(490);
+        let mut live: Vec<_> = (0..200)
+            .map(|_| {
+                let order = rng.below(6) as u8;
+                (BuddyAllocatorApi::allocate(&mut allocator, order).unwrap(), order)
+            })
+            .collect();
+        for _ in 0..50 {
+            let (addr, order) = live.swap_remove(rng.below(live.len() as u64) as usize);
+            allocator.deallocate(addr, order).unwrap();
+        }
+
+        let before = inspect_live(&allocator);
+        let snapshot = allocator.snapshot();
+        assert_eq!(inspect_snapshot(&snapshot), before);
+
+        // Free everything, remove a region, and fill the rest with blocks of other orders
+        for (addr, order) in live.drain(..) {
+            allocator.deallocate(addr, order).unwrap();
+        }
+        allocator.remove_region(size).unwrap();
+        allocator.create_top_level(size * 3).unwrap();
+        while let Some(addr) = BuddyAllocatorApi::allocate(&mut allocator, MAX_ORDER - 3) {
+            live.push((addr, MAX_ORDER - 3));
+        }
+
+        let after = inspect_live(&allocator);
+        assert_ne!(after, before);
+
+        // Inspected from another thread, the snapshot still describes the allocator before
+        let inspected = thread::spawn(move || inspect_snapshot(&snapshot)).join().unwrap();
+        assert_eq!(inspected, before);
+    }
+
+    #[test]
+    fn test_snapshot_unaffected_by_allocator() {
+        check_snapshot_unaffected(BuddyAllocator::<Vec<Block>>::new());
+        check_snapshot_unaffected(BuddyAllocator::<LinkedList<Block>>::new());
+    }
+
+    #[test]
+    fn test_free_orders_match_lists() {
+        check_free_orders(BuddyAllocator::<Vec<Block>>::new());
+        check_free_orders(BuddyAllocator::<LinkedList<Block>>::new());
+    }
+
+    #[test]
+    fn test_allocate_exact_returns_fresh_index() {
+        let mut allocator = BuddyAllocator::<LinkedList<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+
+        let index = allocator.allocate_exact(0).unwrap();
+        assert!(!allocator.is_stale(&index));
+        assert_eq!(allocator.get(&index).unwrap().state(), BlockState::Used);
+    }
+
     #[test]
     fn test_allocate_exact_with_free() {
         let mut allocator = BuddyAllocator::<Vec<Block>>::new();
-        allocator.create_top_level(0);
+        allocator.create_top_level(0).unwrap();
         let index = allocator.allocate_exact(MAX_ORDER).unwrap();
-        let expected_block = Block {
-            begin_address: 0,
-            order: MAX_ORDER,
-            state: BlockState::Used,
-        };
+        let expected_block = Block::new(0, MAX_ORDER, BlockState::Used, 0);
         assert_eq!(*allocator.get(&index).unwrap(), expected_block);
     }
 
     #[test]
     fn test_allocate_exact_no_free() {
         let mut allocator = BuddyAllocator::<Vec<Block>>::new();
-        allocator.create_top_level(0);
+        allocator.create_top_level(0).unwrap();
         let index = allocator.allocate_exact(MAX_ORDER - 2).unwrap();
-        let expected_block = Block {
-            begin_address: 0,
-            order: MAX_ORDER - 2,
-            state: BlockState::Used,
-        };
+        let expected_block = Block::new(0, MAX_ORDER - 2, BlockState::Used, 0);
 
         assert_eq!(*allocator.get(&index).unwrap(), expected_block);
     }
 
+    #[test]
+    fn test_unique_addresses_vecs() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        check_unique_addresses(&mut allocator, 2, &[0, 3, 1, 0, 5, 2], 1000);
+    }
+
     #[test]
     fn test_unique_addresses_linked_lists() {
         let mut allocator = BuddyAllocator::<LinkedList<Block>>::new();
+        check_unique_addresses(&mut allocator, 2, &[0, 3, 1, 0, 5, 2], 1000);
+    }
 
-        for block_number in 0..top_level_blocks(1000, 0) {
-            allocator.create_top_level(
-                2usize.pow((MAX_ORDER + BASE_ORDER) as u32) * block_number as usize,
-            );
-        }
-        let mut seen = Vec::with_capacity(1000);
-        for _ in 0..1000 {
-            let index = allocator.allocate_exact(0).unwrap();
-            let addr = allocator.get(&index).unwrap().begin_address;
+    #[test]
+    fn test_order_too_large() {
+        let expected = BlockAllocateError::OrderTooLarge {
+            order: MAX_ORDER + 1,
+            max_order: MAX_ORDER,
+        };
 
-            if seen.contains(&addr) {
-                panic!("Allocator must return addresses never been allocated before!");
-            } else {
-                seen.push(addr);
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        assert_eq!(allocator.allocate_exact(MAX_ORDER + 1).unwrap_err(), expected);
+
+        let mut allocator = BuddyAllocator::<LinkedList<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        assert_eq!(allocator.allocate_exact(MAX_ORDER + 1).unwrap_err(), expected);
+        assert_eq!(
+            allocator.allocate_exact(u8::max_value()).unwrap_err(),
+            BlockAllocateError::OrderTooLarge {
+                order: u8::max_value(),
+                max_order: MAX_ORDER,
             }
+        );
+
+        let demo_error = DemoError::OrderTooLarge {
+            order: MAX_ORDER + 1,
+            max_order: MAX_ORDER,
+        };
+        assert_eq!(demo_vecs(false, 1, MAX_ORDER + 1), Err(demo_error));
+        assert_eq!(demo_linked_lists(false, 1, MAX_ORDER + 1), Err(demo_error));
+    }
+
+    /// An allocator with one region of which only a block of order 4 is left free
+    fn sixteen_pages_free<L: BlockList>(mut allocator: BuddyAllocator<L>) -> BuddyAllocator<L> {
+        allocator.create_top_level(0).unwrap();
+        for order in (4..MAX_ORDER).rev() {
+            allocator.allocate_exact(order).unwrap();
         }
+        allocator
     }
 
-    #[test]
-    fn test_unique_addresses_vecs() {
-        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+    fn check_alloc_pages_exact_fit<L: BlockList>(allocator: BuddyAllocator<L>) {
+        let mut allocator = sixteen_pages_free(allocator);
 
-        for block_number in 0..top_level_blocks(1000, 0) {
-            allocator.create_top_level(
-                2usize.pow((MAX_ORDER + BASE_ORDER) as u32) * block_number as usize,
-            );
+        let pages: Vec<usize> = allocator.alloc_pages(16).unwrap().collect();
+        let mut sorted = pages.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 16);
+        assert!(sorted.iter().all(|&addr| addr % block_bytes(0).unwrap() == 0));
+
+        assert_eq!(BuddyAllocatorApi::allocate(&mut allocator, 0), None);
+        assert_eq!(allocator.alloc_pages(0).unwrap().len(), 0);
+
+        // Freeing the pages merges them back into the block of order 4
+        for addr in pages {
+            allocator.deallocate(addr, 0).unwrap();
         }
+        assert_eq!(allocator.free_histogram()[4], 1);
+    }
 
-        let mut seen = Vec::with_capacity(1000);
-        for _ in 0..1000 {
-            let index = allocator.allocate_exact(0).unwrap();
-            let addr = allocator.get(&index).unwrap().begin_address;
+    #[test]
+    fn test_alloc_pages_exact_fit() {
+        check_alloc_pages_exact_fit(BuddyAllocator::<Vec<Block>>::new());
+        check_alloc_pages_exact_fit(BuddyAllocator::<LinkedList<Block>>::new());
+    }
 
-            if seen.contains(&addr) {
-                panic!("Allocator must return addresses never been allocated before!");
-            } else {
-                seen.push(addr);
+    #[test]
+    fn test_alloc_pages_failure_leaves_state() {
+        let mut allocator = sixteen_pages_free(BuddyAllocator::<Vec<Block>>::new());
+        let before = inspect_live(&allocator);
+
+        let err = allocator.alloc_pages(17).map(|_| ()).unwrap_err();
+        assert_eq!(err, AllocError { requested: 17, available: 16 });
+        assert_eq!(inspect_live(&allocator), before);
+    }
+
+    #[test]
+    fn test_alloc_pages_interleaved() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        let free = allocator.free_histogram();
+
+        let mut rng = XorShift::new(496);
+        let mut allocated = BlockSet::new();
+        let mut blocks = Vec::new();
+        for _ in 0..50 {
+            let order = rng.below(8) as u8;
+            let addr = BuddyAllocatorApi::allocate(&mut allocator, order).unwrap();
+            assert!(allocated.insert(addr, block_bytes(order).unwrap()));
+            blocks.push((addr, order));
+
+            let pages = allocator.alloc_pages(rng.below(20) as usize).unwrap();
+            for addr in pages {
+                assert!(allocated.insert(addr, block_bytes(0).unwrap()));
+                blocks.push((addr, 0));
             }
         }
+
+        for (addr, order) in blocks {
+            allocator.deallocate(addr, order).unwrap();
+        }
+        assert_eq!(allocator.free_histogram(), free);
+    }
+
+    #[test]
+    fn test_physical_alloc_page_sizes() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+
+        // The second page is the buddy of the first, so both were blocks of the same order
+        let size = 1usize << PageSize::Mib2.power_of_two();
+        let first = PhysicalAllocator::alloc(&mut allocator, PageSize::Mib2).unwrap() as usize;
+        let second = PhysicalAllocator::alloc(&mut allocator, PageSize::Mib2).unwrap() as usize;
+        assert_eq!(first % size, 0);
+        assert_eq!(second, first ^ size);
+    }
+
+    #[test]
+    fn test_physical_dealloc() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        let free = allocator.free_histogram();
+
+        let small = PhysicalAllocator::alloc(&mut allocator, PageSize::Kib4).unwrap();
+        let large = PhysicalAllocator::alloc(&mut allocator, PageSize::Mib2).unwrap();
+        let inside = large as usize + size_of_order(0);
+
+        // Only the beginning of a used page can be freed, and only once
+        assert_eq!(
+            PhysicalAllocator::dealloc(&mut allocator, inside as *const u8),
+            Err(FreeError::NotAllocated { addr: inside })
+        );
+        let outside = size_of_order(MAX_ORDER);
+        assert_eq!(
+            PhysicalAllocator::dealloc(&mut allocator, outside as *const u8),
+            Err(FreeError::OutOfRange { addr: outside })
+        );
+        assert_eq!(PhysicalAllocator::dealloc(&mut allocator, large), Ok(()));
+        assert_eq!(
+            PhysicalAllocator::dealloc(&mut allocator, large),
+            Err(FreeError::NotAllocated { addr: large as usize })
+        );
+        assert_eq!(PhysicalAllocator::dealloc(&mut allocator, small), Ok(()));
+
+        assert_eq!(allocator.free_histogram(), free);
     }
 
-    // TODO test allocate_exact failing case propagates error right
+    #[cfg(feature = "large_config")]
+    #[test]
+    fn test_large_config_addresses() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+
+        allocator.allocate_exact(MAX_ORDER - 1).unwrap();
+        let index = allocator.allocate_exact(MAX_ORDER - 1).unwrap();
+        let addr = allocator.get(&index).unwrap().begin_address();
+
+        assert_eq!(addr, block_bytes(MAX_ORDER - 1).unwrap());
+        assert!(addr as u64 > u64::from(::std::u32::MAX));
+    }
+
+    #[test]
+    fn test_demo_reports_failing_allocation() {
+        // One top level block only fits 4 blocks of order MAX_ORDER - 2
+        let allocator = BuddyAllocator::<Vec<Block>>::new();
+        let res = demo(allocator, 1, false, 5, MAX_ORDER - 2);
+        assert_eq!(res, Err(DemoError::OutOfBlocks { allocation: 4 }));
+
+        let allocator = BuddyAllocator::<LinkedList<Block>>::new();
+        let res = demo(allocator, 0, false, 1, 0);
+        assert_eq!(res, Err(DemoError::OutOfBlocks { allocation: 0 }));
+    }
 }
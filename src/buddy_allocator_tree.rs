@@ -1,5 +1,13 @@
-use super::{top_level_blocks, MAX_ORDER, BASE_ORDER, LEVEL_COUNT, MAX_ORDER_SIZE};
+use super::{top_level_blocks, AllocationPolicy, BuddyAllocatorApi, DemoError, DurationReport, MAX_ORDER, LEVEL_COUNT, MAX_ORDER_SIZE};
+use buddy::{buddy_of, parent_of};
+use geometry::{block_bytes, region_bytes, size_of_order};
 use array_init;
+use metrics::{Latencies, OpTimer};
+#[cfg(feature = "metrics")]
+use metrics::LatencySummary;
+use stats::{largest_free_run, AllocatorStats, BlockInfo, OpCounters, Usage};
+use steady_state;
+use testing::RegionTracker;
 use bit_field::BitField;
 #[cfg(feature = "flame_profile")]
 use flame;
@@ -7,21 +15,113 @@ use intrusive_collections::rbtree::CursorMut;
 use intrusive_collections::{KeyAdapter, RBTree, RBTreeLink, SinglyLinkedList, SinglyLinkedListLink};
 use std::cell::Cell;
 use std::cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::mem;
+use std::ops::Range;
 use std::ptr;
+use std::thread;
 use std::time::{Instant, Duration};
 
 #[derive(Debug)]
 pub struct Block {
     link: RBTreeLink,
-    bit_field: Cell<u64>,
+    bit_field: Cell<layout::Bits>,
 }
 
+/// Zones are stored in 4 bits of the bit field, after the flags
+const ZONE_BITS: u8 = 4;
+/// How many zones, such as a DMA zone below 4 GiB and a normal zone, one allocator can manage
+pub const ZONE_COUNT: u8 = 1 << ZONE_BITS;
+
+/// Where each field is in the bit field of a block. From the lowest bit up, these are the used
+/// flag, the order, the free listed and tombstone flags, the zone and the address.
+#[cfg(not(feature = "compact-blocks"))]
+mod layout {
+    use std::ops::Range;
+
+    pub type Bits = u64;
+
+    pub const USED: usize = 0;
+    pub const ORDER: Range<usize> = 1..9;
+    pub const FREE_LISTED: usize = 9;
+    pub const TOMBSTONE: usize = 10;
+    pub const ZONE: Range<usize> = 11..15;
+    /// Addresses are stored as they are in the top 49 bits
+    pub const ADDRESS: Range<usize> = 15..64;
+    pub const ADDRESS_BITS: u8 = 49;
+    pub const LARGEST_ORDER: u8 = ::std::u8::MAX;
+    #[cfg(test)]
+    pub const LARGEST_ADDRESS: usize = (1 << ADDRESS_BITS) - 1;
+
+    #[inline]
+    pub fn pack_address(addr: usize) -> Bits {
+        addr as Bits
+    }
+
+    #[inline]
+    pub fn unpack_address(bits: Bits) -> usize {
+        bits as usize
+    }
+}
+
+/// Where each field is in the 32 bit field of a compact block, in the same order as the full
+/// layout but with a 5 bit order and the frame number of the address rather than the address. On
+/// 64 bit targets the block is padded to the alignment of its link anyway, so it only shrinks on
+/// targets with smaller pointers.
+#[cfg(feature = "compact-blocks")]
+mod layout {
+    use geometry::{compact_frame, compact_frame_address, COMPACT_FRAME_BITS};
+    use std::ops::Range;
+    use BASE_ORDER;
+
+    pub type Bits = u32;
+
+    pub const USED: usize = 0;
+    pub const ORDER: Range<usize> = 1..6;
+    pub const FREE_LISTED: usize = 6;
+    pub const TOMBSTONE: usize = 7;
+    pub const ZONE: Range<usize> = 8..12;
+    pub const ADDRESS: Range<usize> = 12..32;
+    pub const ADDRESS_BITS: u8 = COMPACT_FRAME_BITS + BASE_ORDER;
+    pub const LARGEST_ORDER: u8 = (1 << 5) - 1;
+    #[cfg(test)]
+    pub const LARGEST_ADDRESS: usize = ((1 << COMPACT_FRAME_BITS) - 1) << BASE_ORDER;
+
+    #[inline]
+    pub fn pack_address(addr: usize) -> Bits {
+        compact_frame(addr)
+            .unwrap_or_else(|| panic!("Block {:#x} does not fit in a compact block!", addr))
+    }
+
+    #[inline]
+    pub fn unpack_address(bits: Bits) -> usize {
+        compact_frame_address(bits)
+    }
+}
+
+// Every order must fit in the order field
+const_assert!(__rb_tree_order_fits_field; (MAX_ORDER as usize) <= layout::LARGEST_ORDER as usize);
+// A block of the largest order must be addressable
+const_assert!(__rb_tree_max_order_size_fits_address; MAX_ORDER_SIZE < layout::ADDRESS_BITS);
+
 impl Block {
-    fn new(begin_address: usize, order: u8, used: bool) -> Self {
-        let mut bit_field = 0u64;
-        bit_field.set_bit(0, used);
-        bit_field.set_bits(1..8, u64::from(order));
-        bit_field.set_bits(8..64, begin_address as u64);
+    fn new(begin_address: usize, order: u8, zone: u8, used: bool) -> Self {
+        debug_assert!(
+            (begin_address as u64) < 1 << layout::ADDRESS_BITS,
+            "Address {:#x} does not fit in {} bits!",
+            begin_address,
+            layout::ADDRESS_BITS
+        );
+        debug_assert!(zone < ZONE_COUNT, "Zone {} not less than {}!", zone, ZONE_COUNT);
+
+        let mut bit_field: layout::Bits = 0;
+        bit_field.set_bit(layout::USED, used);
+        bit_field.set_bits(layout::ORDER, layout::Bits::from(order));
+        bit_field.set_bit(layout::FREE_LISTED, false);
+        bit_field.set_bit(layout::TOMBSTONE, false);
+        bit_field.set_bits(layout::ZONE, layout::Bits::from(zone));
+        bit_field.set_bits(layout::ADDRESS, layout::pack_address(begin_address));
 
         Block {
             link: RBTreeLink::new(),
@@ -29,29 +129,71 @@ impl Block {
         }
     }
 
+    /// A free block in zone 0 which is in no tree or list, e.g. for benchmarking free lists apart
+    /// from an allocator.
+    pub fn new_free(begin_address: usize, order: u8) -> Self {
+        Block::new(begin_address, order, 0, false)
+    }
+
     #[inline]
     fn used(&self) -> bool {
-        self.bit_field.get().get_bit(0)
+        self.bit_field.get().get_bit(layout::USED)
+    }
+
+    /// Whether a pointer to this block is currently in the free list of its order. This is the
+    /// authoritative record of list membership -- a block must never leave the tree while it is set.
+    #[inline]
+    fn free_listed(&self) -> bool {
+        self.bit_field.get().get_bit(layout::FREE_LISTED)
+    }
+
+    /// Unsafe because the caller could not have unique access to the block. Needed to mutate the
+    /// block while it is in the tree
+    #[inline]
+    unsafe fn set_free_listed(&self, listed: bool) {
+        let mut copy = self.bit_field.get();
+        copy.set_bit(layout::FREE_LISTED, listed);
+
+        self.bit_field.set(copy)
+    }
+
+    /// Whether this block has left the tree while a pointer to it was still in a free list. The
+    /// pointer is skipped when it is popped, and until then the list owns the block.
+    #[inline]
+    fn tombstone(&self) -> bool {
+        self.bit_field.get().get_bit(layout::TOMBSTONE)
     }
 
-    /// Set the state of this block. Unsafe because the caller could not have unique access to the
-    /// block. Needed to mutate the block while it is in the tree
     #[inline]
-    unsafe fn set_used(&self, used: bool) {
+    fn set_tombstone(&self, tombstone: bool) {
         let mut copy = self.bit_field.get();
-        copy.set_bit(0, used);
+        copy.set_bit(layout::TOMBSTONE, tombstone);
 
         self.bit_field.set(copy)
     }
 
     #[inline]
     fn order(&self) -> u8 {
-        self.bit_field.get().get_bits(1..8) as u8 // 7 bits for max = 64
+        self.bit_field.get().get_bits(layout::ORDER) as u8
+    }
+
+    /// The zone of the top level block this block was split from
+    #[inline]
+    fn zone(&self) -> u8 {
+        self.bit_field.get().get_bits(layout::ZONE) as u8
     }
 
     #[inline]
     fn address(&self) -> usize {
-        self.bit_field.get().get_bits(8..64) as usize // max physical memory = 2^56 - 1 bytes
+        layout::unpack_address(self.bit_field.get().get_bits(layout::ADDRESS))
+    }
+
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            addr: self.address(),
+            order: self.order(),
+            used: self.used(),
+        }
     }
 }
 
@@ -78,7 +220,9 @@ impl Ord for Block {
 
 impl PartialEq for Block {
     fn eq(&self, other: &Block) -> bool {
-        let properties_eq = self.order() == other.order() && self.used() == other.used();
+        let properties_eq = self.order() == other.order()
+            && self.used() == other.used()
+            && self.zone() == other.zone();
         let address_eq = self.address() == other.address();
 
         // Addresses can't be the same without properties being the same
@@ -94,30 +238,125 @@ impl Eq for Block {}
 
 #[derive(Debug)]
 pub struct BuddyAllocator<L: FreeList> {
+    /// Declared before the tree so that it is dropped first, as freeing the tombstones it owns
+    /// reads the flags of the listed blocks which are still in the tree
+    free: FreeLists<L>,
+    /// Every free block, in address order. Used blocks are never in the tree, so it only grows
+    /// with fragmentation rather than with the number of allocations.
     tree: RBTree<BlockAdapter>,
-    free: [L; LEVEL_COUNT as usize],
+    /// Every used block, by address
+    used: HashMap<usize, UsedBlock>,
+    usage: Usage,
+    /// The usage of each zone alone
+    zone_usage: [Usage; ZONE_COUNT as usize],
+    counters: OpCounters,
+    latencies: Latencies,
+    /// How a free block is chosen to allocate from
+    policy: AllocationPolicy,
+    /// How many blocks are boxed in the tree, kept so that the metadata can be measured cheaply
+    nodes: usize,
+}
+
+/// What is remembered of a used block, which is in neither the tree nor any free list
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct UsedBlock {
+    order: u8,
+    zone: u8,
+}
+
+/// The free list of every order, with the pool of nodes they share.
+///
+/// Blocks are not searched for and removed from their list when they leave the tree. They are
+/// marked as tombstones instead and skipped once popped, and a list is compacted when too many of
+/// its entries are tombstones.
+#[derive(Debug)]
+struct FreeLists<L: FreeList> {
+    lists: [L; LEVEL_COUNT as usize],
+    /// Shared by the free lists of every order
+    pool: L::Pool,
+    /// Bit k is set if the list of order k is not empty, so that searching for a free block can
+    /// skip the empty lists
+    non_empty: u32,
+    /// How many entries, tombstones included, are in the list of each order
+    lens: [usize; LEVEL_COUNT as usize],
+    /// How many entries in the list of each order are tombstones
+    tombstones: [usize; LEVEL_COUNT as usize],
+    /// Boxes of tombstones which have been skipped, kept to be reused for new blocks
+    spare_blocks: Vec<*mut Block>,
+}
+
+/// A free list is compacted when more than this percentage of its entries are tombstones
+const MAX_TOMBSTONE_PERCENT: usize = 50;
+/// Lists with fewer tombstones than this are never compacted, as popping past them is cheap
+const MIN_TOMBSTONES_TO_COMPACT: usize = 16;
+/// The most boxes of skipped tombstones which are kept. Splitting a whole top level block needs
+/// one for each order.
+const MAX_SPARE_BLOCKS: usize = LEVEL_COUNT as usize;
+
+// Every order needs a bit in the mask of non empty lists
+const_assert!(__tree_orders_fit_mask; (LEVEL_COUNT as usize) <= 32);
+
+/// Where a free list keeps the nodes of entries which have been popped or removed, so that they
+/// can be reused by later pushes rather than allocated again.
+pub trait NodePool: Default + Debug {
+    /// How many bytes of heap memory the spare nodes use
+    fn metadata_bytes(&self) -> usize;
+}
+
+/// For free lists which don't allocate a node per entry
+impl NodePool for () {
+    fn metadata_bytes(&self) -> usize {
+        0
+    }
 }
 
 pub trait FreeList {
-    fn push(&mut self, block: *const Block);
-    fn pop(&mut self) -> Option<*const Block>;
+    type Pool: NodePool;
+
+    fn push(&mut self, block: *const Block, pool: &mut Self::Pool);
+    fn pop(&mut self, pool: &mut Self::Pool) -> Option<*const Block>;
     /// Search for an address and remove it from the list
-    fn remove(&mut self, addr: *const Block) -> Option<()>;
+    fn remove(&mut self, addr: *const Block, pool: &mut Self::Pool) -> Option<()>;
+    fn is_empty(&self) -> bool;
+    /// Remove every pointer for which `keep` returns false
+    fn retain<F: FnMut(*const Block) -> bool>(&mut self, keep: F, pool: &mut Self::Pool);
+    /// Call `f` with every pointer in the list, in no particular order
+    fn for_each<F: FnMut(*const Block)>(&self, f: F);
+    /// How many bytes of heap memory the list uses
+    fn metadata_bytes(&self) -> usize;
 }
 
 impl FreeList for Vec<*const Block> {
-    fn push(&mut self, block: *const Block) {
+    type Pool = ();
+
+    fn push(&mut self, block: *const Block, _pool: &mut ()) {
         Vec::push(self, block);
     }
 
-    fn pop(&mut self) -> Option<*const Block> {
+    fn pop(&mut self, _pool: &mut ()) -> Option<*const Block> {
         Vec::pop(self)
     }
 
-    fn remove(&mut self, block: *const Block) -> Option<()> {
+    fn remove(&mut self, block: *const Block, _pool: &mut ()) -> Option<()> {
         self.remove(self.iter().position(|i| ptr::eq(*i, block))?);
         Some(())
     }
+
+    fn is_empty(&self) -> bool {
+        Vec::is_empty(self)
+    }
+
+    fn retain<F: FnMut(*const Block) -> bool>(&mut self, mut keep: F, _pool: &mut ()) {
+        Vec::retain(self, |&block| keep(block))
+    }
+
+    fn for_each<F: FnMut(*const Block)>(&self, f: F) {
+        self.iter().cloned().for_each(f)
+    }
+
+    fn metadata_bytes(&self) -> usize {
+        self.capacity() * mem::size_of::<*const Block>()
+    }
 }
 
 #[derive(Debug)]
@@ -138,38 +377,141 @@ impl BlockPtr {
 
 intrusive_adapter!(pub BlockPtrAdapter = Box<BlockPtr>: BlockPtr { link: SinglyLinkedListLink });
 
+/// The most spare nodes a [BlockPtrPool] keeps. Splitting or merging a whole top level block
+/// moves about one node per order, so this is more than any single operation needs.
+const BLOCK_PTR_POOL_CAPACITY: usize = 256;
+
+/// Nodes of [SinglyLinkedList] free lists which have been popped or removed, kept to be pushed
+/// again so that splits and merges stop allocating once enough nodes have been made. At most
+/// [BLOCK_PTR_POOL_CAPACITY] are kept, and any more are freed.
+#[derive(Debug)]
+pub struct BlockPtrPool {
+    spare: SinglyLinkedList<BlockPtrAdapter>,
+    len: usize,
+    /// How many nodes have been allocated, whether or not they have been freed since
+    allocated: u64,
+}
+
+impl BlockPtrPool {
+    fn take(&mut self, block: *const Block) -> Box<BlockPtr> {
+        match self.spare.pop_front() {
+            Some(mut node) => {
+                self.len -= 1;
+                node.ptr = block;
+                node
+            }
+            None => {
+                self.allocated += 1;
+                Box::new(BlockPtr::new(block))
+            }
+        }
+    }
+
+    fn recycle(&mut self, node: Box<BlockPtr>) {
+        if self.len < BLOCK_PTR_POOL_CAPACITY {
+            self.spare.push_front(node);
+            self.len += 1;
+        }
+    }
+
+    /// How many nodes the pool has allocated, whether or not they have been freed since
+    pub fn allocated(&self) -> u64 {
+        self.allocated
+    }
+}
+
+impl Default for BlockPtrPool {
+    fn default() -> Self {
+        BlockPtrPool {
+            spare: SinglyLinkedList::new(BlockPtrAdapter::new()),
+            len: 0,
+            allocated: 0,
+        }
+    }
+}
+
+impl NodePool for BlockPtrPool {
+    fn metadata_bytes(&self) -> usize {
+        self.len * mem::size_of::<BlockPtr>()
+    }
+}
+
 impl FreeList for SinglyLinkedList<BlockPtrAdapter> {
-    fn push(&mut self, block: *const Block) {
-        self.push_front(Box::new(BlockPtr::new(block)))
+    type Pool = BlockPtrPool;
+
+    fn push(&mut self, block: *const Block, pool: &mut BlockPtrPool) {
+        self.push_front(pool.take(block))
     }
 
-    fn pop(&mut self) -> Option<*const Block> {
-        self.pop_front().map(|b| b.ptr)
+    fn pop(&mut self, pool: &mut BlockPtrPool) -> Option<*const Block> {
+        let node = self.pop_front()?;
+        let block = node.ptr;
+        pool.recycle(node);
+        Some(block)
     }
 
-    fn remove(&mut self, block: *const Block) -> Option<()> {
+    fn remove(&mut self, block: *const Block, pool: &mut BlockPtrPool) -> Option<()> {
         let pos = self.iter().position(|i| ptr::eq(i.ptr, block))?;
 
+        // There is no element before the front to remove after
+        if pos == 0 {
+            pool.recycle(self.pop_front().unwrap());
+            return Some(());
+        }
+
         let mut cursor = self.front_mut();
 
         // Get cursor to be elem before position
-        if pos > 0 {
-            for _ in 0..pos - 1 {
-                cursor.move_next();
-            }
+        for _ in 0..pos - 1 {
+            cursor.move_next();
         }
 
-        cursor.remove_next().unwrap();
+        pool.recycle(cursor.remove_next().unwrap());
 
         Some(())
     }
+
+    fn is_empty(&self) -> bool {
+        SinglyLinkedList::is_empty(self)
+    }
+
+    fn retain<F: FnMut(*const Block) -> bool>(&mut self, mut keep: F, pool: &mut BlockPtrPool) {
+        // Kept nodes are moved to another list and back, which reverses them twice
+        let mut kept = SinglyLinkedList::new(BlockPtrAdapter::new());
+        while let Some(node) = self.pop_front() {
+            if keep(node.ptr) {
+                kept.push_front(node);
+            } else {
+                pool.recycle(node);
+            }
+        }
+
+        while let Some(node) = kept.pop_front() {
+            self.push_front(node);
+        }
+    }
+
+    fn for_each<F: FnMut(*const Block)>(&self, f: F) {
+        self.iter().map(|i| i.ptr).for_each(f)
+    }
+
+    fn metadata_bytes(&self) -> usize {
+        self.iter().count() * mem::size_of::<BlockPtr>()
+    }
 }
 
 impl BuddyAllocator<Vec<*const Block>> {
     pub fn new() -> Self {
         BuddyAllocator {
             tree: RBTree::new(BlockAdapter::new()),
-            free: array_init::array_init(|_| Vec::new()),
+            used: HashMap::new(),
+            free: FreeLists::new(array_init::array_init(|_| Vec::new())),
+            usage: Usage::new(),
+            zone_usage: [Usage::new(); ZONE_COUNT as usize],
+            counters: OpCounters::new(),
+            latencies: Latencies::new(),
+            policy: AllocationPolicy::default(),
+            nodes: 0,
         }
     }
 }
@@ -178,263 +520,1348 @@ impl BuddyAllocator<SinglyLinkedList<BlockPtrAdapter>> {
     pub fn new() -> Self {
         BuddyAllocator {
             tree: RBTree::new(BlockAdapter::new()),
-            free: array_init::array_init(|_| SinglyLinkedList::new(BlockPtrAdapter::new())),
+            used: HashMap::new(),
+            free: FreeLists::new(array_init::array_init(|_| {
+                SinglyLinkedList::new(BlockPtrAdapter::new())
+            })),
+            usage: Usage::new(),
+            zone_usage: [Usage::new(); ZONE_COUNT as usize],
+            counters: OpCounters::new(),
+            latencies: Latencies::new(),
+            policy: AllocationPolicy::default(),
+            nodes: 0,
         }
     }
 }
 
-impl<L: FreeList> BuddyAllocator<L> {
-    pub fn create_top_level(&mut self, begin_address: usize) -> CursorMut<BlockAdapter> {
-        let cursor = self.tree
-            .insert(Box::new(Block::new(begin_address, MAX_ORDER, false)));
-        self.free[MAX_ORDER as usize].push(cursor.get().unwrap() as *const _);
-        cursor
+impl<L: FreeList> FreeLists<L> {
+    fn new(lists: [L; LEVEL_COUNT as usize]) -> Self {
+        FreeLists {
+            lists,
+            pool: L::Pool::default(),
+            non_empty: 0,
+            lens: [0; LEVEL_COUNT as usize],
+            tombstones: [0; LEVEL_COUNT as usize],
+            spare_blocks: Vec::with_capacity(MAX_SPARE_BLOCKS),
+        }
     }
 
-    /// Splits a block in place, returning the addresses of the two blocks split. Does not add them
-    /// to the free list, or remove the original. The cursor will point to the first block.
-    ///
-    /// # Panicking
+    /// Push a block to the free list of its order and record that it is listed.
     ///
-    /// 1. Index incorrect and points null block (this is a programming error)
-    /// 2. Attempt to split used block (this is also a programming error)
-    #[cfg_attr(feature = "flame_profile", flame)]
-    fn split(cursor: &mut CursorMut<BlockAdapter>) -> Result<[*const Block; 2], BlockSplitError> {
-        #[cfg(feature = "flame_profile")]
-        flame::note("split", None);
-        let block = cursor.get().unwrap();
+    /// Unsafe because the block must be in the tree and must not be used.
+    unsafe fn push(&mut self, block: *const Block) {
+        debug_assert!(!(*block).free_listed(), "Block pushed to a free list twice!");
+        (*block).set_free_listed(true);
+
+        let order = (*block).order();
+        self.lists[order as usize].push(block, &mut self.pool);
+        self.lens[order as usize] += 1;
+        self.non_empty |= 1 << order;
+    }
+
+    /// Pop a block from the free list of the given order and record that it is no longer listed.
+    /// Tombstones popped on the way are freed.
+    fn pop(&mut self, order: u8) -> Option<*const Block> {
+        loop {
+            let block = self.lists[order as usize].pop(&mut self.pool)?;
+            self.lens[order as usize] -= 1;
+            if self.lens[order as usize] == 0 {
+                self.non_empty &= !(1 << order);
+            }
+
+            // Safe because listed pointers always point to blocks in the tree or to tombstones,
+            // which are owned by the list
+            unsafe {
+                if (*block).tombstone() {
+                    self.tombstones[order as usize] -= 1;
+                    self.recycle_block(Box::from_raw(block as *mut Block));
+                } else {
+                    (*block).set_free_listed(false);
+                    return Some(block);
+                }
+            }
+        }
+    }
+
+    /// Pop a block from the first free list of an order no smaller than `order` which has a block
+    /// which is not a tombstone.
+    fn pop_smallest(&mut self, order: u8) -> Option<*const Block> {
+        loop {
+            let orders = self.non_empty & (!0 << order);
+            if orders == 0 {
+                return None;
+            }
+
+            // A list of only tombstones is emptied, so the next time round skips it
+            if let Some(block) = self.pop(orders.trailing_zeros() as u8) {
+                return Some(block);
+            }
+        }
+    }
 
-        if block.used() {
-            panic!("Attempted to split used block {:?}!", block);
+    /// Take a block which has just left the tree out of its free list. If it is listed it is
+    /// marked as a tombstone, and the list takes ownership of it until the pointer is popped or
+    /// compacted away, so `None` is returned. Otherwise the block is given back to be reused.
+    fn unlist(&mut self, block: Box<Block>) -> Option<Box<Block>> {
+        if !block.free_listed() {
+            return Some(block);
         }
 
-        let original_order = block.order();
-        let order = original_order - 1;
+        let order = block.order();
+        unsafe { block.set_free_listed(false) };
+        block.set_tombstone(true);
+        // The list now owns the block
+        let _ = Box::into_raw(block);
+        self.tombstones[order as usize] += 1;
+
+        let tombstones = self.tombstones[order as usize];
+        if tombstones >= MIN_TOMBSTONES_TO_COMPACT
+            && tombstones * 100 > self.lens[order as usize] * MAX_TOMBSTONE_PERCENT
+        {
+            self.compact(order);
+        }
+
+        None
+    }
+
+    /// Keep the box of a skipped tombstone to be reused, or free it if enough are kept.
+    fn recycle_block(&mut self, block: Box<Block>) {
+        if self.spare_blocks.len() < MAX_SPARE_BLOCKS {
+            self.spare_blocks.push(Box::into_raw(block));
+        }
+    }
 
-        if block.order() == 0 {
-            return Err(BlockSplitError::BlockSmallestPossible);
+    /// Box a new block, reusing the box of a skipped tombstone if there is one.
+    fn new_block(&mut self, block: Block) -> Box<Block> {
+        match self.spare_blocks.pop() {
+            Some(spare) => {
+                // Safe because spare blocks are only ever made from boxes which nothing else owns
+                let mut spare = unsafe { Box::from_raw(spare) };
+                *spare = block;
+                spare
+            }
+            None => Box::new(block),
         }
+    }
 
-        let buddies: [Block; 2] = array_init::array_init(|n| {
-            Block::new(
-                if n == 0 {
-                    block.address()
+    /// Remove and free every tombstone in the list of the given order.
+    fn compact(&mut self, order: u8) {
+        let mut removed = 0;
+        self.lists[order as usize].retain(
+            |block| unsafe {
+                // Safe for the same reasons as in pop
+                if (*block).tombstone() {
+                    drop(Box::from_raw(block as *mut Block));
+                    removed += 1;
+                    false
                 } else {
-                    block.address() + 2usize.pow(u32::from(order + BASE_ORDER))
-                },
-                order,
-                false,
-            )
-        });
+                    true
+                }
+            },
+            &mut self.pool,
+        );
 
-        let [first, second] = buddies;
+        self.lens[order as usize] -= removed;
+        self.tombstones[order as usize] = 0;
+        if self.lens[order as usize] == 0 {
+            self.non_empty &= !(1 << order);
+        }
+    }
+}
 
-        // Reuse the old box
-        let mut old = cursor.remove().unwrap();
-        *old = first;
-        cursor.insert_before(old);
-        cursor.insert_before(Box::new(second));
+impl<L: FreeList> Drop for FreeLists<L> {
+    fn drop(&mut self) {
+        // The tombstones are owned by the lists, and would otherwise be leaked
+        for order in 0..LEVEL_COUNT {
+            if self.tombstones[order as usize] > 0 {
+                self.compact(order);
+            }
+        }
 
-        // Reversed pointers
-        let ptrs: [*const _; 2] = array_init::array_init(|_| {
-            cursor.move_prev();
-            cursor.get().unwrap() as *const _
-        });
+        for spare in self.spare_blocks.drain(..) {
+            drop(unsafe { Box::from_raw(spare) });
+        }
+    }
+}
 
-        Ok([ptrs[1], ptrs[0]])
+impl<L: FreeList> BuddyAllocator<L> {
+    /// Give the allocator a new top level block in zone 0. Returns an error if the allocator
+    /// already has a top level block at the same address.
+    pub fn create_top_level(
+        &mut self,
+        begin_address: usize,
+    ) -> Result<CursorMut<BlockAdapter>, RegionError> {
+        self.create_top_level_in_zone(begin_address, 0)
     }
 
-    /// Find a frame of a given order or splits other frames recursively until one is made and then
-    /// returns a cursor pointing to it. Does not set state to used.
+    /// Give the allocator a new top level block in the given zone. Every block split from it is in
+    /// the same zone. Returns an error if the allocator already has a top level block at the same
+    /// address, as blocks would otherwise be handed out twice and the tree would hold two blocks
+    /// with the same key.
     ///
     /// # Panicking
     ///
-    /// Panics if the order is greater than max or if a programming error is encountered such as
-    /// attempting to split a block of the smallest possible size.
-    #[cfg_attr(feature = "flame_profile", flame)]
-    fn find_or_split<'a>(
-        free: &mut [L; 19],
-        tree: &'a mut RBTree<BlockAdapter>,
-        order: u8,
-    ) -> Result<CursorMut<'a, BlockAdapter>, BlockAllocateError> {
-        #[cfg(feature = "flame_profile")]
-        flame::note("find_or_split", None);
+    /// Panics if the zone is not less than [ZONE_COUNT], or if the block does not begin at an
+    /// address aligned to its size. The region of a block is found from its address by that
+    /// alignment, as no record of the regions is kept.
+    pub fn create_top_level_in_zone(
+        &mut self,
+        begin_address: usize,
+        zone: u8,
+    ) -> Result<CursorMut<BlockAdapter>, RegionError> {
+        assert!(zone < ZONE_COUNT, "Zone {} not less than {}!", zone, ZONE_COUNT);
+        assert_eq!(
+            begin_address & (top_level_size() - 1),
+            0,
+            "Top level block at {:#x} is not aligned to its size!",
+            begin_address
+        );
 
-        if order > MAX_ORDER {
-            panic!("Order {} larger than max of {}!", order, MAX_ORDER);
+        // Top level blocks are aligned to their size, so one overlapping this block has the same
+        // address. Its region is covered by blocks, one of which begins at that address and is
+        // either free in the tree or used.
+        if self.tree.find(&begin_address).get().is_some() || self.used.contains_key(&begin_address)
+        {
+            return Err(RegionError::Overlapping {
+                begin_address,
+                existing_address: begin_address,
+            });
         }
 
-        // Find free block of size >= order
-        let next_free = free[order as usize].pop();
+        let cursor = self.tree
+            .insert(Box::new(Block::new(begin_address, MAX_ORDER, zone, false)));
+        unsafe { self.free.push(cursor.get().unwrap()) };
+        self.nodes += 1;
+        Ok(cursor)
+    }
+
+    /// Check that every pointer in every free list points to a free block of the list's order
+    /// which is still in the tree or is one of the list's tombstones, that every block marked as
+    /// listed is actually listed, and that the mask and counts of each list are right.
+    pub fn check_free_lists(&self) -> Result<(), FreeListError> {
+        let live: HashSet<*const Block> = self.tree.iter().map(|b| b as *const _).collect();
+        let mut listed = 0;
+        let mut result = Ok(());
 
-        match next_free {
-            Some(ptr) => Ok(unsafe { tree.cursor_mut_from_ptr(ptr) }),
-            None if order == MAX_ORDER => Err(BlockAllocateError::NoBlocksAvailable),
-            None => {
-                let mut cursor = BuddyAllocator::find_or_split(free, tree, order + 1)?;
-                debug_assert!(
-                    !cursor.is_null(),
-                    "Find or split must return a valid pointer!"
-                );
+        for (order, list) in self.free.lists.iter().enumerate() {
+            let order = order as u8;
 
-                // Split block and remove it from the free list
-                let old_ptr = cursor.get().unwrap() as *const _;
-                let ptrs = Self::split(&mut cursor).unwrap();
-                free[order as usize + 1].remove(old_ptr);
+            if (self.free.non_empty & (1 << order) != 0) == list.is_empty() {
+                return Err(FreeListError::WrongMask { order });
+            }
 
-                // Push split blocks to free list
-                free[order as usize].push(ptrs[0]);
-                free[order as usize].push(ptrs[1]);
+            let (mut entries, mut not_in_tree) = (0, 0);
+            list.for_each(|ptr| {
+                entries += 1;
+
+                if result.is_err() {
+                    return;
+                }
+
+                // Pointers to blocks which are not in the tree are never read, as they could
+                // dangle. There must be exactly as many as the list has tombstones.
+                if !live.contains(&ptr) {
+                    not_in_tree += 1;
+                    return;
+                }
+                listed += 1;
+
+                // Safe because we just checked that the block is in the tree
+                let block = unsafe { &*ptr };
+                if block.used() || block.order() != order || !block.free_listed() {
+                    result = Err(FreeListError::WrongBlock {
+                        order,
+                        address: block.address(),
+                    });
+                }
+            });
+
+            result?;
+
+            if not_in_tree != self.free.tombstones[order as usize] {
+                return Err(FreeListError::Dangling { order });
+            }
 
-                Ok(cursor)
+            if entries != self.free.lens[order as usize] {
+                return Err(FreeListError::WrongLength { order });
             }
         }
+
+        if listed != self.tree.iter().filter(|b| b.free_listed()).count() {
+            return Err(FreeListError::MissingBlocks);
+        }
+
+        Ok(())
     }
 
-    #[cfg_attr(feature = "flame_profile", flame)]
-    pub fn allocate_exact(
-        &mut self,
-        order: u8,
-    ) -> Result<CursorMut<BlockAdapter>, BlockAllocateError> {
+    /// Take a free block out of its free list and the tree, and split it down to `order`. The
+    /// upper half of each split is put back in the tree as a free block, so every split inserts a
+    /// single node. Returns the address of the block of `order`, which is in neither the tree nor
+    /// any free list.
+    ///
+    /// Unsafe because the block must be in the tree.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the block is used or smaller than `order` (these are programming errors).
+    unsafe fn take(&mut self, block: *const Block, order: u8) -> usize {
         #[cfg(feature = "flame_profile")]
-        flame::note("allocate exact", None);
+        let _span = flame::start_guard("take");
 
-        if order > MAX_ORDER {
-            return Err(BlockAllocateError::OrderTooLarge(order));
+        if (*block).used() || (*block).order() < order {
+            panic!("Attempted to take {:?} for a block of order {}!", *block, order);
         }
 
-        #[cfg(feature = "flame_profile")]
-        flame::note("allocate begin", None);
+        let mut cursor = self.tree.cursor_mut_from_ptr(block);
+        let taken = cursor.remove().unwrap();
+        self.nodes -= 1;
+
+        // The cursor is now on the block after the one taken. Upper halves are split off from the
+        // highest down, so each is inserted just before the last. A block which is still listed
+        // has its box kept by its list as a tombstone, so it can't be reused.
+        let (address, taken_order, zone) = (taken.address(), taken.order(), taken.zone());
+        let mut upper = self.free.unlist(taken);
+        for split_order in (order..taken_order).rev() {
+            let half_size = block_bytes(split_order).unwrap();
+            let half = Block::new(address + half_size, split_order, zone, false);
+
+            // Reuse the old box
+            let half = match upper.take() {
+                Some(mut old) => {
+                    *old = half;
+                    old
+                }
+                None => self.free.new_block(half),
+            };
+
+            let ptr = &*half as *const Block;
+            cursor.insert_before(half);
+            cursor.move_prev();
+            self.free.push(ptr);
+            self.counters.splits[split_order as usize + 1] += 1;
+            self.nodes += 1;
+        }
 
-        let block = BuddyAllocator::find_or_split(&mut self.free, &mut self.tree, order)?;
+        address
+    }
 
-        // Safe because we have exclusive access to `block`.
-        unsafe {
-            block.get().unwrap().set_used(true);
-        }
+    /// Pop a free block of the smallest order no smaller than `order` which has one.
+    ///
+    /// The order must have already been checked to be no greater than [MAX_ORDER] by the caller.
+    fn pop_smallest_free(&mut self, order: u8) -> Option<*const Block> {
+        debug_assert!(order <= MAX_ORDER, "Order {} larger than max of {}!", order, MAX_ORDER);
+        self.free.pop_smallest(order)
+    }
 
-        let ptr = block.get().unwrap() as *const _;
-        self.free[order as usize].remove(ptr);
+    /// The free block at the lowest address of an order no smaller than `order`, found by walking
+    /// the tree in address order. Taking it keeps its address, so this is where the lowest block
+    /// of `order` which can be allocated is.
+    fn lowest_free(&self, order: u8) -> Option<*const Block> {
+        self.tree
+            .iter()
+            .find(|block| block.order() >= order)
+            .map(|block| block as *const Block)
+    }
 
-        Ok(block)
+    /// Set how free blocks are chosen to allocate from, returning the previous policy.
+    pub fn set_policy(&mut self, policy: AllocationPolicy) -> AllocationPolicy {
+        mem::replace(&mut self.policy, policy)
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-pub enum BlockSplitError {
-    BlockSmallestPossible,
-}
+    pub fn policy(&self) -> AllocationPolicy {
+        self.policy
+    }
 
-#[derive(Debug, Copy, Clone)]
-pub enum BlockAllocateError {
-    NoBlocksAvailable,
-    OrderTooLarge(u8),
-}
+    /// Allocate a block of exactly the given order, splitting a larger free block if there is no
+    /// free block of the order, and return its address. With [AllocationPolicy::Deterministic] the
+    /// lowest free block which is large enough is taken rather than the smallest.
+    pub fn allocate_exact(&mut self, order: u8) -> Result<usize, BlockAllocateError> {
+        #[cfg(feature = "flame_profile")]
+        let _span = flame::start_guard("allocate_exact");
 
-pub fn demo_vecs(print_addresses: bool, blocks: u32, block_size: u8) -> Duration {
-    let allocator = BuddyAllocator::<Vec<*const Block>>::new();
-    demo(allocator, print_addresses, blocks, block_size)
-}
+        let timer = OpTimer::start();
+        let result = self.allocate_exact_untimed(order);
+        self.latencies.record(timer);
+        result
+    }
 
-pub fn demo_linked_lists(print_addresses: bool, blocks: u32, block_size: u8) -> Duration {
-    let allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
-    demo(allocator, print_addresses, blocks, block_size)
-}
+    fn allocate_exact_untimed(&mut self, order: u8) -> Result<usize, BlockAllocateError> {
+        if order > MAX_ORDER {
+            return Err(BlockAllocateError::OrderTooLarge {
+                order,
+                max_order: MAX_ORDER,
+            });
+        }
 
-fn demo<L: FreeList>(
-    mut allocator: BuddyAllocator<L>,
-    print_addresses: bool,
-    blocks: u32,
-    block_size: u8,
-) -> Duration {
-    let top_level_blocks = top_level_blocks(blocks, block_size);
+        let block = match self.policy {
+            AllocationPolicy::FirstFound => self.pop_smallest_free(order),
+            AllocationPolicy::Deterministic => self.lowest_free(order),
+        };
+        let block = block.ok_or(BlockAllocateError::NoBlocksAvailable)?;
 
-    for block_number in 0..top_level_blocks {
-        allocator
-            .create_top_level(2usize.pow(u32::from(MAX_ORDER + BASE_ORDER)) * block_number as usize);
+        // Safe because listed pointers always point to free blocks in the tree
+        let zone = unsafe { (*block).zone() };
+        let address = unsafe { self.take(block, order) };
+        self.record_allocation(address, order, zone);
+
+        Ok(address)
     }
 
-    let begin = Instant::now();
+    /// Mark the block of the given order and zone at `address`, which has just been taken, as used.
+    fn record_allocation(&mut self, address: usize, order: u8, zone: u8) {
+        self.used.insert(address, UsedBlock { order, zone });
+        self.usage.allocated(order);
+        self.zone_usage[zone as usize].allocated(order);
+        self.counters.allocations[order as usize] += 1;
+    }
 
-    for _ in 0..blocks {
-        let cursor = allocator.allocate_exact(block_size).unwrap();
-        let addr = cursor.get().unwrap().address();
+    /// Split free blocks ahead of time until at least `count` blocks of `order` are free, so that
+    /// allocating them later does not pay for the splits. Nothing is marked as used, and fewer
+    /// blocks are split if there are no larger free blocks left.
+    pub fn warm_up(&mut self, order: u8, count: usize) {
+        if order >= MAX_ORDER {
+            return;
+        }
 
-        if print_addresses {
-            println!("Address: {:#x}", addr);
+        let mut free = self.free.lens[order as usize] - self.free.tombstones[order as usize];
+
+        while free < count {
+            let block = match self.pop_smallest_free(order + 1) {
+                Some(block) => block,
+                None => return,
+            };
+
+            // Taking splits the block down to the order, leaving each upper half free, and the
+            // lower block of the order is put back just as free
+            let zone = unsafe { (*block).zone() };
+            let address = unsafe { self.take(block, order) };
+            let block = self.free.new_block(Block::new(address, order, zone, false));
+            let cursor = self.tree.insert(block);
+            unsafe { self.free.push(cursor.get().unwrap()) };
+            self.nodes += 1;
+            free += 2;
         }
     }
 
-    begin.elapsed()
-}
+    /// Allocate a block of the given order which lies entirely below `limit`, e.g. for a device
+    /// which can only address the low 16 MiB. Only free blocks whose lowest block of the order
+    /// would end at or before the limit are candidates, and the smallest candidate is split down to
+    /// the order, so the block returned begins at the candidate's address.
+    pub fn alloc_below(&mut self, order: u8, limit: usize) -> Result<usize, BlockAllocateError> {
+        let timer = OpTimer::start();
+        let result = self.alloc_below_untimed(order, limit);
+        self.latencies.record(timer);
+        result
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    fn alloc_below_untimed(
+        &mut self,
+        order: u8,
+        limit: usize,
+    ) -> Result<usize, BlockAllocateError> {
+        if order > MAX_ORDER {
+            return Err(BlockAllocateError::OrderTooLarge {
+                order,
+                max_order: MAX_ORDER,
+            });
+        }
 
-    #[test]
-    fn test_create_top_level() {
-        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
-        allocator.create_top_level(0);
-        allocator.create_top_level(2usize.pow(MAX_ORDER_SIZE as u32));
+        let size = size_of_order(order);
+        if limit < size {
+            return Err(BlockAllocateError::NoBlocksAvailable);
+        }
+        let last_addr = limit - size;
+
+        let mut candidate = None;
+        for candidate_order in order..=MAX_ORDER {
+            self.free.lists[candidate_order as usize].for_each(|ptr| {
+                // Safe because listed pointers always point to blocks in the tree or to tombstones
+                let (address, tombstone) = unsafe { ((*ptr).address(), (*ptr).tombstone()) };
+                let lower = candidate.map_or(true, |(_, lowest)| address < lowest);
+                if !tombstone && address <= last_addr && lower {
+                    candidate = Some((ptr, address));
+                }
+            });
+
+            if candidate.is_some() {
+                break;
+            }
+        }
 
-        let expected = vec![
-            Block::new(0, MAX_ORDER, false),
-            Block::new(2usize.pow(MAX_ORDER_SIZE as u32), MAX_ORDER, false),
-        ];
+        let (ptr, address) = candidate.ok_or(BlockAllocateError::NoBlocksAvailable)?;
 
-        assert_eq!(
-            allocator
-                .tree
-                .into_iter()
-                .map(|b| *b)
-                .collect::<Vec<Block>>(),
-            expected
-        );
+        // Taking keeps the lower half of each split, which keeps the candidate's address
+        let zone = unsafe { (*ptr).zone() };
+        let taken = unsafe { self.take(ptr, order) };
+        debug_assert_eq!(taken, address);
+        self.record_allocation(address, order, zone);
+        Ok(address)
     }
 
-    #[test]
-    fn split() {
-        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
-        let mut block = allocator.create_top_level(0);
-        BuddyAllocator::<Vec<*const Block>>::split(&mut block).unwrap();
+    /// Allocate a block of the given order from the given zone only, splitting the smallest free
+    /// block of the zone which is large enough. Like [BuddyAllocator::alloc_below], this searches
+    /// the free lists rather than popping from them, so it is slower than
+    /// [BuddyAllocator::allocate_exact]. A zone with no top level blocks, including one not less
+    /// than [ZONE_COUNT], has no blocks available.
+    pub fn alloc_in_zone(&mut self, zone: u8, order: u8) -> Result<usize, BlockAllocateError> {
+        let timer = OpTimer::start();
+        let result = self.alloc_in_zone_untimed(zone, order);
+        self.latencies.record(timer);
+        result
+    }
 
-        let expected = vec![
-            Block::new(0, MAX_ORDER - 1, false),
-            Block::new(
-                2usize.pow((MAX_ORDER_SIZE - 1) as u32),
-                MAX_ORDER - 1,
-                false,
-            ),
-        ];
+    fn alloc_in_zone_untimed(&mut self, zone: u8, order: u8) -> Result<usize, BlockAllocateError> {
+        if order > MAX_ORDER {
+            return Err(BlockAllocateError::OrderTooLarge {
+                order,
+                max_order: MAX_ORDER,
+            });
+        }
 
-        assert_eq!(
-            allocator
-                .tree
-                .into_iter()
-                .map(|b| *b)
-                .collect::<Vec<Block>>(),
-            expected
-        );
+        let mut candidate = None;
+        for candidate_order in order..=MAX_ORDER {
+            self.free.lists[candidate_order as usize].for_each(|ptr| {
+                // Safe because listed pointers always point to blocks in the tree or to tombstones
+                let (block_zone, tombstone) = unsafe { ((*ptr).zone(), (*ptr).tombstone()) };
+                if candidate.is_none() && !tombstone && block_zone == zone {
+                    candidate = Some(ptr);
+                }
+            });
+
+            if candidate.is_some() {
+                break;
+            }
+        }
+
+        let ptr = candidate.ok_or(BlockAllocateError::NoBlocksAvailable)?;
+        let address = unsafe { self.take(ptr, order) };
+        self.record_allocation(address, order, zone);
+        Ok(address)
     }
 
-    #[test]
-    fn test_allocate_exact_with_free() {
-        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
-        allocator.create_top_level(0);
-        let cursor = allocator.allocate_exact(MAX_ORDER).unwrap();
-        let expected_block = Block::new(0, MAX_ORDER, true);
-        assert_eq!(*cursor.get().unwrap(), expected_block);
+    /// Free the used block beginning at `address`, merging it with its buddy for as long as the
+    /// buddy is also free.
+    pub fn deallocate(&mut self, address: usize) -> Result<(), BlockDeallocateError> {
+        let timer = OpTimer::start();
+        let result = self.deallocate_untimed(address);
+        self.latencies.record(timer);
+        result
     }
 
-    #[test]
-    fn test_allocate_exact_no_free() {
+    fn deallocate_untimed(&mut self, address: usize) -> Result<(), BlockDeallocateError> {
+        let UsedBlock { mut order, zone } = match self.used.remove(&address) {
+            Some(used) => used,
+            None if self.tree.find(&address).get().is_some() => {
+                return Err(BlockDeallocateError::BlockNotUsed)
+            }
+            None => return Err(BlockDeallocateError::NoBlockAtAddress),
+        };
+        self.usage.freed(order);
+        self.zone_usage[zone as usize].freed(order);
+        self.counters.frees[order as usize] += 1;
+        let mut address = address;
+        let mut spare = None;
+        // Top level blocks are aligned to their size
+        let region_base = address & !(top_level_size() - 1);
+
+        while order < MAX_ORDER {
+            let buddy_address = buddy_of(address, order, region_base);
+
+            let buddy = match self.tree.find(&buddy_address).get() {
+                Some(buddy) if buddy.order() == order => buddy as *const Block,
+                _ => break,
+            };
+
+            // Buddies are always in the same zone, as they are split from the same top level block.
+            // The buddy is left in its free list as a tombstone, which then owns its box. The block
+            // being freed was used, so it is in neither the tree nor a list.
+            let buddy = unsafe { self.tree.cursor_mut_from_ptr(buddy) }.remove().unwrap();
+            if let Some(buddy) = self.free.unlist(buddy) {
+                spare = Some(buddy);
+            }
+            self.nodes -= 1;
+
+            address = parent_of(address, order, region_base);
+            order += 1;
+            self.counters.merges[order as usize] += 1;
+        }
+
+        // Reuse the box of the last buddy merged with
+        let merged = Block::new(address, order, zone, false);
+        let merged = match spare {
+            Some(mut old) => {
+                *old = merged;
+                old
+            }
+            None => self.free.new_block(merged),
+        };
+
+        let ptr = &*merged as *const Block;
+        self.tree.insert(merged);
+        self.nodes += 1;
+        unsafe { self.free.push(ptr) };
+
+        if cfg!(debug_assertions) {
+            if let Err(err) = self.check_free_lists() {
+                panic!("Free lists corrupted after free: {:?}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The block beginning at `address`, whether it is free or used, or `None` if no block begins
+    /// there.
+    pub fn find(&self, address: usize) -> Option<BlockInfo> {
+        if let Some(&UsedBlock { order, .. }) = self.used.get(&address) {
+            return Some(BlockInfo {
+                addr: address,
+                order,
+                used: true,
+            });
+        }
+
+        self.tree.find(&address).get().map(Block::info)
+    }
+
+    /// The zone of the block beginning at `address`, whether it is free or used, or `None` if no
+    /// block begins there.
+    pub fn zone(&self, address: usize) -> Option<u8> {
+        if let Some(used) = self.used.get(&address) {
+            return Some(used.zone);
+        }
+
+        self.tree.find(&address).get().map(Block::zone)
+    }
+
+    /// The usage of the given zone alone. The usage of every zone together is
+    /// [AllocatorStats::usage].
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the zone is not less than [ZONE_COUNT].
+    pub fn zone_usage(&self, zone: u8) -> &Usage {
+        &self.zone_usage[zone as usize]
+    }
+
+    /// How many entries of the free list of each order are tombstones of blocks which have left
+    /// the tree, and which will be skipped when popped
+    pub fn tombstones(&self) -> [usize; LEVEL_COUNT as usize] {
+        self.free.tombstones
+    }
+
+    /// The address of every top level block, in address order. No record of them is kept, so
+    /// they are found from the blocks split from them.
+    fn top_levels(&self) -> Vec<usize> {
+        let mut top_levels: Vec<usize> = self.tree
+            .iter()
+            .map(Block::address)
+            .chain(self.used.keys().cloned())
+            .map(|address| address & !(top_level_size() - 1))
+            .collect();
+        top_levels.sort_unstable();
+        top_levels.dedup();
+        top_levels
+    }
+
+    /// The ranges of addresses below `limit` which are in no top level block, in address order,
+    /// including the range below the lowest top level block and the range from the end of the
+    /// highest up to `limit`. Adjacent top level blocks have no gap between them. E.g. to check
+    /// at boot that every usable range of the firmware's memory map is managed.
+    pub fn gaps(&self, limit: usize) -> impl Iterator<Item = Range<usize>> {
+        let mut gaps = Vec::new();
+        let mut begin = 0;
+
+        for base in self.top_levels() {
+            if base >= limit {
+                break;
+            }
+
+            if base > begin {
+                gaps.push(begin..base);
+            }
+
+            // Addresses are stored in fewer bits than a usize, so this can't overflow
+            begin = base + top_level_size();
+        }
+
+        if limit > begin {
+            gaps.push(begin..limit);
+        }
+
+        gaps.into_iter()
+    }
+
+    /// A cursor over every block, free or used, in address order. It begins on the lowest block,
+    /// or on no block if the allocator has no top level blocks.
+    pub fn cursor(&mut self) -> BlocksCursor<L> {
+        let top_levels = self.top_levels();
+        let position = top_levels.first().cloned();
+        BlocksCursor {
+            allocator: self,
+            top_levels,
+            position,
+        }
+    }
+
+    /// The block beginning at `address`, whether it is free or used.
+    fn view(&self, address: usize) -> Option<BlockView> {
+        if let Some(&UsedBlock { order, zone }) = self.used.get(&address) {
+            return Some(BlockView { address, order, zone, used: true });
+        }
+
+        self.tree.find(&address).get().map(|block| BlockView {
+            address,
+            order: block.order(),
+            zone: block.zone(),
+            used: false,
+        })
+    }
+}
+
+fn top_level_size() -> usize {
+    size_of_order(MAX_ORDER)
+}
+
+impl<L: FreeList> BuddyAllocatorApi for BuddyAllocator<L> {
+    fn create_top_level(&mut self, begin_address: usize) {
+        if let Err(err) = BuddyAllocator::create_top_level(self, begin_address) {
+            panic!("Could not create top level block: {:?}", err);
+        }
+    }
+
+    fn allocate(&mut self, order: u8) -> Option<usize> {
+        self.allocate_exact(order).ok()
+    }
+
+    fn deallocate(&mut self, address: usize, order: u8) -> bool {
+        if self.used.get(&address).map(|used| used.order) != Some(order) {
+            return false;
+        }
+
+        BuddyAllocator::deallocate(self, address).is_ok()
+    }
+
+    fn warm_up(&mut self, order: u8, count: usize) {
+        BuddyAllocator::warm_up(self, order, count)
+    }
+}
+
+impl<L: FreeList> AllocatorStats for BuddyAllocator<L> {
+    fn free_histogram(&self) -> [usize; LEVEL_COUNT as usize] {
+        let mut histogram = [0; LEVEL_COUNT as usize];
+
+        // Split blocks are removed from the tree and buddies are merged on deallocation, so every
+        // block in the tree is free and maximal but for those split ahead of time by warm_up
+        for block in self.tree.iter() {
+            histogram[block.order() as usize] += 1;
+        }
+
+        histogram
+    }
+
+    fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    fn reset_peaks(&mut self) {
+        self.usage.reset_peaks();
+        for usage in self.zone_usage.iter_mut() {
+            usage.reset_peaks();
+        }
+    }
+
+    fn op_counters(&self) -> OpCounters {
+        self.counters
+    }
+
+    fn reset_op_counters(&mut self) {
+        self.counters = OpCounters::new();
+    }
+
+    #[cfg(feature = "metrics")]
+    fn latency_summary(&self) -> LatencySummary {
+        self.latencies.summary()
+    }
+
+    fn metadata_bytes(&self) -> usize {
+        let lists: usize = self.free.lists.iter().map(FreeList::metadata_bytes).sum::<usize>()
+            + self.free.pool.metadata_bytes()
+            + (self.free.tombstones.iter().sum::<usize>() + self.free.spare_blocks.len())
+                * mem::size_of::<Block>()
+            + self.free.spare_blocks.capacity() * mem::size_of::<*mut Block>();
+
+        // Roughly, as the map also keeps a control byte per entry
+        let used = self.used.capacity() * (mem::size_of::<(usize, UsedBlock)>() + 1);
+        self.nodes * mem::size_of::<Block>() + lists + used
+    }
+
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockInfo)) {
+        for block in self.tree.iter() {
+            f(block.info());
+        }
+
+        for (&addr, &UsedBlock { order, .. }) in &self.used {
+            f(BlockInfo { addr, order, used: true });
+        }
+    }
+
+    fn largest_free_extent(&self) -> Option<(usize, usize)> {
+        // The tree already iterates over the free blocks in address order, and free blocks with a
+        // used block between them are never adjacent
+        largest_free_run(self.tree.iter().map(Block::info))
+    }
+}
+
+/// A block as seen through a [BlocksCursor].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BlockView {
+    pub address: usize,
+    pub order: u8,
+    pub zone: u8,
+    pub used: bool,
+}
+
+/// Walks every block of a [BuddyAllocator], free or used, in address order, and can change the
+/// block it is on while keeping the tree, the free lists and the usage right.
+///
+/// Like [CursorMut], the cursor can also be on no block, which lies between the last block and the
+/// first: moving forward from it goes to the first block, and moving back to the last. The cursor
+/// is positioned by address rather than by a node in the tree, as used blocks are not in the tree.
+///
+/// In debug builds, the free lists are checked when the cursor is dropped.
+#[derive(Debug)]
+pub struct BlocksCursor<'a, L: FreeList + 'a> {
+    allocator: &'a mut BuddyAllocator<L>,
+    /// The address of every top level block, in order. Top level blocks cannot be created through
+    /// the cursor, so these never change.
+    top_levels: Vec<usize>,
+    /// The address of the block the cursor is on
+    position: Option<usize>,
+}
+
+/// Why a [BlocksCursor] could not change the block it is on
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CursorError {
+    /// The cursor is not on a block
+    NoBlock,
+    /// The block is used, but must be free
+    BlockUsed,
+    /// The block is free, but must be used
+    BlockNotUsed,
+    /// Blocks of order 0 cannot be split
+    OrderZero,
+}
+
+impl<'a, L: FreeList> BlocksCursor<'a, L> {
+    /// The block the cursor is on, or `None` if it is on no block
+    pub fn peek(&self) -> Option<BlockView> {
+        self.position.and_then(|address| self.allocator.view(address))
+    }
+
+    /// Move to the next block, or to no block if the cursor is on the last block.
+    pub fn move_next(&mut self) {
+        let current = match self.peek() {
+            Some(current) => current,
+            None => {
+                self.position = self.top_levels.first().cloned();
+                return;
+            }
+        };
+
+        // Blocks cover each top level block without gaps, so the next block begins where the
+        // current one ends unless that is the end of the top level block
+        let end = current.address + size_of_order(current.order);
+        if end & (top_level_size() - 1) != 0 {
+            self.position = Some(end);
+            return;
+        }
+
+        self.position = self.top_levels.iter().cloned().find(|&top| top >= end);
+    }
+
+    /// Move to the previous block, or to no block if the cursor is on the first block.
+    pub fn move_prev(&mut self) {
+        let begin = match self.position {
+            Some(begin) => begin,
+            None => {
+                self.position = self.top_levels
+                    .last()
+                    .map(|&top| self.block_ending_at(top + top_level_size()));
+                return;
+            }
+        };
+
+        if begin & (top_level_size() - 1) != 0 {
+            self.position = Some(self.block_ending_at(begin));
+            return;
+        }
+
+        self.position = self.top_levels
+            .iter()
+            .rev()
+            .find(|&&top| top < begin)
+            .map(|&top| self.block_ending_at(top + top_level_size()));
+    }
+
+    /// The address of the block which ends at `end`, which must be the beginning or end of a block.
+    fn block_ending_at(&self, end: usize) -> usize {
+        for order in 0..=MAX_ORDER {
+            let size = size_of_order(order);
+
+            // A block must be aligned to its size, so no larger block can end here either
+            if end & (size - 1) != 0 {
+                break;
+            }
+
+            if self.allocator.view(end - size).map(|block| block.order) == Some(order) {
+                return end - size;
+            }
+        }
+
+        panic!("No block ends at {:#x}!", end);
+    }
+
+    /// The free block the cursor is on
+    fn free_block(&self) -> Result<*const Block, CursorError> {
+        let address = self.position.ok_or(CursorError::NoBlock)?;
+        match self.allocator.tree.find(&address).get() {
+            Some(block) => Ok(block as *const Block),
+            None => Err(CursorError::BlockUsed),
+        }
+    }
+
+    /// Allocate the free block the cursor is on, as if it had been returned by
+    /// [BuddyAllocator::allocate_exact].
+    pub fn mark_used(&mut self) -> Result<(), CursorError> {
+        let block = self.free_block()?;
+
+        // Safe because the block was just found in the tree
+        let (order, zone) = unsafe { ((*block).order(), (*block).zone()) };
+        let address = unsafe { self.allocator.take(block, order) };
+        self.allocator.record_allocation(address, order, zone);
+        Ok(())
+    }
+
+    /// Free the used block the cursor is on. Unlike [BuddyAllocator::deallocate], it is not merged
+    /// with its buddy, so that the cursor stays on it. Like blocks split by
+    /// [BuddyAllocator::warm_up], free buddies are merged once either is freed again.
+    pub fn mark_free(&mut self) -> Result<(), CursorError> {
+        let address = self.position.ok_or(CursorError::NoBlock)?;
+        let allocator = &mut *self.allocator;
+        let UsedBlock { order, zone } = allocator
+            .used
+            .remove(&address)
+            .ok_or(CursorError::BlockNotUsed)?;
+
+        allocator.usage.freed(order);
+        allocator.zone_usage[zone as usize].freed(order);
+        allocator.counters.frees[order as usize] += 1;
+
+        let block = allocator.free.new_block(Block::new(address, order, zone, false));
+        let cursor = allocator.tree.insert(block);
+        unsafe { allocator.free.push(cursor.get().unwrap()) };
+        allocator.nodes += 1;
+        Ok(())
+    }
+
+    /// Split the free block the cursor is on into two free blocks of the order below, leaving the
+    /// cursor on the lower half. Returns the addresses of the lower and upper halves.
+    pub fn split_here(&mut self) -> Result<(usize, usize), CursorError> {
+        let block = self.free_block()?;
+
+        // Safe because the block was just found in the tree
+        let (order, zone) = unsafe { ((*block).order(), (*block).zone()) };
+        if order == 0 {
+            return Err(CursorError::OrderZero);
+        }
+
+        // Taking puts the upper half back as a free block, and the lower is put back just as free
+        let allocator = &mut *self.allocator;
+        let lower = unsafe { allocator.take(block, order - 1) };
+        let block = allocator.free.new_block(Block::new(lower, order - 1, zone, false));
+        let cursor = allocator.tree.insert(block);
+        unsafe { allocator.free.push(cursor.get().unwrap()) };
+        allocator.nodes += 1;
+
+        Ok((lower, lower + size_of_order(order - 1)))
+    }
+}
+
+impl<'a, L: FreeList> Drop for BlocksCursor<'a, L> {
+    fn drop(&mut self) {
+        if cfg!(debug_assertions) && !thread::panicking() {
+            if let Err(err) = self.allocator.check_free_lists() {
+                panic!("Free lists corrupted through a cursor: {:?}", err);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BlockAllocateError {
+    NoBlocksAvailable,
+    OrderTooLarge { order: u8, max_order: u8 },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BlockDeallocateError {
+    /// No block begins at the given address
+    NoBlockAtAddress,
+    /// The block at the given address is already free
+    BlockNotUsed,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RegionError {
+    /// The top level block overlaps the one beginning at `existing_address`, which was added
+    /// earlier
+    Overlapping {
+        begin_address: usize,
+        existing_address: usize,
+    },
+}
+
+/// A problem found in the free lists by [BuddyAllocator::check_free_lists]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FreeListError {
+    /// The free list of this order contains a pointer to a block which is no longer in the tree
+    Dangling { order: u8 },
+    /// The free list of this order points to a block which is used, of another order, or not
+    /// marked as listed
+    WrongBlock { order: u8, address: usize },
+    /// Some blocks are marked as listed but are not in any free list
+    MissingBlocks,
+    /// The mask of non empty free lists is wrong for the list of this order
+    WrongMask { order: u8 },
+    /// The count of entries in the free list of this order is wrong
+    WrongLength { order: u8 },
+}
+
+pub fn demo_vecs(print_addresses: bool, blocks: u32, block_size: u8) -> Result<Duration, DemoError> {
+    let allocator = BuddyAllocator::<Vec<*const Block>>::new();
+    demo(allocator, print_addresses, blocks, block_size)
+}
+
+pub fn demo_linked_lists(print_addresses: bool, blocks: u32, block_size: u8) -> Result<Duration, DemoError> {
+    let allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+    demo(allocator, print_addresses, blocks, block_size)
+}
+
+pub fn demo_vecs_steady_state(
+    print_addresses: bool,
+    blocks: u32,
+    block_size: u8,
+    runs: usize,
+) -> Result<Vec<Duration>, DemoError> {
+    let allocator = BuddyAllocator::<Vec<*const Block>>::new();
+    steady_state::demo(allocator, print_addresses, blocks, block_size, runs)
+}
+
+pub fn demo_linked_lists_steady_state(
+    print_addresses: bool,
+    blocks: u32,
+    block_size: u8,
+    runs: usize,
+) -> Result<Vec<Duration>, DemoError> {
+    let allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+    steady_state::demo(allocator, print_addresses, blocks, block_size, runs)
+}
+
+pub fn demo_vecs_for_duration(
+    print_addresses: bool,
+    blocks: u32,
+    block_size: u8,
+    budget: Duration,
+) -> Result<DurationReport, DemoError> {
+    let allocator = BuddyAllocator::<Vec<*const Block>>::new();
+    steady_state::demo_for(allocator, print_addresses, blocks, block_size, budget)
+}
+
+pub fn demo_linked_lists_for_duration(
+    print_addresses: bool,
+    blocks: u32,
+    block_size: u8,
+    budget: Duration,
+) -> Result<DurationReport, DemoError> {
+    let allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+    steady_state::demo_for(allocator, print_addresses, blocks, block_size, budget)
+}
+
+fn demo<L: FreeList>(
+    mut allocator: BuddyAllocator<L>,
+    print_addresses: bool,
+    blocks: u32,
+    block_size: u8,
+) -> Result<Duration, DemoError> {
+    let top_level_blocks = top_level_blocks(blocks, block_size);
+
+    let mut regions = RegionTracker::new();
+
+    for block_number in 0..top_level_blocks {
+        let begin_address = region_bytes().unwrap() * block_number as usize;
+        allocator.create_top_level(begin_address).unwrap();
+        regions.add(begin_address, region_bytes().unwrap());
+    }
+
+    let begin = Instant::now();
+
+    for allocation in 0..blocks {
+        let addr = allocator
+            .allocate_exact(block_size)
+            .map_err(|err| match err {
+                BlockAllocateError::NoBlocksAvailable => DemoError::OutOfBlocks { allocation },
+                BlockAllocateError::OrderTooLarge { order, max_order } => {
+                    DemoError::OrderTooLarge { order, max_order }
+                }
+            })?;
+
+        if cfg!(debug_assertions) {
+            regions.assert_valid(addr, block_bytes(block_size).unwrap());
+        }
+
+        if print_addresses {
+            println!("Address: {:#x}", addr);
+        }
+    }
+
+    Ok(begin.elapsed())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::{check_unique_addresses, BlockSet, XorShift};
+    use workload::{self, WorkloadSpec};
+
+    #[test]
+    fn test_create_top_level() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(region_bytes().unwrap()).unwrap();
+
+        let expected = vec![
+            Block::new(0, MAX_ORDER, 0, false),
+            Block::new(region_bytes().unwrap(), MAX_ORDER, 0, false),
+        ];
+
+        assert_eq!(
+            allocator
+                .tree
+                .into_iter()
+                .map(|b| *b)
+                .collect::<Vec<Block>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_create_top_level_duplicate() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(0).unwrap();
+
+        let overlapping = Err(RegionError::Overlapping {
+            begin_address: 0,
+            existing_address: 0,
+        });
+        assert_eq!(allocator.create_top_level(0).map(|_| ()), overlapping);
+        assert_eq!(allocator.create_top_level_in_zone(0, 1).map(|_| ()), overlapping);
+
+        // The original block is untouched and is the only one handed out
+        assert_eq!(allocator.tree.iter().count(), 1);
+        assert_eq!(allocator.check_free_lists(), Ok(()));
+        assert_eq!(allocator.allocate_exact(MAX_ORDER), Ok(0));
+        assert_eq!(allocator.allocate_exact(MAX_ORDER), Err(BlockAllocateError::NoBlocksAvailable));
+
+        // A block which is in use is not in the tree, but is still found
+        assert_eq!(allocator.create_top_level(0).map(|_| ()), overlapping);
+        allocator.deallocate(0).unwrap();
+        assert_eq!(allocator.allocate_exact(0), Ok(0));
+        assert_eq!(allocator.create_top_level(0).map(|_| ()), overlapping);
+    }
+
+    #[test]
+    fn split() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        assert_eq!(allocator.allocate_exact(MAX_ORDER - 1), Ok(0));
+        assert_eq!(
+            allocator.find(0),
+            Some(BlockInfo { addr: 0, order: MAX_ORDER - 1, used: true })
+        );
+
+        // Only the free upper half is left in the tree
+        let expected = vec![Block::new(
+            block_bytes(MAX_ORDER - 1).unwrap(),
+            MAX_ORDER - 1,
+            0,
+            false,
+        )];
+
+        assert_eq!(
+            allocator
+                .tree
+                .into_iter()
+                .map(|b| *b)
+                .collect::<Vec<Block>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_free_histogram() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        assert_eq!(allocator.free_histogram(), [0; LEVEL_COUNT as usize]);
+
+        // Pristine: one top level block per region
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(region_bytes().unwrap()).unwrap();
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 2;
+        assert_eq!(allocator.free_histogram(), expected);
+
+        // Splitting down to order 0 leaves one free buddy on every order below the top
+        let address = allocator.allocate_exact(0).unwrap();
+        let mut expected = [1; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 1;
+        assert_eq!(allocator.free_histogram(), expected);
+
+        // Freeing it merges everything back
+        allocator.deallocate(address).unwrap();
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 2;
+        assert_eq!(allocator.free_histogram(), expected);
+    }
+
+    #[test]
+    fn test_free_histogram_fragmented() {
+        // Every other order 0 block is used, so no free blocks of a higher order exist
+        let mut allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+        for n in 0..16 {
+            let address = n * block_bytes(0).unwrap();
+            if n % 2 == 0 {
+                allocator.used.insert(address, UsedBlock { order: 0, zone: 0 });
+            } else {
+                allocator.tree.insert(Box::new(Block::new(address, 0, 0, false)));
+            }
+        }
+
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[0] = 8;
+        assert_eq!(allocator.free_histogram(), expected);
+    }
+
+    #[test]
+    fn test_render_map() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        allocator.allocate_exact(MAX_ORDER - 2).unwrap();
+        allocator.allocate_exact(MAX_ORDER - 3).unwrap();
+
+        // The first buddy of a split is allocated, so blocks come from the bottom up
+        assert_eq!(
+            allocator.render_map(8),
+            format!(
+                "###.....\n0x0..{:#x}, 1 char = {:#x} bytes: \
+                 '.' free, '#' used, '+' partly used, '-' reserved\n",
+                region_bytes().unwrap(),
+                block_bytes(MAX_ORDER - 3).unwrap()
+            )
+        );
+        assert!(allocator.render_map(2).starts_with("+.\n"));
+    }
+
+    #[test]
+    fn test_export_usage() {
+        let mut allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+        assert_eq!(allocator.export_usage(0x1000), Vec::<f32>::new());
+
+        let eighth = block_bytes(MAX_ORDER - 3).unwrap();
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(eighth * 8).unwrap();
+        allocator.allocate_exact(MAX_ORDER - 2).unwrap();
+        allocator.allocate_exact(MAX_ORDER - 3).unwrap();
+
+        // The top level block created last is split first
+        let mut expected = vec![0.0; 8];
+        expected.extend(vec![1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(allocator.export_usage(eighth), expected);
+
+        // Buckets spanning blocks, with the last one cut short by the end of the second region
+        assert_eq!(
+            allocator.export_usage(eighth * 3),
+            vec![0.0, 0.0, 1.0 / 3.0, 2.0 / 3.0, 0.0, 0.0]
+        );
+        assert_eq!(
+            allocator.export_usage(eighth * 2),
+            vec![0.0, 0.0, 0.0, 0.0, 1.0, 0.5, 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn test_allocate_exact_with_free() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        let address = allocator.allocate_exact(MAX_ORDER).unwrap();
+        let expected_block = BlockInfo { addr: 0, order: MAX_ORDER, used: true };
+        assert_eq!(allocator.find(address), Some(expected_block));
+        assert!(allocator.tree.is_empty());
+    }
+
+    #[test]
+    fn test_allocate_exact_no_free() {
         let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
-        allocator.create_top_level(0);
-        let cursor = allocator.allocate_exact(MAX_ORDER - 2).unwrap();
-        let expected_block = Block::new(0, MAX_ORDER - 2, true);
+        allocator.create_top_level(0).unwrap();
+        let address = allocator.allocate_exact(MAX_ORDER - 2).unwrap();
+        let expected_block = BlockInfo { addr: 0, order: MAX_ORDER - 2, used: true };
 
-        assert_eq!(*cursor.get().unwrap(), expected_block);
+        assert_eq!(allocator.find(address), Some(expected_block));
+    }
+
+    #[test]
+    fn test_block_ptr_pool_steady_state() {
+        let mut allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+        allocator.create_top_level(0).unwrap();
+        let orders = [0, 3, 1, 0, 5, 2, MAX_ORDER - 1, 0];
+
+        // Allocating these splits down from the top level block, and freeing them in another
+        // order merges back up to it
+        let cycle = |allocator: &mut BuddyAllocator<_>| {
+            let addresses: Vec<_> = orders
+                .iter()
+                .map(|&order| allocator.allocate_exact(order).unwrap())
+                .collect();
+            for &index in &[3, 0, 6, 1, 7, 5, 2, 4] {
+                allocator.deallocate(addresses[index]).unwrap();
+            }
+        };
+
+        cycle(&mut allocator);
+        let allocated = allocator.free.pool.allocated();
+        assert!(allocated > 0);
+
+        for _ in 0..100 {
+            cycle(&mut allocator);
+        }
+        assert_eq!(allocator.free.pool.allocated(), allocated);
+        assert!(allocator.counters.splits.iter().sum::<u64>() > 100 * orders.len() as u64);
+        assert_eq!(allocator.check_free_lists(), Ok(()));
+    }
+
+    #[test]
+    fn test_block_ptr_pool_bounded() {
+        let mut list = SinglyLinkedList::<BlockPtrAdapter>::new(BlockPtrAdapter::new());
+        let mut pool = BlockPtrPool::default();
+        let count = BLOCK_PTR_POOL_CAPACITY + 10;
+
+        for block in 0..count {
+            list.push(block as *const _, &mut pool);
+        }
+        while list.pop(&mut pool).is_some() {}
+
+        assert_eq!(pool.allocated() as usize, count);
+        assert_eq!(pool.len, BLOCK_PTR_POOL_CAPACITY);
+        assert_eq!(pool.metadata_bytes(), BLOCK_PTR_POOL_CAPACITY * mem::size_of::<BlockPtr>());
     }
 
     #[test]
@@ -445,69 +1872,563 @@ mod test {
         list.push_front(Box::new(BlockPtr::new(3 as *const _)));
         list.push_front(Box::new(BlockPtr::new(4 as *const _)));
         list.push_front(Box::new(BlockPtr::new(5 as *const _)));
-        list.remove(2 as *const _).unwrap();
+        let mut pool = BlockPtrPool::default();
+        list.remove(2 as *const _, &mut pool).unwrap();
 
         assert_eq!(
             list.iter().map(|i| i.ptr).collect::<Vec<*const Block>>(),
             vec![5 as *const _, 4 as *const _, 3 as *const _, 1 as *const _]
         );
+
+        list.remove(5 as *const _, &mut pool).unwrap();
+        list.remove(1 as *const _, &mut pool).unwrap();
+        assert_eq!(list.remove(2 as *const _, &mut pool), None);
+
+        // The removed nodes are kept to be pushed again
+        assert_eq!(pool.len, 3);
+        list.push(6 as *const _, &mut pool);
+        assert_eq!((pool.len, pool.allocated()), (2, 0));
+        assert_eq!(list.pop(&mut pool), Some(6 as *const _));
+
+        assert_eq!(
+            list.iter().map(|i| i.ptr).collect::<Vec<*const Block>>(),
+            vec![4 as *const _, 3 as *const _]
+        );
     }
 
     #[test]
     fn test_unique_addresses_vecs() {
         let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        check_unique_addresses(&mut allocator, 2, &[0, 3, 1, 0, 5, 2], 1000);
+    }
+
+    #[test]
+    fn test_unique_addresses_linked_lists() {
+        let mut allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+        check_unique_addresses(&mut allocator, 2, &[0, 3, 1, 0, 5, 2], 1000);
+    }
+
+    #[test]
+    fn test_order_too_large() {
+        let expected = BlockAllocateError::OrderTooLarge {
+            order: MAX_ORDER + 1,
+            max_order: MAX_ORDER,
+        };
+
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        assert_eq!(allocator.allocate_exact(MAX_ORDER + 1).err(), Some(expected));
+
+        let mut allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+        allocator.create_top_level(0).unwrap();
+        assert_eq!(allocator.allocate_exact(MAX_ORDER + 1).err(), Some(expected));
+        assert_eq!(
+            allocator.allocate_exact(u8::max_value()).err(),
+            Some(BlockAllocateError::OrderTooLarge {
+                order: u8::max_value(),
+                max_order: MAX_ORDER,
+            })
+        );
+
+        let demo_error = DemoError::OrderTooLarge {
+            order: MAX_ORDER + 1,
+            max_order: MAX_ORDER,
+        };
+        assert_eq!(demo_vecs(false, 1, MAX_ORDER + 1), Err(demo_error));
+        assert_eq!(demo_linked_lists(false, 1, MAX_ORDER + 1), Err(demo_error));
+    }
+
+    #[test]
+    fn test_block_bitfields() {
+        let max_address = layout::LARGEST_ADDRESS;
+        let order = layout::LARGEST_ORDER - 1;
+        let block = Block::new(max_address, order, ZONE_COUNT - 1, false);
+
+        assert!(!block.used());
+        assert!(!block.free_listed());
+        assert!(!block.tombstone());
+        assert_eq!(block.order(), order);
+        assert_eq!(block.zone(), ZONE_COUNT - 1);
+        assert_eq!(block.address(), max_address);
+
+        unsafe { block.set_free_listed(true) };
+        assert!(block.free_listed());
+        assert!(!block.tombstone());
+        assert!(!block.used());
+        assert_eq!(block.order(), order);
+        assert_eq!(block.zone(), ZONE_COUNT - 1);
+        assert_eq!(block.address(), max_address);
+
+        block.set_tombstone(true);
+        assert!(block.tombstone());
+        assert!(block.free_listed());
+        assert_eq!(block.order(), order);
+        assert_eq!(block.zone(), ZONE_COUNT - 1);
+        assert_eq!(block.address(), max_address);
+
+        let block = Block::new(max_address, layout::LARGEST_ORDER, 0, true);
+        assert_eq!(block.order(), layout::LARGEST_ORDER);
+        assert_eq!(block.zone(), 0);
+        assert_eq!(block.address(), max_address);
+        assert!(block.used());
+        assert!(!block.free_listed());
+        assert!(!block.tombstone());
+    }
+
+    #[test]
+    fn test_zones() {
+        let size = region_bytes().unwrap();
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level_in_zone(0, 0).unwrap();
+        allocator.create_top_level_in_zone(size, 1).unwrap();
+
+        // Blocks split from a top level block are in its zone
+        let low = allocator.alloc_in_zone(0, MAX_ORDER - 1).unwrap();
+        assert_eq!(allocator.zone(low), Some(0));
+        assert_eq!(allocator.zone(low ^ (size / 2)), Some(0));
+        assert_eq!(allocator.alloc_in_zone(0, MAX_ORDER - 1), Ok(low ^ (size / 2)));
+
+        // Zone 0 is exhausted, but unrestricted allocation can still use zone 1
+        assert_eq!(allocator.alloc_in_zone(0, 0), Err(BlockAllocateError::NoBlocksAvailable));
+        assert_eq!(allocator.alloc_in_zone(2, 0), Err(BlockAllocateError::NoBlocksAvailable));
+        let high = allocator.allocate_exact(0).unwrap();
+        assert!(high >= size);
+        assert_eq!(allocator.zone(high), Some(1));
+        assert_eq!(allocator.alloc_in_zone(1, 0), Ok(high + block_bytes(0).unwrap()));
+
+        assert_eq!(allocator.zone_usage(0).used_bytes(), size);
+        assert_eq!(allocator.zone_usage(0).outstanding_allocations(), 2);
+        assert_eq!(allocator.zone_usage(1).outstanding_allocations(), 2);
+        assert_eq!(allocator.usage().outstanding_allocations(), 4);
+
+        // Freeing merges back into a top level block of the same zone
+        allocator.deallocate(low).unwrap();
+        assert_eq!(allocator.zone_usage(0).outstanding_allocations(), 1);
+        assert_eq!(allocator.zone_usage(1).outstanding_allocations(), 2);
+        assert_eq!(allocator.zone(low), Some(0));
+        assert_eq!(allocator.alloc_in_zone(0, MAX_ORDER - 1), Ok(low));
+
+        allocator.deallocate(high).unwrap();
+        allocator.deallocate(high + block_bytes(0).unwrap()).unwrap();
+        assert_eq!(allocator.zone_usage(1).used_bytes(), 0);
+        assert_eq!(
+            allocator.find(size),
+            Some(BlockInfo { addr: size, order: MAX_ORDER, used: false })
+        );
+        assert_eq!(allocator.zone(size), Some(1));
+        allocator.check_free_lists().unwrap();
+    }
+
+    #[cfg(feature = "large_config")]
+    #[test]
+    fn test_large_config_addresses() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(region_bytes().unwrap());
+
+        allocator.allocate_exact(MAX_ORDER - 1).unwrap();
+        let address = allocator.allocate_exact(MAX_ORDER - 1).unwrap();
+        let expected = region_bytes().unwrap() + block_bytes(MAX_ORDER - 1).unwrap();
+
+        assert!(expected as u64 > u64::from(::std::u32::MAX));
+        assert_eq!(address, expected);
+        assert_eq!(allocator.find(address).map(|block| block.used), Some(true));
+    }
+
+    #[test]
+    fn test_dangling_free_list_pointer_detected() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        allocator.allocate_exact(MAX_ORDER - 1).unwrap();
+        assert_eq!(allocator.check_free_lists(), Ok(()));
+
+        // What a merge used to do: drop the free buddy from the tree without telling its free list
+        let buddy_address = block_bytes(MAX_ORDER - 1).unwrap();
+        let buddy = allocator.tree.find_mut(&buddy_address).remove().unwrap();
+
+        assert_eq!(
+            allocator.check_free_lists(),
+            Err(FreeListError::Dangling { order: MAX_ORDER - 1 })
+        );
+
+        // Would otherwise be leaked, as the list still points to it
+        drop(buddy);
+        allocator.free.lists[MAX_ORDER as usize - 1].clear();
+    }
+
+    fn check_non_empty_mask<L: FreeList>(mut allocator: BuddyAllocator<L>) {
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(region_bytes().unwrap()).unwrap();
+
+        let spec = WorkloadSpec::uniform(2000, MAX_ORDER, 463);
+        workload::run_with(&mut allocator, &spec, |allocator, _| {
+            for (order, list) in allocator.free.lists.iter().enumerate() {
+                assert_eq!(allocator.free.non_empty & (1 << order) != 0, !list.is_empty());
+            }
+        });
+    }
 
-        for block_number in 0..top_level_blocks(1000, 0) {
-            allocator.create_top_level(
-                2usize.pow((MAX_ORDER + BASE_ORDER) as u32) * block_number as usize,
-            );
+    #[test]
+    fn test_non_empty_mask_matches_lists() {
+        check_non_empty_mask(BuddyAllocator::<Vec<*const Block>>::new());
+        check_non_empty_mask(BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new());
+    }
+
+    #[test]
+    fn test_wrong_mask_detected() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        allocator.free.non_empty |= 1;
+        assert_eq!(allocator.check_free_lists(), Err(FreeListError::WrongMask { order: 0 }));
+    }
+
+    fn check_tombstone_freed_again<L: FreeList>(mut allocator: BuddyAllocator<L>) {
+        allocator.create_top_level(0).unwrap();
+        let block_size = block_bytes(0).unwrap();
+
+        // Allocating below a limit takes the listed buddy at 4 KiB without popping it
+        assert_eq!(allocator.allocate_exact(0), Ok(0));
+        assert_eq!(allocator.alloc_below(0, 2 * block_size), Ok(block_size));
+        assert_eq!(allocator.tombstones()[0], 1);
+
+        // The same block is free again before its tombstone has been skipped
+        allocator.deallocate(block_size).unwrap();
+        assert_eq!(allocator.tombstones()[0], 1);
+        assert_eq!(allocator.free.lens[0], 2);
+        assert_eq!(allocator.check_free_lists(), Ok(()));
+
+        // It is handed out once, skipping the tombstone, and then the next block is split off
+        assert_eq!(allocator.allocate_exact(0), Ok(block_size));
+        assert_eq!(allocator.allocate_exact(0), Ok(2 * block_size));
+        assert_eq!(allocator.tombstones()[0], 0);
+        assert_eq!(allocator.check_free_lists(), Ok(()));
+
+        for &address in &[0, block_size, 2 * block_size] {
+            allocator.deallocate(address).unwrap();
         }
+        assert_eq!(
+            allocator.find(0),
+            Some(BlockInfo { addr: 0, order: MAX_ORDER, used: false })
+        );
+        assert_eq!(allocator.check_free_lists(), Ok(()));
+    }
 
-        let mut seen = Vec::with_capacity(1000);
-        for _ in 0..1000 {
-            let cursor = allocator.allocate_exact(0).unwrap();
-            let addr = cursor.get().unwrap().address();
+    #[test]
+    fn test_tombstone_freed_again() {
+        check_tombstone_freed_again(BuddyAllocator::<Vec<*const Block>>::new());
+        check_tombstone_freed_again(BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new());
+    }
 
-            if seen.contains(&addr) {
-                panic!("Allocator must return addresses never been allocated before!");
-            } else {
-                seen.push(addr);
+    #[test]
+    fn test_tombstones_compacted() {
+        let mut allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+        for n in 0..4 {
+            allocator.create_top_level(n * region_bytes().unwrap()).unwrap();
+        }
+
+        // Every free merges with the buddies split off by the allocation, leaving a tombstone for
+        // each in the lists below the top level
+        let mut rng = XorShift::new(464);
+        for _ in 0..500 {
+            let addresses: Vec<usize> = (0..=rng.below(32))
+                .map(|_| allocator.allocate_exact(rng.below(4) as u8).unwrap())
+                .collect();
+            for address in addresses {
+                allocator.deallocate(address).unwrap();
+            }
+
+            for order in 0..LEVEL_COUNT as usize {
+                let tombstones = allocator.tombstones()[order];
+                assert!(
+                    tombstones < MIN_TOMBSTONES_TO_COMPACT
+                        || tombstones * 100 <= allocator.free.lens[order] * MAX_TOMBSTONE_PERCENT
+                );
             }
         }
+
+        assert!(allocator.tombstones().iter().any(|&tombstones| tombstones > 0));
+        assert_eq!(allocator.check_free_lists(), Ok(()));
+        assert_eq!(allocator.free_histogram()[MAX_ORDER as usize], 4);
     }
 
     #[test]
-    fn test_unique_addresses_linked_lists() {
+    fn test_deallocate_merges_buddies() {
         let mut allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+        allocator.create_top_level(0).unwrap();
+
+        let addresses: Vec<usize> = (0..4)
+            .map(|_| allocator.allocate_exact(MAX_ORDER - 2).unwrap())
+            .collect();
+        assert!(allocator.allocate_exact(MAX_ORDER - 2).is_err());
 
-        for block_number in 0..top_level_blocks(1000, 0) {
-            allocator.create_top_level(
-                2usize.pow((MAX_ORDER + BASE_ORDER) as u32) * block_number as usize,
-            );
+        // Free out of order so that merges happen with both left and right buddies
+        for &addr in &[addresses[1], addresses[2], addresses[0], addresses[3]] {
+            allocator.deallocate(addr).unwrap();
+            assert_eq!(allocator.check_free_lists(), Ok(()));
         }
 
-        let mut seen = Vec::with_capacity(1000);
-        for _ in 0..1000 {
-            let cursor = allocator.allocate_exact(0).unwrap();
-            let addr = cursor.get().unwrap().address();
+        assert_eq!(
+            allocator.tree.iter().map(|b| (b.address(), b.order())).collect::<Vec<_>>(),
+            vec![(0, MAX_ORDER)]
+        );
 
-            if seen.contains(&addr) {
-                panic!("Allocator must return addresses never been allocated before!");
-            } else {
-                seen.push(addr);
+        // The merged block must be allocatable again, and no stale pointers may be resurrected
+        assert_eq!(allocator.allocate_exact(MAX_ORDER), Ok(0));
+        assert_eq!(allocator.find(0).map(|block| block.used), Some(true));
+        assert!(allocator.tree.is_empty());
+    }
+
+    #[test]
+    fn test_deallocate_errors() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        let addr = allocator.allocate_exact(0).unwrap();
+
+        assert_eq!(allocator.deallocate(addr + 1), Err(BlockDeallocateError::NoBlockAtAddress));
+        assert_eq!(
+            allocator.deallocate(block_bytes(0).unwrap()),
+            Err(BlockDeallocateError::BlockNotUsed)
+        );
+
+        allocator.deallocate(addr).unwrap();
+        assert_eq!(allocator.deallocate(addr), Err(BlockDeallocateError::BlockNotUsed));
+        assert_eq!(allocator.check_free_lists(), Ok(()));
+    }
+
+    #[test]
+    fn test_tree_holds_only_free_blocks() {
+        let mut allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+        allocator.create_top_level(0).unwrap();
+
+        let mut rng = XorShift::new(456);
+        let mut live = Vec::new();
+        for _ in 0..10_000 {
+            let order = rng.below(4) as u8;
+            live.push((allocator.allocate_exact(order).unwrap(), order));
+        }
+
+        // Allocating from the bottom up leaves at most one free block of each order
+        assert!(allocator.tree.iter().all(|block| !block.used() && block.free_listed()));
+        assert!(allocator.tree.iter().count() <= LEVEL_COUNT as usize);
+        assert_eq!(allocator.nodes, allocator.tree.iter().count());
+        assert_eq!(allocator.check_free_lists(), Ok(()));
+
+        for &(addr, order) in &live {
+            assert_eq!(allocator.find(addr), Some(BlockInfo { addr, order, used: true }));
+        }
+
+        // Blocks are only freed with the order they were allocated with
+        let (addr, order) = live[0];
+        assert!(!BuddyAllocatorApi::deallocate(&mut allocator, addr, order + 1));
+        assert_eq!(allocator.find(addr).map(|block| block.used), Some(true));
+
+        for &(addr, order) in &live {
+            assert!(BuddyAllocatorApi::deallocate(&mut allocator, addr, order));
+            assert!(!BuddyAllocatorApi::deallocate(&mut allocator, addr, order));
+        }
+
+        assert_eq!(
+            allocator.find(0),
+            Some(BlockInfo { addr: 0, order: MAX_ORDER, used: false })
+        );
+        assert_eq!(allocator.find(0x1000), None);
+        assert_eq!(allocator.nodes, 1);
+        assert!(allocator.used.is_empty());
+    }
+
+    #[test]
+    fn test_alloc_below() {
+        let top_level_size = 1 << MAX_ORDER_SIZE;
+        let limit = 16 << 20;
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(top_level_size).unwrap();
+        allocator.create_top_level(0).unwrap();
+
+        // Low memory runs out while the rest of the first region and all of the second are free
+        let mut allocated = BlockSet::new();
+        let mut rng = XorShift::new(453);
+        loop {
+            let order = rng.below(9) as u8;
+            match allocator.alloc_below(order, limit) {
+                Ok(addr) => {
+                    let size = size_of_order(order);
+                    assert!(addr + size <= limit, "{:#x} straddles the limit", addr);
+                    assert!(allocated.insert(addr, size));
+                    assert_eq!(allocator.check_free_lists(), Ok(()));
+                }
+                Err(BlockAllocateError::NoBlocksAvailable) if order == 0 => break,
+                Err(err) => assert_eq!(err, BlockAllocateError::NoBlocksAvailable),
             }
         }
+
+        assert_eq!(allocator.usage().used_bytes(), limit);
+        assert_eq!(allocator.alloc_below(0, limit), Err(BlockAllocateError::NoBlocksAvailable));
+        assert_eq!(
+            allocator.alloc_below(MAX_ORDER + 1, limit),
+            Err(BlockAllocateError::OrderTooLarge { order: MAX_ORDER + 1, max_order: MAX_ORDER })
+        );
+
+        // Plenty of high memory is still free
+        assert!(allocator.allocate_exact(MAX_ORDER).is_ok());
+        assert_eq!(allocator.alloc_below(0, usize::max_value()), Ok(limit));
+        assert_eq!(allocator.check_free_lists(), Ok(()));
     }
 
     #[test]
-    fn test_block_bitfields() {
-        let block = Block::new(2usize.pow(56) - 1, 64, false);
+    fn test_cursor_split_and_allocate() {
+        let top_level_size = 1 << MAX_ORDER_SIZE;
+        let mut allocator = BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level_in_zone(top_level_size * 2, 3).unwrap();
+
+        {
+            let mut cursor = allocator.cursor();
+            let top = BlockView { address: 0, order: MAX_ORDER, zone: 0, used: false };
+            assert_eq!(cursor.peek(), Some(top));
+
+            // Split the first top level block down to order 0 and allocate its lowest block
+            for order in (0..MAX_ORDER).rev() {
+                let half = size_of_order(order);
+                assert_eq!(cursor.split_here(), Ok((0, half)));
+            }
+            assert_eq!(cursor.split_here(), Err(CursorError::OrderZero));
+            assert_eq!(cursor.mark_free(), Err(CursorError::BlockNotUsed));
+            assert_eq!(cursor.mark_used(), Ok(()));
+            assert_eq!(cursor.mark_used(), Err(CursorError::BlockUsed));
+            assert_eq!(cursor.split_here(), Err(CursorError::BlockUsed));
+            assert_eq!(cursor.peek(), Some(BlockView { order: 0, used: true, ..top }));
+
+            // Every upper half follows, each twice the size of the last
+            let mut address = 0;
+            for order in 0..MAX_ORDER {
+                cursor.move_next();
+                address += size_of_order(order.saturating_sub(1));
+                let expected = BlockView { address, order, zone: 0, used: false };
+                assert_eq!(cursor.peek(), Some(expected));
+            }
 
-        assert!(!block.used());
-        assert_eq!(block.order(), 64);
-        assert_eq!(block.address(), 2usize.pow(56) - 1);
+            // The gap between the top level blocks is skipped
+            cursor.move_next();
+            let other = BlockView { address: top_level_size * 2, zone: 3, ..top };
+            assert_eq!(cursor.peek(), Some(other));
+            cursor.move_next();
+            assert_eq!(cursor.peek(), None);
+            assert_eq!(cursor.mark_used(), Err(CursorError::NoBlock));
 
-        unsafe { block.set_used(true) };
-        assert!(block.used());
+            // Moving back wraps around to the last block, and back again over the gap
+            cursor.move_prev();
+            assert_eq!(cursor.peek(), Some(other));
+            cursor.move_prev();
+            let last = BlockView { address: top_level_size / 2, order: MAX_ORDER - 1, ..top };
+            assert_eq!(cursor.peek(), Some(last));
+            cursor.move_prev();
+            cursor.move_prev();
+            assert_eq!(cursor.peek().map(|block| block.order), Some(MAX_ORDER - 3));
+
+            // Allocate the upper block of order 1 too
+            cursor.move_prev();
+            assert_eq!(cursor.peek().map(|block| block.order), Some(MAX_ORDER - 4));
+            cursor.mark_used().unwrap();
+        }
+
+        assert_eq!(allocator.check_free_lists(), Ok(()));
+        assert_eq!(allocator.usage().outstanding_allocations(), 2);
+        assert_eq!(allocator.find(0), Some(BlockInfo { addr: 0, order: 0, used: true }));
+
+        // Blocks allocated through the cursor are freed like any other
+        let address = size_of_order(MAX_ORDER - 4);
+        assert_eq!(allocator.deallocate(address), Ok(()));
+        assert_eq!(allocator.deallocate(0), Ok(()));
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 2;
+        assert_eq!(allocator.free_histogram(), expected);
+    }
+
+    #[test]
+    fn test_cursor_mark_free() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        let address = allocator.allocate_exact(MAX_ORDER - 1).unwrap();
+
+        {
+            let mut cursor = allocator.cursor();
+            assert_eq!(cursor.peek().map(|block| block.used), Some(true));
+            assert_eq!(cursor.mark_free(), Ok(()));
+
+            // The buddies are both free, but not merged
+            let freed = BlockView { address, order: MAX_ORDER - 1, zone: 0, used: false };
+            assert_eq!(cursor.peek(), Some(freed));
+            cursor.move_next();
+            assert_eq!(cursor.peek().map(|block| block.order), Some(MAX_ORDER - 1));
+        }
+
+        assert_eq!(allocator.usage().outstanding_allocations(), 0);
+        assert_eq!(allocator.check_free_lists(), Ok(()));
+        assert_eq!(allocator.allocate_exact(MAX_ORDER - 1), Ok(address));
+        assert_eq!(allocator.deallocate(address), Ok(()));
+        assert_eq!(allocator.allocate_exact(MAX_ORDER), Ok(0));
+    }
+
+    #[test]
+    fn test_cursor_empty() {
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        let mut cursor = allocator.cursor();
+        assert_eq!(cursor.peek(), None);
+        cursor.move_next();
+        cursor.move_prev();
+        assert_eq!(cursor.peek(), None);
+        assert_eq!(cursor.split_here(), Err(CursorError::NoBlock));
+    }
+    fn gaps<L: FreeList>(allocator: &BuddyAllocator<L>, limit: usize) -> Vec<Range<usize>> {
+        allocator.gaps(limit).collect()
+    }
+
+    #[test]
+    fn test_gaps_empty() {
+        let allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        assert_eq!(gaps(&allocator, 0), vec![]);
+        assert_eq!(gaps(&allocator, 0x5000), vec![0..0x5000]);
+    }
+
+    #[test]
+    fn test_gaps_single_region() {
+        let size = region_bytes().unwrap();
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        allocator.create_top_level(size).unwrap();
+        allocator.allocate_exact(0).unwrap();
+
+        assert_eq!(gaps(&allocator, 0), vec![]);
+        assert_eq!(gaps(&allocator, size / 2), vec![0..size / 2]);
+        assert_eq!(gaps(&allocator, size + size / 2), vec![0..size]);
+        assert_eq!(gaps(&allocator, size * 2), vec![0..size]);
+        assert_eq!(gaps(&allocator, size * 3), vec![0..size, size * 2..size * 3]);
+
+        // An adjacent top level block leaves no gap
+        allocator.create_top_level(size * 2).unwrap();
+        assert_eq!(gaps(&allocator, size * 3), vec![0..size]);
+    }
+
+    // Three discontiguous top level blocks do not fit below the 4 GiB compact blocks can address
+    #[cfg(not(feature = "compact-blocks"))]
+    #[test]
+    fn test_gaps_three_regions() {
+        let size = region_bytes().unwrap();
+        let mut allocator = BuddyAllocator::<Vec<*const Block>>::new();
+        for &base in &[size * 6, size, size * 3] {
+            allocator.create_top_level(base).unwrap();
+        }
+
+        // Whether the blocks of a top level block are free or used does not matter
+        let spec = WorkloadSpec::uniform(300, 8, 495);
+        workload::run(&mut allocator, &spec);
+        allocator.allocate_exact(MAX_ORDER).unwrap();
+
+        let expected = vec![0..size, size * 2..size * 3, size * 4..size * 6, size * 7..size * 8];
+        assert_eq!(gaps(&allocator, size * 8), expected);
+        assert_eq!(gaps(&allocator, size * 7), &expected[..3]);
+        assert_eq!(gaps(&allocator, size * 6 + size / 2), &expected[..3]);
+        assert_eq!(
+            gaps(&allocator, size * 5),
+            vec![0..size, size * 2..size * 3, size * 4..size * 5]
+        );
     }
 }
@@ -0,0 +1,292 @@
+//! The geometry of an allocator chosen at runtime rather than by the crate wide constants.
+
+use std::{cmp, mem};
+use geometry::size_of_order_in;
+use super::{PageSize, BASE_ORDER, LEVEL_COUNT};
+
+/// The smallest base order a configuration may have, so that a block of order 0 can always hold a
+/// 4 KiB page.
+pub const MIN_BASE_ORDER: u8 = 12;
+
+/// The base order and number of levels of an allocator. A block of order `k` is
+/// `2^(k + base_order)` bytes, and the largest block is of order `levels - 1`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BuddyConfig {
+    base_order: u8,
+    levels: u8,
+}
+
+/// Why a configuration was rejected.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ConfigError {
+    /// The base order must be at least [MIN_BASE_ORDER]
+    BaseOrderTooSmall { base_order: u8 },
+    /// There must be between 1 and [LEVEL_COUNT] levels, as the allocators have room for no more
+    InvalidLevels { levels: u8 },
+    /// A block of the largest order would not fit in a `usize`
+    TopBlockTooLarge { base_order: u8, levels: u8 },
+}
+
+/// Pages of `size` are larger than a block of the largest order of a configuration.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct UnsupportedPageSize {
+    pub size: PageSize,
+}
+
+impl BuddyConfig {
+    pub fn new(base_order: u8, levels: u8) -> Result<Self, ConfigError> {
+        if base_order < MIN_BASE_ORDER {
+            return Err(ConfigError::BaseOrderTooSmall { base_order });
+        }
+
+        if levels == 0 || levels > LEVEL_COUNT {
+            return Err(ConfigError::InvalidLevels { levels });
+        }
+
+        let top_block_bits = usize::from(base_order) + usize::from(levels) - 1;
+        if top_block_bits >= mem::size_of::<usize>() * 8 {
+            return Err(ConfigError::TopBlockTooLarge { base_order, levels });
+        }
+
+        Ok(BuddyConfig { base_order, levels })
+    }
+
+    pub fn base_order(&self) -> u8 {
+        self.base_order
+    }
+
+    pub fn levels(&self) -> u8 {
+        self.levels
+    }
+
+    pub fn max_order(&self) -> u8 {
+        self.levels - 1
+    }
+
+    /// The size in bytes of a block of the given order, which must be no greater than
+    /// [BuddyConfig::max_order].
+    pub fn block_size(&self, order: u8) -> usize {
+        debug_assert!(
+            order <= self.max_order(),
+            "Order {} larger than max of {}!",
+            order,
+            self.max_order()
+        );
+        size_of_order_in(order, self.base_order)
+    }
+
+    /// The size in bytes of a top level block, which is also what top level blocks must be aligned
+    /// to.
+    pub fn top_level_size(&self) -> usize {
+        self.block_size(self.max_order())
+    }
+
+    /// How many top level blocks are needed to allocate `blocks` blocks of the given order.
+    pub fn top_level_blocks(&self, blocks: u32, order: u8) -> usize {
+        let per_top_level = 1usize << (self.max_order() - order);
+        (blocks as usize + per_top_level - 1) / per_top_level
+    }
+
+    /// The smallest order whose blocks can hold `bytes`, or `None` if not even a block of the
+    /// largest order can. Sizes up to that of a block of order 0, including 0, are of order 0.
+    pub fn order_ceil(&self, bytes: usize) -> Option<u8> {
+        let bits = bytes.checked_next_power_of_two()?.trailing_zeros() as u8;
+        let order = bits.saturating_sub(self.base_order);
+
+        if order <= self.max_order() {
+            Some(order)
+        } else {
+            None
+        }
+    }
+
+    /// The largest order whose blocks are no larger than `bytes`, or `None` if even a block of
+    /// order 0 is larger. Sizes larger than a block of the largest order are of the largest order.
+    pub fn order_floor(&self, bytes: usize) -> Option<u8> {
+        if bytes == 0 {
+            return None;
+        }
+
+        let bits = (mem::size_of::<usize>() * 8 - 1) as u8 - bytes.leading_zeros() as u8;
+        bits.checked_sub(self.base_order)
+            .map(|order| cmp::min(order, self.max_order()))
+    }
+
+    /// `bytes` rounded up to a whole number of blocks of the given order, or `None` if the order is
+    /// larger than the largest or the rounded size does not fit in a `usize`.
+    pub fn round_up_to_order(&self, bytes: usize, order: u8) -> Option<usize> {
+        if order > self.max_order() {
+            return None;
+        }
+
+        let mask = self.block_size(order) - 1;
+        bytes.checked_add(mask).map(|bytes| bytes & !mask)
+    }
+
+    /// The order of the blocks which pages of `size` are allocated as. Pages smaller than a block
+    /// of order 0 are given a whole block of order 0.
+    pub fn order_for(&self, size: PageSize) -> Result<u8, UnsupportedPageSize> {
+        // Page sizes are at most 1 GiB, so always fit in a usize
+        self.order_ceil(1 << size.power_of_two())
+            .ok_or(UnsupportedPageSize { size })
+    }
+
+    pub fn size_supported(&self, size: PageSize) -> bool {
+        self.order_for(size).is_ok()
+    }
+}
+
+/// [BuddyConfig::order_ceil] of the configuration given by the crate wide constants
+pub fn order_ceil(bytes: usize) -> Option<u8> {
+    BuddyConfig::default().order_ceil(bytes)
+}
+
+/// [BuddyConfig::order_floor] of the configuration given by the crate wide constants
+pub fn order_floor(bytes: usize) -> Option<u8> {
+    BuddyConfig::default().order_floor(bytes)
+}
+
+/// [BuddyConfig::round_up_to_order] of the configuration given by the crate wide constants
+pub fn round_up_to_order(bytes: usize, order: u8) -> Option<usize> {
+    BuddyConfig::default().round_up_to_order(bytes, order)
+}
+
+impl Default for BuddyConfig {
+    /// The configuration given by the crate wide [BASE_ORDER] and [LEVEL_COUNT].
+    fn default() -> Self {
+        BuddyConfig {
+            base_order: BASE_ORDER,
+            levels: LEVEL_COUNT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {MAX_ORDER, MAX_ORDER_SIZE};
+
+    #[test]
+    fn test_validation() {
+        assert_eq!(
+            BuddyConfig::new(11, 5),
+            Err(ConfigError::BaseOrderTooSmall { base_order: 11 })
+        );
+        assert_eq!(BuddyConfig::new(12, 0), Err(ConfigError::InvalidLevels { levels: 0 }));
+        assert_eq!(
+            BuddyConfig::new(12, LEVEL_COUNT + 1),
+            Err(ConfigError::InvalidLevels { levels: LEVEL_COUNT + 1 })
+        );
+
+        let bits = mem::size_of::<usize>() as u8 * 8;
+        let base_order = bits - LEVEL_COUNT + 1;
+        assert_eq!(
+            BuddyConfig::new(base_order, LEVEL_COUNT),
+            Err(ConfigError::TopBlockTooLarge { base_order, levels: LEVEL_COUNT })
+        );
+        assert!(BuddyConfig::new(base_order - 1, LEVEL_COUNT).is_ok());
+    }
+
+    #[test]
+    fn test_geometry() {
+        let config = BuddyConfig::new(12, 5).unwrap();
+        assert_eq!(config.max_order(), 4);
+        assert_eq!(config.block_size(0), 0x1000);
+        assert_eq!(config.top_level_size(), 0x10000);
+        assert_eq!(config.top_level_blocks(16, 0), 1);
+        assert_eq!(config.top_level_blocks(17, 0), 2);
+        assert_eq!(config.top_level_blocks(3, 4), 3);
+
+        let default = BuddyConfig::default();
+        assert_eq!(default.top_level_size(), 1 << MAX_ORDER_SIZE);
+    }
+
+    #[test]
+    fn test_order_for() {
+        let default = BuddyConfig::default();
+        assert_eq!(default.order_for(PageSize::Kib4), Ok(0));
+        assert_eq!(default.order_for(PageSize::Mib2), Ok(9));
+
+        // 2 MiB pages are blocks of order 8 rather than 9 with 8 KiB blocks of order 0
+        let config = BuddyConfig::new(13, 10).unwrap();
+        assert_eq!(config.order_for(PageSize::Kib4), Ok(0));
+        assert_eq!(config.order_for(PageSize::Mib2), Ok(8));
+        assert_eq!(
+            config.order_for(PageSize::Gib1),
+            Err(UnsupportedPageSize { size: PageSize::Gib1 })
+        );
+
+        // A top level block of 2 MiB can hold a 2 MiB page, but not a 1 GiB one
+        let config = BuddyConfig::new(12, 10).unwrap();
+        assert_eq!(config.order_for(PageSize::Mib2), Ok(9));
+        assert!(config.size_supported(PageSize::Mib2));
+        assert_eq!(
+            config.order_for(PageSize::Gib1),
+            Err(UnsupportedPageSize { size: PageSize::Gib1 })
+        );
+        assert!(!config.size_supported(PageSize::Gib1));
+    }
+    #[test]
+    fn test_order_ceil() {
+        let base = 1 << BASE_ORDER;
+        assert_eq!(order_ceil(0), Some(0));
+        assert_eq!(order_ceil(1), Some(0));
+        assert_eq!(order_ceil(base - 1), Some(0));
+        assert_eq!(order_ceil(base), Some(0));
+        assert_eq!(order_ceil(base + 1), Some(1));
+        assert_eq!(order_ceil(base * 2), Some(1));
+        assert_eq!(order_ceil(base * 2 + 1), Some(2));
+
+        let top = 1 << MAX_ORDER_SIZE;
+        assert_eq!(order_ceil(top), Some(MAX_ORDER));
+        assert_eq!(order_ceil(top + 1), None);
+        assert_eq!(order_ceil(usize::max_value()), None);
+
+        let config = BuddyConfig::new(13, 5).unwrap();
+        assert_eq!(config.order_ceil(0x2000), Some(0));
+        assert_eq!(config.order_ceil(0x2001), Some(1));
+        assert_eq!(config.order_ceil(0x20000), Some(4));
+        assert_eq!(config.order_ceil(0x20001), None);
+    }
+
+    #[test]
+    fn test_order_floor() {
+        let base = 1 << BASE_ORDER;
+        assert_eq!(order_floor(0), None);
+        assert_eq!(order_floor(base - 1), None);
+        assert_eq!(order_floor(base), Some(0));
+        assert_eq!(order_floor(base + 1), Some(0));
+        assert_eq!(order_floor(base * 2 - 1), Some(0));
+        assert_eq!(order_floor(base * 2), Some(1));
+
+        let top = 1 << MAX_ORDER_SIZE;
+        assert_eq!(order_floor(top - 1), Some(MAX_ORDER - 1));
+        assert_eq!(order_floor(top), Some(MAX_ORDER));
+        assert_eq!(order_floor(top + 1), Some(MAX_ORDER));
+        assert_eq!(order_floor(usize::max_value()), Some(MAX_ORDER));
+
+        let config = BuddyConfig::new(13, 5).unwrap();
+        assert_eq!(config.order_floor(0x1fff), None);
+        assert_eq!(config.order_floor(0x4000), Some(1));
+    }
+
+    #[test]
+    fn test_round_up_to_order() {
+        let base = 1 << BASE_ORDER;
+        assert_eq!(round_up_to_order(0, 0), Some(0));
+        assert_eq!(round_up_to_order(1, 0), Some(base));
+        assert_eq!(round_up_to_order(base - 1, 0), Some(base));
+        assert_eq!(round_up_to_order(base, 0), Some(base));
+        assert_eq!(round_up_to_order(base + 1, 0), Some(base * 2));
+        assert_eq!(round_up_to_order(base + 1, 2), Some(base * 4));
+        assert_eq!(round_up_to_order(base * 4, 2), Some(base * 4));
+        assert_eq!(round_up_to_order(1, MAX_ORDER + 1), None);
+
+        // The largest multiple of a block which fits, and anything above it
+        let last = usize::max_value() & !(base - 1);
+        assert_eq!(round_up_to_order(last, 0), Some(last));
+        assert_eq!(round_up_to_order(last + 1, 0), None);
+        assert_eq!(round_up_to_order(usize::max_value(), 0), None);
+        assert_eq!(round_up_to_order(last, MAX_ORDER), None);
+    }
+}
@@ -0,0 +1,429 @@
+//! A plain text dump of an allocator's blocks, for bug reports. A dump can be parsed back and used
+//! to give any of the allocators the same state, so that a maintainer can reproduce a report in a
+//! test. For example:
+//!
+//! ```text
+//! buddy allocator dump: base_order=12 levels=19
+//! region 0x40000000
+//! block 0x40000000 order=3 used
+//! block 0x40008000 order=3 free
+//! ...
+//! ```
+//!
+//! Regions are the top level blocks, and every block is listed in address order. When parsing,
+//! lines may come in any order, and blank lines and lines beginning with `#` are ignored.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, Write};
+use stats::{AllocatorStats, BlockInfo};
+use geometry::size_of_order;
+use super::{BuddyAllocatorApi, BASE_ORDER, LEVEL_COUNT, MAX_ORDER};
+
+const HEADER: &str = "buddy allocator dump:";
+
+/// Why a dump could not be parsed. Lines are numbered from 1.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseError {
+    /// Reading the dump failed
+    Io(io::ErrorKind),
+    /// The first line is not a dump header
+    MissingHeader,
+    /// The dump was taken with a different [BASE_ORDER] or [LEVEL_COUNT]
+    ConfigMismatch { base_order: u8, levels: u8 },
+    /// The line is neither a region nor a block
+    Syntax { line: usize },
+    /// The region is not aligned to the size of a top level block or is listed twice
+    InvalidRegion { line: usize },
+    /// The block is unaligned, lies outside of every region, overlaps another block or is free
+    /// while its buddy is free too
+    InvalidBlock { line: usize },
+    /// Part of a region is not covered by any block
+    Incomplete,
+}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> Self {
+        ParseError::Io(err.kind())
+    }
+}
+
+/// The regions and blocks of an allocator, independent of how the allocator stores them.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AllocatorState {
+    /// Base address of every top level block, ascending
+    regions: Vec<usize>,
+    /// Every free and used block, ascending by address. Together they cover the regions exactly.
+    blocks: Vec<BlockInfo>,
+}
+
+impl AllocatorState {
+    /// The state of an allocator. Memory the allocator does not manage inside a top level block,
+    /// such as the bitmap allocator's reserved memory, is reported as used blocks.
+    pub fn of<S: AllocatorStats + ?Sized>(stats: &S) -> Self {
+        let mut blocks = Vec::new();
+        stats.for_each_block(&mut |block| blocks.push(block));
+        blocks.sort_unstable_by_key(|block| block.addr);
+
+        let mut regions: Vec<usize> = blocks.iter().map(|block| top_level_of(block.addr)).collect();
+        regions.dedup();
+
+        AllocatorState { regions, blocks }
+    }
+
+    pub fn regions(&self) -> &[usize] {
+        &self.regions
+    }
+
+    pub fn blocks(&self) -> &[BlockInfo] {
+        &self.blocks
+    }
+
+    /// Write the state in the format read by [parse_text_dump].
+    pub fn write_text<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "{} base_order={} levels={}", HEADER, BASE_ORDER, LEVEL_COUNT)?;
+
+        for region in &self.regions {
+            writeln!(w, "region {:#x}", region)?;
+        }
+
+        for block in &self.blocks {
+            let state = if block.used { "used" } else { "free" };
+            writeln!(w, "block {:#x} order={} {}", block.addr, block.order, state)?;
+        }
+
+        Ok(())
+    }
+
+    /// Give a newly constructed allocator the regions and used blocks of this state. The free
+    /// blocks then match as well, since every allocator merges free buddies. The peaks and
+    /// operation counters are reset afterwards, as reaching the state takes extra allocations.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the allocator already manages memory.
+    pub fn init<A: BuddyAllocatorApi + AllocatorStats>(&self, allocator: &mut A) {
+        assert_eq!(allocator.managed_bytes(), 0, "The allocator must not manage any memory yet!");
+
+        for &region in &self.regions {
+            allocator.create_top_level(region);
+        }
+
+        let used: BTreeMap<usize, u8> = self
+            .blocks
+            .iter()
+            .filter(|block| block.used)
+            .map(|block| (block.addr, block.order))
+            .collect();
+
+        // Take every free block of each order from the largest down. Blocks containing smaller used
+        // blocks are given back to be split at the next order, and the rest are held until the end.
+        let mut held = Vec::new();
+        for order in (0..=MAX_ORDER).rev() {
+            let mut to_split = Vec::new();
+
+            while let Some(addr) = allocator.allocate(order) {
+                let last = addr + (size_of_order(order) - 1);
+
+                if used.get(&addr) == Some(&order) {
+                    continue;
+                } else if used.range(addr..=last).next().is_some() {
+                    to_split.push(addr);
+                } else {
+                    held.push((addr, order));
+                }
+            }
+
+            for addr in to_split {
+                assert!(allocator.deallocate(addr, order));
+            }
+        }
+
+        for (addr, order) in held {
+            assert!(allocator.deallocate(addr, order));
+        }
+
+        allocator.reset_peaks();
+        allocator.reset_op_counters();
+    }
+}
+
+fn top_level_of(addr: usize) -> usize {
+    addr & !(size_of_order(MAX_ORDER) - 1)
+}
+
+/// Parse a dump written by [AllocatorState::write_text], checking that its blocks could belong to
+/// an allocator.
+pub fn parse_text_dump<R: BufRead>(r: R) -> Result<AllocatorState, ParseError> {
+    let mut lines = r.lines();
+
+    let header = lines.next().ok_or(ParseError::MissingHeader)??;
+    parse_header(&header)?;
+
+    let mut regions = BTreeSet::new();
+    // Address mapped to the block and the line it is on
+    let mut blocks: BTreeMap<usize, (BlockInfo, usize)> = BTreeMap::new();
+
+    for (index, text) in lines.enumerate() {
+        let text = text?;
+        let text = text.trim();
+        let line = index + 2;
+
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words[..] {
+            ["region", addr] => {
+                let addr = parse_addr(addr).ok_or(ParseError::Syntax { line })?;
+                if top_level_of(addr) != addr || !regions.insert(addr) {
+                    return Err(ParseError::InvalidRegion { line });
+                }
+            }
+            ["block", addr, order, state] => {
+                let block = parse_block(addr, order, state).ok_or(ParseError::Syntax { line })?;
+                if block.order > MAX_ORDER
+                    || block.addr & (block.size() - 1) != 0
+                    || blocks.insert(block.addr, (block, line)).is_some()
+                {
+                    return Err(ParseError::InvalidBlock { line });
+                }
+            }
+            _ => return Err(ParseError::Syntax { line }),
+        }
+    }
+
+    check_blocks(&regions, &blocks)?;
+
+    Ok(AllocatorState {
+        regions: regions.into_iter().collect(),
+        blocks: blocks.values().map(|&(block, _)| block).collect(),
+    })
+}
+
+fn parse_header(header: &str) -> Result<(), ParseError> {
+    let words: Vec<&str> = header.split_whitespace().collect();
+    let (base_order, levels) = match words[..] {
+        ["buddy", "allocator", "dump:", base_order, levels] => (
+            field(base_order, "base_order=").ok_or(ParseError::MissingHeader)?,
+            field(levels, "levels=").ok_or(ParseError::MissingHeader)?,
+        ),
+        _ => return Err(ParseError::MissingHeader),
+    };
+
+    if base_order != BASE_ORDER || levels != LEVEL_COUNT {
+        return Err(ParseError::ConfigMismatch { base_order, levels });
+    }
+
+    Ok(())
+}
+
+/// Check that the blocks lie inside the regions without overlapping, cover them completely and
+/// have no free buddies left unmerged.
+fn check_blocks(
+    regions: &BTreeSet<usize>,
+    blocks: &BTreeMap<usize, (BlockInfo, usize)>,
+) -> Result<(), ParseError> {
+    let mut covered = 0usize;
+    // The last byte of the previous block, inclusive so that a block may end at the very top of the
+    // address space
+    let mut previous_last: Option<usize> = None;
+
+    for &(block, line) in blocks.values() {
+        let last = block.addr + (block.size() - 1);
+
+        if !regions.contains(&top_level_of(block.addr))
+            || previous_last.map_or(false, |previous| previous >= block.addr)
+        {
+            return Err(ParseError::InvalidBlock { line });
+        }
+
+        if !block.used && block.order < MAX_ORDER {
+            let buddy = blocks.get(&(block.addr ^ block.size()));
+            if buddy.map_or(false, |&(buddy, _)| !buddy.used && buddy.order == block.order) {
+                return Err(ParseError::InvalidBlock { line });
+            }
+        }
+
+        covered += block.size();
+        previous_last = Some(last);
+    }
+
+    // Overlapping blocks were rejected, so the regions are covered if the sizes add up
+    if regions.len().checked_mul(size_of_order(MAX_ORDER)) != Some(covered) {
+        return Err(ParseError::Incomplete);
+    }
+
+    Ok(())
+}
+
+fn parse_block(addr: &str, order: &str, state: &str) -> Option<BlockInfo> {
+    Some(BlockInfo {
+        addr: parse_addr(addr)?,
+        order: field(order, "order=")?,
+        used: match state {
+            "used" => true,
+            "free" => false,
+            _ => return None,
+        },
+    })
+}
+
+fn parse_addr(text: &str) -> Option<usize> {
+    if !text.starts_with("0x") {
+        return None;
+    }
+
+    usize::from_str_radix(&text[2..], 16).ok()
+}
+
+/// The number after `name`, which includes the `=`, e.g. `order=`.
+fn field(text: &str, name: &str) -> Option<u8> {
+    if !text.starts_with(name) {
+        return None;
+    }
+
+    text[name.len()..].parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buddy_allocator_bitmap::Forest;
+    use buddy_allocator_lists::{self, Block};
+    use buddy_allocator_tree::{self, Block as TreeBlock};
+    use workload::{self, WorkloadSpec};
+    use std::collections::LinkedList;
+    use MAX_ORDER_SIZE;
+
+    type ListsAllocator = buddy_allocator_lists::BuddyAllocator<Vec<Block>>;
+    type LinkedListsAllocator = buddy_allocator_lists::BuddyAllocator<LinkedList<Block>>;
+    type TreeAllocator = buddy_allocator_tree::BuddyAllocator<Vec<*const TreeBlock>>;
+
+    fn dump_of<S: AllocatorStats>(stats: &S) -> String {
+        let mut text = Vec::new();
+        stats.dump_text(&mut text).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    /// Parse `text` and give every allocator the state, checking that they all dump it unchanged.
+    fn check_init_all(text: &str) {
+        let state = parse_text_dump(text.as_bytes()).unwrap();
+
+        let mut forest = Forest::new();
+        state.init(&mut forest);
+        assert_eq!(dump_of(&forest), text);
+
+        let mut lists = ListsAllocator::new();
+        state.init(&mut lists);
+        assert_eq!(dump_of(&lists), text);
+
+        let mut linked_lists = LinkedListsAllocator::new();
+        state.init(&mut linked_lists);
+        assert_eq!(dump_of(&linked_lists), text);
+
+        let mut tree = TreeAllocator::new();
+        state.init(&mut tree);
+        assert_eq!(dump_of(&tree), text);
+
+        let used = state.blocks().iter().filter(|block| block.used).count();
+        assert_eq!(tree.usage().outstanding_allocations(), used);
+        assert_eq!(tree.peak_outstanding_allocations(), used);
+    }
+
+    #[test]
+    #[cfg(not(feature = "large_config"))]
+    fn test_dump_golden() {
+        let golden = include_str!("../testdata/dump_golden.txt");
+
+        let mut forest = Forest::new();
+        forest.create_top_level(0x4000_0000);
+        forest.create_top_level(0xc000_0000);
+        let first = forest.allocate(3).unwrap();
+        forest.allocate(0).unwrap();
+        let freed = forest.allocate(0).unwrap();
+        forest.allocate(5).unwrap();
+        forest.deallocate(freed, 0);
+        assert_eq!(first, 0x4000_0000);
+
+        assert_eq!(dump_of(&forest), golden);
+        check_init_all(golden);
+    }
+
+    #[test]
+    fn test_dump_round_trip() {
+        let mut forest = Forest::new();
+        forest.create_top_level(0);
+        forest.create_top_level(2 << MAX_ORDER_SIZE);
+
+        workload::run(&mut forest, &WorkloadSpec::uniform(300, 5, 449));
+
+        let text = dump_of(&forest);
+        assert_eq!(parse_text_dump(text.as_bytes()).unwrap(), AllocatorState::of(&forest));
+        check_init_all(&text);
+    }
+
+    #[test]
+    fn test_parse_ignores_comments() {
+        let text = format!(
+            "{} base_order={} levels={}\n\n# From a bug report\n\
+             block 0x0 order={} free\n  region 0x0\n",
+            HEADER, BASE_ORDER, LEVEL_COUNT, MAX_ORDER
+        );
+        let state = parse_text_dump(text.as_bytes()).unwrap();
+        assert_eq!(state.regions(), &[0]);
+        assert_eq!(state.blocks(), &[BlockInfo { addr: 0, order: MAX_ORDER, used: false }]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |body: &str| {
+            let header = format!("{} base_order={} levels={}\n", HEADER, BASE_ORDER, LEVEL_COUNT);
+            let text = header + body;
+            parse_text_dump(text.as_bytes()).err()
+        };
+        let top = format!("block 0x0 order={} used\n", MAX_ORDER);
+        let half = MAX_ORDER - 1;
+        let second_half = 1usize << (MAX_ORDER_SIZE - 1);
+
+        assert_eq!(parse_text_dump(&b""[..]).err(), Some(ParseError::MissingHeader));
+        assert_eq!(parse_text_dump(&b"region 0x0\n"[..]).err(), Some(ParseError::MissingHeader));
+        assert_eq!(
+            parse_text_dump(&b"buddy allocator dump: base_order=10 levels=3\n"[..]).err(),
+            Some(ParseError::ConfigMismatch { base_order: 10, levels: 3 })
+        );
+
+        assert_eq!(parse(&format!("region 0x0\n{}", top)), None);
+        assert_eq!(parse("region 0\n"), Some(ParseError::Syntax { line: 2 }));
+        assert_eq!(parse("region 0x0\nblock 0x0 order=1\n"), Some(ParseError::Syntax { line: 3 }));
+        assert_eq!(parse("block 0x0 order=x used\n"), Some(ParseError::Syntax { line: 2 }));
+        assert_eq!(parse("block 0x0 order=0 taken\n"), Some(ParseError::Syntax { line: 2 }));
+
+        assert_eq!(parse("region 0x1000\n"), Some(ParseError::InvalidRegion { line: 2 }));
+        assert_eq!(parse("region 0x0\nregion 0x0\n"), Some(ParseError::InvalidRegion { line: 3 }));
+
+        // Too large, unaligned, at the same address as another block, outside of every region,
+        // overlapping another block, and free next to its free buddy
+        let outside = format!("block {:#x} order={} used\n", 2usize << MAX_ORDER_SIZE, MAX_ORDER);
+        let invalid = [
+            (format!("region 0x0\nblock 0x0 order={} used\n", LEVEL_COUNT), 3),
+            (format!("region 0x0\nblock 0x1000 order={} used\n", half), 3),
+            (format!("region 0x0\n{}{}", top, top), 4),
+            (format!("region 0x0\n{}{}", top, outside), 4),
+            (format!("region 0x0\nblock {:#x} order={} used\n{}", second_half, half, top), 3),
+            (
+                format!(
+                    "region 0x0\nblock 0x0 order={} free\nblock {:#x} order={} free\n",
+                    half, second_half, half
+                ),
+                3,
+            ),
+        ];
+        for &(ref body, line) in &invalid {
+            assert_eq!(parse(body), Some(ParseError::InvalidBlock { line }), "{}", body);
+        }
+
+        let half_covered = format!("region 0x0\nblock 0x0 order={} used\n", half);
+        assert_eq!(parse(&half_covered), Some(ParseError::Incomplete));
+    }
+}
@@ -0,0 +1,225 @@
+//! A C interface to the bitmap allocator, for kernels which are not written in Rust. Build the
+//! crate as a `staticlib` or `cdylib` with the `ffi` feature and include
+//! `include/buddy_allocator.h`.
+//!
+//! Allocators are passed around as opaque handles. Every handle is checked for a magic value
+//! before it is used, so null pointers, destroyed handles and most garbage are rejected instead of
+//! being dereferenced as an allocator.
+
+use std::mem;
+use std::ptr;
+use buddy_allocator_bitmap::Forest;
+use mem_map::MemRegion;
+use stats::AllocatorStats;
+use BuddyAllocatorApi;
+
+/// "BUDDYHDL" in ASCII
+const HANDLE_MAGIC: u64 = 0x4255_4444_5948_444c;
+
+/// Returned by [buddy_alloc] when no block could be allocated
+pub const BUDDY_ALLOC_FAILED: u64 = u64::max_value();
+pub const BUDDY_OK: i32 = 0;
+/// The handle was null or not a live handle
+pub const BUDDY_BAD_HANDLE: i32 = -1;
+/// No used block of the given order begins at the given address
+pub const BUDDY_NOT_ALLOCATED: i32 = -2;
+/// A pointer argument other than the handle was null
+pub const BUDDY_NULL_POINTER: i32 = -3;
+
+/// An allocator as seen from C. Only ever handled through a pointer.
+pub struct BuddyHandle {
+    magic: u64,
+    forest: Forest,
+}
+
+/// A snapshot of an allocator's usage, filled in by [buddy_stats].
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CStats {
+    pub managed_bytes: u64,
+    pub used_bytes: u64,
+    pub outstanding_allocations: u64,
+    pub peak_used_bytes: u64,
+    pub peak_outstanding_allocations: u64,
+}
+
+/// The allocator behind a handle, or `None` if the handle is not a live handle.
+///
+/// Unsafe because a non null, aligned pointer which does not point to readable memory cannot be
+/// detected.
+unsafe fn allocator<'a>(handle: *mut BuddyHandle) -> Option<&'a mut Forest> {
+    if handle.is_null() || handle as usize % mem::align_of::<BuddyHandle>() != 0 {
+        return None;
+    }
+
+    if ptr::read(&(*handle).magic) != HANDLE_MAGIC {
+        return None;
+    }
+
+    Some(&mut (*handle).forest)
+}
+
+/// Create an allocator managing the `len` bytes beginning at `base`, shrunk inward to whole blocks
+/// of order 0. Returns null if that leaves no memory to manage.
+#[no_mangle]
+pub extern "C" fn buddy_create(base: u64, len: u64) -> *mut BuddyHandle {
+    let forest = Forest::from_regions(&[MemRegion {
+        start: base,
+        len,
+        usable: true,
+    }]);
+
+    if forest.managed_bytes() == 0 {
+        return ptr::null_mut();
+    }
+
+    Box::into_raw(Box::new(BuddyHandle {
+        magic: HANDLE_MAGIC,
+        forest,
+    }))
+}
+
+/// Allocate a block of the given order, returning its address or [BUDDY_ALLOC_FAILED].
+#[no_mangle]
+pub unsafe extern "C" fn buddy_alloc(handle: *mut BuddyHandle, order: u8) -> u64 {
+    allocator(handle)
+        .and_then(|forest| forest.allocate(order))
+        .map_or(BUDDY_ALLOC_FAILED, |addr| addr as u64)
+}
+
+/// Free the block of the given order beginning at `addr`. Returns [BUDDY_OK] on success.
+#[no_mangle]
+pub unsafe extern "C" fn buddy_free(handle: *mut BuddyHandle, addr: u64, order: u8) -> i32 {
+    let forest = match allocator(handle) {
+        Some(forest) => forest,
+        None => return BUDDY_BAD_HANDLE,
+    };
+
+    if forest.deallocate(addr as usize, order) {
+        BUDDY_OK
+    } else {
+        BUDDY_NOT_ALLOCATED
+    }
+}
+
+/// Write the allocator's usage to `stats`. Returns [BUDDY_OK] on success.
+#[no_mangle]
+pub unsafe extern "C" fn buddy_stats(handle: *mut BuddyHandle, stats: *mut CStats) -> i32 {
+    let forest = match allocator(handle) {
+        Some(forest) => forest,
+        None => return BUDDY_BAD_HANDLE,
+    };
+
+    if stats.is_null() {
+        return BUDDY_NULL_POINTER;
+    }
+
+    let usage = forest.usage();
+    *stats = CStats {
+        managed_bytes: forest.managed_bytes() as u64,
+        used_bytes: usage.used_bytes() as u64,
+        outstanding_allocations: usage.outstanding_allocations() as u64,
+        peak_used_bytes: usage.peak_used_bytes() as u64,
+        peak_outstanding_allocations: usage.peak_outstanding_allocations() as u64,
+    };
+    BUDDY_OK
+}
+
+/// Destroy an allocator, after which its handle is rejected. Does nothing if the handle is not a
+/// live handle.
+#[no_mangle]
+pub unsafe extern "C" fn buddy_destroy(handle: *mut BuddyHandle) {
+    if allocator(handle).is_some() {
+        // Clear the magic first so that the stale handle is rejected while the memory is unchanged
+        (*handle).magic = 0;
+        drop(Box::from_raw(handle));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CStats, BUDDY_ALLOC_FAILED, BUDDY_BAD_HANDLE, BUDDY_NOT_ALLOCATED, BUDDY_OK};
+    use std::ptr;
+    use BASE_ORDER;
+
+    /// An opaque handle, as C sees it
+    #[repr(C)]
+    struct Handle {
+        _private: [u8; 0],
+    }
+
+    // Call the functions through their C signatures, as a C caller would
+    extern "C" {
+        fn buddy_create(base: u64, len: u64) -> *mut Handle;
+        fn buddy_alloc(handle: *mut Handle, order: u8) -> u64;
+        fn buddy_free(handle: *mut Handle, addr: u64, order: u8) -> i32;
+        fn buddy_stats(handle: *mut Handle, stats: *mut CStats) -> i32;
+        fn buddy_destroy(handle: *mut Handle);
+    }
+
+    #[test]
+    fn test_ffi_round_trip() {
+        let base = 0x10_0000;
+        let len = 0x40_0000;
+
+        unsafe {
+            let handle = buddy_create(base, len);
+            assert!(!handle.is_null());
+
+            let addresses: Vec<u64> = (0..16).map(|_| buddy_alloc(handle, 2)).collect();
+            for &addr in &addresses {
+                assert_ne!(addr, BUDDY_ALLOC_FAILED);
+                assert!(addr >= base && addr < base + len);
+                assert_eq!(addr % (4 << BASE_ORDER), 0);
+            }
+
+            let mut stats = CStats::default();
+            assert_eq!(buddy_stats(handle, &mut stats), BUDDY_OK);
+            assert_eq!(stats.managed_bytes, len);
+            assert_eq!(stats.used_bytes, 16 * (4 << BASE_ORDER));
+            assert_eq!(stats.outstanding_allocations, 16);
+
+            // The parent of two allocated buddies is used, but was not allocated itself
+            let pair = addresses
+                .iter()
+                .cloned()
+                .find(|&addr| {
+                    addr % (8 << BASE_ORDER) == 0 && addresses.contains(&(addr + (4 << BASE_ORDER)))
+                })
+                .unwrap();
+            assert_eq!(buddy_free(handle, pair, 3), BUDDY_NOT_ALLOCATED);
+
+            for &addr in &addresses {
+                assert_eq!(buddy_free(handle, addr, 2), BUDDY_OK);
+            }
+            assert_eq!(buddy_free(handle, addresses[0], 2), BUDDY_NOT_ALLOCATED);
+
+            // Larger than the whole region
+            assert_eq!(buddy_alloc(handle, 11), BUDDY_ALLOC_FAILED);
+
+            assert_eq!(buddy_stats(handle, &mut stats), BUDDY_OK);
+            assert_eq!(stats.used_bytes, 0);
+            assert_eq!(stats.peak_outstanding_allocations, 16);
+
+            buddy_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn test_ffi_rejects_bad_handles() {
+        unsafe {
+            assert!(buddy_create(0x1800, 0x1000).is_null());
+
+            let mut garbage = [0xdead_beef_u64; 16];
+            let garbage = garbage.as_mut_ptr() as *mut Handle;
+            let mut stats = CStats::default();
+
+            for &handle in &[ptr::null_mut(), garbage, (garbage as usize + 1) as *mut Handle] {
+                assert_eq!(buddy_alloc(handle, 0), BUDDY_ALLOC_FAILED);
+                assert_eq!(buddy_free(handle, 0, 0), BUDDY_BAD_HANDLE);
+                assert_eq!(buddy_stats(handle, &mut stats), BUDDY_BAD_HANDLE);
+                buddy_destroy(handle);
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Physical frame allocation for kernels using the `x86_64` crate's paging structures.
+
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame};
+use x86_64::PhysAddr;
+use super::{BuddyAllocatorApi, BASE_ORDER, MAX_ORDER};
+
+/// Hands out the blocks of a buddy allocator as physical frames. Frames of every size up to a block
+/// of [MAX_ORDER] are supported; larger frames can never be allocated.
+#[derive(Debug)]
+pub struct BuddyFrameAllocator<A: BuddyAllocatorApi> {
+    allocator: A,
+}
+
+impl<A: BuddyAllocatorApi> BuddyFrameAllocator<A> {
+    /// Wrap an allocator which has already been given the physical memory to manage.
+    pub fn new(allocator: A) -> Self {
+        BuddyFrameAllocator { allocator }
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.allocator
+    }
+
+    pub fn allocator_mut(&mut self) -> &mut A {
+        &mut self.allocator
+    }
+
+    pub fn into_inner(self) -> A {
+        self.allocator
+    }
+}
+
+/// The order of the blocks which frames of the given size are made of, or `None` if they are
+/// larger than a block of [MAX_ORDER].
+pub fn frame_order<S: PageSize>() -> Option<u8> {
+    debug_assert!(S::SIZE.is_power_of_two(), "Frame sizes must be powers of two!");
+    (S::SIZE.trailing_zeros() as u8)
+        .checked_sub(BASE_ORDER)
+        .filter(|&order| order <= MAX_ORDER)
+}
+
+impl<A: BuddyAllocatorApi, S: PageSize> FrameAllocator<S> for BuddyFrameAllocator<A> {
+    fn alloc(&mut self) -> Option<PhysFrame<S>> {
+        let addr = self.allocator.allocate(frame_order::<S>()?)?;
+
+        // Blocks are aligned to their size, so this never fails
+        Some(PhysFrame::from_start_address(PhysAddr::new(addr as u64)).unwrap())
+    }
+}
+
+impl<A: BuddyAllocatorApi, S: PageSize> FrameDeallocator<S> for BuddyFrameAllocator<A> {
+    /// # Panicking
+    ///
+    /// Panics if the frame was not allocated from this allocator.
+    fn dealloc(&mut self, frame: PhysFrame<S>) {
+        let order = frame_order::<S>().expect("Frame is too large to have been allocated!");
+        let addr = frame.start_address().as_u64() as usize;
+
+        assert!(
+            self.allocator.deallocate(addr, order),
+            "Frame {:#x} was not allocated!",
+            addr
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buddy_allocator_bitmap::Forest;
+    use stats::AllocatorStats;
+    use testing::BlockSet;
+    use x86_64::structures::paging::{Size1GiB, Size2MiB, Size4KiB};
+    use MAX_ORDER_SIZE;
+
+    /// Allocate `count` frames of size `S`, checking that each is aligned and overlaps no frame
+    /// allocated before it.
+    fn alloc_frames<S: PageSize>(
+        frames: &mut BuddyFrameAllocator<Forest>,
+        allocated: &mut BlockSet,
+        count: usize,
+    ) -> Vec<PhysFrame<S>> {
+        (0..count)
+            .map(|_| {
+                let frame: PhysFrame<S> = frames.alloc().unwrap();
+                let addr = frame.start_address().as_u64();
+
+                assert_eq!(addr % S::SIZE, 0, "Frame {:#x} is not aligned!", addr);
+                assert!(
+                    allocated.insert(addr as usize, S::SIZE as usize),
+                    "Frame {:#x} overlaps another frame!",
+                    addr
+                );
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_frame_orders() {
+        assert_eq!(frame_order::<Size4KiB>(), Some(12 - BASE_ORDER));
+        assert_eq!(frame_order::<Size2MiB>(), Some(21 - BASE_ORDER));
+
+        let gib_order = 30 - BASE_ORDER;
+        let expected = if gib_order <= MAX_ORDER { Some(gib_order) } else { None };
+        assert_eq!(frame_order::<Size1GiB>(), expected);
+    }
+
+    #[test]
+    fn test_frames_of_each_size() {
+        let top_level_size = 1usize << MAX_ORDER_SIZE;
+        let mut forest = Forest::new();
+        forest.create_top_level(0);
+        forest.create_top_level(top_level_size);
+        let mut frames = BuddyFrameAllocator::new(forest);
+
+        let mut allocated = BlockSet::new();
+        let small = alloc_frames::<Size4KiB>(&mut frames, &mut allocated, 100);
+        let large = alloc_frames::<Size2MiB>(&mut frames, &mut allocated, 10);
+        let huge = if frame_order::<Size1GiB>().is_some() {
+            alloc_frames::<Size1GiB>(&mut frames, &mut allocated, 1)
+        } else {
+            Vec::new()
+        };
+
+        for frame in small {
+            frames.dealloc(frame);
+        }
+        for frame in large {
+            frames.dealloc(frame);
+        }
+        for frame in huge {
+            frames.dealloc(frame);
+        }
+
+        // Every frame went back to the pool, so both top level blocks are whole again
+        assert_eq!(frames.allocator().usage().outstanding_allocations(), 0);
+        assert!(frames.allocator_mut().allocate(MAX_ORDER).is_some());
+        assert!(frames.allocator_mut().allocate(MAX_ORDER).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "not allocated")]
+    fn test_dealloc_unallocated_frame() {
+        let mut forest = Forest::new();
+        forest.create_top_level(0);
+        let mut frames = BuddyFrameAllocator::new(forest);
+
+        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(0x1000)).unwrap();
+        frames.dealloc(frame);
+    }
+}
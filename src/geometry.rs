@@ -0,0 +1,279 @@
+//! The sizes and alignment of blocks, computed with checked shifts so that an order whose blocks
+//! would not fit in a `usize` gives `None` rather than overflowing.
+//!
+//! A block of order `k` is `2^(k + BASE_ORDER)` bytes and is aligned to its size. Every order up
+//! to [MAX_ORDER] fits, so the results for those orders can be unwrapped.
+
+use std::collections::BTreeMap;
+use super::{BASE_ORDER, MAX_ORDER};
+
+/// The size in bytes of a block of the given order when blocks of order 0 are `2^base_order`
+/// bytes. Every block size in the crate is computed here, so that the relationship between
+/// orders and sizes lives in one place.
+///
+/// # Panicking
+///
+/// In debug builds, panics if the block does not fit in a `usize`.
+#[inline]
+pub fn size_of_order_in(order: u8, base_order: u8) -> usize {
+    debug_assert!(
+        fits_usize(order, base_order),
+        "A block of order {} with base order {} does not fit in a usize!",
+        order,
+        base_order
+    );
+    1 << (order + base_order)
+}
+
+fn fits_usize(order: u8, base_order: u8) -> bool {
+    u32::from(order) + u32::from(base_order) < usize::BITS
+}
+
+/// The size in bytes of a block of one of the allocators' orders, all of which fit in a `usize`.
+///
+/// # Panicking
+///
+/// In debug builds, panics if the order is larger than [MAX_ORDER].
+#[inline]
+pub fn size_of_order(order: u8) -> usize {
+    debug_assert!(order <= MAX_ORDER, "Order {} larger than max of {}!", order, MAX_ORDER);
+    size_of_order_in(order, BASE_ORDER)
+}
+
+/// The size in bytes of a block of the given order, or `None` if it does not fit in a `usize`.
+pub fn block_bytes(order: u8) -> Option<usize> {
+    if fits_usize(order, BASE_ORDER) {
+        Some(size_of_order_in(order, BASE_ORDER))
+    } else {
+        None
+    }
+}
+
+/// The size in bytes of a top level block, which is the block of [MAX_ORDER] that each region is
+/// made of.
+pub fn region_bytes() -> Option<usize> {
+    block_bytes(MAX_ORDER)
+}
+
+/// `addr` rounded down to a multiple of the size of a block of the given order, or `None` if the
+/// block does not fit in a `usize`.
+pub fn align_down(addr: usize, order: u8) -> Option<usize> {
+    block_bytes(order).map(|size| addr & !(size - 1))
+}
+
+/// `addr` rounded up to a multiple of the size of a block of the given order, or `None` if the
+/// block or the rounded address does not fit in a `usize`.
+pub fn align_up(addr: usize, order: u8) -> Option<usize> {
+    let size = block_bytes(order)?;
+    addr.checked_add(size - 1).map(|addr| addr & !(size - 1))
+}
+
+/// Whether `addr` is a multiple of the size of a block of the given order. Only 0 is a multiple
+/// of a size too large for a `usize`.
+pub fn is_aligned(addr: usize, order: u8) -> bool {
+    match block_bytes(order) {
+        Some(size) => addr & (size - 1) == 0,
+        None => addr == 0,
+    }
+}
+
+/// How many bits hold the frame number of a block's address with the `compact-blocks` feature.
+/// Blocks must then lie below `2^(COMPACT_FRAME_BITS + BASE_ORDER)`, which is 4 GiB by default.
+pub const COMPACT_FRAME_BITS: u8 = 20;
+
+/// The number of the block of order 0 beginning at `addr`, as stored by compact blocks, or `None`
+/// if `addr` is not aligned to a block of order 0 or its number does not fit in
+/// [COMPACT_FRAME_BITS].
+pub fn compact_frame(addr: usize) -> Option<u32> {
+    if !is_aligned(addr, 0) {
+        return None;
+    }
+
+    let frame = addr >> BASE_ORDER;
+    if frame < 1 << COMPACT_FRAME_BITS {
+        Some(frame as u32)
+    } else {
+        None
+    }
+}
+
+/// The address of the block of order 0 of the given number, the inverse of [compact_frame].
+pub fn compact_frame_address(frame: u32) -> usize {
+    (frame as usize) << BASE_ORDER
+}
+
+/// The first byte address of a range in `ranges` which overlaps `[first, last]`, if any. `ranges`
+/// maps the first byte address of each range to a value from which `last_of` reads its last byte
+/// address, and its ranges must not overlap each other. The bounds are inclusive so that a range
+/// may end at the very top of the address space.
+pub fn find_overlap<V, F>(
+    ranges: &BTreeMap<usize, V>,
+    first: usize,
+    last: usize,
+    last_of: F,
+) -> Option<usize>
+where
+    F: Fn(&V) -> usize,
+{
+    // The ranges never overlap each other, so only the nearest range on either side can overlap
+    let below = ranges
+        .range(..=first)
+        .next_back()
+        .filter(|&(_, below)| last_of(below) >= first);
+    let above = ranges
+        .range(first..)
+        .next()
+        .filter(|&(&above_first, _)| above_first <= last);
+
+    below.or(above).map(|(&existing_first, _)| existing_first)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+    use MAX_ORDER_SIZE;
+
+    const USIZE_BITS: u32 = mem::size_of::<usize>() as u32 * 8;
+
+    /// Every order, including those too large for a `usize`
+    fn orders() -> impl Iterator<Item = u8> {
+        0..=u8::max_value()
+    }
+
+    /// The size of a block of the given order computed without shifting a `usize`
+    fn expected_bytes(order: u8) -> Option<usize> {
+        let bits = u32::from(order) + u32::from(BASE_ORDER);
+        if bits < USIZE_BITS {
+            Some((1u128 << bits) as usize)
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn test_block_bytes() {
+        for order in orders() {
+            assert_eq!(block_bytes(order), expected_bytes(order), "order {}", order);
+        }
+
+        assert_eq!(block_bytes(0), Some(1 << BASE_ORDER));
+        assert_eq!(region_bytes(), Some(1 << MAX_ORDER_SIZE));
+
+        // Every order of the allocators fits, and the first which does not is the first too large
+        assert!((0..=MAX_ORDER).all(|order| block_bytes(order).is_some()));
+        let largest = (USIZE_BITS - 1 - u32::from(BASE_ORDER)) as u8;
+        assert_eq!(block_bytes(largest), Some(1 << (USIZE_BITS - 1)));
+        assert_eq!(block_bytes(largest + 1), None);
+        assert_eq!(block_bytes(u8::max_value()), None);
+    }
+
+    #[test]
+    fn test_size_of_order() {
+        for order in 0..=MAX_ORDER {
+            assert_eq!(Some(size_of_order(order)), block_bytes(order), "order {}", order);
+        }
+        assert_eq!(size_of_order(MAX_ORDER), 1 << MAX_ORDER_SIZE);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "larger than max")]
+    fn test_size_of_order_too_large() {
+        size_of_order(MAX_ORDER + 1);
+    }
+
+    #[test]
+    fn test_size_of_order_in() {
+        assert_eq!(size_of_order_in(0, 6), 64);
+        assert_eq!(size_of_order_in(3, 16), 1 << 19);
+        for order in 0..=MAX_ORDER {
+            assert_eq!(size_of_order_in(order, BASE_ORDER), size_of_order(order));
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "does not fit in a usize")]
+    fn test_size_of_order_in_too_large() {
+        size_of_order_in(USIZE_BITS as u8, 0);
+    }
+
+    #[test]
+    fn test_align() {
+        for order in orders() {
+            let size = match expected_bytes(order) {
+                Some(size) => size,
+                None => {
+                    assert_eq!(align_down(usize::max_value(), order), None);
+                    assert_eq!(align_up(0, order), None);
+                    assert!(is_aligned(0, order));
+                    assert!(!is_aligned(1 << (USIZE_BITS - 1), order));
+                    continue;
+                }
+            };
+
+            for &addr in &[0, size, size.wrapping_mul(3)] {
+                assert!(is_aligned(addr, order));
+                assert_eq!(align_down(addr, order), Some(addr));
+                assert_eq!(align_up(addr, order), Some(addr));
+            }
+
+            for &addr in &[1, size - 1, size + 1, size.wrapping_mul(2).wrapping_sub(1)] {
+                assert_eq!(is_aligned(addr, order), addr % size == 0, "{:#x}", addr);
+                assert_eq!(align_down(addr, order), Some(addr / size * size));
+            }
+            assert_eq!(align_up(1, order), Some(size));
+            assert_eq!(align_up(size + 1, order), Some(size.wrapping_mul(2)).filter(|&up| up != 0));
+
+            // Near the top of the address space, where rounding up overflows
+            let last = usize::max_value() - (size - 1);
+            assert!(is_aligned(last, order));
+            assert!(!is_aligned(usize::max_value(), order));
+            assert_eq!(align_down(usize::max_value(), order), Some(last));
+            assert_eq!(align_up(last, order), Some(last));
+            assert_eq!(align_up(last - 1, order), Some(last));
+            assert_eq!(align_up(last + 1, order), None);
+        }
+    }
+
+    #[test]
+    fn test_compact_frame() {
+        let frame_size = block_bytes(0).unwrap();
+        let limit = compact_frame_address(1 << COMPACT_FRAME_BITS);
+
+        for &addr in &[0, frame_size, frame_size * 12345, limit - frame_size] {
+            let frame = compact_frame(addr).unwrap();
+            assert_eq!(frame as usize, addr / frame_size);
+            assert_eq!(compact_frame_address(frame), addr);
+        }
+
+        assert_eq!(compact_frame(limit), None);
+        assert_eq!(compact_frame(usize::max_value() & !(frame_size - 1)), None);
+        assert_eq!(compact_frame(1), None);
+        assert_eq!(compact_frame(frame_size + frame_size / 2), None);
+    }
+
+    #[test]
+    fn test_find_overlap() {
+        let top = usize::max_value() - 0xfff;
+        let ranges: BTreeMap<usize, usize> =
+            vec![(0x1000, 0x1fff), (0x4000, 0x4fff), (top, usize::max_value())]
+                .into_iter()
+                .collect();
+        let overlap = |first, last| find_overlap(&ranges, first, last, |&last| last);
+
+        // Touching either side is not overlapping
+        assert_eq!(overlap(0x0, 0xfff), None);
+        assert_eq!(overlap(0x2000, 0x3fff), None);
+        assert_eq!(overlap(0x5000, 0x5fff), None);
+
+        assert_eq!(overlap(0x1800, 0x1800), Some(0x1000));
+        assert_eq!(overlap(0x0, 0x1000), Some(0x1000));
+        assert_eq!(overlap(0x1fff, 0x2fff), Some(0x1000));
+        assert_eq!(overlap(0x3000, 0x4000), Some(0x4000));
+        // A range containing another is found from either end
+        assert_eq!(overlap(0x3000, 0x6000), Some(0x4000));
+        assert_eq!(overlap(usize::max_value(), usize::max_value()), Some(top));
+    }
+}
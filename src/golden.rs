@@ -0,0 +1,222 @@
+//! Golden file tests: a fixed workload is run against each allocator and everything it did is
+//! compared line by line against a file checked in under `testdata/golden`, so that a refactor
+//! which changes which blocks are handed out is caught even if the blocks are still valid.
+//!
+//! When a change to the addresses is intended, regenerate the files by running the tests with the
+//! `UPDATE_GOLDEN` environment variable set, e.g. `UPDATE_GOLDEN=1 cargo test golden`, and review
+//! the diff of the files. The self-test only ever compares against them.
+
+use std::collections::LinkedList;
+use std::env;
+use std::fmt::{self, Display, Formatter, Write};
+use std::fs;
+use std::path::PathBuf;
+use buddy_allocator_bitmap::Forest;
+use buddy_allocator_lists::{self, BuddyAllocator as ListsAllocator};
+use buddy_allocator_tree::{self, BlockPtrAdapter, BuddyAllocator as TreeAllocator};
+use intrusive_collections::SinglyLinkedList;
+use workload::{self, Outcome, WorkloadSpec};
+use super::{BuddyAllocatorApi, MAX_ORDER_SIZE};
+
+/// Set to regenerate the golden files rather than compare against them
+const UPDATE_VAR: &str = "UPDATE_GOLDEN";
+
+/// The first line at which the output of a run differs from its golden file
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Divergence {
+    /// The 0 indexed line, not counting comments
+    line: usize,
+    /// The golden line, or `None` if the golden file ended first
+    expected: Option<String>,
+    /// The line of the run, or `None` if the run ended first
+    actual: Option<String>,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let or_end = |line: &Option<String>| line.clone().unwrap_or_else(|| "<end>".to_string());
+        write!(
+            f,
+            "first divergence at line {}:\n  expected: {}\n    actual: {}",
+            self.line,
+            or_end(&self.expected),
+            or_end(&self.actual),
+        )
+    }
+}
+
+/// The lines of a golden file which are compared, skipping `#` comments
+fn data_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.starts_with('#'))
+}
+
+fn first_divergence(expected: &str, actual: &str) -> Option<Divergence> {
+    let (mut expected, mut actual) = (data_lines(expected), data_lines(actual));
+    let mut line = 0;
+
+    loop {
+        match (expected.next(), actual.next()) {
+            (None, None) => return None,
+            (Some(e), Some(a)) if e == a => line += 1,
+            (e, a) => {
+                return Some(Divergence {
+                    line,
+                    expected: e.map(str::to_string),
+                    actual: a.map(str::to_string),
+                })
+            }
+        }
+    }
+}
+
+/// Every outcome of a run, one per line, as written to a golden file
+fn serialize(outcomes: &[Outcome]) -> String {
+    let mut text = String::new();
+    for outcome in outcomes {
+        match *outcome {
+            Outcome::Allocated { address, order } => {
+                writeln!(text, "alloc {} {:#x}", order, address)
+            }
+            Outcome::OutOfBlocks { order } => writeln!(text, "out_of_blocks {}", order),
+            Outcome::Freed { address, order } => writeln!(text, "free {} {:#x}", order, address),
+            Outcome::NothingToFree => writeln!(text, "nothing_to_free"),
+        }.unwrap();
+    }
+
+    text
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "testdata", "golden", &format!("{}.txt", name)]
+        .iter()
+        .collect()
+}
+
+/// Compare `actual` against the golden file of the given name, or overwrite the file with it if
+/// [UPDATE_VAR] is set when testing.
+fn check_golden(name: &str, header: &str, actual: &str) {
+    let path = golden_path(name);
+
+    if cfg!(test) && env::var_os(UPDATE_VAR).is_some() {
+        fs::write(&path, format!("{}{}", header, actual))
+            .unwrap_or_else(|err| panic!("Could not write {}: {}", path.display(), err));
+        return;
+    }
+
+    let expected = fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!(
+            "Could not read {}: {}. Run {}=1 cargo test golden to generate it.",
+            path.display(),
+            err,
+            UPDATE_VAR
+        )
+    });
+
+    if let Some(divergence) = first_divergence(&expected, actual) {
+        panic!(
+            "{} differs from its golden file {}, {}\n\
+             Run {}=1 cargo test golden if this is intended.",
+            name,
+            path.display(),
+            divergence,
+            UPDATE_VAR
+        );
+    }
+}
+
+/// The workloads every allocator is checked with, by name
+fn workloads() -> Vec<(&'static str, WorkloadSpec)> {
+    vec![
+        ("uniform", WorkloadSpec::uniform(400, 8, 480)),
+        (
+            "small_churn",
+            WorkloadSpec {
+                total_ops: 400,
+                order_distribution: vec![8, 4, 2, 1],
+                free_ratio: 0.45,
+                seed: 481,
+            },
+        ),
+    ]
+}
+
+/// Run every workload against a new allocator from `new`, with top level blocks at 0 and at twice
+/// the size of a top level block, and check the outcomes against the golden files of `name`.
+fn check_allocator<A, F>(name: &str, mut new: F)
+where
+    A: BuddyAllocatorApi,
+    F: FnMut() -> A,
+{
+    for (workload, spec) in workloads() {
+        let mut allocator = new();
+        allocator.create_top_level(0);
+        allocator.create_top_level(2 << MAX_ORDER_SIZE);
+
+        let mut outcomes = Vec::with_capacity(spec.total_ops);
+        workload::run_with(&mut allocator, &spec, |_, outcome| outcomes.push(outcome));
+
+        let header = format!(
+            "# Outcomes of the {} allocator on {:?}\n# Regenerate with {}=1 cargo test golden\n",
+            name, spec, UPDATE_VAR
+        );
+        check_golden(&format!("{}_{}", name, workload), &header, &serialize(&outcomes));
+    }
+}
+
+#[test]
+fn test_first_divergence() {
+    assert_eq!(first_divergence("# comment\na\nb\n", "a\nb\n"), None);
+    assert_eq!(
+        first_divergence("a\nb\nc\n", "a\nx\nc\n"),
+        Some(Divergence {
+            line: 1,
+            expected: Some("b".to_string()),
+            actual: Some("x".to_string()),
+        })
+    );
+    assert_eq!(
+        first_divergence("a\n", "a\nb\n"),
+        Some(Divergence { line: 1, expected: None, actual: Some("b".to_string()) })
+    );
+    assert_eq!(
+        first_divergence("a\nb\n", "a\n"),
+        Some(Divergence { line: 1, expected: Some("b".to_string()), actual: None })
+    );
+}
+
+// Lists of different types hand out free blocks in different orders, so each has its own files
+
+pub fn check_lists() {
+    check_allocator("vecs", ListsAllocator::<Vec<buddy_allocator_lists::Block>>::new);
+    check_allocator(
+        "linked_lists",
+        ListsAllocator::<LinkedList<buddy_allocator_lists::Block>>::new,
+    );
+}
+
+pub fn check_rb_tree() {
+    check_allocator("rb_tree_vecs", TreeAllocator::<Vec<*const buddy_allocator_tree::Block>>::new);
+    check_allocator(
+        "rb_tree_linked_lists",
+        TreeAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new,
+    );
+}
+
+pub fn check_bitmap() {
+    check_allocator("bitmap", Forest::new);
+}
+
+#[test]
+fn test_golden_lists() {
+    check_lists();
+}
+
+#[test]
+fn test_golden_rb_tree() {
+    check_rb_tree();
+}
+
+#[test]
+fn test_golden_bitmap() {
+    check_bitmap();
+}
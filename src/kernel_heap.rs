@@ -0,0 +1,222 @@
+//! A kernel heap over a fixed range of memory, with the same shape of API as the `Heap` of the
+//! `linked_list_allocator` crate so that the two can be swapped.
+//!
+//! Every allocation takes a whole block, so layouts smaller than a block of order 0 waste the rest
+//! of it: a 24 byte `Box` takes 4 KiB. This heap suits kernels whose allocations are mostly page
+//! sized, or which put a slab allocator in front of it for small objects.
+
+use std::alloc::Layout;
+use std::cmp;
+use std::ptr::NonNull;
+use buddy_allocator_bitmap::Forest;
+use config;
+use mem_map::MemRegion;
+use stats::AllocatorStats;
+use super::BuddyAllocatorApi;
+
+/// A heap backed by a bitmap allocator.
+pub struct KernelHeap {
+    forest: Forest,
+    bottom: usize,
+    size: usize,
+}
+
+impl KernelHeap {
+    /// A heap with no memory, which must be given memory with [KernelHeap::init] before use.
+    pub fn empty() -> Self {
+        KernelHeap {
+            forest: Forest::new(),
+            bottom: 0,
+            size: 0,
+        }
+    }
+
+    /// A heap managing the `size` bytes beginning at `bottom`.
+    ///
+    /// Unsafe for the same reasons as [KernelHeap::init].
+    pub unsafe fn new(bottom: usize, size: usize) -> Self {
+        let mut heap = KernelHeap::empty();
+        heap.init(bottom, size);
+        heap
+    }
+
+    /// Give the heap the `size` bytes beginning at `bottom`. Only the whole blocks of order 0
+    /// inside the range are handed out, and never the first block at address 0, as a pointer to it
+    /// would be null.
+    ///
+    /// Unsafe because the memory must be valid and must not be used by anything but the heap.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the heap has already been initialized.
+    pub unsafe fn init(&mut self, bottom: usize, size: usize) {
+        assert_eq!(self.size, 0, "The heap has already been initialized!");
+
+        let start = cmp::max(bottom, 1);
+        self.forest = Forest::from_regions(&[MemRegion {
+            start: start as u64,
+            len: size.saturating_sub(start - bottom) as u64,
+            usable: true,
+        }]);
+        self.bottom = bottom;
+        self.size = size;
+    }
+
+    /// Allocate a block which fits the layout, which is the block of the smallest order that is at
+    /// least as large as both the size and the alignment. Blocks are aligned to their size.
+    pub fn allocate_first_fit(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        let order = order_of(&layout).ok_or(())?;
+        let addr = self.forest.allocate(order).ok_or(())?;
+
+        // The first block is never managed when the heap begins at 0
+        Ok(NonNull::new(addr as *mut u8).unwrap())
+    }
+
+    /// Free memory returned by [KernelHeap::allocate_first_fit].
+    ///
+    /// Unsafe because the memory must not be used after it is freed.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the memory was not allocated from this heap with the same layout.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let order = order_of(&layout).expect("Layout is too large to have been allocated!");
+        let addr = ptr.as_ptr() as usize;
+
+        assert!(
+            self.forest.deallocate(addr, order),
+            "Memory at {:#x} was not allocated with this layout!",
+            addr
+        );
+    }
+
+    /// The address the heap begins at
+    pub fn bottom(&self) -> usize {
+        self.bottom
+    }
+
+    /// The size of the heap, including memory around its edges which cannot be allocated
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The bytes of the blocks which are allocated, including the waste from rounding up layouts
+    pub fn used(&self) -> usize {
+        self.forest.usage().used_bytes()
+    }
+
+    /// The bytes of the blocks which are free
+    pub fn free(&self) -> usize {
+        self.forest.managed_bytes() - self.used()
+    }
+}
+
+/// The order of the blocks which fit `layout`, or `None` if it is larger than a block of
+/// [MAX_ORDER].
+fn order_of(layout: &Layout) -> Option<u8> {
+    config::order_ceil(cmp::max(layout.size(), layout.align()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use geometry::size_of_order;
+    use testing::BlockSet;
+    use {BASE_ORDER, MAX_ORDER, MAX_ORDER_SIZE};
+
+    const HEAP_BOTTOM: usize = 0x4444_0000;
+    const HEAP_SIZE: usize = 16 << 20;
+
+    #[test]
+    fn test_layout_orders() {
+        let order = |size, align| order_of(&Layout::from_size_align(size, align).unwrap());
+        assert_eq!(order(0, 1), Some(0));
+        assert_eq!(order(24, 8), Some(0));
+        assert_eq!(order(1 << BASE_ORDER, 8), Some(0));
+        assert_eq!(order((1 << BASE_ORDER) + 1, 8), Some(1));
+        assert_eq!(order(64, 1 << (BASE_ORDER + 3)), Some(3));
+        assert_eq!(order(1 << MAX_ORDER_SIZE, 8), Some(MAX_ORDER));
+        assert_eq!(order((1 << MAX_ORDER_SIZE) + 1, 8), None);
+    }
+
+    #[test]
+    fn test_replay_kernel_trace() {
+        let trace = include_str!("../testdata/kernel_heap_trace.txt");
+        let mut heap = unsafe { KernelHeap::new(HEAP_BOTTOM, HEAP_SIZE) };
+        assert_eq!(heap.free(), HEAP_SIZE);
+
+        let mut live: HashMap<u32, (NonNull<u8>, Layout)> = HashMap::new();
+        let mut allocated = BlockSet::new();
+        let mut peak_used = 0;
+
+        for line in trace.lines() {
+            let line = line.split('#').next().unwrap();
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            match words[..] {
+                ["alloc", id, size, align] => {
+                    let (size, align) = (size.parse().unwrap(), align.parse().unwrap());
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    let ptr = heap.allocate_first_fit(layout).unwrap();
+                    let addr = ptr.as_ptr() as usize;
+                    let block_size = size_of_order(order_of(&layout).unwrap());
+
+                    assert_eq!(addr % align, 0, "{:#x} is not aligned for {:?}", addr, layout);
+                    assert!(addr >= HEAP_BOTTOM && addr + block_size <= HEAP_BOTTOM + HEAP_SIZE);
+                    assert!(allocated.insert(addr, block_size), "{:#x} overlaps", addr);
+                    assert!(live.insert(id.parse().unwrap(), (ptr, layout)).is_none());
+                    peak_used = cmp::max(peak_used, heap.used());
+                }
+                ["free", id] => {
+                    let (ptr, layout) = live.remove(&id.parse().unwrap()).unwrap();
+                    assert!(allocated.remove(ptr.as_ptr() as usize));
+                    unsafe { heap.deallocate(ptr, layout) };
+                }
+                [] => {}
+                _ => panic!("Unknown trace line {:?}", line),
+            }
+        }
+
+        assert!(live.is_empty());
+        assert!(peak_used > 0);
+        assert_eq!(heap.used(), 0);
+        assert_eq!(heap.free(), HEAP_SIZE);
+    }
+
+    #[test]
+    fn test_heap_edges() {
+        // Unaligned edges and the null page are not handed out
+        let mut heap = unsafe { KernelHeap::new(0, 0x3800) };
+        assert_eq!(heap.free(), 0x2000);
+        let layout = Layout::from_size_align(4096, 4096).unwrap();
+        let first = heap.allocate_first_fit(layout).unwrap();
+        let second = heap.allocate_first_fit(layout).unwrap();
+        assert_eq!(heap.allocate_first_fit(layout), Err(()));
+
+        let mut addresses = [first.as_ptr() as usize, second.as_ptr() as usize];
+        addresses.sort();
+        assert_eq!(addresses, [0x1000, 0x2000]);
+
+        let mut empty = KernelHeap::empty();
+        assert_eq!(empty.allocate_first_fit(layout), Err(()));
+        assert_eq!(empty.free(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "already been initialized")]
+    fn test_double_init() {
+        unsafe {
+            let mut heap = KernelHeap::new(HEAP_BOTTOM, HEAP_SIZE);
+            heap.init(HEAP_BOTTOM, HEAP_SIZE);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not allocated with this layout")]
+    fn test_deallocate_wrong_layout() {
+        let mut heap = unsafe { KernelHeap::new(HEAP_BOTTOM, HEAP_SIZE) };
+        let ptr = heap.allocate_first_fit(Layout::from_size_align(16, 8).unwrap()).unwrap();
+        unsafe { heap.deallocate(ptr, Layout::from_size_align(8192, 8).unwrap()) };
+    }
+}
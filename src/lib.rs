@@ -1,17 +1,4 @@
-#![feature(const_fn)]
-#![feature(nll)]
-#![feature(slice_patterns)]
-#![feature(duration_extras)]
-#![feature(arbitrary_self_types)]
-#![feature(test)]
-#![feature(integer_atomics)]
-#![feature(box_syntax)]
-#![feature(custom_attribute)]
-#![feature(plugin)]
-#![cfg_attr(feature = "flame_profile", plugin(flamer))]
-
 extern crate array_init;
-extern crate test;
 #[macro_use]
 extern crate static_assertions;
 #[macro_use]
@@ -19,17 +6,61 @@ extern crate intrusive_collections;
 extern crate bit_field;
 #[cfg(feature = "flame_profile")]
 extern crate flame;
+#[cfg(feature = "multiboot2")]
+extern crate multiboot2;
+#[cfg(feature = "x86_64")]
+extern crate x86_64;
+#[cfg(feature = "rayon")]
+extern crate rayon;
 
+pub mod atomic_tree;
+pub mod buddy;
 pub mod buddy_allocator_bitmap;
 pub mod buddy_allocator_lists;
 pub mod buddy_allocator_tree;
+pub mod config;
+pub mod dump;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "x86_64")]
+pub mod frame_allocator;
+pub mod geometry;
+// The golden addresses depend on the size of a top level block
+#[cfg(not(feature = "large_config"))]
+mod golden;
+pub mod kernel_heap;
+pub mod locked;
+pub mod mem_map;
+pub mod metrics;
+#[cfg(feature = "metrics-export")]
+pub mod metrics_export;
+pub mod numa;
+pub mod observer;
+pub mod rc_frames;
+pub mod self_test;
+pub mod shared_forest;
+pub mod snapshot;
+pub mod stats;
+pub mod steady_state;
+pub mod testing;
+pub mod workload;
+
+use std::mem;
+use std::time::Duration;
+use std::vec;
 
 /// Number of orders. **This constant is OK to modify for configuration.**
+#[cfg(not(feature = "large_config"))]
 pub const LEVEL_COUNT: u8 = 19;
+/// Number of orders when testing close to the limits of the representations: the largest block is
+/// 8 GiB, so addresses no longer fit in 32 bits.
+#[cfg(feature = "large_config")]
+pub const LEVEL_COUNT: u8 = 22;
 /// The maximum order. **This constant is not Ok to modify for configuration.**
 pub const MAX_ORDER: u8 = LEVEL_COUNT - 1;
-/// The minimum order. All orders are in context of this -- i.e the size of a block of order `k` is
-/// `2^(k + MIN_ORDER)`, not `2^k`. **This constant is OK to modify for configuration.**
+/// The base order. All orders are in context of this -- i.e the size of a block of order `k` is
+/// `2^(k + BASE_ORDER)`, not `2^k`, which [geometry::size_of_order] computes. **This constant is
+/// OK to modify for configuration.**
 ///
 /// # Note
 ///
@@ -39,10 +70,143 @@ pub const BASE_ORDER: u8 = 12;
 const_assert!(__min_order_less_or_eq_than_4kib; BASE_ORDER <= 12);
 /// The size as a power of two of the maximum order.
 pub const MAX_ORDER_SIZE: u8 = BASE_ORDER + MAX_ORDER;
+// The constants are defined in terms of each other, so that changing one cannot leave the others
+// stale
+const_assert!(__one_level_per_order; MAX_ORDER + 1 == LEVEL_COUNT);
+const_assert!(__max_order_size_is_sum; MAX_ORDER_SIZE == BASE_ORDER + MAX_ORDER);
+// geometry::size_of_order computes block sizes as `1 << (order + BASE_ORDER)` in a usize
+const_assert!(__max_order_size_fits_usize; (MAX_ORDER_SIZE as usize) < mem::size_of::<usize>() * 8);
+
+/// The operations every allocator in the workshop supports, so that the same tests and tools can
+/// drive any of them. Addresses are in bytes.
+pub trait BuddyAllocatorApi {
+    /// Give the allocator a new block of [MAX_ORDER] beginning at `begin_address`, which must be
+    /// aligned to the size of a block of [MAX_ORDER].
+    fn create_top_level(&mut self, begin_address: usize);
+
+    /// Allocate a block of exactly the given order and return its address. Returns `None` if no
+    /// block could be allocated, including if the order is larger than [MAX_ORDER].
+    fn allocate(&mut self, order: u8) -> Option<usize>;
+
+    /// Free the block of the given order beginning at `address`. Returns `false` if the allocator
+    /// found that no used block of that order begins there, in which case nothing is freed.
+    fn deallocate(&mut self, address: usize, order: u8) -> bool;
+
+    /// Split free blocks ahead of time until at least `count` blocks of `order` are free, so that
+    /// the first allocations from new memory don't pay for every split down from [MAX_ORDER].
+    /// Nothing is allocated. Allocators which have nothing to split ahead of time do nothing.
+    fn warm_up(&mut self, order: u8, count: usize);
+
+    /// Allocate `n` blocks of order 0 together and return their addresses, in the order they were
+    /// allocated. Either all of them are allocated or none are: if fewer than `n` can be, the
+    /// blocks allocated on the way are freed again, which merges every split back.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the allocator does not accept one of the blocks it just allocated back.
+    fn alloc_pages(&mut self, n: usize) -> Result<PageIter, AllocError> {
+        let mut pages = Vec::new();
+
+        while pages.len() < n {
+            match self.allocate(0) {
+                Some(addr) => pages.push(addr),
+                None => {
+                    let available = pages.len();
+                    for addr in pages.into_iter().rev() {
+                        assert!(self.deallocate(addr, 0), "Page {:#x} could not be freed!", addr);
+                    }
+
+                    return Err(AllocError { requested: n, available });
+                }
+            }
+        }
+
+        Ok(PageIter::new(pages))
+    }
+}
+
+/// The addresses of the blocks of order 0 allocated by [BuddyAllocatorApi::alloc_pages]
+#[derive(Debug, Clone)]
+pub struct PageIter {
+    pages: vec::IntoIter<usize>,
+}
+
+impl PageIter {
+    fn new(pages: Vec<usize>) -> Self {
+        PageIter { pages: pages.into_iter() }
+    }
+}
+
+impl Iterator for PageIter {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        self.pages.next()
+    }
 
-trait PhysicalAllocator {
-    fn alloc(&mut self, size: PageSize) -> *const u8;
-    fn dealloc(&mut self, addr: *const u8);
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.pages.size_hint()
+    }
+}
+
+impl ExactSizeIterator for PageIter {}
+
+/// Fewer blocks of order 0 could be allocated than [BuddyAllocatorApi::alloc_pages] was asked
+/// for, so none were.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AllocError {
+    pub requested: usize,
+    /// How many blocks of order 0 could have been allocated
+    pub available: usize,
+}
+
+/// How an allocator chooses between the free blocks which could satisfy an allocation.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AllocationPolicy {
+    /// Whichever block the allocator's free structures give up first, which is the fastest to
+    /// find. Which block that is differs between the allocators and the kinds of their lists.
+    FirstFound,
+    /// The block at the lowest address which has room for the order, so that every allocator hands
+    /// out the same addresses for the same sequence of requests, e.g. for reproducible boot
+    /// layouts. The lists and rb-tree allocators search all of their free blocks to find it.
+    ///
+    /// Free buddies split ahead of time by [BuddyAllocatorApi::warm_up] are not merged until one
+    /// of them is allocated and freed, so the addresses are only the same without warming up.
+    Deterministic,
+}
+
+impl Default for AllocationPolicy {
+    fn default() -> Self {
+        AllocationPolicy::FirstFound
+    }
+}
+
+/// An allocator which hands out pages of the sizes of [PageSize], as blocks of the order which
+/// [BuddyConfig::order_for](config::BuddyConfig::order_for) gives for them.
+pub trait PhysicalAllocator {
+    /// The configuration the allocator was built with, which decides the order pages are
+    /// allocated as.
+    fn config(&self) -> config::BuddyConfig;
+    /// Allocate a page of the given size, which is aligned to its size.
+    fn alloc(&mut self, size: PageSize) -> Result<*const u8, PageAllocError>;
+    /// Free the page beginning at `addr`. Fails if no page was allocated at `addr`, freeing
+    /// nothing.
+    fn dealloc(&mut self, addr: *const u8) -> Result<(), buddy_allocator_bitmap::FreeError>;
+}
+
+/// Why [PhysicalAllocator::alloc] could not allocate a page
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PageAllocError {
+    /// Pages of the size are larger than a block of the largest order of the configuration.
+    Unsupported { size: PageSize },
+    /// No block the size of the page is free.
+    OutOfMemory { size: PageSize },
+}
+
+impl From<config::UnsupportedPageSize> for PageAllocError {
+    fn from(err: config::UnsupportedPageSize) -> Self {
+        PageAllocError::Unsupported { size: err.size }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -63,6 +227,53 @@ impl PageSize {
     }
 }
 
+/// An error which caused a demo to stop before finishing all of its allocations
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DemoError {
+    /// The allocator had no blocks left. `allocation` is the (0 indexed) number of the allocation
+    /// which failed.
+    OutOfBlocks { allocation: u32 },
+    /// The order of blocks to allocate was larger than the maximum order.
+    OrderTooLarge { order: u8, max_order: u8 },
+    /// The demo's allocator cannot be built with the configured base order.
+    UnsupportedBaseOrder { base_order: u8 },
+}
+
+/// What a demo run with a [BuddyConfig](config::BuddyConfig) did.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DemoReport {
+    /// How long the allocations took
+    pub duration: Duration,
+    /// The bytes allocated by the demo
+    pub allocated_bytes: usize,
+    /// The bytes of every top level block created for the demo
+    pub managed_bytes: usize,
+}
+
+/// What a demo run for a fixed duration rather than a fixed number of blocks did.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DurationReport {
+    /// The allocations and frees performed
+    pub operations: u64,
+    /// How long they took, which is at least the duration asked for
+    pub elapsed: Duration,
+}
+
+impl DurationReport {
+    pub fn ops_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + f64::from(self.elapsed.subsec_nanos()) / 1e9;
+        self.operations as f64 / secs
+    }
+}
+
+/// A region could not be removed from an allocator because it is not a single free top level
+/// block: some of it is allocated, its free blocks have not all merged back, or no region begins at
+/// `begin_address` at all.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RegionBusy {
+    pub begin_address: usize,
+}
+
 pub fn top_level_blocks(blocks: u32, block_size: u8) -> u64 {
     let a = 2f64.powi(i32::from(block_size + BASE_ORDER)) * f64::from(blocks)
         / 2f64.powi(i32::from(MAX_ORDER + BASE_ORDER));
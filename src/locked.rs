@@ -0,0 +1,291 @@
+//! A spinlock around an allocator, so that one allocator can be shared from a `static` without
+//! depending on a lock crate or lazy initialization.
+
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{self, AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Instant;
+use buddy_allocator_bitmap::{FreeError, Tree};
+use {DemoError, DurationReport, MAX_ORDER};
+
+/// An allocator which can only be used by one caller at a time.
+pub struct Locked<A> {
+    locked: AtomicBool,
+    inner: UnsafeCell<A>,
+}
+
+// The lock hands out access to the allocator to one thread at a time
+unsafe impl<A: Send> Sync for Locked<A> {}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            locked: AtomicBool::new(false),
+            inner: UnsafeCell::new(inner),
+        }
+    }
+
+    /// Spin until the allocator is free, then lock it until the guard is dropped.
+    pub fn lock(&self) -> LockedGuard<A> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+
+            while self.locked.load(Ordering::Relaxed) {
+                atomic::spin_loop_hint();
+            }
+        }
+    }
+
+    /// Lock the allocator if no one else has it locked.
+    pub fn try_lock(&self) -> Option<LockedGuard<A>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| LockedGuard { lock: self })
+    }
+
+    /// The allocator, without locking as the borrow is already unique.
+    pub fn get_mut(&mut self) -> &mut A {
+        unsafe { &mut *self.inner.get() }
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner.into_inner()
+    }
+}
+
+/// Access to a locked allocator, which is unlocked when the guard is dropped.
+pub struct LockedGuard<'a, A: 'a> {
+    lock: &'a Locked<A>,
+}
+
+impl<'a, A> Deref for LockedGuard<'a, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<'a, A> DerefMut for LockedGuard<'a, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+impl<'a, A> Drop for LockedGuard<'a, A> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// A bitmap tree shared between threads, each operation taking the lock for only as long as it
+/// runs. Other operations can be reached through [LockedTree::lock].
+pub struct LockedTree(Locked<Tree>);
+
+impl LockedTree {
+    pub const fn new(tree: Tree) -> Self {
+        LockedTree(Locked::new(tree))
+    }
+
+    /// Allocate a block of the given order like [Tree::alloc_exact].
+    pub fn alloc_exact(&self, desired_order: u8) -> Option<*const u8> {
+        self.0.lock().alloc_exact(desired_order)
+    }
+
+    /// Free the used block beginning at `addr` like [Tree::dealloc].
+    pub fn dealloc(&self, addr: *const u8) -> Result<(), FreeError> {
+        self.0.lock().dealloc(addr)
+    }
+
+    /// Lock the tree until the guard is dropped.
+    pub fn lock(&self) -> LockedGuard<Tree> {
+        self.0.lock()
+    }
+
+    pub fn into_inner(self) -> Tree {
+        self.0.into_inner()
+    }
+}
+
+/// Split `blocks` allocations of the given order between `threads` threads, which all allocate
+/// from one [LockedTree] at once, then free their blocks. Reports the allocations and how long
+/// they took, from the moment every thread was ready until the last finished allocating, so that
+/// the cost of contention shows in the allocations per second.
+pub fn demo_threads(threads: usize, blocks: u32, order: u8) -> Result<DurationReport, DemoError> {
+    assert!(threads > 0, "At least one thread must allocate!");
+
+    if order > MAX_ORDER {
+        return Err(DemoError::OrderTooLarge { order, max_order: MAX_ORDER });
+    }
+
+    // One tree holds the whole demo, so rather than failing part way stop before starting
+    let capacity = 1u64 << (MAX_ORDER - order);
+    if u64::from(blocks) > capacity {
+        return Err(DemoError::OutOfBlocks { allocation: capacity as u32 });
+    }
+
+    let tree = Arc::new(LockedTree::new(Tree::new_at(0)));
+    let start = Arc::new(Barrier::new(threads + 1));
+    let finish = Arc::new(Barrier::new(threads + 1));
+
+    let handles: Vec<_> = (0..threads)
+        .map(|thread_number| {
+            // The first threads allocate the remainder
+            let share =
+                blocks as usize / threads + (thread_number < blocks as usize % threads) as usize;
+            let (tree, start, finish) = (tree.clone(), start.clone(), finish.clone());
+
+            thread::spawn(move || {
+                let mut addresses = Vec::with_capacity(share);
+                start.wait();
+                for _ in 0..share {
+                    addresses.push(tree.alloc_exact(order).unwrap());
+                }
+                finish.wait();
+
+                for addr in addresses {
+                    tree.dealloc(addr).unwrap();
+                }
+            })
+        })
+        .collect();
+
+    start.wait();
+    let began = Instant::now();
+    finish.wait();
+    let elapsed = began.elapsed();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(DurationReport {
+        operations: u64::from(blocks),
+        elapsed,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buddy_allocator_bitmap::{Tree, TreeInitError};
+    use stats::AllocatorStats;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use {BASE_ORDER, LEVEL_COUNT, MAX_ORDER_SIZE};
+
+    static ALLOCATOR: Locked<Tree> = Locked::new(Tree::empty());
+    static SHARED: Locked<Tree> = Locked::new(Tree::empty());
+
+    #[test]
+    fn test_static_init_later() {
+        {
+            // Before initialization nothing can be allocated or freed
+            let mut tree = ALLOCATOR.lock();
+            assert!(!tree.is_initialized());
+            assert_eq!(tree.alloc_exact(0), None);
+            assert!(!tree.dealloc_exact(0 as *const u8, 0));
+            assert_eq!(tree.managed_bytes(), 0);
+        }
+
+        let base_address = 1 << MAX_ORDER_SIZE;
+        assert_eq!(ALLOCATOR.lock().init(base_address, LEVEL_COUNT), Ok(()));
+
+        let addr = ALLOCATOR.lock().alloc_exact(3).unwrap();
+        assert_eq!(addr as usize, base_address);
+        assert_eq!(
+            ALLOCATOR.lock().init(0, LEVEL_COUNT),
+            Err(TreeInitError::AlreadyInitialized)
+        );
+
+        // The rejected initialization left the tree as it was
+        let mut tree = ALLOCATOR.lock();
+        assert_eq!(tree.usage().used_bytes(), 8 << BASE_ORDER);
+        assert!(tree.dealloc_exact(addr, 3));
+    }
+
+    #[test]
+    fn test_locked_across_threads() {
+        SHARED.lock().init(0, LEVEL_COUNT).unwrap();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(|| {
+                    for _ in 0..1000 {
+                        let addr = SHARED.lock().alloc_exact(0).unwrap();
+                        assert!(SHARED.lock().dealloc_exact(addr, 0));
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let tree = SHARED.lock();
+        assert_eq!(tree.usage().outstanding_allocations(), 0);
+        assert_eq!(tree.op_counters().allocations[0], 4000);
+    }
+
+    #[test]
+    fn test_try_lock() {
+        let mut locked = Locked::new(Tree::empty());
+        {
+            let _guard = locked.lock();
+            assert!(locked.try_lock().is_none());
+        }
+        assert!(locked.try_lock().is_some());
+        assert!(!locked.get_mut().is_initialized());
+    }
+
+    #[test]
+    fn test_locked_tree_unique_across_threads() {
+        let tree = Arc::new(LockedTree::new(Tree::new_at(0)));
+        let seen = Arc::new(Mutex::new(HashSet::new()));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (tree, seen) = (tree.clone(), seen.clone());
+                thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        let addr = tree.alloc_exact(0).unwrap() as usize;
+                        assert!(seen.lock().unwrap().insert(addr), "{:#x} handed out twice", addr);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let seen = Arc::try_unwrap(seen).unwrap().into_inner().unwrap();
+        assert_eq!(seen.len(), 40_000);
+        for &addr in &seen {
+            assert_eq!(tree.dealloc(addr as *const u8), Ok(()));
+        }
+        assert_eq!(tree.lock().usage().outstanding_allocations(), 0);
+    }
+
+    #[test]
+    fn test_demo_threads() {
+        let report = demo_threads(3, 1000, 2).unwrap();
+        assert_eq!(report.operations, 1000);
+
+        let capacity = 1 << (MAX_ORDER - 2);
+        assert_eq!(
+            demo_threads(3, capacity + 1, 2),
+            Err(DemoError::OutOfBlocks { allocation: capacity })
+        );
+        assert_eq!(
+            demo_threads(1, 1, MAX_ORDER + 1),
+            Err(DemoError::OrderTooLarge { order: MAX_ORDER + 1, max_order: MAX_ORDER })
+        );
+    }
+}
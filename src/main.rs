@@ -1,9 +1,3 @@
-#![feature(custom_attribute)]
-#![feature(plugin)]
-#![feature(nll)]
-#![cfg_attr(feature = "flame_profile", plugin(flamer))]
-#![allow(unused_attributes)]
-
 extern crate buddy_allocator_workshop;
 
 #[macro_use]
@@ -12,10 +6,15 @@ extern crate structopt;
 extern crate flame;
 #[macro_use]
 extern crate failure;
+#[cfg(feature = "metrics-export")]
+extern crate intrusive_collections;
 
 use buddy_allocator_workshop::*;
+use buddy_allocator_workshop::config::{BuddyConfig, ConfigError};
 use failure::Fail;
 use structopt::StructOpt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 
 const DEFAULT_DEMOS: &[&str] = &[
@@ -33,17 +32,57 @@ struct Options {
     /// such should not be used for benchmarking.
     #[structopt(short = "p", long = "print-addresses")]
     print_addresses: bool,
-    /// Which demos to run. Defaults to all demos. Accepted values: `vecs`, `linked_lists`,
-    /// `rb_tree_vecs`, `rb_tree_linked_lists`.
+    /// Which demos to run. Defaults to all demos but `locked_bitmap`. Accepted values: `vecs`,
+    /// `linked_lists`, `rb_tree_vecs`, `rb_tree_linked_lists`, `bitmap`, `locked_bitmap`. The
+    /// blocks and order of one run can be overridden as in `bitmap:blocks=1000000,order=9`, so a
+    /// demo can be run more than once.
     #[structopt(short = "d", long = "demos")]
-    demos: Vec<String>,
+    demos: Vec<RunSpec>,
     /// How many blocks to demo allocate. Defaults to 100 000
     #[structopt(short = "b", long = "blocks")]
     blocks: Option<u32>,
-    /// The order of the blocks to allocate. Defaults to `0`, which is `2^MIN_ORDER` bytes. Must not
-    /// be greater than `MAX_ORDER`.
+    /// The order of the blocks to allocate. Defaults to `0`, which is `2^BASE_ORDER` bytes. Must
+    /// not be greater than `MAX_ORDER`, or one less than the configured levels.
     #[structopt(short = "o", long = "order")]
     order: Option<u8>,
+    /// Build each allocator once and reuse it for every run, freeing the blocks of one run before
+    /// the next. Reports the time of each run, so that slowdowns as the allocator is reused show.
+    #[structopt(long = "steady-state")]
+    steady_state: bool,
+    /// The base order of the allocators, so that blocks of order 0 are `2^base-order` bytes.
+    /// Defaults to `BASE_ORDER`. Must be at least 12. Only the `bitmap` demo can be configured.
+    #[structopt(long = "base-order")]
+    base_order: Option<u8>,
+    /// How many orders the allocators have. Defaults to `LEVEL_COUNT`, which is also the most
+    /// allowed. Only the `bitmap` demo can be configured.
+    #[structopt(long = "levels")]
+    levels: Option<u8>,
+    /// Write the metrics of the allocators in the Prometheus text format to this file after each
+    /// demo, replacing it with the metrics of every demo run so far. Requires the `metrics-export`
+    /// feature, and cannot be combined with `--steady-state`, `--base-order` or `--levels`.
+    #[structopt(long = "metrics-out", parse(from_os_str))]
+    metrics_out: Option<PathBuf>,
+    /// Rather than allocating a fixed number of blocks, keep freeing and allocating them again for
+    /// this long, e.g. `500ms`, `2s` or `1m`, and report how many operations were performed per
+    /// second. Cannot be combined with `--base-order` or `--levels`.
+    #[structopt(long = "duration", parse(try_from_str = "parse_duration"))]
+    duration: Option<Duration>,
+    /// How many threads the `locked_bitmap` demo shares its one tree between, splitting the blocks
+    /// among them. Defaults to 4. Only the `locked_bitmap` demo, which can only be run on its own
+    /// and without the other options, uses threads.
+    #[structopt(long = "threads")]
+    threads: Option<usize>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Rather than running demos, check that every allocator agrees with the others, keeps its
+    /// blocks valid and inside its regions, and hands out the golden addresses. Prints a line per
+    /// check and exits with an error if any fail.
+    #[structopt(name = "self-test")]
+    SelfTest,
 }
 
 #[derive(Debug, Fail)]
@@ -53,9 +92,165 @@ enum DemosError {
     #[fail(display = "Order {} too large, max is {}", order, max_order)]
     OrderTooLarge {
         order: u8,
-        /// Must be equal to [MAX_ORDER]. Required as a field due to a limitation in fail.
+        /// The maximum order of the configuration. Required as a field due to a limitation in fail.
         max_order: u8,
     },
+    #[fail(display = "{} demo ran out of blocks on allocation {}", name, allocation)]
+    OutOfBlocks { name: String, allocation: u32 },
+    #[fail(display = "Invalid base order and levels: {:?}", error)]
+    InvalidConfig { error: ConfigError },
+    #[fail(display = "{} demo cannot be run with a configured base order or levels", name)]
+    UnsupportedConfig { name: String },
+    #[fail(display = "{} demo cannot be run with base order {}", name, base_order)]
+    UnsupportedBaseOrder { name: String, base_order: u8 },
+    #[cfg(not(feature = "metrics-export"))]
+    #[fail(display = "Metrics can only be written when built with the metrics-export feature")]
+    MetricsNotBuilt,
+    #[fail(display = "Metrics cannot be written by steady state, duration or configured runs")]
+    MetricsUnsupported,
+    #[cfg(feature = "metrics-export")]
+    #[fail(display = "Could not write metrics to {}: {}", path, error)]
+    MetricsWrite { path: String, error: String },
+    #[fail(display = "--duration cannot be combined with --base-order or --levels")]
+    DurationUnsupported,
+    #[fail(display = "--threads can only be given to the locked_bitmap demo")]
+    ThreadsUnsupported,
+    #[fail(display = "locked_bitmap demo cannot be run in steady state, for a duration, configured \
+                      or with metrics")]
+    LockedBitmapUnsupported,
+    #[fail(display = "--threads must be at least 1")]
+    NoThreads,
+}
+
+/// One run of a demo, with the options it overrides. Parsed from the name of the demo, optionally
+/// followed by a colon and comma separated `key=value` parameters, of which `blocks` and `order`
+/// are accepted.
+#[derive(Debug, Clone, PartialEq)]
+struct RunSpec {
+    name: String,
+    blocks: Option<u32>,
+    order: Option<u8>,
+}
+
+#[derive(Debug, Fail, PartialEq)]
+enum RunSpecError {
+    #[fail(display = "Missing demo name in \"{}\"", spec)]
+    MissingName { spec: String },
+    #[fail(display = "Expected a parameter of the form key=value, found \"{}\"", param)]
+    MalformedParameter { param: String },
+    #[fail(display = "Unknown parameter \"{}\", expected `blocks` or `order`", key)]
+    UnknownKey { key: String },
+    #[fail(display = "Parameter \"{}\" given more than once", key)]
+    DuplicateKey { key: String },
+    #[fail(display = "Invalid value \"{}\" for parameter \"{}\"", value, key)]
+    InvalidValue { key: String, value: String },
+}
+
+impl RunSpec {
+    fn new(name: &str) -> Self {
+        RunSpec {
+            name: name.to_string(),
+            blocks: None,
+            order: None,
+        }
+    }
+
+    /// The name of the demo, followed by the options the run overrides so that runs of the same
+    /// demo can be told apart
+    fn label(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(blocks) = self.blocks {
+            params.push(format!("blocks={}", blocks));
+        }
+        if let Some(order) = self.order {
+            params.push(format!("order={}", order));
+        }
+
+        if params.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", self.name, params.join(", "))
+        }
+    }
+}
+
+impl FromStr for RunSpec {
+    type Err = RunSpecError;
+
+    fn from_str(spec: &str) -> Result<Self, RunSpecError> {
+        let mut parts = spec.splitn(2, ':');
+        let name = parts.next().unwrap();
+        if name.is_empty() {
+            return Err(RunSpecError::MissingName { spec: spec.to_string() });
+        }
+
+        let mut run = RunSpec::new(name);
+        let params = match parts.next() {
+            Some(params) => params,
+            None => return Ok(run),
+        };
+
+        for param in params.split(',') {
+            let mut key_value = param.splitn(2, '=');
+            let (key, value) = match (key_value.next(), key_value.next()) {
+                (Some(key), Some(value)) if !key.is_empty() && !value.is_empty() => (key, value),
+                _ => return Err(RunSpecError::MalformedParameter { param: param.to_string() }),
+            };
+
+            let invalid = || RunSpecError::InvalidValue {
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+            let duplicate = RunSpecError::DuplicateKey { key: key.to_string() };
+
+            match key {
+                "blocks" if run.blocks.is_some() => return Err(duplicate),
+                "order" if run.order.is_some() => return Err(duplicate),
+                "blocks" => run.blocks = Some(value.parse().map_err(|_| invalid())?),
+                "order" => run.order = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(RunSpecError::UnknownKey { key: key.to_string() }),
+            }
+        }
+
+        Ok(run)
+    }
+}
+
+#[derive(Debug, Fail, PartialEq)]
+enum DurationError {
+    #[fail(display = "Expected a duration such as 500ms, 2s or 1m, found \"{}\"", duration)]
+    Malformed { duration: String },
+    #[fail(display = "Unknown unit \"{}\", expected `ms`, `s` or `m`", unit)]
+    UnknownUnit { unit: String },
+    #[fail(display = "Duration must be longer than 0")]
+    Zero,
+}
+
+/// Parse a whole number of milliseconds, seconds or minutes, such as `500ms`, `2s` or `1m`
+fn parse_duration(duration: &str) -> Result<Duration, DurationError> {
+    let malformed = || DurationError::Malformed { duration: duration.to_string() };
+
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(malformed)?;
+    let (number, unit) = duration.split_at(split);
+    if !unit.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(malformed());
+    }
+    let number: u64 = number.parse().map_err(|_| malformed())?;
+
+    let duration = match unit {
+        "ms" => Duration::from_millis(number),
+        "s" => Duration::from_secs(number),
+        "m" => Duration::from_secs(number.checked_mul(60).ok_or_else(malformed)?),
+        _ => return Err(DurationError::UnknownUnit { unit: unit.to_string() }),
+    };
+
+    if duration == Duration::from_secs(0) {
+        return Err(DurationError::Zero);
+    }
+
+    Ok(duration)
 }
 
 fn main() {
@@ -64,45 +259,120 @@ fn main() {
         demos,
         blocks,
         order,
+        steady_state,
+        base_order,
+        levels,
+        metrics_out,
+        duration,
+        threads,
+        command,
     } = Options::from_args();
 
+    if let Some(Command::SelfTest) = command {
+        run_self_test();
+        return;
+    }
+
+    let config = if base_order.is_some() || levels.is_some() {
+        BuddyConfig::new(
+            base_order.unwrap_or(BASE_ORDER),
+            levels.unwrap_or(LEVEL_COUNT),
+        ).map_err(|error| DemosError::InvalidConfig { error })
+            .raise()
+    } else {
+        BuddyConfig::default()
+    };
+
     let demos = if demos.is_empty() {
-        DEFAULT_DEMOS.iter().map(|s| s.to_string()).collect()
+        DEFAULT_DEMOS.iter().map(|name| RunSpec::new(name)).collect()
     } else {
         demos
     };
 
+    // Every configuration has a base order of at least 12, so can always hold a 4 KiB page
     let (blocks, order) = (
         blocks.unwrap_or(100_000),
-        order.unwrap_or(PageSize::Kib4.power_of_two() - BASE_ORDER),
+        order.unwrap_or_else(|| config.order_for(PageSize::Kib4).unwrap()),
     );
 
-    if order > MAX_ORDER {
-        raise(DemosError::OrderTooLarge {
-            order,
-            max_order: MAX_ORDER,
-        });
+    let locked_bitmap = demos.iter().any(|run| run.name == "locked_bitmap");
+    if threads.is_some() && !locked_bitmap {
+        raise(DemosError::ThreadsUnsupported);
+    }
+    let modes_given = metrics_out.is_some() || duration.is_some() || steady_state;
+    if locked_bitmap && (modes_given || config != BuddyConfig::default()) {
+        raise(DemosError::LockedBitmapUnsupported);
+    }
+    let threads = threads.unwrap_or(4);
+    if threads == 0 {
+        raise(DemosError::NoThreads);
+    }
+
+    for run in &demos {
+        let order = run.order.unwrap_or(order);
+        if order > config.max_order() {
+            raise(DemosError::OrderTooLarge {
+                order,
+                max_order: config.max_order(),
+            });
+        }
+    }
+
+    if let Some(path) = metrics_out {
+        if steady_state || duration.is_some() || config != BuddyConfig::default() {
+            raise(DemosError::MetricsUnsupported);
+        }
+
+        run_metrics_demos(&path, demos, print_addresses, blocks, order);
+        flame_dump();
+        return;
+    }
+
+    if let Some(budget) = duration {
+        if config != BuddyConfig::default() {
+            raise(DemosError::DurationUnsupported);
+        }
+
+        run_duration_demos(demos, print_addresses, blocks, order, budget);
+        flame_dump();
+        return;
+    }
+
+    if config != BuddyConfig::default() {
+        run_configured_demos(&config, demos, print_addresses, blocks, order, steady_state);
+        flame_dump();
+        return;
+    }
+
+    if steady_state {
+        run_steady_state_demos(demos, print_addresses, blocks, order);
+        flame_dump();
+        return;
     }
 
     demos
         .into_iter()
-        .map(|name| {
-            (
-                match &*name {
-                    "linked_lists" => buddy_allocator_lists::demo_linked_lists,
-                    "vecs" => buddy_allocator_lists::demo_vecs,
-                    "rb_tree_vecs" => buddy_allocator_tree::demo_vecs,
-                    "rb_tree_linked_lists" => buddy_allocator_tree::demo_linked_lists,
-                    "bitmap" => buddy_allocator_bitmap::demo,
-                    _ => Err(DemosError::UnknownDemo { name: name.to_string() }).raise(),
-                },
-                name
-            )
+        .map(|run| {
+            // The locked bitmap demo takes the thread count, so is run apart from the others
+            let demo: Option<DemoFn> = match &*run.name {
+                "linked_lists" => Some(buddy_allocator_lists::demo_linked_lists),
+                "vecs" => Some(buddy_allocator_lists::demo_vecs),
+                "rb_tree_vecs" => Some(buddy_allocator_tree::demo_vecs),
+                "rb_tree_linked_lists" => Some(buddy_allocator_tree::demo_linked_lists),
+                "bitmap" => Some(buddy_allocator_bitmap::demo),
+                "locked_bitmap" => None,
+                _ => Err(DemosError::UnknownDemo { name: run.name.clone() }).raise(),
+            };
+            (demo, run)
         })
         .collect::<Vec<_>>() // Force detect unknown demos ASAP
         .into_iter()
-        .for_each(|(demo, name)| {
-            run_demo(demo, print_addresses, blocks, order, name)
+        .for_each(|(demo, run)| {
+            let (blocks, order) = (run.blocks.unwrap_or(blocks), run.order.unwrap_or(order));
+            match demo {
+                Some(demo) => run_demo(demo, print_addresses, blocks, order, run.label()),
+                None => run_threads_demo(threads, blocks, order, run.label()),
+            }
         });
 
     flame_dump();
@@ -126,7 +396,25 @@ fn raise<F: Fail>(failure: F) -> ! {
     std::process::exit(1)
 }
 
-fn run_demo(demo: fn(bool, u32, u8) -> Duration, print_addresses: bool, blocks: u32, order: u8, name: String) {
+fn run_self_test() {
+    let stdout = std::io::stdout();
+    let passed = self_test::run_checks(&self_test::checks(), stdout.lock())
+        .expect("Could not write to stdout");
+
+    if !passed {
+        std::process::exit(1)
+    }
+}
+
+type DemoFn = fn(bool, u32, u8) -> Result<Duration, DemoError>;
+
+fn run_demo(
+    demo: DemoFn,
+    print_addresses: bool,
+    blocks: u32,
+    order: u8,
+    name: String,
+) {
     const NANOS_PER_SEC: f64 = 1_000_000_000.0; // Taken from std::time::Duration because las
     const RUN_COUNT: usize = 1;
 
@@ -134,7 +422,9 @@ fn run_demo(demo: fn(bool, u32, u8) -> Duration, print_addresses: bool, blocks:
 
     let mut durations = Vec::with_capacity(RUN_COUNT);
     for _ in 0..RUN_COUNT {
-        durations.push(demo(print_addresses, blocks, order));
+        let duration = demo(print_addresses, blocks, order).map_err(|err| demo_error(err, &name));
+
+        durations.push(duration.raise());
     }
 
     let times_sum: Duration = durations.into_iter().sum();
@@ -146,6 +436,245 @@ fn run_demo(demo: fn(bool, u32, u8) -> Duration, print_addresses: bool, blocks:
     );
 }
 
+fn run_threads_demo(threads: usize, blocks: u32, order: u8, name: String) {
+    println!("Running {} demo on {} threads...", name, threads);
+
+    let report = locked::demo_threads(threads, blocks, order)
+        .map_err(|err| demo_error(err, &name))
+        .raise();
+
+    println!(
+        "Finished {} demo: {} allocations in {}s, {:.0} allocations per second",
+        name.replace('_', " "),
+        report.operations,
+        report.elapsed.as_secs() as f64
+            + f64::from(report.elapsed.subsec_nanos()) / 1_000_000_000.0,
+        report.ops_per_second(),
+    );
+}
+
+fn run_steady_state_demos(demos: Vec<RunSpec>, print_addresses: bool, blocks: u32, order: u8) {
+    const RUN_COUNT: usize = 8;
+
+    demos
+        .into_iter()
+        .map(|run| {
+            (
+                match &*run.name {
+                    "linked_lists" => buddy_allocator_lists::demo_linked_lists_steady_state,
+                    "vecs" => buddy_allocator_lists::demo_vecs_steady_state,
+                    "rb_tree_vecs" => buddy_allocator_tree::demo_vecs_steady_state,
+                    "rb_tree_linked_lists" => buddy_allocator_tree::demo_linked_lists_steady_state,
+                    "bitmap" => buddy_allocator_bitmap::demo_steady_state,
+                    _ => Err(DemosError::UnknownDemo { name: run.name.clone() }).raise(),
+                },
+                run
+            )
+        })
+        .collect::<Vec<_>>() // Force detect unknown demos ASAP
+        .into_iter()
+        .for_each(|(demo, run)| {
+            let (blocks, order) = (run.blocks.unwrap_or(blocks), run.order.unwrap_or(order));
+            let name = run.label();
+            println!("Running {} demo in steady state...", name);
+
+            let durations = demo(print_addresses, blocks, order, RUN_COUNT)
+                .map_err(|err| demo_error(err, &name))
+                .raise();
+
+            for (run, duration) in durations.into_iter().enumerate() {
+                println!(
+                    "Run {} of {} demo took {}s",
+                    run + 1,
+                    name.replace('_', " "),
+                    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0,
+                );
+            }
+        });
+}
+
+fn run_duration_demos(
+    demos: Vec<RunSpec>,
+    print_addresses: bool,
+    blocks: u32,
+    order: u8,
+    budget: Duration,
+) {
+    demos
+        .into_iter()
+        .map(|run| {
+            (
+                match &*run.name {
+                    "linked_lists" => buddy_allocator_lists::demo_linked_lists_for_duration,
+                    "vecs" => buddy_allocator_lists::demo_vecs_for_duration,
+                    "rb_tree_vecs" => buddy_allocator_tree::demo_vecs_for_duration,
+                    "rb_tree_linked_lists" => buddy_allocator_tree::demo_linked_lists_for_duration,
+                    "bitmap" => buddy_allocator_bitmap::demo_for_duration,
+                    _ => Err(DemosError::UnknownDemo { name: run.name.clone() }).raise(),
+                },
+                run
+            )
+        })
+        .collect::<Vec<_>>() // Force detect unknown demos ASAP
+        .into_iter()
+        .for_each(|(demo, run)| {
+            let (blocks, order) = (run.blocks.unwrap_or(blocks), run.order.unwrap_or(order));
+            let name = run.label();
+            println!("Running {} demo for {:?}...", name, budget);
+
+            let report = demo(print_addresses, blocks, order, budget)
+                .map_err(|err| demo_error(err, &name))
+                .raise();
+
+            println!(
+                "Finished {} demo: {} operations in {}s, {:.0} operations per second",
+                name.replace('_', " "),
+                report.operations,
+                report.elapsed.as_secs() as f64
+                    + f64::from(report.elapsed.subsec_nanos()) / 1_000_000_000.0,
+                report.ops_per_second(),
+            );
+        });
+}
+
+fn run_configured_demos(
+    config: &BuddyConfig,
+    demos: Vec<RunSpec>,
+    print_addresses: bool,
+    blocks: u32,
+    order: u8,
+    steady_state: bool,
+) {
+    // Force detect demos which cannot be configured ASAP
+    for run in &demos {
+        match &*run.name {
+            "bitmap" if !steady_state => {}
+            "linked_lists" | "vecs" | "rb_tree_vecs" | "rb_tree_linked_lists" | "bitmap" => {
+                raise(DemosError::UnsupportedConfig { name: run.name.clone() })
+            }
+            _ => raise(DemosError::UnknownDemo { name: run.name.clone() }),
+        }
+    }
+
+    for run in demos {
+        let (blocks, order) = (run.blocks.unwrap_or(blocks), run.order.unwrap_or(order));
+        let name = run.label();
+        println!("Running {} demo...", name);
+
+        let report =
+            buddy_allocator_bitmap::demo_with_config(config, print_addresses, blocks, order)
+                .map_err(|err| demo_error(err, &name))
+                .raise();
+
+        println!(
+            "Finished {} demo in {}s, allocating {} of {} bytes",
+            name.replace('_', " "),
+            report.duration.as_secs() as f64
+                + f64::from(report.duration.subsec_nanos()) / 1_000_000_000.0,
+            report.allocated_bytes,
+            report.managed_bytes,
+        );
+    }
+}
+
+/// Run each demo once, as a single steady state run so that the allocator is kept afterwards, and
+/// write the metrics of every allocator so far to `path` after each demo.
+#[cfg(feature = "metrics-export")]
+fn run_metrics_demos(
+    path: &Path,
+    demos: Vec<RunSpec>,
+    print_addresses: bool,
+    blocks: u32,
+    order: u8,
+) {
+    use buddy_allocator_lists::{self as lists, BuddyAllocator as ListsAllocator};
+    use buddy_allocator_tree::{self as tree, BlockPtrAdapter, BuddyAllocator as TreeAllocator};
+    use buddy_allocator_bitmap::Forest;
+    use intrusive_collections::SinglyLinkedList;
+    use metrics_export::Metrics;
+    use std::collections::LinkedList;
+    use std::fs;
+
+    // Force detect unknown demos ASAP
+    for run in &demos {
+        if !DEFAULT_DEMOS.contains(&&*run.name) {
+            raise(DemosError::UnknownDemo { name: run.name.clone() });
+        }
+    }
+
+    let mut metrics = Metrics::new();
+
+    for run in demos {
+        let (blocks, order) = (run.blocks.unwrap_or(blocks), run.order.unwrap_or(order));
+        let name = run.label();
+        println!("Running {} demo...", name);
+
+        let args = (&*name, print_addresses, blocks, order);
+        let (duration, run_metrics) = match &*run.name {
+            "linked_lists" => metrics_demo(ListsAllocator::<LinkedList<lists::Block>>::new(), args),
+            "vecs" => metrics_demo(ListsAllocator::<Vec<lists::Block>>::new(), args),
+            "rb_tree_vecs" => metrics_demo(TreeAllocator::<Vec<*const tree::Block>>::new(), args),
+            "rb_tree_linked_lists" => {
+                metrics_demo(TreeAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new(), args)
+            }
+            "bitmap" => metrics_demo(Forest::new(), args),
+            _ => unreachable!(),
+        }.map_err(|err| demo_error(err, &name))
+            .raise();
+
+        println!(
+            "Finished {} demo in {}s",
+            name.replace('_', " "),
+            duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0,
+        );
+
+        // The file is rewritten rather than appended to, as each family must only appear once
+        metrics.extend(run_metrics);
+        let mut text = Vec::new();
+        metrics
+            .encode(&mut text)
+            .and_then(|()| fs::write(path, text))
+            .map_err(|error| DemosError::MetricsWrite {
+                path: path.display().to_string(),
+                error: error.to_string(),
+            })
+            .raise();
+    }
+}
+
+/// Allocate the blocks of one demo run from `allocator`, returning how long it took and the
+/// metrics of the allocator afterwards, labelled with the name of the run.
+#[cfg(feature = "metrics-export")]
+fn metrics_demo<A: BuddyAllocatorApi + stats::AllocatorStats>(
+    allocator: A,
+    (name, print_addresses, blocks, order): (&str, bool, u32, u8),
+) -> Result<(Duration, metrics_export::Metrics), DemoError> {
+    let mut demo = steady_state::SteadyStateDemo::new(allocator, blocks, order)?;
+    let duration = demo.run(print_addresses)?;
+    Ok((duration, metrics_export::Metrics::of(demo.allocator(), name)))
+}
+
+#[cfg(not(feature = "metrics-export"))]
+fn run_metrics_demos(_: &Path, _: Vec<RunSpec>, _: bool, _: u32, _: u8) {
+    raise(DemosError::MetricsNotBuilt)
+}
+
+fn demo_error(err: DemoError, name: &str) -> DemosError {
+    match err {
+        DemoError::OutOfBlocks { allocation } => DemosError::OutOfBlocks {
+            name: name.to_string(),
+            allocation,
+        },
+        DemoError::OrderTooLarge { order, max_order } => {
+            DemosError::OrderTooLarge { order, max_order }
+        }
+        DemoError::UnsupportedBaseOrder { base_order } => DemosError::UnsupportedBaseOrder {
+            name: name.to_string(),
+            base_order,
+        },
+    }
+}
+
 #[cfg(feature = "flame_profile")]
 fn flame_dump() {
     use std::fs::File;
@@ -154,3 +683,91 @@ fn flame_dump() {
 
 #[cfg(not(feature = "flame_profile"))]
 fn flame_dump() {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+
+        let malformed = |duration: &str| {
+            Err(DurationError::Malformed { duration: duration.to_string() })
+        };
+        for duration in &["", "5", "s", "-1s", "1.5s", " 2s", "2 s", "2s "] {
+            assert_eq!(parse_duration(duration), malformed(duration));
+        }
+        assert_eq!(parse_duration("99999999999999999999s"), malformed("99999999999999999999s"));
+        assert_eq!(parse_duration("18446744073709551615m"), malformed("18446744073709551615m"));
+
+        let unknown = |unit: &str| Err(DurationError::UnknownUnit { unit: unit.to_string() });
+        assert_eq!(parse_duration("2h"), unknown("h"));
+        assert_eq!(parse_duration("2S"), unknown("S"));
+        assert_eq!(parse_duration("0s"), Err(DurationError::Zero));
+        assert_eq!(parse_duration("0ms"), Err(DurationError::Zero));
+    }
+
+    #[test]
+    fn test_parse_run_spec() {
+        assert_eq!("bitmap".parse(), Ok(RunSpec::new("bitmap")));
+        assert_eq!(
+            "bitmap:blocks=1000000,order=9".parse(),
+            Ok(RunSpec {
+                name: "bitmap".to_string(),
+                blocks: Some(1_000_000),
+                order: Some(9),
+            })
+        );
+        assert_eq!(
+            "vecs:order=3".parse(),
+            Ok(RunSpec { order: Some(3), ..RunSpec::new("vecs") })
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_run_spec() {
+        fn parse(spec: &str) -> Result<RunSpec, RunSpecError> {
+            spec.parse()
+        }
+
+        let spec = |spec: &str| spec.to_string();
+        assert_eq!(parse(""), Err(RunSpecError::MissingName { spec: spec("") }));
+        assert_eq!(parse(":order=1"), Err(RunSpecError::MissingName { spec: spec(":order=1") }));
+
+        for &param in &["", "order", "order=", "=1"] {
+            assert_eq!(
+                parse(&format!("bitmap:{}", param)),
+                Err(RunSpecError::MalformedParameter { param: spec(param) })
+            );
+        }
+        assert_eq!(
+            parse("bitmap:order=1,"),
+            Err(RunSpecError::MalformedParameter { param: spec("") })
+        );
+
+        assert_eq!(parse("bitmap:size=1"), Err(RunSpecError::UnknownKey { key: spec("size") }));
+        assert_eq!(
+            parse("bitmap:order=1,order=2"),
+            Err(RunSpecError::DuplicateKey { key: spec("order") })
+        );
+        assert_eq!(
+            parse("bitmap:order=256"),
+            Err(RunSpecError::InvalidValue { key: spec("order"), value: spec("256") })
+        );
+        assert_eq!(
+            parse("bitmap:blocks=-1"),
+            Err(RunSpecError::InvalidValue { key: spec("blocks"), value: spec("-1") })
+        );
+    }
+
+    #[test]
+    fn test_run_spec_label() {
+        assert_eq!(RunSpec::new("rb_tree_vecs").label(), "rb_tree_vecs");
+        let run: RunSpec = "bitmap:order=9,blocks=1000".parse().unwrap();
+        assert_eq!(run.label(), "bitmap (blocks=1000, order=9)");
+    }
+}
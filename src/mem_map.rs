@@ -0,0 +1,302 @@
+//! Turning a firmware memory map into the regions an allocator can manage.
+
+use std::cmp;
+#[cfg(any(feature = "multiboot2", feature = "bootinfo"))]
+use buddy_allocator_bitmap::Forest;
+#[cfg(feature = "multiboot2")]
+use multiboot2::{MemoryAreaType, MemoryMapTag};
+use geometry::size_of_order;
+use super::{BASE_ORDER, MAX_ORDER};
+
+/// One entry of a memory map, as reported by the firmware. Entries may overlap, be unaligned or
+/// lie outside of the address space the allocators can represent.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MemRegion {
+    pub start: u64,
+    pub len: u64,
+    /// Whether the memory is available RAM. Memory which is not usable wins over usable memory it
+    /// overlaps.
+    pub usable: bool,
+}
+
+/// The address after the last byte of the highest top level block which fits in the address
+/// space, so that the end of every block can be represented.
+fn representable_end() -> u64 {
+    let top_level_size = size_of_order(MAX_ORDER) as u64;
+    (usize::max_value() as u64 / top_level_size) * top_level_size
+}
+
+/// The usable memory in `regions` as sorted, disjoint `[begin, end)` ranges which do not touch.
+/// Usable entries are clamped to the representable address range, have any memory which is also
+/// covered by an unusable entry cut out, and are then shrunk inward to multiples of the size of a
+/// block of order 0. Ranges too small to hold a block are dropped.
+pub fn usable_ranges(regions: &[MemRegion]) -> Vec<(usize, usize)> {
+    let clamp = |region: &MemRegion| {
+        let end = cmp::min(region.start.saturating_add(region.len), representable_end());
+        (cmp::min(region.start, end), end)
+    };
+
+    let mut usable: Vec<(u64, u64)> = regions.iter().filter(|r| r.usable).map(clamp).collect();
+    let mut unusable: Vec<(u64, u64)> = regions.iter().filter(|r| !r.usable).map(clamp).collect();
+    usable.sort_unstable();
+    unusable.sort_unstable();
+
+    // Join overlapping and adjacent usable entries
+    let mut joined: Vec<(u64, u64)> = Vec::new();
+    for (begin, end) in usable.into_iter().filter(|&(begin, end)| begin < end) {
+        match joined.last_mut() {
+            Some(last) if begin <= last.1 => last.1 = cmp::max(last.1, end),
+            _ => joined.push((begin, end)),
+        }
+    }
+
+    let align = 1u64 << BASE_ORDER;
+    let mut ranges = Vec::new();
+
+    for (begin, end) in joined {
+        let mut begin = begin;
+
+        for &(hole_begin, hole_end) in &unusable {
+            if hole_end <= begin || hole_begin >= end {
+                continue;
+            }
+
+            if hole_begin > begin {
+                ranges.push((begin, hole_begin));
+            }
+            begin = cmp::max(begin, hole_end);
+        }
+
+        if begin < end {
+            ranges.push((begin, end));
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(begin, end)| ((begin + align - 1) & !(align - 1), end & !(align - 1)))
+        .filter(|&(begin, end)| begin < end)
+        .map(|(begin, end)| (begin as usize, end as usize))
+        .collect()
+}
+
+/// Build a forest managing the available RAM of a multiboot2 memory map, with the holes between
+/// the available areas reserved. Available memory overlapped by an area of any other type is cut
+/// out.
+#[cfg(feature = "multiboot2")]
+pub fn init_from_multiboot2(mmap: &MemoryMapTag) -> Forest {
+    let areas = mmap.memory_areas().iter().map(|area| {
+        let available = MemoryAreaType::from(area.typ()) == MemoryAreaType::Available;
+        (area.start_address(), area.size(), available)
+    });
+    Forest::from_regions(&multiboot2_regions(areas))
+}
+
+/// The regions of multiboot2 memory areas given as `(start, size, available)`. Every area is
+/// listed, whatever its type, so only the available ones are usable.
+#[cfg(any(feature = "multiboot2", test))]
+fn multiboot2_regions<I: Iterator<Item = (u64, u64, bool)>>(areas: I) -> Vec<MemRegion> {
+    areas
+        .map(|(start, len, usable)| MemRegion { start, len, usable })
+        .collect()
+}
+
+/// Build a forest from the memory regions passed by the `bootloader` crate, given as
+/// `(start, end, usable)` physical address ranges. Adjacent usable regions are merged, usable
+/// memory overlapped by any other region is cut out, and ranges too small to hold a block of order
+/// 0 after alignment are dropped. Everything else is reserved.
+#[cfg(feature = "bootinfo")]
+pub fn init_from_boot_regions<I: Iterator<Item = (u64, u64, bool)>>(regions: I) -> Forest {
+    Forest::from_regions(&boot_regions(regions))
+}
+
+#[cfg(any(feature = "bootinfo", test))]
+fn boot_regions<I: Iterator<Item = (u64, u64, bool)>>(regions: I) -> Vec<MemRegion> {
+    regions
+        .map(|(start, end, usable)| MemRegion {
+            start,
+            len: end.saturating_sub(start),
+            usable,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buddy_allocator_bitmap::Forest;
+    use stats::AllocatorStats;
+    use BuddyAllocatorApi;
+
+    fn usable(start: u64, len: u64) -> MemRegion {
+        MemRegion { start, len, usable: true }
+    }
+
+    fn reserved(start: u64, len: u64) -> MemRegion {
+        MemRegion { start, len, usable: false }
+    }
+
+    #[test]
+    fn test_usable_ranges_aligns_inward() {
+        let regions = [usable(0x500, 0x3000), usable(0x10000, 0xfff), usable(0x20fff, 0x2002)];
+        assert_eq!(usable_ranges(&regions), vec![(0x1000, 0x3000), (0x21000, 0x23000)]);
+    }
+
+    #[test]
+    fn test_usable_ranges_overlapping() {
+        // Overlapping and adjacent usable entries are joined before aligning, in any order
+        let regions = [
+            usable(0x8000, 0x4000),
+            usable(0x1800, 0x1000),
+            usable(0x2800, 0x1000),
+            usable(0x9000, 0x1000),
+            usable(0xc000, 0x800),
+        ];
+        assert_eq!(usable_ranges(&regions), vec![(0x2000, 0x3000), (0x8000, 0xc000)]);
+    }
+
+    #[test]
+    fn test_usable_ranges_reserved_wins() {
+        let regions = [
+            usable(0, 0x10000),
+            reserved(0x2800, 0x1000),
+            reserved(0x8000, 0x800),
+            reserved(0xf000, 0x2000),
+            // Reserved memory over nothing usable is ignored
+            reserved(0x20000, 0x1000),
+        ];
+        assert_eq!(
+            usable_ranges(&regions),
+            vec![(0, 0x2000), (0x4000, 0x8000), (0x9000, 0xf000)]
+        );
+    }
+
+    #[test]
+    fn test_usable_ranges_clamped() {
+        let end = representable_end();
+        let regions = [usable(end - 0x2000, 0x4000), usable(end + 0x1000, 0x1000), usable(0, 0)];
+        assert_eq!(
+            usable_ranges(&regions),
+            vec![((end - 0x2000) as usize, end as usize)]
+        );
+
+        // A length which would wrap around is clamped too
+        let regions = [usable(0x1000, u64::max_value())];
+        assert_eq!(usable_ranges(&regions), vec![(0x1000, end as usize)]);
+    }
+
+    /// A memory map which has caught out a loader before, with the ranges which should be managed.
+    /// Both [boot_regions] and [multiboot2_regions] are given every entry, so they should agree.
+    struct Fixture {
+        name: &'static str,
+        entries: Vec<MemRegion>,
+        expected: Vec<(usize, usize)>,
+    }
+
+    fn fixtures() -> Vec<Fixture> {
+        let end = representable_end();
+        let kernel = reserved(0x20_0000, 0x8_0800);
+
+        vec![
+            Fixture {
+                name: "zero length",
+                entries: vec![usable(0x10_0000, 0), usable(0x20_0000, 0x10_0000), reserved(0, 0)],
+                expected: vec![(0x20_0000, 0x30_0000)],
+            },
+            Fixture {
+                name: "overlapping the kernel",
+                entries: vec![usable(0x10_0000, 0x70_0000), kernel],
+                expected: vec![(0x10_0000, 0x20_0000), (0x28_1000, 0x80_0000)],
+            },
+            Fixture {
+                name: "adjacent and tiny",
+                entries: vec![usable(0x1000, 0x800), usable(0x1800, 0x800), usable(0x5000, 0xfff)],
+                expected: vec![(0x1000, 0x2000)],
+            },
+            Fixture {
+                name: "unaligned",
+                entries: vec![
+                    usable(0x1234, 0x5000),
+                    reserved(0x3800, 0x10),
+                    usable(0x9fff, 0x1002),
+                ],
+                expected: vec![(0x2000, 0x3000), (0x4000, 0x6000), (0xa000, 0xb000)],
+            },
+            Fixture {
+                name: "overlapping usable",
+                entries: vec![
+                    usable(0x4000, 0x6000),
+                    usable(0x1000, 0x4800),
+                    usable(0x6000, 0x1000),
+                    reserved(0x8000, 0x1000),
+                    usable(0x8800, 0x2000),
+                ],
+                expected: vec![(0x1000, 0x8000), (0x9000, 0xa000)],
+            },
+            Fixture {
+                name: "above the maximum address",
+                entries: vec![usable(end - 0x2000, 0x10_0000), usable(end, 0x1000)],
+                expected: vec![((end - 0x2000) as usize, end as usize)],
+            },
+        ]
+    }
+
+    /// Check that the forest manages exactly the expected ranges by allocating all of its memory.
+    fn check_forest(name: &str, mut forest: Forest, expected: &[(usize, usize)]) {
+        let expected_bytes: usize = expected.iter().map(|&(begin, end)| end - begin).sum();
+        assert_eq!(forest.managed_bytes(), expected_bytes, "{}", name);
+
+        let mut allocated = 0;
+        while let Some(addr) = forest.allocate(0) {
+            assert!(
+                expected.iter().any(|&(begin, end)| addr >= begin && addr < end),
+                "{}: {:#x} is not in a usable range",
+                name,
+                addr
+            );
+            allocated += 1 << BASE_ORDER;
+        }
+        assert_eq!(allocated, expected_bytes, "{}", name);
+    }
+
+    #[test]
+    fn test_boot_regions_fixtures() {
+        for fixture in fixtures() {
+            let tuples = fixture
+                .entries
+                .iter()
+                .map(|entry| (entry.start, entry.start.saturating_add(entry.len), entry.usable));
+            let regions = boot_regions(tuples);
+
+            assert_eq!(usable_ranges(&regions), fixture.expected, "{}", fixture.name);
+            check_forest(fixture.name, Forest::from_regions(&regions), &fixture.expected);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "bootinfo")]
+    fn test_init_from_boot_regions_fixtures() {
+        for fixture in fixtures() {
+            let tuples = fixture
+                .entries
+                .iter()
+                .map(|entry| (entry.start, entry.start.saturating_add(entry.len), entry.usable));
+
+            check_forest(fixture.name, init_from_boot_regions(tuples), &fixture.expected);
+        }
+    }
+
+    #[test]
+    fn test_multiboot2_regions_fixtures() {
+        for fixture in fixtures() {
+            let areas = fixture
+                .entries
+                .iter()
+                .map(|entry| (entry.start, entry.len, entry.usable));
+            let regions = multiboot2_regions(areas);
+
+            assert_eq!(usable_ranges(&regions), fixture.expected, "{}", fixture.name);
+            check_forest(fixture.name, Forest::from_regions(&regions), &fixture.expected);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Timing of the individual allocator operations. With the `metrics` feature disabled everything
+//! here is zero sized and does nothing, so the allocators can time their operations
+//! unconditionally and still compile down to direct calls.
+
+#[cfg(feature = "metrics")]
+use std::time::{Duration, Instant};
+
+/// How many of the most recent operation durations are kept for the percentiles
+#[cfg(feature = "metrics")]
+pub const LATENCY_SAMPLES: usize = 4096;
+
+/// Marks when an operation began.
+#[derive(Debug, Copy, Clone)]
+pub struct OpTimer {
+    #[cfg(feature = "metrics")]
+    start: Instant,
+}
+
+impl OpTimer {
+    #[cfg(feature = "metrics")]
+    #[inline(always)]
+    pub fn start() -> Self {
+        OpTimer {
+            start: Instant::now(),
+        }
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    #[inline(always)]
+    pub fn start() -> Self {
+        OpTimer {}
+    }
+}
+
+/// The durations of the operations an allocator has performed. The most recent
+/// [LATENCY_SAMPLES] durations are kept in a ring buffer which is allocated in full by the first
+/// recording, so recording allocates only once and construction not at all. The count, mean and
+/// maximum cover every operation.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone)]
+pub struct Latencies {
+    samples: Vec<u64>,
+    /// Index in `samples` which the next duration is written to
+    next: usize,
+    count: u64,
+    total_ns: u64,
+    max_ns: u64,
+}
+
+#[cfg(not(feature = "metrics"))]
+#[derive(Debug, Clone)]
+pub struct Latencies;
+
+#[cfg(feature = "metrics")]
+impl Latencies {
+    pub const fn new() -> Self {
+        Latencies {
+            samples: Vec::new(),
+            next: 0,
+            count: 0,
+            total_ns: 0,
+            max_ns: 0,
+        }
+    }
+
+    /// Record the duration of the operation begun when `timer` was started.
+    #[inline]
+    pub fn record(&mut self, timer: OpTimer) {
+        self.record_ns(nanos(timer.start.elapsed()));
+    }
+
+    fn record_ns(&mut self, ns: u64) {
+        if self.samples.len() < LATENCY_SAMPLES {
+            self.samples.reserve_exact(LATENCY_SAMPLES - self.samples.len());
+            self.samples.push(ns);
+        } else {
+            self.samples[self.next] = ns;
+        }
+        self.next = (self.next + 1) % LATENCY_SAMPLES;
+        self.count += 1;
+        self.total_ns = self.total_ns.saturating_add(ns);
+
+        if ns > self.max_ns {
+            self.max_ns = ns;
+        }
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+
+        let percentile = |percent: usize| match sorted.len() {
+            0 => 0,
+            len => sorted[(len - 1) * percent / 100],
+        };
+
+        LatencySummary {
+            count: self.count,
+            mean_ns: if self.count == 0 { 0 } else { self.total_ns / self.count },
+            p50_ns: percentile(50),
+            p99_ns: percentile(99),
+            max_ns: self.max_ns,
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+impl Latencies {
+    #[inline(always)]
+    pub const fn new() -> Self {
+        Latencies
+    }
+
+    #[inline(always)]
+    pub fn record(&mut self, _timer: OpTimer) {}
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Latencies::new()
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())
+}
+
+/// A summary of the durations of an allocator's operations, in nanoseconds. The percentiles are
+/// taken over the most recent [LATENCY_SAMPLES] operations, and the rest over every operation.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean_ns: u64,
+    pub p50_ns: u64,
+    pub p99_ns: u64,
+    pub max_ns: u64,
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_latencies_ring_wraps() {
+        let mut latencies = Latencies::new();
+        assert_eq!(latencies.summary(), LatencySummary::default());
+
+        // The one slow operation is pushed out of the ring but not out of the maximum
+        latencies.record_ns(1_000_000);
+        for ns in 0..LATENCY_SAMPLES as u64 {
+            latencies.record_ns(ns % 100);
+        }
+
+        let summary = latencies.summary();
+        assert_eq!(summary.count, LATENCY_SAMPLES as u64 + 1);
+        assert_eq!(summary.max_ns, 1_000_000);
+        assert_eq!(summary.p50_ns, 49);
+        assert_eq!(summary.p99_ns, 98);
+        assert!(summary.mean_ns > 49);
+    }
+}
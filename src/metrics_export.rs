@@ -0,0 +1,334 @@
+//! The statistics of the allocators in the Prometheus text exposition format, so that they can be
+//! scraped by a monitoring pipeline alongside everything else.
+//!
+//! Samples are grouped into families by metric name, and each family is written once with its
+//! `# HELP` and `# TYPE` lines, so the metrics of several allocators can be collected into one
+//! [Metrics] and told apart by their `allocator` label. Labels are written in the order they are
+//! given, and every line, including the last, ends with a newline.
+
+use std::io::{self, Write};
+use stats::AllocatorStats;
+use geometry::size_of_order;
+use super::LEVEL_COUNT;
+
+/// The type of the samples of a family, written in its `# TYPE` line
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MetricKind {
+    /// Only ever increases, such as a count of operations
+    Counter,
+    /// Can go up and down, such as a count of free blocks
+    Gauge,
+}
+
+impl MetricKind {
+    fn name(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Family {
+    name: &'static str,
+    kind: MetricKind,
+    help: &'static str,
+    samples: Vec<Sample>,
+}
+
+/// Samples grouped by metric name, in the order each name was first pushed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    families: Vec<Family>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics { families: Vec::new() }
+    }
+
+    /// The free and used bytes, free blocks of each order, operation counts and fragmentation of
+    /// `stats`, each labelled with `allocator`. Fragmentation is the fraction of the free bytes
+    /// which are not in the largest run of contiguous free memory, or 0 if nothing is free.
+    pub fn of<S: AllocatorStats + ?Sized>(stats: &S, allocator: &str) -> Self {
+        let mut metrics = Metrics::new();
+        let histogram = stats.free_histogram();
+        let counters = stats.op_counters();
+
+        for order in 0..LEVEL_COUNT {
+            let labels = [("allocator", allocator), ("order", &*order.to_string())];
+            let free = histogram[order as usize];
+
+            metrics.push(
+                "buddy_free_bytes",
+                MetricKind::Gauge,
+                "Bytes in maximal free blocks of each order",
+                &labels,
+                (free * size_of_order(order)) as f64,
+            );
+            metrics.push(
+                "buddy_free_blocks",
+                MetricKind::Gauge,
+                "Maximal free blocks of each order",
+                &labels,
+                free as f64,
+            );
+            metrics.push(
+                "buddy_allocations_total",
+                MetricKind::Counter,
+                "Blocks of each order allocated",
+                &labels,
+                counters.allocations[order as usize] as f64,
+            );
+            metrics.push(
+                "buddy_frees_total",
+                MetricKind::Counter,
+                "Blocks of each order freed",
+                &labels,
+                counters.frees[order as usize] as f64,
+            );
+        }
+
+        let labels = [("allocator", allocator)];
+        metrics.push(
+            "buddy_used_bytes",
+            MetricKind::Gauge,
+            "Bytes in allocated blocks",
+            &labels,
+            stats.usage().used_bytes() as f64,
+        );
+
+        let free_bytes: usize = (0..LEVEL_COUNT)
+            .map(|order| histogram[order as usize] * size_of_order(order))
+            .sum();
+        let fragmentation = match stats.largest_free_extent() {
+            Some((_, largest)) if free_bytes > 0 => 1.0 - largest as f64 / free_bytes as f64,
+            _ => 0.0,
+        };
+        metrics.push(
+            "buddy_fragmentation_ratio",
+            MetricKind::Gauge,
+            "Fraction of free bytes outside the largest contiguous free run",
+            &labels,
+            fragmentation,
+        );
+
+        metrics
+    }
+
+    /// Add a sample to the family of the given name, which is created if it has no samples yet.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the family already exists with another kind, as it could not be written.
+    pub fn push(
+        &mut self,
+        name: &'static str,
+        kind: MetricKind,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        let sample = Sample {
+            labels: labels
+                .iter()
+                .map(|&(label, value)| (label.to_string(), value.to_string()))
+                .collect(),
+            value,
+        };
+
+        match self.families.iter_mut().find(|family| family.name == name) {
+            Some(family) => {
+                assert_eq!(family.kind, kind, "Metric {} pushed with two kinds!", name);
+                family.samples.push(sample);
+            }
+            None => self.families.push(Family {
+                name,
+                kind,
+                help,
+                samples: vec![sample],
+            }),
+        }
+    }
+
+    /// Add every sample of `other`, such as the metrics of another allocator.
+    pub fn extend(&mut self, other: Metrics) {
+        for family in other.families {
+            match self.families.iter_mut().find(|ours| ours.name == family.name) {
+                Some(ours) => {
+                    assert_eq!(ours.kind, family.kind, "Metric {} has two kinds!", family.name);
+                    ours.samples.extend(family.samples);
+                }
+                None => self.families.push(family),
+            }
+        }
+    }
+
+    /// Write every family in the text exposition format. Nothing is written if there are no
+    /// samples.
+    pub fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for family in &self.families {
+            writeln!(w, "# HELP {} {}", family.name, escape_help(family.help))?;
+            writeln!(w, "# TYPE {} {}", family.name, family.kind.name())?;
+
+            for sample in &family.samples {
+                write!(w, "{}", family.name)?;
+
+                if !sample.labels.is_empty() {
+                    let labels: Vec<_> = sample
+                        .labels
+                        .iter()
+                        .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+                        .collect();
+                    write!(w, "{{{}}}", labels.join(","))?;
+                }
+
+                writeln!(w, " {}", sample.value)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Escape the backslashes, double quotes and newlines of a label value
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Escape the backslashes and newlines of help text, which may contain double quotes as they are
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buddy_allocator_lists::{Block, BuddyAllocator};
+    use {BuddyAllocatorApi, BASE_ORDER, MAX_ORDER, MAX_ORDER_SIZE};
+
+    fn encoded(metrics: &Metrics) -> String {
+        let mut out = Vec::new();
+        metrics.encode(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_encode_format() {
+        let mut metrics = Metrics::new();
+        metrics.push(
+            "requests_total",
+            MetricKind::Counter,
+            "Requests \"seen\", by path\\name\nand order",
+            &[("path", "C:\\dir \"quoted\"\nnext"), ("order", "3")],
+            12345.0,
+        );
+        metrics.push("ratio", MetricKind::Gauge, "A ratio", &[], 0.25);
+        metrics.push("requests_total", MetricKind::Counter, "Ignored", &[("order", "0")], 0.0);
+
+        assert_eq!(
+            encoded(&metrics),
+            "# HELP requests_total Requests \"seen\", by path\\\\name\\nand order\n\
+             # TYPE requests_total counter\n\
+             requests_total{path=\"C:\\\\dir \\\"quoted\\\"\\nnext\",order=\"3\"} 12345\n\
+             requests_total{order=\"0\"} 0\n\
+             # HELP ratio A ratio\n\
+             # TYPE ratio gauge\n\
+             ratio 0.25\n"
+        );
+        assert_eq!(encoded(&Metrics::new()), "");
+    }
+
+    #[test]
+    fn test_extend_groups_families() {
+        let mut first = Metrics::new();
+        first.push("a", MetricKind::Gauge, "A", &[("allocator", "x")], 1.0);
+        first.push("b", MetricKind::Gauge, "B", &[("allocator", "x")], 2.0);
+        let mut second = Metrics::new();
+        second.push("b", MetricKind::Gauge, "B", &[("allocator", "y")], 3.0);
+        second.push("c", MetricKind::Counter, "C", &[("allocator", "y")], 4.0);
+        first.extend(second);
+
+        assert_eq!(
+            encoded(&first),
+            "# HELP a A\n# TYPE a gauge\na{allocator=\"x\"} 1\n\
+             # HELP b B\n# TYPE b gauge\nb{allocator=\"x\"} 2\nb{allocator=\"y\"} 3\n\
+             # HELP c C\n# TYPE c counter\nc{allocator=\"y\"} 4\n"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "two kinds")]
+    fn test_conflicting_kinds() {
+        let mut metrics = Metrics::new();
+        metrics.push("a", MetricKind::Gauge, "A", &[], 1.0);
+        metrics.push("a", MetricKind::Counter, "A", &[], 1.0);
+    }
+
+    #[test]
+    fn test_allocator_metrics() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        BuddyAllocatorApi::allocate(&mut allocator, MAX_ORDER - 1).unwrap();
+        BuddyAllocatorApi::allocate(&mut allocator, MAX_ORDER - 2).unwrap();
+
+        let mut out = Vec::new();
+        allocator.encode_metrics("vecs", &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+
+        let quarter = 1usize << (MAX_ORDER_SIZE - 2);
+        let expected = [
+            format!("buddy_free_bytes{{allocator=\"vecs\",order=\"{}\"}} {}", MAX_ORDER - 2, quarter),
+            format!("buddy_free_blocks{{allocator=\"vecs\",order=\"{}\"}} 1", MAX_ORDER - 2),
+            format!("buddy_free_blocks{{allocator=\"vecs\",order=\"{}\"}} 0", MAX_ORDER),
+            format!("buddy_allocations_total{{allocator=\"vecs\",order=\"{}\"}} 1", MAX_ORDER - 1),
+            format!("buddy_used_bytes{{allocator=\"vecs\"}} {}", quarter * 3),
+            "buddy_fragmentation_ratio{allocator=\"vecs\"} 0".to_string(),
+            "# TYPE buddy_frees_total counter".to_string(),
+        ];
+        for line in &expected {
+            assert!(lines.contains(&&**line), "{:?} missing from\n{}", line, text);
+        }
+
+        // Every order has a sample in each of the four families by order
+        let samples = lines.iter().filter(|line| !line.starts_with('#')).count();
+        assert_eq!(samples, LEVEL_COUNT as usize * 4 + 2);
+        assert!(text.ends_with(" 0\n"));
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        // The first four blocks of order 0 are allocated, and the second freed again
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        allocator.create_top_level(0).unwrap();
+        let addrs: Vec<_> = (0..4)
+            .map(|_| BuddyAllocatorApi::allocate(&mut allocator, 0).unwrap())
+            .collect();
+        allocator.deallocate(addrs[1], 0).unwrap();
+
+        // The largest run is everything from the fifth block up, and only the second block is
+        // free outside it
+        let size = 1usize << BASE_ORDER;
+        let free = (1 << MAX_ORDER_SIZE) - size * 3;
+        let metrics = Metrics::of(&allocator, "vecs");
+        let fragmentation = metrics
+            .families
+            .iter()
+            .find(|family| family.name == "buddy_fragmentation_ratio")
+            .unwrap()
+            .samples[0]
+            .value;
+        assert_eq!(fragmentation, 1.0 - (free - size) as f64 / free as f64);
+    }
+}
@@ -0,0 +1,348 @@
+//! Memory grouped by NUMA node, so that each caller is given memory close to it and only falls back
+//! to the memory of other nodes once its own node has none left.
+//!
+//! Every region added is managed by its own [Forest] (its arena), and remembers the node it was
+//! added on so that freed blocks are returned to the arena they came from.
+
+use buddy_allocator_bitmap::Forest;
+use mem_map::MemRegion;
+use stats::{AllocatorStats, Usage};
+use MAX_ORDER;
+
+/// A NUMA node, as numbered by the caller (e.g. from the ACPI SRAT).
+pub type NodeId = u32;
+
+/// How many operations a node has performed.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct NodeCounters {
+    /// Blocks allocated from the node for callers which preferred it
+    pub local_allocations: usize,
+    /// Blocks allocated from the node for callers which preferred another node
+    pub remote_allocations: usize,
+    /// Blocks of the node which were freed, whichever node was preferred when they were allocated
+    pub frees: usize,
+}
+
+/// The state of a node's memory.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct NodeStats {
+    /// The memory allocated from the node, whichever node was preferred
+    pub usage: Usage,
+    pub counters: NodeCounters,
+    /// The bytes managed by the node's arenas, whether free or used
+    pub managed_bytes: usize,
+}
+
+/// A region of memory and the forest managing it.
+struct Arena {
+    begin: usize,
+    end: usize,
+    forest: Forest,
+}
+
+struct Node {
+    id: NodeId,
+    arenas: Vec<Arena>,
+    /// The other nodes to try, or `None` to try every other node in the order they were added
+    fallback: Option<Vec<NodeId>>,
+    usage: Usage,
+    counters: NodeCounters,
+}
+
+/// An allocator over the memory of several NUMA nodes.
+#[derive(Default)]
+pub struct NumaForest {
+    /// In the order the nodes were first mentioned
+    nodes: Vec<Node>,
+}
+
+impl NumaForest {
+    pub fn new() -> Self {
+        NumaForest { nodes: Vec::new() }
+    }
+
+    /// The index of a node, adding it without any memory if it is not known yet.
+    fn node_index(&mut self, id: NodeId) -> usize {
+        if let Some(index) = self.nodes.iter().position(|node| node.id == id) {
+            return index;
+        }
+
+        self.nodes.push(Node {
+            id,
+            arenas: Vec::new(),
+            fallback: None,
+            usage: Usage::new(),
+            counters: NodeCounters::default(),
+        });
+        self.nodes.len() - 1
+    }
+
+    /// Add the `len` bytes beginning at `start` to the memory of `node`, as a new arena. Only the
+    /// whole blocks of order 0 inside the region are handed out.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the region wraps around the address space or overlaps a region already added, on
+    /// any node.
+    pub fn add_region_on_node(&mut self, node: NodeId, start: usize, len: usize) {
+        let end = start
+            .checked_add(len)
+            .expect("The region wraps around the address space!");
+
+        let overlaps = self
+            .nodes
+            .iter()
+            .flat_map(|node| node.arenas.iter())
+            .any(|arena| start < arena.end && arena.begin < end);
+        assert!(
+            !overlaps,
+            "Region {:#x}..{:#x} overlaps a region which was already added!",
+            start,
+            end
+        );
+
+        let forest = Forest::from_regions(&[MemRegion {
+            start: start as u64,
+            len: len as u64,
+            usable: true,
+        }]);
+
+        let index = self.node_index(node);
+        self.nodes[index].arenas.push(Arena {
+            begin: start,
+            end,
+            forest,
+        });
+    }
+
+    /// Set the other nodes to try, in order, when `node` has no free block of the order asked for.
+    /// Nodes which are not listed are never tried, so an empty order keeps every allocation for
+    /// `node` on `node`. Until this is called, every other node is tried in the order the nodes
+    /// were added.
+    ///
+    /// The node does not need any memory of its own, for CPUs on nodes which only have memory of
+    /// other nodes.
+    pub fn set_fallback_order(&mut self, node: NodeId, order: &[NodeId]) {
+        let index = self.node_index(node);
+        let order = order.iter().cloned().filter(|&other| other != node).collect();
+        self.nodes[index].fallback = Some(order);
+    }
+
+    /// The indices of the nodes to try for an allocation preferring `node`, best first.
+    fn allocation_order(&self, node: NodeId) -> Vec<usize> {
+        let index_of = |id: NodeId| self.nodes.iter().position(|node| node.id == id);
+        let preferred = index_of(node);
+
+        let fallback: Vec<usize> = match preferred.and_then(|i| self.nodes[i].fallback.as_ref()) {
+            Some(order) => order.iter().filter_map(|&id| index_of(id)).collect(),
+            None => (0..self.nodes.len()).filter(|&i| Some(i) != preferred).collect(),
+        };
+
+        preferred.into_iter().chain(fallback).collect()
+    }
+
+    /// Allocate a block of the given order, from the arenas of `node` if any of them have one free
+    /// and otherwise from the nodes in its fallback order. `node` need not have been added, in
+    /// which case every node is tried in the order they were added.
+    pub fn alloc_on_node(&mut self, node: NodeId, order: u8) -> Option<usize> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        for index in self.allocation_order(node) {
+            let candidate = &mut self.nodes[index];
+            let addr = candidate
+                .arenas
+                .iter_mut()
+                .filter_map(|arena| arena.forest.alloc_exact(order))
+                .next();
+
+            if let Some(addr) = addr {
+                candidate.usage.allocated(order);
+                if candidate.id == node {
+                    candidate.counters.local_allocations += 1;
+                } else {
+                    candidate.counters.remote_allocations += 1;
+                }
+
+                return Some(addr as usize);
+            }
+        }
+
+        None
+    }
+
+    /// Free the block of the given order beginning at `addr`, returning it to the arena of the
+    /// node it belongs to. Returns `false` if that arena has no used block of that order there.
+    pub fn dealloc(&mut self, addr: usize, order: u8) -> bool {
+        for node in &mut self.nodes {
+            let arena = node
+                .arenas
+                .iter_mut()
+                .find(|arena| arena.begin <= addr && addr < arena.end);
+
+            if let Some(arena) = arena {
+                let freed = arena.forest.dealloc_exact(addr as *const u8, order);
+                if freed {
+                    node.usage.freed(order);
+                    node.counters.frees += 1;
+                }
+
+                return freed;
+            }
+        }
+
+        false
+    }
+
+    /// The node whose memory contains `addr`, or `None` if no region added contains it.
+    pub fn node_of(&self, addr: usize) -> Option<NodeId> {
+        self.nodes
+            .iter()
+            .find(|node| {
+                node.arenas
+                    .iter()
+                    .any(|arena| arena.begin <= addr && addr < arena.end)
+            })
+            .map(|node| node.id)
+    }
+
+    /// The state of a node's memory, or `None` if the node is not known.
+    pub fn node_stats(&self, node: NodeId) -> Option<NodeStats> {
+        let node = self.nodes.iter().find(|other| other.id == node)?;
+
+        Some(NodeStats {
+            usage: node.usage,
+            counters: node.counters,
+            managed_bytes: node.arenas.iter().map(|arena| arena.forest.managed_bytes()).sum(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use BASE_ORDER;
+
+    const PAGES_PER_NODE: usize = 16;
+    const PAGE_SIZE: usize = 1 << BASE_ORDER;
+
+    /// Nodes 0, 1 and 2, each with a region of [PAGES_PER_NODE] pages
+    fn three_nodes() -> NumaForest {
+        let mut numa = NumaForest::new();
+        for node in 0..3 {
+            let start = 0x4000_0000 + node as usize * 0x100_0000;
+            numa.add_region_on_node(node, start, PAGES_PER_NODE * PAGE_SIZE);
+        }
+
+        numa
+    }
+
+    /// Allocate pages preferring `node` until none are left, returning the node of each page.
+    fn alloc_all(numa: &mut NumaForest, node: NodeId) -> Vec<NodeId> {
+        let mut nodes = Vec::new();
+        while let Some(addr) = numa.alloc_on_node(node, 0) {
+            nodes.push(numa.node_of(addr).unwrap());
+        }
+
+        nodes
+    }
+
+    fn counters(numa: &NumaForest, node: NodeId) -> NodeCounters {
+        numa.node_stats(node).unwrap().counters
+    }
+
+    #[test]
+    fn test_exhaust_node_spills_in_fallback_order() {
+        let mut numa = three_nodes();
+        numa.set_fallback_order(0, &[2, 1]);
+
+        let nodes = alloc_all(&mut numa, 0);
+        assert_eq!(nodes.len(), 3 * PAGES_PER_NODE);
+        assert!(nodes[..PAGES_PER_NODE].iter().all(|&node| node == 0));
+        assert!(nodes[PAGES_PER_NODE..2 * PAGES_PER_NODE].iter().all(|&node| node == 2));
+        assert!(nodes[2 * PAGES_PER_NODE..].iter().all(|&node| node == 1));
+
+        assert_eq!(counters(&numa, 0).local_allocations, PAGES_PER_NODE);
+        assert_eq!(counters(&numa, 0).remote_allocations, 0);
+        for &node in &[1, 2] {
+            let stats = numa.node_stats(node).unwrap();
+            assert_eq!(stats.counters.local_allocations, 0);
+            assert_eq!(stats.counters.remote_allocations, PAGES_PER_NODE);
+            assert_eq!(stats.usage.used_bytes(), stats.managed_bytes);
+        }
+    }
+
+    #[test]
+    fn test_default_and_restricted_fallback() {
+        // Unconfigured nodes fall back in the order the nodes were added
+        let mut numa = three_nodes();
+        let nodes = alloc_all(&mut numa, 1);
+        let expected: Vec<NodeId> = [1, 0, 2]
+            .iter()
+            .flat_map(|&node| vec![node; PAGES_PER_NODE])
+            .collect();
+        assert_eq!(nodes, expected);
+
+        // Only the listed nodes are tried
+        let mut numa = three_nodes();
+        numa.set_fallback_order(2, &[]);
+        numa.set_fallback_order(1, &[1, 0]);
+        assert_eq!(alloc_all(&mut numa, 2), vec![2; PAGES_PER_NODE]);
+        let mut expected = vec![1; PAGES_PER_NODE];
+        expected.extend(vec![0; PAGES_PER_NODE]);
+        assert_eq!(alloc_all(&mut numa, 1), expected);
+
+        // A node without memory of its own borrows from the others
+        let mut numa = three_nodes();
+        numa.set_fallback_order(7, &[2]);
+        assert_eq!(alloc_all(&mut numa, 7), vec![2; PAGES_PER_NODE]);
+        assert_eq!(counters(&numa, 7), NodeCounters::default());
+        assert_eq!(numa.node_stats(7).unwrap().managed_bytes, 0);
+        assert_eq!(numa.node_stats(8), None);
+    }
+
+    #[test]
+    fn test_dealloc_routes_to_node() {
+        let mut numa = three_nodes();
+        numa.set_fallback_order(0, &[1]);
+
+        let local: Vec<usize> = (0..PAGES_PER_NODE)
+            .map(|_| numa.alloc_on_node(0, 0).unwrap())
+            .collect();
+        let spilled = numa.alloc_on_node(0, 1).unwrap();
+        assert_eq!(numa.node_of(spilled), Some(1));
+        assert_eq!(counters(&numa, 1).remote_allocations, 1);
+
+        // The spilled block goes back to node 1, and node 0 is left as it was
+        assert!(numa.dealloc(spilled, 1));
+        assert!(!numa.dealloc(spilled, 1));
+        assert_eq!(numa.node_stats(1).unwrap().usage.used_bytes(), 0);
+        assert_eq!(counters(&numa, 1).frees, 1);
+        assert_eq!(counters(&numa, 0).frees, 0);
+        assert_eq!(numa.node_stats(0).unwrap().usage.used_bytes(), PAGES_PER_NODE * PAGE_SIZE);
+
+        for &addr in &local {
+            assert_eq!(numa.node_of(addr), Some(0));
+            assert!(numa.dealloc(addr, 0));
+        }
+
+        let stats = numa.node_stats(0).unwrap();
+        assert_eq!(stats.usage.used_bytes(), 0);
+        assert_eq!(stats.usage.peak_used_bytes(), PAGES_PER_NODE * PAGE_SIZE);
+        assert_eq!(stats.counters.frees, PAGES_PER_NODE);
+
+        // Freed memory is preferred again over the fallback
+        let addr = numa.alloc_on_node(0, 0).unwrap();
+        assert_eq!(numa.node_of(addr), Some(0));
+        assert!(!numa.dealloc(0x1000, 0));
+        assert_eq!(numa.node_of(0x1000), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps a region")]
+    fn test_overlapping_regions() {
+        let mut numa = three_nodes();
+        numa.add_region_on_node(5, 0x4000_0000 + PAGE_SIZE, PAGE_SIZE);
+    }
+}
@@ -0,0 +1,348 @@
+//! Hooks for watching what an allocator does without modifying its internals, e.g. to visualise it
+//! or to verify it.
+
+use std::fmt::{self, Debug};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use super::BuddyAllocatorApi;
+
+/// Receives the operations an allocator performs as they happen. Every method does nothing by
+/// default so that observers only need to implement what they are interested in. Observers must be
+/// `Send` so that an observed allocator can still be shared behind a lock.
+pub trait AllocObserver: Send {
+    /// A block of the given order beginning at `addr` was allocated
+    fn on_alloc(&mut self, _addr: usize, _order: u8) {}
+    /// A block of the given order beginning at `addr` was freed, before it is merged with its buddy
+    fn on_dealloc(&mut self, _addr: usize, _order: u8) {}
+    /// A free block of the given order beginning at `addr` was split into two of the order below
+    fn on_split(&mut self, _addr: usize, _order: u8) {}
+    /// Two free buddies were merged into the block of the given order beginning at `addr`
+    fn on_merge(&mut self, _addr: usize, _order: u8) {}
+}
+
+/// One call made to an [AllocObserver].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AllocEvent {
+    Alloc { addr: usize, order: u8 },
+    Dealloc { addr: usize, order: u8 },
+    Split { addr: usize, order: u8 },
+    Merge { addr: usize, order: u8 },
+}
+
+/// The observer of an allocator, if one is set. Notifying it is only a branch when none is set.
+#[derive(Default)]
+pub struct ObserverSlot {
+    observer: Option<Box<dyn AllocObserver>>,
+}
+
+impl ObserverSlot {
+    pub const fn new() -> Self {
+        ObserverSlot { observer: None }
+    }
+
+    /// Set the observer, returning the previous one if there was one.
+    pub fn set(&mut self, observer: Box<dyn AllocObserver>) -> Option<Box<dyn AllocObserver>> {
+        mem::replace(&mut self.observer, Some(observer))
+    }
+
+    /// Remove the observer, returning it if there was one.
+    pub fn take(&mut self) -> Option<Box<dyn AllocObserver>> {
+        self.observer.take()
+    }
+
+    #[inline]
+    pub fn notify(&mut self, event: AllocEvent) {
+        if let Some(ref mut observer) = self.observer {
+            match event {
+                AllocEvent::Alloc { addr, order } => observer.on_alloc(addr, order),
+                AllocEvent::Dealloc { addr, order } => observer.on_dealloc(addr, order),
+                AllocEvent::Split { addr, order } => observer.on_split(addr, order),
+                AllocEvent::Merge { addr, order } => observer.on_merge(addr, order),
+            }
+        }
+    }
+}
+
+impl Debug for ObserverSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ObserverSlot")
+            .field("set", &self.observer.is_some())
+            .finish()
+    }
+}
+
+/// An operation recorded in an [OpLog].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Op {
+    Alloc { addr: usize, order: u8 },
+    Dealloc { addr: usize, order: u8 },
+}
+
+impl Op {
+    /// The size of an encoded operation: a tag, the order and a little endian 64 bit address.
+    const ENCODED_SIZE: usize = 10;
+    const ALLOC_TAG: u8 = 0;
+    const DEALLOC_TAG: u8 = 1;
+}
+
+/// An observer which records every allocation and deallocation so that they can be replayed later,
+/// turning any observed bug into a deterministic regression test. Clones share the same log, so a
+/// clone can be kept to read the log after giving one to an allocator.
+///
+/// Splits and merges are not recorded as they follow from the allocations and deallocations.
+#[derive(Debug, Default, Clone)]
+pub struct OpLog {
+    ops: Arc<Mutex<Vec<Op>>>,
+}
+
+/// The result of replaying a logged operation differed from the result which was logged.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ReplayDivergence {
+    /// The index of the operation in the log
+    pub index: usize,
+    pub expected: Op,
+    pub actual: ReplayOutcome,
+}
+
+/// What an allocator did when an operation was replayed on it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReplayOutcome {
+    /// The allocation returned this address, or failed
+    Allocated(Option<usize>),
+    /// Whether the deallocation succeeded
+    Deallocated(bool),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OpLogDecodeError {
+    /// The buffer does not begin with the log header, or is of an unknown version
+    BadHeader,
+    /// The buffer ends part way through an operation
+    Truncated,
+    /// The operation at this index has an unknown tag
+    UnknownOp { index: usize, tag: u8 },
+}
+
+impl OpLog {
+    const MAGIC: &'static [u8] = b"BALG";
+    const VERSION: u8 = 1;
+
+    pub fn new() -> Self {
+        OpLog::default()
+    }
+
+    /// The operations recorded so far.
+    pub fn ops(&self) -> Vec<Op> {
+        self.ops.lock().unwrap().clone()
+    }
+
+    /// Record an operation directly, as if an observed allocator had performed it.
+    pub fn push(&self, op: Op) {
+        self.ops.lock().unwrap().push(op);
+    }
+
+    /// Apply the log to an allocator and check that every operation has the same result that it
+    /// had when it was recorded. The allocator must have been given the same top level blocks as
+    /// the allocator which was observed, as their creation is not logged.
+    pub fn replay(&self, allocator: &mut impl BuddyAllocatorApi) -> Result<(), ReplayDivergence> {
+        for (index, &op) in self.ops().iter().enumerate() {
+            let (matches, actual) = match op {
+                Op::Alloc { addr, order } => {
+                    let actual = allocator.allocate(order);
+                    (actual == Some(addr), ReplayOutcome::Allocated(actual))
+                }
+                Op::Dealloc { addr, order } => {
+                    let actual = allocator.deallocate(addr, order);
+                    (actual, ReplayOutcome::Deallocated(actual))
+                }
+            };
+
+            if !matches {
+                return Err(ReplayDivergence {
+                    index,
+                    expected: op,
+                    actual,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encode the log as a header followed by [Op::ENCODED_SIZE] bytes per operation.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let ops = self.ops.lock().unwrap();
+        let mut bytes = Vec::with_capacity(OpLog::MAGIC.len() + 1 + ops.len() * Op::ENCODED_SIZE);
+        bytes.extend_from_slice(OpLog::MAGIC);
+        bytes.push(OpLog::VERSION);
+
+        for op in ops.iter() {
+            let (tag, addr, order) = match *op {
+                Op::Alloc { addr, order } => (Op::ALLOC_TAG, addr, order),
+                Op::Dealloc { addr, order } => (Op::DEALLOC_TAG, addr, order),
+            };
+
+            bytes.push(tag);
+            bytes.push(order);
+            for byte in 0..8 {
+                bytes.push((addr as u64 >> (byte * 8)) as u8);
+            }
+        }
+
+        bytes
+    }
+
+    /// Decode a log encoded by [to_bytes].
+    pub fn from_bytes(bytes: &[u8]) -> Result<OpLog, OpLogDecodeError> {
+        let header_len = OpLog::MAGIC.len() + 1;
+        if bytes.len() < header_len
+            || &bytes[..OpLog::MAGIC.len()] != OpLog::MAGIC
+            || bytes[OpLog::MAGIC.len()] != OpLog::VERSION
+        {
+            return Err(OpLogDecodeError::BadHeader);
+        }
+
+        let body = &bytes[header_len..];
+        if body.len() % Op::ENCODED_SIZE != 0 {
+            return Err(OpLogDecodeError::Truncated);
+        }
+
+        let log = OpLog::new();
+        for (index, encoded) in body.chunks(Op::ENCODED_SIZE).enumerate() {
+            let addr = encoded[2..]
+                .iter()
+                .enumerate()
+                .fold(0u64, |addr, (byte, &value)| addr | u64::from(value) << (byte * 8));
+            let addr = addr as usize;
+            let order = encoded[1];
+
+            let op = match encoded[0] {
+                Op::ALLOC_TAG => Op::Alloc { addr, order },
+                Op::DEALLOC_TAG => Op::Dealloc { addr, order },
+                tag => return Err(OpLogDecodeError::UnknownOp { index, tag }),
+            };
+            log.push(op);
+        }
+
+        Ok(log)
+    }
+}
+
+impl AllocObserver for OpLog {
+    fn on_alloc(&mut self, addr: usize, order: u8) {
+        self.push(Op::Alloc { addr, order });
+    }
+
+    fn on_dealloc(&mut self, addr: usize, order: u8) {
+        self.push(Op::Dealloc { addr, order });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buddy_allocator_lists::BuddyAllocator;
+    use testing::XorShift;
+    use {MAX_ORDER, MAX_ORDER_SIZE};
+
+    fn allocator() -> BuddyAllocator<Vec<::buddy_allocator_lists::Block>> {
+        let mut allocator = BuddyAllocator::<Vec<_>>::new();
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(1 << MAX_ORDER_SIZE).unwrap();
+        allocator
+    }
+
+    /// Record a random workload of allocations and deallocations
+    fn recorded_log() -> OpLog {
+        let log = OpLog::new();
+        let mut allocator = allocator();
+        allocator.set_observer(Box::new(log.clone()));
+
+        let mut rng = XorShift::new(439);
+        let mut allocated = Vec::new();
+        for _ in 0..200 {
+            if allocated.is_empty() || rng.below(3) != 0 {
+                let order = rng.below(u64::from(MAX_ORDER) / 2) as u8;
+                allocated.push((allocator.allocate(order).unwrap(), order));
+            } else {
+                let index = rng.below(allocated.len() as u64) as usize;
+                let (addr, order) = allocated.swap_remove(index);
+                allocator.deallocate(addr, order).unwrap();
+            }
+        }
+
+        log
+    }
+
+    #[test]
+    fn test_replay_matches() {
+        let log = recorded_log();
+        assert_eq!(log.ops().len(), 200);
+        assert_eq!(log.replay(&mut allocator()), Ok(()));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let log = recorded_log();
+        let bytes = log.to_bytes();
+        assert_eq!(bytes.len(), 5 + 200 * Op::ENCODED_SIZE);
+
+        let decoded = OpLog::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.ops(), log.ops());
+        assert_eq!(decoded.replay(&mut allocator()), Ok(()));
+
+        let log = OpLog::new();
+        log.push(Op::Dealloc {
+            addr: usize::max_value(),
+            order: u8::max_value(),
+        });
+        assert_eq!(OpLog::from_bytes(&log.to_bytes()).unwrap().ops(), log.ops());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let bytes = recorded_log().to_bytes();
+
+        assert_eq!(OpLog::from_bytes(&[]).err(), Some(OpLogDecodeError::BadHeader));
+        assert_eq!(OpLog::from_bytes(&bytes[1..]).err(), Some(OpLogDecodeError::BadHeader));
+        assert_eq!(
+            OpLog::from_bytes(&bytes[..bytes.len() - 1]).err(),
+            Some(OpLogDecodeError::Truncated)
+        );
+
+        let mut bad_tag = bytes.clone();
+        bad_tag[5 + 3 * Op::ENCODED_SIZE] = 7;
+        assert_eq!(
+            OpLog::from_bytes(&bad_tag).err(),
+            Some(OpLogDecodeError::UnknownOp { index: 3, tag: 7 })
+        );
+    }
+
+    #[test]
+    fn test_replay_detects_divergence() {
+        let log = recorded_log();
+        let mut ops = log.ops();
+
+        // Inject a different address into the tenth operation
+        let injected = match ops[10] {
+            Op::Alloc { addr, order } => Op::Alloc {
+                addr: addr ^ (1 << MAX_ORDER_SIZE),
+                order,
+            },
+            Op::Dealloc { addr, order } => Op::Dealloc {
+                addr: addr ^ (1 << MAX_ORDER_SIZE),
+                order,
+            },
+        };
+        ops[10] = injected;
+
+        let diverging = OpLog::new();
+        for &op in &ops {
+            diverging.push(op);
+        }
+
+        let divergence = diverging.replay(&mut allocator()).unwrap_err();
+        assert_eq!(divergence.index, 10);
+        assert_eq!(divergence.expected, injected);
+    }
+}
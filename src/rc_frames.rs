@@ -0,0 +1,175 @@
+//! Frames shared by reference count on top of any allocator, e.g. for copy-on-write memory which a
+//! fork shares between two address spaces until either writes to it.
+//!
+//! Every frame begins with one reference, and only frames with more than one have an entry in the
+//! count table, so the common unshared frame costs nothing but the record of its order. A frame is
+//! only freed to the allocator once its last reference is released.
+
+use std::collections::HashMap;
+use BuddyAllocatorApi;
+
+/// A reference to a shared frame, which is given back with [RcFrames::release].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FrameRef {
+    addr: usize,
+    order: u8,
+}
+
+impl FrameRef {
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn order(&self) -> u8 {
+        self.order
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FrameError {
+    /// No frame allocated through the layer begins at the address, or it has already been freed
+    UnknownFrame { addr: usize },
+}
+
+pub struct RcFrames<A: BuddyAllocatorApi> {
+    allocator: A,
+    /// The order of every frame which has not been freed
+    orders: HashMap<usize, u8>,
+    /// The references to every frame with more than one. Any other frame has exactly one.
+    counts: HashMap<usize, usize>,
+}
+
+impl<A: BuddyAllocatorApi> RcFrames<A> {
+    pub fn new(allocator: A) -> Self {
+        RcFrames {
+            allocator,
+            orders: HashMap::new(),
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Allocate a frame of the given order with one reference. Returns `None` if the allocator
+    /// has no block of the order free.
+    pub fn alloc_shared(&mut self, order: u8) -> Option<FrameRef> {
+        let addr = self.allocator.allocate(order)?;
+        self.orders.insert(addr, order);
+        Some(FrameRef { addr, order })
+    }
+
+    /// Add a reference to the frame beginning at `addr`.
+    pub fn clone_ref(&mut self, addr: usize) -> Result<FrameRef, FrameError> {
+        let order = *self.orders.get(&addr).ok_or(FrameError::UnknownFrame { addr })?;
+        *self.counts.entry(addr).or_insert(1) += 1;
+        Ok(FrameRef { addr, order })
+    }
+
+    /// Release a reference to the frame beginning at `addr`, freeing the frame if it was the last.
+    /// Returns how many references are left, which is 0 once the frame has been freed.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the allocator does not accept the frame back, as only the layer frees its frames.
+    pub fn release(&mut self, addr: usize) -> Result<usize, FrameError> {
+        if !self.orders.contains_key(&addr) {
+            return Err(FrameError::UnknownFrame { addr });
+        }
+
+        if let Some(count) = self.counts.get_mut(&addr) {
+            *count -= 1;
+            let left = *count;
+            if left == 1 {
+                self.counts.remove(&addr);
+            }
+            return Ok(left);
+        }
+
+        let order = self.orders.remove(&addr).unwrap();
+        assert!(
+            self.allocator.deallocate(addr, order),
+            "Frame {:#x} of order {} was not accepted by the allocator!",
+            addr,
+            order
+        );
+        Ok(0)
+    }
+
+    /// How many references the frame beginning at `addr` has, or `None` if no frame does.
+    pub fn ref_count(&self, addr: usize) -> Option<usize> {
+        self.orders
+            .get(&addr)
+            .map(|_| self.counts.get(&addr).cloned().unwrap_or(1))
+    }
+
+    /// How many frames have more than one reference, and so an entry in the count table
+    pub fn shared_frames(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.allocator
+    }
+
+    pub fn allocator_mut(&mut self) -> &mut A {
+        &mut self.allocator
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buddy_allocator_bitmap::Forest;
+    use buddy_allocator_lists::{Block, BuddyAllocator};
+    use MAX_ORDER;
+
+    /// A parent's frame is shared with two children on fork, which each release it when they
+    /// write to it, and only the parent's release frees it.
+    fn check_fork<A: BuddyAllocatorApi>(allocator: A) {
+        let mut frames = RcFrames::new(allocator);
+        frames.allocator_mut().create_top_level(0);
+
+        let frame = frames.alloc_shared(MAX_ORDER).unwrap();
+        assert_eq!(frames.ref_count(frame.addr()), Some(1));
+        assert_eq!(frames.shared_frames(), 0);
+
+        for refs in 2..=3 {
+            assert_eq!(frames.clone_ref(frame.addr()), Ok(frame));
+            assert_eq!(frames.ref_count(frame.addr()), Some(refs));
+        }
+        assert_eq!(frames.shared_frames(), 1);
+
+        // The children copy the frame and release their references, which keeps it alive
+        assert_eq!(frames.release(frame.addr()), Ok(2));
+        assert_eq!(frames.release(frame.addr()), Ok(1));
+        assert_eq!(frames.shared_frames(), 0);
+        assert_eq!(frames.alloc_shared(MAX_ORDER), None);
+
+        // The last release frees it, so it can be allocated again
+        assert_eq!(frames.release(frame.addr()), Ok(0));
+        assert_eq!(frames.ref_count(frame.addr()), None);
+        assert_eq!(frames.alloc_shared(MAX_ORDER), Some(frame));
+    }
+
+    #[test]
+    fn test_fork_copy_on_write() {
+        check_fork(BuddyAllocator::<Vec<Block>>::new());
+        check_fork(Forest::new());
+    }
+
+    #[test]
+    fn test_unknown_frames_rejected() {
+        let mut frames = RcFrames::new(BuddyAllocator::<Vec<Block>>::new());
+        frames.allocator_mut().create_top_level(0).unwrap();
+        let frame = frames.alloc_shared(0).unwrap();
+        let other = frame.addr() + 0x1000;
+
+        assert_eq!(frames.release(other), Err(FrameError::UnknownFrame { addr: other }));
+        assert_eq!(frames.clone_ref(other), Err(FrameError::UnknownFrame { addr: other }));
+        assert_eq!(frames.ref_count(frame.addr()), Some(1));
+
+        // A frame which has been freed is unknown too
+        assert_eq!(frames.release(frame.addr()), Ok(0));
+        let error = Err(FrameError::UnknownFrame { addr: frame.addr() });
+        assert_eq!(frames.release(frame.addr()), error);
+        assert_eq!(frames.clone_ref(frame.addr()).map(|_| 0), error);
+    }
+}
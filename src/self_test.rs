@@ -0,0 +1,296 @@
+//! A battery of checks run by the `self-test` subcommand, so that a change to an allocator can be
+//! validated with one command before it is benchmarked. The checks drive every allocator through
+//! the same helpers as the unit tests: the workloads of [workload], the containment and overlap
+//! checks of [testing], the validation of [dump] and the golden files under `testdata/golden`.
+//!
+//! A check fails by panicking, as the helpers do. The panic is caught and reported, and the
+//! remaining checks still run.
+
+use std::any::Any;
+use std::collections::LinkedList;
+use std::io::{self, Write};
+use std::panic;
+use buddy_allocator_bitmap::Forest;
+use buddy_allocator_lists::{self, BuddyAllocator as ListsAllocator};
+use buddy_allocator_tree::{self, BlockPtrAdapter, BuddyAllocator as TreeAllocator};
+use dump::{parse_text_dump, AllocatorState};
+#[cfg(not(feature = "large_config"))]
+use golden;
+use intrusive_collections::SinglyLinkedList;
+use stats::AllocatorStats;
+use testing::check_unique_addresses;
+use workload::{self, Outcome, WorkloadSpec};
+use super::{AllocationPolicy, BuddyAllocatorApi, LEVEL_COUNT, MAX_ORDER, MAX_ORDER_SIZE};
+
+type Vecs = ListsAllocator<Vec<buddy_allocator_lists::Block>>;
+type LinkedLists = ListsAllocator<LinkedList<buddy_allocator_lists::Block>>;
+type RbTreeVecs = TreeAllocator<Vec<*const buddy_allocator_tree::Block>>;
+type RbTreeLinkedLists = TreeAllocator<SinglyLinkedList<BlockPtrAdapter>>;
+
+/// One named check, which fails by panicking
+#[derive(Copy, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+/// Every built in check, in the order they are run
+pub fn checks() -> Vec<Check> {
+    let mut checks = vec![
+        Check { name: "differential workloads", run: check_differential },
+        Check { name: "invariants after churn", run: check_invariants },
+        Check { name: "containment and alignment", run: check_containment },
+    ];
+    checks.extend(golden_checks());
+    checks
+}
+
+#[cfg(not(feature = "large_config"))]
+fn golden_checks() -> Vec<Check> {
+    vec![
+        Check { name: "golden lists", run: golden::check_lists },
+        Check { name: "golden rb-tree", run: golden::check_rb_tree },
+        Check { name: "golden bitmap", run: golden::check_bitmap },
+    ]
+}
+
+/// The golden addresses depend on the size of a top level block, so there are none to compare
+#[cfg(feature = "large_config")]
+fn golden_checks() -> Vec<Check> {
+    Vec::new()
+}
+
+/// Run every check, writing a line with whether it passed to `out` and then a summary. Returns
+/// whether every check passed.
+pub fn run_checks<W: Write>(checks: &[Check], mut out: W) -> io::Result<bool> {
+    let mut failed = 0;
+
+    for check in checks {
+        match panic::catch_unwind(check.run) {
+            Ok(()) => writeln!(out, "check {} ... ok", check.name)?,
+            Err(payload) => {
+                failed += 1;
+                writeln!(out, "check {} ... FAILED: {}", check.name, panic_message(&*payload))?;
+            }
+        }
+    }
+
+    let result = if failed == 0 { "ok" } else { "FAILED" };
+    writeln!(
+        out,
+        "\nself-test result: {}. {} passed; {} failed",
+        result,
+        checks.len() - failed,
+        failed
+    )?;
+
+    Ok(failed == 0)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<unknown panic>"
+    }
+}
+
+/// The top level blocks every check gives its allocators. They are not in address order, so that
+/// the first region is not the lowest.
+fn bases() -> [usize; 3] {
+    [2 << MAX_ORDER_SIZE, 0, 1 << MAX_ORDER_SIZE]
+}
+
+fn workloads() -> Vec<WorkloadSpec> {
+    vec![
+        WorkloadSpec::uniform(1500, 10, 497),
+        WorkloadSpec {
+            total_ops: 1500,
+            order_distribution: vec![8, 4, 2, 1],
+            free_ratio: 0.45,
+            seed: 498,
+        },
+        // Large blocks run out, so allocations fail part of the time
+        WorkloadSpec::uniform(300, MAX_ORDER, 499),
+    ]
+}
+
+/// Every outcome of `spec` on `allocator`, given the top level blocks of [bases]
+fn outcomes<A: BuddyAllocatorApi>(mut allocator: A, spec: &WorkloadSpec) -> Vec<Outcome> {
+    for &base in &bases() {
+        allocator.create_top_level(base);
+    }
+
+    let mut outcomes = Vec::with_capacity(spec.total_ops);
+    workload::run_with(&mut allocator, spec, |_, outcome| outcomes.push(outcome));
+    outcomes
+}
+
+/// With the deterministic policy every allocator must hand out the same blocks for a workload.
+fn check_differential() {
+    for spec in &workloads() {
+        let mut vecs = Vecs::new();
+        vecs.set_policy(AllocationPolicy::Deterministic);
+        let expected = outcomes(vecs, spec);
+
+        let mut linked_lists = LinkedLists::new();
+        linked_lists.set_policy(AllocationPolicy::Deterministic);
+        assert_agrees("linked_lists", outcomes(linked_lists, spec), &expected, spec);
+
+        let mut rb_tree_vecs = RbTreeVecs::new();
+        rb_tree_vecs.set_policy(AllocationPolicy::Deterministic);
+        assert_agrees("rb_tree_vecs", outcomes(rb_tree_vecs, spec), &expected, spec);
+
+        let mut rb_tree_linked_lists = RbTreeLinkedLists::new();
+        rb_tree_linked_lists.set_policy(AllocationPolicy::Deterministic);
+        let actual = outcomes(rb_tree_linked_lists, spec);
+        assert_agrees("rb_tree_linked_lists", actual, &expected, spec);
+
+        let mut bitmap = Forest::new();
+        bitmap.set_policy(AllocationPolicy::Deterministic);
+        assert_agrees("bitmap", outcomes(bitmap, spec), &expected, spec);
+    }
+}
+
+fn assert_agrees(name: &str, actual: Vec<Outcome>, expected: &[Outcome], spec: &WorkloadSpec) {
+    if let Some(op) = actual.iter().zip(expected).position(|(a, e)| a != e) {
+        panic!(
+            "{} disagrees with vecs at operation {} of {:?}: {:?} rather than {:?}",
+            name, op, spec, actual[op], expected[op]
+        );
+    }
+}
+
+/// Churn every allocator with each workload, then check that its blocks are valid, that the used
+/// ones are exactly the live ones, and that freeing them merges every region back.
+fn check_invariants() {
+    for spec in &workloads() {
+        check_churn("vecs", &mut Vecs::new(), spec);
+        check_churn("linked_lists", &mut LinkedLists::new(), spec);
+
+        let mut rb_tree_vecs = RbTreeVecs::new();
+        check_churn("rb_tree_vecs", &mut rb_tree_vecs, spec);
+        rb_tree_vecs.check_free_lists().expect("rb_tree_vecs free lists are inconsistent");
+
+        let mut rb_tree_linked_lists = RbTreeLinkedLists::new();
+        check_churn("rb_tree_linked_lists", &mut rb_tree_linked_lists, spec);
+        rb_tree_linked_lists
+            .check_free_lists()
+            .expect("rb_tree_linked_lists free lists are inconsistent");
+
+        check_churn("bitmap", &mut Forest::new(), spec);
+    }
+}
+
+fn check_churn<A>(name: &str, allocator: &mut A, spec: &WorkloadSpec)
+where
+    A: BuddyAllocatorApi + AllocatorStats,
+{
+    for &base in &bases() {
+        allocator.create_top_level(base);
+    }
+    let driver = workload::run(allocator, spec);
+
+    // A dump is only parsed if its blocks cover the regions without overlapping and every free
+    // buddy has been merged
+    let mut text = Vec::new();
+    AllocatorState::of(allocator).write_text(&mut text).unwrap();
+    let state = parse_text_dump(&text[..])
+        .unwrap_or_else(|err| panic!("{} has invalid blocks after {:?}: {:?}", name, spec, err));
+
+    let mut used: Vec<(usize, u8)> = state
+        .blocks()
+        .iter()
+        .filter(|block| block.used)
+        .map(|block| (block.addr, block.order))
+        .collect();
+    let mut live = driver.live().to_vec();
+    used.sort();
+    live.sort();
+    assert_eq!(used, live, "{} used blocks are not the live blocks after {:?}", name, spec);
+
+    for &(addr, order) in driver.live() {
+        assert!(allocator.deallocate(addr, order), "{} did not free {:#x}", name, addr);
+    }
+
+    let mut expected = [0; LEVEL_COUNT as usize];
+    expected[MAX_ORDER as usize] = bases().len();
+    assert_eq!(allocator.free_histogram(), expected, "{} did not merge back", name);
+}
+
+/// Every block must lie inside a region, be aligned to its size and overlap no other block.
+fn check_containment() {
+    let orders = [0, 1, 3, 0, 5, 2];
+    check_unique_addresses(&mut Vecs::new(), 2, &orders, 2000);
+    check_unique_addresses(&mut LinkedLists::new(), 2, &orders, 2000);
+    check_unique_addresses(&mut RbTreeVecs::new(), 2, &orders, 2000);
+    check_unique_addresses(&mut RbTreeLinkedLists::new(), 2, &orders, 2000);
+    check_unique_addresses(&mut Forest::new(), 2, &orders, 2000);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn passing() {}
+
+    fn failing() {
+        panic!("Block 0x1000 overlaps a block allocated before it!");
+    }
+
+    fn failing_formatted() {
+        panic!("{} failed", "formatted");
+    }
+
+    fn report(checks: &[Check]) -> (bool, String) {
+        let mut out = Vec::new();
+        let passed = run_checks(checks, &mut out).unwrap();
+        (passed, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_all_pass() {
+        let checks = [
+            Check { name: "first", run: passing },
+            Check { name: "second", run: passing },
+        ];
+        let (passed, out) = report(&checks);
+
+        assert!(passed);
+        assert_eq!(
+            out,
+            "check first ... ok\ncheck second ... ok\n\nself-test result: ok. 2 passed; 0 failed\n"
+        );
+    }
+
+    #[test]
+    fn test_failure_reported() {
+        let checks = [
+            Check { name: "failing", run: failing },
+            Check { name: "passing", run: passing },
+            Check { name: "formatted", run: failing_formatted },
+        ];
+        let (passed, out) = report(&checks);
+
+        // The checks after a failure still run
+        assert!(!passed);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "check failing ... FAILED: Block 0x1000 overlaps a block allocated before it!",
+                "check passing ... ok",
+                "check formatted ... FAILED: formatted failed",
+                "",
+                "self-test result: FAILED. 1 passed; 2 failed",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_checks() {
+        assert_eq!(report(&[]), (true, "\nself-test result: ok. 0 passed; 0 failed\n".to_string()));
+    }
+}
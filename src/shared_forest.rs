@@ -0,0 +1,228 @@
+//! A forest of bitmap trees which is shared between threads, and which grows by a tree whenever
+//! every tree is full.
+//!
+//! Each tree has its own lock, so threads allocating from different trees don't wait for each
+//! other. Growing takes a separate growth lock, which also guards the [RegionProvider]. A thread
+//! which finds every tree full waits for the growth lock, then checks again whether another thread
+//! grew the forest while it waited, so only one tree is added however many threads run out at once.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use buddy_allocator_bitmap::{block_size, Tree};
+use locked::Locked;
+use MAX_ORDER;
+
+/// Supplies the memory a [SharedForest] grows into. Closures returning `Option<usize>` are
+/// providers.
+pub trait RegionProvider: Send {
+    /// The address of the next top level block to add a tree for, which must be aligned to the
+    /// size of a block of [MAX_ORDER], or `None` if there is no physical memory left. Once `None`
+    /// has been returned, the forest does not ask again.
+    fn next_region(&mut self) -> Option<usize>;
+}
+
+impl<F: FnMut() -> Option<usize> + Send> RegionProvider for F {
+    fn next_region(&mut self) -> Option<usize> {
+        self()
+    }
+}
+
+pub struct SharedForest<P: RegionProvider> {
+    /// Each tree with its base address, so that finding the tree of an address locks no others.
+    /// Only ever appended to, and only while the growth lock is held.
+    trees: RwLock<Vec<(usize, Locked<Tree>)>>,
+    /// The growth lock
+    provider: Locked<P>,
+    /// Set once the provider has no more regions
+    exhausted: AtomicBool,
+}
+
+impl<P: RegionProvider> SharedForest<P> {
+    /// A forest with no trees, which asks `provider` for its first tree when first allocated from.
+    pub fn new(provider: P) -> Self {
+        SharedForest {
+            trees: RwLock::new(Vec::new()),
+            provider: Locked::new(provider),
+            exhausted: AtomicBool::new(false),
+        }
+    }
+
+    /// Add a tree managing the block of [MAX_ORDER] beginning at `begin_address`, without asking
+    /// the provider.
+    pub fn create_top_level(&self, begin_address: usize) {
+        let _growing = self.provider.lock();
+        self.push_tree(begin_address);
+    }
+
+    fn push_tree(&self, begin_address: usize) {
+        let tree = Locked::new(Tree::new_at(begin_address));
+        self.trees.write().unwrap().push((begin_address, tree));
+    }
+
+    /// How many trees the forest has, including those it grew by
+    pub fn tree_count(&self) -> usize {
+        self.trees.read().unwrap().len()
+    }
+
+    /// Allocate a block of the given order from the first tree which has one free, growing the
+    /// forest if none has. Returns `None` if the order is larger than [MAX_ORDER] or the provider
+    /// has no memory left.
+    pub fn alloc_exact(&self, desired_order: u8) -> Option<*const u8> {
+        if desired_order > MAX_ORDER {
+            return None;
+        }
+
+        loop {
+            let seen = {
+                let trees = self.trees.read().unwrap();
+                let addr = trees
+                    .iter()
+                    .filter_map(|(_, tree)| tree.lock().alloc_exact(desired_order))
+                    .next();
+
+                if addr.is_some() {
+                    return addr;
+                }
+
+                trees.len()
+            };
+
+            if !self.grow(seen) {
+                return None;
+            }
+        }
+    }
+
+    /// Add a tree from the provider, unless another thread has added one since the caller found
+    /// `seen` trees full. Returns `false` if the provider has no memory left.
+    fn grow(&self, seen: usize) -> bool {
+        let mut provider = self.provider.lock();
+
+        // Trees are only added under the growth lock, so whoever grew the forest while this thread
+        // waited for it has finished. Retry the new trees rather than growing again.
+        if self.tree_count() > seen {
+            return true;
+        }
+
+        if self.exhausted.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        match provider.next_region() {
+            Some(begin_address) => {
+                self.push_tree(begin_address);
+                true
+            }
+            None => {
+                self.exhausted.store(true, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Free the block of the given order beginning at `addr`. Returns `false` if the tree
+    /// containing the address has no used block of that order there, or no tree contains it.
+    pub fn dealloc_exact(&self, addr: *const u8, order: u8) -> bool {
+        let top_level_size = block_size(MAX_ORDER);
+        let trees = self.trees.read().unwrap();
+
+        // Only the tree containing the address can accept it, so only it is locked
+        trees
+            .iter()
+            .find(|&&(base, _)| addr as usize >= base && addr as usize - base < top_level_size)
+            .map_or(false, |(_, tree)| tree.lock().dealloc_exact(addr, order))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use testing::BlockSet;
+    use MAX_ORDER_SIZE;
+
+    /// A provider of consecutive top level blocks from address 0, which counts how often it was
+    /// asked and stops after `limit`.
+    fn counting_provider(
+        limit: usize,
+        calls: Arc<AtomicUsize>,
+    ) -> impl FnMut() -> Option<usize> + Send {
+        move || {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            if call < limit {
+                Some(call << MAX_ORDER_SIZE)
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_grows_on_exhaustion() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let forest = SharedForest::new(counting_provider(2, calls.clone()));
+        assert_eq!(forest.tree_count(), 0);
+
+        // Each tree holds exactly two blocks of the order below the top
+        let order = MAX_ORDER - 1;
+        let addrs: Vec<_> = (0..4).map(|_| forest.alloc_exact(order).unwrap()).collect();
+        assert_eq!(forest.tree_count(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // The provider is asked once more, and then never again
+        assert_eq!(forest.alloc_exact(order), None);
+        assert_eq!(forest.alloc_exact(order), None);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(forest.tree_count(), 2);
+
+        // Freed blocks can be allocated again without growing
+        assert!(forest.dealloc_exact(addrs[3], order));
+        assert!(!forest.dealloc_exact(addrs[3], order));
+        assert!(!forest.dealloc_exact((4usize << MAX_ORDER_SIZE) as *const u8, order));
+        assert_eq!(forest.alloc_exact(order), Some(addrs[3]));
+        assert_eq!(forest.alloc_exact(MAX_ORDER + 1), None);
+    }
+
+    #[test]
+    fn test_concurrent_growth() {
+        const THREADS: usize = 8;
+        const ALLOCATIONS: usize = 5;
+        // Every tree holds exactly four blocks of two orders below the top, and nothing is freed,
+        // so the forest must grow to exactly as many trees as the blocks need
+        const PER_TREE: usize = 4;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let forest = Arc::new(SharedForest::new(counting_provider(64, calls.clone())));
+        forest.create_top_level(1 << 40);
+        let order = MAX_ORDER - 2;
+
+        let addrs = Arc::new(Mutex::new(Vec::new()));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (forest, addrs) = (forest.clone(), addrs.clone());
+                thread::spawn(move || {
+                    for _ in 0..ALLOCATIONS {
+                        let addr = forest.alloc_exact(order).unwrap();
+                        addrs.lock().unwrap().push(addr as usize);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let trees = (THREADS * ALLOCATIONS + PER_TREE - 1) / PER_TREE;
+        assert_eq!(forest.tree_count(), trees);
+        assert_eq!(calls.load(Ordering::SeqCst), trees - 1);
+
+        let mut blocks = BlockSet::new();
+        for &addr in addrs.lock().unwrap().iter() {
+            assert!(blocks.insert(addr, block_size(order)), "{:#x} handed out twice", addr);
+        }
+        assert_eq!(blocks.len(), THREADS * ALLOCATIONS);
+    }
+}
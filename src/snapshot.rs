@@ -0,0 +1,207 @@
+//! Saving the state of an allocator to bytes and restoring it, e.g. to resume a kernel's physical
+//! allocator after a hibernation or to reproduce a bug from a dump.
+//!
+//! Each allocator writes a header of four magic bytes and a version, followed by fields encoded as
+//! little endian integers. Derived structures are never saved; they are rebuilt from the saved
+//! state, which is checked against the allocator's invariants when loaded.
+
+use stats::Usage;
+
+/// The version written by [SnapshotWriter::new] and the only version which can be read
+pub const SNAPSHOT_VERSION: u8 = 2;
+
+/// Why a snapshot could not be loaded.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SnapshotError {
+    /// The bytes do not begin with the magic bytes of the allocator being restored
+    BadHeader,
+    /// The snapshot was written by a different version of the format
+    UnsupportedVersion { version: u8 },
+    /// The bytes end part way through the snapshot
+    Truncated,
+    /// There are bytes after the end of the snapshot
+    TrailingBytes { count: usize },
+    /// A field has a value which no allocator could have saved
+    InvalidField { field: &'static str },
+    /// The block at this index (its meaning depends on the allocator) breaks an invariant
+    InvalidBlock { index: usize },
+}
+
+/// Encodes a snapshot.
+#[derive(Debug)]
+pub struct SnapshotWriter {
+    bytes: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    /// Begin a snapshot with the given magic bytes and [SNAPSHOT_VERSION].
+    pub fn new(magic: &[u8; 4]) -> Self {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(magic);
+        bytes.push(SNAPSHOT_VERSION);
+        SnapshotWriter { bytes }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        for byte in 0..8 {
+            self.bytes.push((value >> (byte * 8)) as u8);
+        }
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn usage(&mut self, usage: &Usage) {
+        self.u64(usage.used_bytes() as u64);
+        self.u64(usage.outstanding_allocations() as u64);
+        self.u64(usage.peak_used_bytes() as u64);
+        self.u64(usage.peak_outstanding_allocations() as u64);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Decodes a snapshot written by a [SnapshotWriter].
+#[derive(Debug)]
+pub struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    /// Check the header of a snapshot, which must have the given magic bytes.
+    pub fn new(bytes: &'a [u8], magic: &[u8; 4]) -> Result<Self, SnapshotError> {
+        if bytes.len() < magic.len() || &bytes[..magic.len()] != magic {
+            return Err(SnapshotError::BadHeader);
+        }
+
+        let mut reader = SnapshotReader {
+            bytes: &bytes[magic.len()..],
+        };
+
+        match reader.u8()? {
+            SNAPSHOT_VERSION => Ok(reader),
+            version => Err(SnapshotError::UnsupportedVersion { version }),
+        }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let bytes = self.bytes(8)?;
+        Ok(bytes
+            .iter()
+            .enumerate()
+            .fold(0u64, |value, (byte, &part)| value | u64::from(part) << (byte * 8)))
+    }
+
+    /// A u64 which must fit in a usize.
+    pub fn usize(&mut self, field: &'static str) -> Result<usize, SnapshotError> {
+        let value = self.u64()?;
+        if value > usize::max_value() as u64 {
+            return Err(SnapshotError::InvalidField { field });
+        }
+
+        Ok(value as usize)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < len {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let (read, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(read)
+    }
+
+    /// Usage saved by [SnapshotWriter::usage]. The peaks must be no lower than the counts.
+    pub fn usage(&mut self) -> Result<Usage, SnapshotError> {
+        let used_bytes = self.usize("used bytes")?;
+        let outstanding_allocations = self.usize("outstanding allocations")?;
+        let peak_used_bytes = self.usize("peak used bytes")?;
+        let peak_outstanding_allocations = self.usize("peak outstanding allocations")?;
+
+        if peak_used_bytes < used_bytes {
+            return Err(SnapshotError::InvalidField { field: "peak used bytes" });
+        }
+
+        if peak_outstanding_allocations < outstanding_allocations {
+            return Err(SnapshotError::InvalidField { field: "peak outstanding allocations" });
+        }
+
+        Ok(Usage::with_counts(
+            used_bytes,
+            outstanding_allocations,
+            peak_used_bytes,
+            peak_outstanding_allocations,
+        ))
+    }
+
+    /// Check that the whole snapshot was read.
+    pub fn finish(self) -> Result<(), SnapshotError> {
+        match self.bytes.len() {
+            0 => Ok(()),
+            count => Err(SnapshotError::TrailingBytes { count }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_fields() {
+        let mut writer = SnapshotWriter::new(b"TEST");
+        writer.u8(7);
+        writer.u64(0x0123_4567_89ab_cdef);
+        writer.bytes(&[1, 2, 3]);
+        let bytes = writer.finish();
+        assert_eq!(bytes.len(), 4 + 1 + 1 + 8 + 3);
+
+        let mut reader = SnapshotReader::new(&bytes, b"TEST").unwrap();
+        assert_eq!(reader.u8(), Ok(7));
+        assert_eq!(reader.u64(), Ok(0x0123_4567_89ab_cdef));
+        assert_eq!(reader.bytes(3), Ok(&[1, 2, 3][..]));
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_header_errors() {
+        let mut bytes = SnapshotWriter::new(b"TEST").finish();
+        let read = |bytes: &[u8], magic: &[u8; 4]| SnapshotReader::new(bytes, magic).err();
+        assert_eq!(read(&bytes, b"OTHR"), Some(SnapshotError::BadHeader));
+        assert_eq!(read(&bytes[..2], b"TEST"), Some(SnapshotError::BadHeader));
+        assert_eq!(read(&bytes[..4], b"TEST"), Some(SnapshotError::Truncated));
+
+        bytes[4] = SNAPSHOT_VERSION + 1;
+        assert_eq!(
+            read(&bytes, b"TEST"),
+            Some(SnapshotError::UnsupportedVersion { version: SNAPSHOT_VERSION + 1 })
+        );
+    }
+
+    #[test]
+    fn test_reader_errors() {
+        let mut writer = SnapshotWriter::new(b"TEST");
+        writer.u64(5);
+        writer.u8(0);
+        let bytes = writer.finish();
+
+        let mut reader = SnapshotReader::new(&bytes[..bytes.len() - 2], b"TEST").unwrap();
+        assert_eq!(reader.u64(), Err(SnapshotError::Truncated));
+
+        let mut reader = SnapshotReader::new(&bytes, b"TEST").unwrap();
+        reader.u64().unwrap();
+        assert_eq!(reader.finish(), Err(SnapshotError::TrailingBytes { count: 1 }));
+    }
+}
@@ -0,0 +1,628 @@
+//! Statistics about the state of the allocators, used to visualise fragmentation.
+
+use std::cmp;
+use std::io::{self, Write};
+use dump::AllocatorState;
+#[cfg(feature = "metrics")]
+use metrics::LatencySummary;
+#[cfg(feature = "metrics-export")]
+use metrics_export::Metrics;
+use geometry::size_of_order;
+use super::{BASE_ORDER, LEVEL_COUNT};
+
+/// Statistics which every allocator can report about its blocks.
+pub trait AllocatorStats {
+    /// The number of free blocks of each order, indexed by order. Only maximal free blocks are
+    /// counted: the free blocks inside a larger free block are not counted again.
+    fn free_histogram(&self) -> [usize; LEVEL_COUNT as usize];
+
+    /// How much memory is allocated now and at most since construction or [reset_peaks].
+    fn usage(&self) -> &Usage;
+
+    /// Start tracking the peaks from the current usage.
+    fn reset_peaks(&mut self);
+
+    /// The most bytes which have been allocated at once since construction or [reset_peaks].
+    fn peak_used_bytes(&self) -> usize {
+        self.usage().peak_used_bytes()
+    }
+
+    /// The most blocks which have been allocated at once since construction or [reset_peaks].
+    fn peak_outstanding_allocations(&self) -> usize {
+        self.usage().peak_outstanding_allocations()
+    }
+
+    /// How many operations of each kind have been performed since construction or
+    /// [reset_op_counters].
+    fn op_counters(&self) -> OpCounters;
+
+    /// Set all of the operation counters back to 0.
+    fn reset_op_counters(&mut self);
+
+    /// The distribution of the durations of the allocation and free operations performed since
+    /// construction.
+    #[cfg(feature = "metrics")]
+    fn latency_summary(&self) -> LatencySummary;
+
+    /// Call `f` with every free block and every used block, in no particular order. Free blocks
+    /// inside larger free blocks are not visited.
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockInfo));
+
+    /// How many bytes of heap memory the allocator uses to keep track of its blocks.
+    fn metadata_bytes(&self) -> usize;
+
+    /// How many bytes of memory the allocator manages, whether free or used.
+    fn managed_bytes(&self) -> usize {
+        collect_blocks(self).iter().map(BlockInfo::size).sum()
+    }
+
+    /// Write every region and block as a text dump, which can be read back with
+    /// [parse_text_dump](::dump::parse_text_dump).
+    fn dump_text<W: Write>(&self, w: W) -> io::Result<()>
+    where
+        Self: Sized,
+    {
+        AllocatorState::of(self).write_text(w)
+    }
+
+    /// Write the free and used bytes, free blocks of each order, operation counts and
+    /// fragmentation of the allocator in the Prometheus text exposition format, each sample
+    /// labelled with `allocator`. See [Metrics::of](::metrics_export::Metrics::of).
+    #[cfg(feature = "metrics-export")]
+    fn encode_metrics<W: Write>(&self, allocator: &str, w: &mut W) -> io::Result<()>
+    where
+        Self: Sized,
+    {
+        Metrics::of(self, allocator).encode(w)
+    }
+
+    /// The bytes of metadata kept per byte of managed memory, or 0 if no memory is managed.
+    fn overhead_ratio(&self) -> f64 {
+        match self.managed_bytes() {
+            0 => 0.0,
+            managed => self.metadata_bytes() as f64 / managed as f64,
+        }
+    }
+
+    /// Render the managed address space as a strip of at most `cols` characters followed by a
+    /// legend. Each character covers the same power of two number of bytes, no less than a block
+    /// of order 0, and shows whether that memory is free, used, partly used, or not managed by the
+    /// allocator at all (reserved).
+    fn render_map(&self, cols: usize) -> String {
+        assert!(cols > 0, "The map must be at least one column wide!");
+
+        let blocks = collect_blocks(self);
+        let (begin, end) = match managed_range(&blocks) {
+            Some(range) => range,
+            None => return String::from("(no memory managed)\n"),
+        };
+
+        let span = end - begin;
+        let bucket_size = cmp::max(1 << BASE_ORDER, ((span + cols - 1) / cols).next_power_of_two());
+        let buckets = bucket_bytes_of(&blocks, begin, end, bucket_size);
+
+        let mut map: String = buckets
+            .iter()
+            .map(|&(used, free)| match (used, free) {
+                (0, 0) => '-',
+                (0, _) => '.',
+                (_, 0) => '#',
+                _ => '+',
+            })
+            .collect();
+
+        map.push_str(&format!(
+            "\n{:#x}..{:#x}, 1 char = {:#x} bytes: \
+             '.' free, '#' used, '+' partly used, '-' reserved\n",
+            begin, end, bucket_size
+        ));
+        map
+    }
+
+    /// The base address and length of the largest run of contiguous free memory, or `None` if no
+    /// memory is free. Adjacent free blocks form one run even if they could never be merged, such
+    /// as neighbours with different parents or the edges of regions which happen to be adjacent.
+    /// The lowest run wins a tie.
+    fn largest_free_extent(&self) -> Option<(usize, usize)> {
+        let mut blocks = collect_blocks(self);
+        blocks.sort_unstable_by_key(|block| block.addr);
+        largest_free_run(blocks)
+    }
+
+    /// The fraction of bytes which are allocated in each `bucket_bytes` sized bucket of the managed
+    /// address space, from the lowest managed address up. Blocks spanning several buckets count
+    /// towards each bucket by how many of their bytes lie in it. The last bucket may be cut short
+    /// by the end of the managed address space, in which case only the bytes before the end count.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the buckets are smaller than a block of order 0.
+    fn export_usage(&self, bucket_bytes: usize) -> Vec<f32> {
+        assert!(
+            bucket_bytes >= 1 << BASE_ORDER,
+            "Buckets of {:#x} bytes are smaller than the smallest block!",
+            bucket_bytes
+        );
+
+        let blocks = collect_blocks(self);
+        let (begin, end) = match managed_range(&blocks) {
+            Some(range) => range,
+            None => return Vec::new(),
+        };
+
+        bucket_bytes_of(&blocks, begin, end, bucket_bytes)
+            .iter()
+            .enumerate()
+            .map(|(bucket, &(used, _))| {
+                let bucket_begin = begin + bucket * bucket_bytes;
+                let bucket_len = cmp::min(bucket_bytes, end - bucket_begin);
+                used as f32 / bucket_len as f32
+            })
+            .collect()
+    }
+}
+
+fn collect_blocks<S: AllocatorStats + ?Sized>(stats: &S) -> Vec<BlockInfo> {
+    let mut blocks = Vec::new();
+    stats.for_each_block(&mut |block| blocks.push(block));
+    blocks
+}
+
+/// The base address and length of the largest run of adjacent free blocks. The blocks must be
+/// given in address order.
+pub fn largest_free_run<I: IntoIterator<Item = BlockInfo>>(blocks: I) -> Option<(usize, usize)> {
+    let mut largest: Option<(usize, usize)> = None;
+    let mut run: Option<(usize, usize)> = None;
+
+    for block in blocks {
+        if block.used {
+            run = None;
+            continue;
+        }
+
+        run = match run {
+            Some((begin, end)) if end == block.addr => Some((begin, block.end())),
+            _ => Some((block.addr, block.end())),
+        };
+
+        let (begin, end) = run.unwrap();
+        if largest.map_or(true, |(_, len)| end - begin > len) {
+            largest = Some((begin, end - begin));
+        }
+    }
+
+    largest
+}
+
+/// The lowest address of any block and the address after the end of the highest block.
+fn managed_range(blocks: &[BlockInfo]) -> Option<(usize, usize)> {
+    let begin = blocks.iter().map(|block| block.addr).min()?;
+    let end = blocks.iter().map(BlockInfo::end).max()?;
+    Some((begin, end))
+}
+
+/// Split `[begin, end)` into buckets of `bucket_size` bytes and count the (used, free) bytes of the
+/// blocks in each, apportioning blocks which span several buckets.
+fn bucket_bytes_of(
+    blocks: &[BlockInfo],
+    begin: usize,
+    end: usize,
+    bucket_size: usize,
+) -> Vec<(usize, usize)> {
+    let mut buckets = vec![(0, 0); (end - begin + bucket_size - 1) / bucket_size];
+
+    for block in blocks {
+        let first_bucket = (block.addr - begin) / bucket_size;
+        let last_bucket = (block.end() - 1 - begin) / bucket_size;
+
+        for (bucket, counts) in buckets[first_bucket..=last_bucket].iter_mut().enumerate() {
+            let bucket_begin = begin + (first_bucket + bucket) * bucket_size;
+            let overlap_begin = cmp::max(block.addr, bucket_begin);
+            let overlap_end = cmp::min(block.end(), bucket_begin + bucket_size);
+
+            if block.used {
+                counts.0 += overlap_end - overlap_begin;
+            } else {
+                counts.1 += overlap_end - overlap_begin;
+            }
+        }
+    }
+
+    buckets
+}
+
+/// A free or used block reported by [AllocatorStats::for_each_block].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BlockInfo {
+    pub addr: usize,
+    pub order: u8,
+    pub used: bool,
+}
+
+impl BlockInfo {
+    pub fn size(&self) -> usize {
+        size_of_order(self.order)
+    }
+
+    /// The address of the first byte after the block
+    pub fn end(&self) -> usize {
+        self.addr + self.size()
+    }
+}
+
+/// Counts of the memory currently allocated from an allocator and the highest those counts have
+/// been. Updated by the allocators on every allocation and free, so it must stay cheap.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Usage {
+    used_bytes: usize,
+    outstanding_allocations: usize,
+    peak_used_bytes: usize,
+    peak_outstanding_allocations: usize,
+}
+
+impl Usage {
+    pub const fn new() -> Self {
+        Usage {
+            used_bytes: 0,
+            outstanding_allocations: 0,
+            peak_used_bytes: 0,
+            peak_outstanding_allocations: 0,
+        }
+    }
+
+    /// Usage with the given counts, e.g. those of an allocator being restored. The peaks must be no
+    /// lower than the counts.
+    pub fn with_counts(
+        used_bytes: usize,
+        outstanding_allocations: usize,
+        peak_used_bytes: usize,
+        peak_outstanding_allocations: usize,
+    ) -> Self {
+        debug_assert!(peak_used_bytes >= used_bytes);
+        debug_assert!(peak_outstanding_allocations >= outstanding_allocations);
+
+        Usage {
+            used_bytes,
+            outstanding_allocations,
+            peak_used_bytes,
+            peak_outstanding_allocations,
+        }
+    }
+
+    /// Record that a block of the given order was allocated.
+    #[inline]
+    pub fn allocated(&mut self, order: u8) {
+        self.allocated_bytes(size_of_order(order));
+    }
+
+    /// Record that a block of `size` bytes was allocated, for allocators whose blocks are not
+    /// sized by the crate wide base order.
+    #[inline]
+    pub fn allocated_bytes(&mut self, size: usize) {
+        self.used_bytes += size;
+        self.outstanding_allocations += 1;
+
+        if self.used_bytes > self.peak_used_bytes {
+            self.peak_used_bytes = self.used_bytes;
+        }
+
+        if self.outstanding_allocations > self.peak_outstanding_allocations {
+            self.peak_outstanding_allocations = self.outstanding_allocations;
+        }
+    }
+
+    /// Record that a block of the given order was freed.
+    #[inline]
+    pub fn freed(&mut self, order: u8) {
+        self.freed_bytes(size_of_order(order));
+    }
+
+    /// Record that a block of `size` bytes was freed, like [Usage::allocated_bytes].
+    #[inline]
+    pub fn freed_bytes(&mut self, size: usize) {
+        debug_assert!(self.outstanding_allocations > 0, "Freed more blocks than were allocated!");
+        self.used_bytes -= size;
+        self.outstanding_allocations -= 1;
+    }
+
+    pub fn reset_peaks(&mut self) {
+        self.peak_used_bytes = self.used_bytes;
+        self.peak_outstanding_allocations = self.outstanding_allocations;
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    pub fn outstanding_allocations(&self) -> usize {
+        self.outstanding_allocations
+    }
+
+    pub fn peak_used_bytes(&self) -> usize {
+        self.peak_used_bytes
+    }
+
+    pub fn peak_outstanding_allocations(&self) -> usize {
+        self.peak_outstanding_allocations
+    }
+}
+
+/// Cumulative counts of the operations an allocator has performed, each indexed by order.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct OpCounters {
+    /// Blocks of the order which were allocated
+    pub allocations: [u64; LEVEL_COUNT as usize],
+    /// Blocks of the order which were freed
+    pub frees: [u64; LEVEL_COUNT as usize],
+    /// Blocks of the order which were split into two blocks of the order below
+    pub splits: [u64; LEVEL_COUNT as usize],
+    /// Blocks of the order which were made by merging two buddies of the order below
+    pub merges: [u64; LEVEL_COUNT as usize],
+}
+
+impl OpCounters {
+    pub const fn new() -> Self {
+        OpCounters {
+            allocations: [0; LEVEL_COUNT as usize],
+            frees: [0; LEVEL_COUNT as usize],
+            splits: [0; LEVEL_COUNT as usize],
+            merges: [0; LEVEL_COUNT as usize],
+        }
+    }
+
+    /// Add the counts of `other` to these counts.
+    pub fn add(&mut self, other: &OpCounters) {
+        for order in 0..LEVEL_COUNT as usize {
+            self.allocations[order] += other.allocations[order];
+            self.frees[order] += other.frees[order];
+            self.splits[order] += other.splits[order];
+            self.merges[order] += other.merges[order];
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buddy_allocator_bitmap::Forest;
+    use buddy_allocator_lists;
+    use buddy_allocator_tree;
+    use std::collections::LinkedList;
+    use intrusive_collections::SinglyLinkedList;
+    use {BuddyAllocatorApi, MAX_ORDER, MAX_ORDER_SIZE};
+
+    /// Allocate two blocks of order `MAX_ORDER - 2` from one top level block and free them again,
+    /// which splits the top level block twice and merges it back twice.
+    fn check_op_counters<A: BuddyAllocatorApi + AllocatorStats>(mut allocator: A) {
+        allocator.create_top_level(0);
+        allocator.create_top_level(1 << MAX_ORDER_SIZE);
+        let order = MAX_ORDER - 2;
+
+        let first = allocator.allocate(order).unwrap();
+        let second = allocator.allocate(order).unwrap();
+        assert!(allocator.deallocate(first, order));
+        assert!(allocator.deallocate(second, order));
+
+        let mut expected = OpCounters::new();
+        expected.allocations[order as usize] = 2;
+        expected.frees[order as usize] = 2;
+        expected.splits[MAX_ORDER as usize] = 1;
+        expected.splits[MAX_ORDER as usize - 1] = 1;
+        expected.merges[MAX_ORDER as usize] = 1;
+        expected.merges[MAX_ORDER as usize - 1] = 1;
+        assert_eq!(allocator.op_counters(), expected);
+
+        allocator.reset_op_counters();
+        assert_eq!(allocator.op_counters(), OpCounters::new());
+
+        // Everything was merged back, so allocating the whole block splits nothing
+        allocator.allocate(MAX_ORDER).unwrap();
+        let mut expected = OpCounters::new();
+        expected.allocations[MAX_ORDER as usize] = 1;
+        assert_eq!(allocator.op_counters(), expected);
+    }
+
+    #[test]
+    fn test_op_counters() {
+        check_op_counters(Forest::new());
+        check_op_counters(buddy_allocator_lists::BuddyAllocator::<Vec<_>>::new());
+        check_op_counters(buddy_allocator_lists::BuddyAllocator::<LinkedList<_>>::new());
+        check_op_counters(buddy_allocator_tree::BuddyAllocator::<Vec<_>>::new());
+        check_op_counters(buddy_allocator_tree::BuddyAllocator::<SinglyLinkedList<_>>::new());
+    }
+
+    /// Warm up a pristine top level block for four blocks of order 0, then check that nothing was
+    /// allocated by it and that allocating the four blocks splits nothing more.
+    fn check_warm_up<A: BuddyAllocatorApi + AllocatorStats>(mut allocator: A) {
+        allocator.create_top_level(0);
+        allocator.warm_up(0, 4);
+
+        // Splitting down to order 0 left a free upper half at every order above, and the upper
+        // half of order 1 was split too
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[0] = 4;
+        for order in 2..MAX_ORDER {
+            expected[order as usize] = 1;
+        }
+        assert_eq!(allocator.free_histogram(), expected);
+        assert_eq!(allocator.usage().outstanding_allocations(), 0);
+
+        // There are already enough free blocks
+        let splits = allocator.op_counters().splits;
+        allocator.warm_up(0, 3);
+        assert_eq!(allocator.op_counters().splits, splits);
+
+        for _ in 0..4 {
+            allocator.allocate(0).unwrap();
+        }
+        assert_eq!(allocator.op_counters().splits, splits);
+        assert_eq!(allocator.free_histogram()[0], 0);
+    }
+
+    #[test]
+    fn test_warm_up() {
+        check_warm_up(buddy_allocator_lists::BuddyAllocator::<Vec<_>>::new());
+        check_warm_up(buddy_allocator_lists::BuddyAllocator::<LinkedList<_>>::new());
+        check_warm_up(buddy_allocator_tree::BuddyAllocator::<Vec<_>>::new());
+        check_warm_up(buddy_allocator_tree::BuddyAllocator::<SinglyLinkedList<_>>::new());
+
+        // The bitmap has nothing to split ahead of time
+        let mut forest = Forest::new();
+        forest.create_top_level(0);
+        forest.warm_up(0, 4);
+        let mut expected = [0; LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 1;
+        assert_eq!(forest.free_histogram(), expected);
+        assert_eq!(forest.op_counters(), OpCounters::new());
+    }
+
+    #[test]
+    fn test_warm_up_runs_out() {
+        // Only one top level block can be split, and nothing larger than the top level
+        let mut allocator = buddy_allocator_lists::BuddyAllocator::<Vec<_>>::new();
+        allocator.create_top_level(0).unwrap();
+        allocator.warm_up(MAX_ORDER - 1, 5);
+        allocator.warm_up(MAX_ORDER, 5);
+        assert_eq!(allocator.free_histogram()[MAX_ORDER as usize - 1], 2);
+
+        let mut allocator = buddy_allocator_tree::BuddyAllocator::<Vec<_>>::new();
+        allocator.create_top_level(0).unwrap();
+        allocator.warm_up(MAX_ORDER - 1, 5);
+        assert_eq!(allocator.free_histogram()[MAX_ORDER as usize - 1], 2);
+        allocator.check_free_lists().unwrap();
+    }
+
+    /// Make some allocations which split and some frees which merge, then check that every one
+    /// of them was timed.
+    #[cfg(feature = "metrics")]
+    fn check_latency_summary<A: BuddyAllocatorApi + AllocatorStats>(mut allocator: A) {
+        allocator.create_top_level(0);
+        assert_eq!(allocator.latency_summary().count, 0);
+
+        let orders = [0, 3, 1, MAX_ORDER - 1, 0, 2];
+        let addresses: Vec<_> = orders
+            .iter()
+            .map(|&order| allocator.allocate(order).unwrap())
+            .collect();
+        for (&address, &order) in addresses.iter().zip(orders.iter()) {
+            assert!(allocator.deallocate(address, order));
+        }
+
+        let summary = allocator.latency_summary();
+        assert_eq!(summary.count, orders.len() as u64 * 2);
+        assert!(summary.p99_ns >= summary.p50_ns);
+        assert!(summary.max_ns >= summary.p99_ns);
+        assert!(summary.max_ns >= summary.mean_ns);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_latency_summary() {
+        check_latency_summary(Forest::new());
+        check_latency_summary(buddy_allocator_lists::BuddyAllocator::<Vec<_>>::new());
+        check_latency_summary(buddy_allocator_lists::BuddyAllocator::<LinkedList<_>>::new());
+        check_latency_summary(buddy_allocator_tree::BuddyAllocator::<Vec<_>>::new());
+        check_latency_summary(buddy_allocator_tree::BuddyAllocator::<SinglyLinkedList<_>>::new());
+    }
+
+    /// Record the overhead ratio of one top level block (1 GiB by default) after every 1000 of
+    /// 10000 allocations of order 0.
+    fn overhead_ratios<A: BuddyAllocatorApi + AllocatorStats>(mut allocator: A) -> Vec<f64> {
+        allocator.create_top_level(0);
+        assert_eq!(allocator.managed_bytes(), 1 << MAX_ORDER_SIZE);
+
+        let mut ratios = vec![allocator.overhead_ratio()];
+        for allocation in 1..=10_000 {
+            allocator.allocate(0).unwrap();
+            if allocation % 1000 == 0 {
+                ratios.push(allocator.overhead_ratio());
+            }
+        }
+
+        ratios
+    }
+
+    #[test]
+    fn test_overhead_ratio() {
+        let bitmap = overhead_ratios(Forest::new());
+        let lists = overhead_ratios(buddy_allocator_lists::BuddyAllocator::<Vec<_>>::new());
+        let rb_tree = overhead_ratios(buddy_allocator_tree::BuddyAllocator::<Vec<_>>::new());
+
+        // The bitmap is allocated up front for the whole block, while the others grow with it
+        assert!(bitmap[0] > 0.0);
+        let constant = |&ratio: &f64| (ratio - bitmap[0]).abs() < ::std::f64::EPSILON;
+        assert!(bitmap.iter().all(constant), "{:?}", bitmap);
+
+        // The rb-tree keeps its used blocks in a map which grows in steps, while the few nodes of
+        // its free blocks come and go in between
+        assert!(lists.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", lists);
+        for ratios in &[lists, rb_tree] {
+            assert!(ratios[ratios.len() - 1] > ratios[0], "{:?}", ratios);
+        }
+    }
+
+    /// Free quarters of two adjacent top level blocks so that the largest free extent spans both
+    /// a boundary between buddies of different parents and the boundary between the regions.
+    fn check_largest_free_extent<A: BuddyAllocatorApi + AllocatorStats>(mut allocator: A) {
+        let top_level_size = 1usize << MAX_ORDER_SIZE;
+        let quarter = top_level_size / 4;
+        assert_eq!(allocator.largest_free_extent(), None);
+
+        allocator.create_top_level(0);
+        allocator.create_top_level(top_level_size);
+        assert_eq!(allocator.largest_free_extent(), Some((0, top_level_size * 2)));
+
+        for _ in 0..8 {
+            allocator.allocate(MAX_ORDER - 2).unwrap();
+        }
+        assert_eq!(allocator.largest_free_extent(), None);
+
+        // The second and third quarters are not buddies, so they are never merged
+        assert!(allocator.deallocate(quarter, MAX_ORDER - 2));
+        assert!(allocator.deallocate(quarter * 2, MAX_ORDER - 2));
+        assert_eq!(allocator.largest_free_extent(), Some((quarter, quarter * 2)));
+
+        assert!(allocator.deallocate(top_level_size + quarter * 3, MAX_ORDER - 2));
+        assert!(allocator.deallocate(quarter * 3, MAX_ORDER - 2));
+        assert!(allocator.deallocate(top_level_size, MAX_ORDER - 2));
+        assert_eq!(allocator.largest_free_extent(), Some((quarter, quarter * 4)));
+    }
+
+    #[test]
+    fn test_largest_free_extent() {
+        check_largest_free_extent(Forest::new());
+        check_largest_free_extent(buddy_allocator_lists::BuddyAllocator::<Vec<_>>::new());
+        check_largest_free_extent(buddy_allocator_lists::BuddyAllocator::<LinkedList<_>>::new());
+        check_largest_free_extent(buddy_allocator_tree::BuddyAllocator::<Vec<_>>::new());
+        check_largest_free_extent(
+            buddy_allocator_tree::BuddyAllocator::<SinglyLinkedList<_>>::new(),
+        );
+    }
+
+    #[test]
+    fn test_largest_free_run_ties() {
+        let block = |addr, used| BlockInfo { addr, order: 0, used };
+        let size = 1 << BASE_ORDER;
+        let blocks = vec![block(0, false), block(size, true), block(size * 2, false)];
+        assert_eq!(largest_free_run(blocks), Some((0, size)));
+    }
+
+    #[test]
+    fn test_usage_peaks() {
+        let mut usage = Usage::new();
+        usage.allocated(0);
+        usage.allocated(1);
+        usage.freed(1);
+        usage.allocated(0);
+
+        assert_eq!(usage.used_bytes(), 2 << BASE_ORDER);
+        assert_eq!(usage.outstanding_allocations(), 2);
+        assert_eq!(usage.peak_used_bytes(), 3 << BASE_ORDER);
+        assert_eq!(usage.peak_outstanding_allocations(), 2);
+
+        usage.freed(0);
+        usage.reset_peaks();
+        assert_eq!(usage.peak_used_bytes(), 1 << BASE_ORDER);
+        assert_eq!(usage.peak_outstanding_allocations(), 1);
+    }
+}
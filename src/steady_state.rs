@@ -0,0 +1,283 @@
+//! A demo which keeps one allocator across all of its runs. Every run but the first begins by
+//! freeing the blocks of the run before it, so later runs allocate from memory which has been
+//! split and merged again rather than from freshly created top level blocks.
+
+use std::mem;
+use std::time::{Duration, Instant};
+use geometry::size_of_order;
+use testing::RegionTracker;
+use super::{top_level_blocks, BuddyAllocatorApi, DemoError, DurationReport, MAX_ORDER};
+
+/// How many operations a demo run for a duration performs between looks at the clock, so that
+/// reading the clock costs little next to the operations themselves
+pub const OPS_PER_CLOCK_CHECK: u32 = 256;
+
+/// An allocator along with the blocks allocated by its latest run.
+pub struct SteadyStateDemo<A: BuddyAllocatorApi> {
+    allocator: A,
+    regions: RegionTracker,
+    /// Addresses of the blocks allocated by the latest run, which the next run frees
+    allocated: Vec<usize>,
+    blocks: u32,
+    order: u8,
+}
+
+impl<A: BuddyAllocatorApi> SteadyStateDemo<A> {
+    /// Give `allocator` enough top level blocks, beginning at address 0, for `blocks` blocks of
+    /// `order` to be allocated by each run.
+    pub fn new(mut allocator: A, blocks: u32, order: u8) -> Result<Self, DemoError> {
+        if order > MAX_ORDER {
+            return Err(DemoError::OrderTooLarge {
+                order,
+                max_order: MAX_ORDER,
+            });
+        }
+
+        let mut regions = RegionTracker::new();
+        for block_number in 0..top_level_blocks(blocks, order) {
+            let begin_address = size_of_order(MAX_ORDER) * block_number as usize;
+            allocator.create_top_level(begin_address);
+            regions.add(begin_address, size_of_order(MAX_ORDER));
+        }
+
+        Ok(SteadyStateDemo {
+            allocator,
+            regions,
+            allocated: Vec::with_capacity(blocks as usize),
+            blocks,
+            order,
+        })
+    }
+
+    /// Free the blocks of the run before this one, then allocate the blocks of this run. Only the
+    /// allocations are timed.
+    pub fn run(&mut self, print_addresses: bool) -> Result<Duration, DemoError> {
+        self.free_previous_run();
+
+        let start = Instant::now();
+
+        for allocation in 0..self.blocks {
+            let addr = self.allocator
+                .allocate(self.order)
+                .ok_or(DemoError::OutOfBlocks { allocation })?;
+
+            if cfg!(debug_assertions) {
+                self.regions.assert_valid(addr, size_of_order(self.order));
+            }
+
+            if print_addresses {
+                println!("Address: {:#x}", addr);
+            }
+
+            self.allocated.push(addr);
+        }
+
+        Ok(start.elapsed())
+    }
+
+    /// Free every block allocated by the latest run. Does nothing if they have already been freed.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the allocator does not accept one of its own blocks back.
+    pub fn free_previous_run(&mut self) {
+        for addr in mem::replace(&mut self.allocated, Vec::new()) {
+            assert!(
+                self.allocator.deallocate(addr, self.order),
+                "Block {:#x} could not be freed!",
+                addr
+            );
+        }
+    }
+
+    /// Keep freeing the blocks of the latest run and allocating them again, one operation at a
+    /// time, until `budget` has passed. The clock is only read every [OPS_PER_CLOCK_CHECK]
+    /// operations, so the run may overshoot the budget by that many operations.
+    pub fn run_for(
+        &mut self,
+        print_addresses: bool,
+        budget: Duration,
+    ) -> Result<DurationReport, DemoError> {
+        let start = Instant::now();
+        let mut batch = OpBatch::new(OPS_PER_CLOCK_CHECK);
+        let mut operations = 0;
+
+        let mut out_of_time = |operations: &mut u64| {
+            *operations += 1;
+            batch.tick() && start.elapsed() >= budget
+        };
+
+        loop {
+            while let Some(addr) = self.allocated.pop() {
+                assert!(
+                    self.allocator.deallocate(addr, self.order),
+                    "Block {:#x} could not be freed!",
+                    addr
+                );
+
+                if out_of_time(&mut operations) {
+                    return Ok(DurationReport { operations, elapsed: start.elapsed() });
+                }
+            }
+
+            for allocation in 0..self.blocks {
+                let addr = self.allocator
+                    .allocate(self.order)
+                    .ok_or(DemoError::OutOfBlocks { allocation })?;
+
+                if print_addresses {
+                    println!("Address: {:#x}", addr);
+                }
+                self.allocated.push(addr);
+
+                if out_of_time(&mut operations) {
+                    return Ok(DurationReport { operations, elapsed: start.elapsed() });
+                }
+            }
+        }
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.allocator
+    }
+}
+
+/// Counts operations in batches, so that something expensive is only done once per batch.
+#[derive(Debug, Copy, Clone)]
+struct OpBatch {
+    size: u32,
+    /// Operations left before the end of the current batch
+    left: u32,
+}
+
+impl OpBatch {
+    fn new(size: u32) -> Self {
+        assert!(size > 0, "Batches must not be empty!");
+        OpBatch { size, left: size }
+    }
+
+    /// Count an operation, returning whether it was the last of its batch.
+    fn tick(&mut self) -> bool {
+        self.left -= 1;
+        if self.left == 0 {
+            self.left = self.size;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Run `runs` steady state runs on `allocator`, returning the time taken by each run in order.
+pub fn demo<A: BuddyAllocatorApi>(
+    allocator: A,
+    print_addresses: bool,
+    blocks: u32,
+    order: u8,
+    runs: usize,
+) -> Result<Vec<Duration>, DemoError> {
+    let mut demo = SteadyStateDemo::new(allocator, blocks, order)?;
+    (0..runs).map(|_| demo.run(print_addresses)).collect()
+}
+
+/// Churn through `blocks` blocks on `allocator` until `budget` has passed, as in
+/// [SteadyStateDemo::run_for].
+pub fn demo_for<A: BuddyAllocatorApi>(
+    allocator: A,
+    print_addresses: bool,
+    blocks: u32,
+    order: u8,
+    budget: Duration,
+) -> Result<DurationReport, DemoError> {
+    SteadyStateDemo::new(allocator, blocks, order)?.run_for(print_addresses, budget)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buddy_allocator_bitmap::Forest;
+    use buddy_allocator_lists::{Block, BuddyAllocator};
+    use stats::AllocatorStats;
+
+    /// Run 2 must begin with every block of run 1 freed and merged back into top level blocks.
+    fn check_second_run_starts_freed<A: BuddyAllocatorApi + AllocatorStats>(allocator: A) {
+        // Blocks filling one and a half top level blocks
+        let (blocks, order) = (24, MAX_ORDER - 4);
+        let mut demo = SteadyStateDemo::new(allocator, blocks, order).unwrap();
+        demo.run(false).unwrap();
+        assert_eq!(demo.allocator().usage().outstanding_allocations(), blocks as usize);
+
+        demo.free_previous_run();
+        let stats = demo.allocator();
+        assert_eq!(stats.usage().outstanding_allocations(), 0);
+        assert_eq!(stats.peak_outstanding_allocations(), blocks as usize);
+        let mut expected = [0; ::LEVEL_COUNT as usize];
+        expected[MAX_ORDER as usize] = 2;
+        assert_eq!(stats.free_histogram(), expected);
+
+        // Freeing again does nothing, and the next run can allocate all of its blocks again
+        demo.free_previous_run();
+        demo.run(false).unwrap();
+        demo.run(false).unwrap();
+        assert_eq!(demo.allocator().usage().outstanding_allocations(), blocks as usize);
+    }
+
+    #[test]
+    fn test_second_run_starts_freed() {
+        check_second_run_starts_freed(BuddyAllocator::<Vec<Block>>::new());
+        check_second_run_starts_freed(Forest::new());
+    }
+
+    #[test]
+    fn test_op_batch() {
+        let mut batch = OpBatch::new(4);
+        let ticks: Vec<_> = (0..12).map(|_| batch.tick()).collect();
+        let ends = ticks.iter().enumerate().filter(|&(_, &end)| end).map(|(op, _)| op);
+        assert_eq!(ends.collect::<Vec<_>>(), vec![3, 7, 11]);
+
+        // Every operation is checked with batches of one
+        let mut batch = OpBatch::new(1);
+        assert!((0..5).all(|_| batch.tick()));
+    }
+
+    #[test]
+    fn test_run_for() {
+        let (blocks, order) = (24, MAX_ORDER - 4);
+        let mut demo = SteadyStateDemo::new(BuddyAllocator::<Vec<Block>>::new(), blocks, order)
+            .unwrap();
+
+        // The budget has passed by the first look at the clock, which ends the first batch
+        let report = demo.run_for(false, Duration::from_secs(0)).unwrap();
+        assert_eq!(report.operations, u64::from(OPS_PER_CLOCK_CHECK));
+
+        // Whatever the run ended on, the blocks allocated so far can be freed as usual
+        let report = demo.run_for(false, Duration::from_millis(20)).unwrap();
+        assert!(report.elapsed >= Duration::from_millis(20));
+        assert_eq!(report.operations % u64::from(OPS_PER_CLOCK_CHECK), 0);
+        demo.free_previous_run();
+        assert_eq!(demo.allocator().usage().outstanding_allocations(), 0);
+    }
+
+    #[test]
+    fn test_run_for_out_of_blocks() {
+        // One of the two top level blocks made for the run is taken beforehand
+        let order = MAX_ORDER;
+        let mut demo = SteadyStateDemo::new(Forest::new(), 2, order).unwrap();
+        demo.allocator.allocate(order).unwrap();
+        assert_eq!(
+            demo.run_for(false, Duration::from_secs(1)),
+            Err(DemoError::OutOfBlocks { allocation: 1 })
+        );
+    }
+
+    #[test]
+    fn test_demo_order_too_large() {
+        assert_eq!(
+            demo(Forest::new(), false, 1, MAX_ORDER + 1, 1),
+            Err(DemoError::OrderTooLarge {
+                order: MAX_ORDER + 1,
+                max_order: MAX_ORDER,
+            })
+        );
+    }
+}
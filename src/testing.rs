@@ -0,0 +1,328 @@
+//! Helpers for checking the output of the allocators in tests and demos.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use geometry::{find_overlap, size_of_order};
+use observer::{AllocEvent, AllocObserver};
+use super::{BuddyAllocatorApi, MAX_ORDER};
+
+/// Records every top level region handed to an allocator so that the addresses it returns can be
+/// checked to actually lie inside memory it was given.
+#[derive(Debug, Default)]
+pub struct RegionTracker {
+    /// `(first, last)` byte addresses of each region, inclusive so that a region may end at the
+    /// very top of the address space.
+    regions: Vec<(usize, usize)>,
+}
+
+impl RegionTracker {
+    pub fn new() -> Self {
+        RegionTracker {
+            regions: Vec::new(),
+        }
+    }
+
+    /// Record a region of `size` bytes starting at `begin`.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the region is empty or would wrap around the address space.
+    pub fn add(&mut self, begin: usize, size: usize) {
+        assert_ne!(size, 0, "Region must not be empty!");
+        let last = begin
+            .checked_add(size - 1)
+            .expect("Region must not wrap around the address space!");
+        self.regions.push((begin, last));
+    }
+
+    /// Whether the block `[addr, addr + size)` lies entirely inside one recorded region. Empty
+    /// blocks are never contained.
+    pub fn contains(&self, addr: usize, size: usize) -> bool {
+        if size == 0 {
+            return false;
+        }
+
+        let last = match addr.checked_add(size - 1) {
+            Some(last) => last,
+            None => return false,
+        };
+
+        self.regions
+            .iter()
+            .any(|&(first, region_last)| addr >= first && last <= region_last)
+    }
+
+    /// Assert that the block `[addr, addr + size)` lies inside a recorded region and is naturally
+    /// aligned to its size, which must be a power of two.
+    pub fn assert_valid(&self, addr: usize, size: usize) {
+        assert!(
+            self.contains(addr, size),
+            "Block {:#x} of size {:#x} does not lie in any created region!",
+            addr,
+            size
+        );
+        assert_eq!(
+            addr & (size - 1),
+            0,
+            "Block {:#x} is not aligned to its size {:#x}!",
+            addr,
+            size
+        );
+    }
+}
+
+/// The blocks which are currently allocated, used to check that no two of them overlap. Unlike
+/// comparing addresses this also catches a block overlapping a block of a different order.
+#[derive(Debug, Default)]
+pub struct BlockSet {
+    /// First byte address of each block mapped to its last byte address
+    blocks: BTreeMap<usize, usize>,
+}
+
+impl BlockSet {
+    pub fn new() -> Self {
+        BlockSet {
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    /// Record the block `[addr, addr + size)`. Returns `false` and records nothing if it overlaps a
+    /// block already in the set.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the block is empty or would wrap around the address space.
+    pub fn insert(&mut self, addr: usize, size: usize) -> bool {
+        assert_ne!(size, 0, "Block must not be empty!");
+        let last = addr
+            .checked_add(size - 1)
+            .expect("Block must not wrap around the address space!");
+
+        if find_overlap(&self.blocks, addr, last, |&last| last).is_some() {
+            return false;
+        }
+
+        self.blocks.insert(addr, last);
+        true
+    }
+
+    /// Forget the block beginning at `addr`, returning `false` if no block begins there.
+    pub fn remove(&mut self, addr: usize) -> bool {
+        self.blocks.remove(&addr).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// Create `top_level_blocks` adjacent top level blocks starting at address 0, then make
+/// `allocations` allocations cycling through `orders`, asserting that every block lies in a created
+/// region, is aligned and overlaps no block allocated before it.
+///
+/// # Panicking
+///
+/// Panics if any of the checks fail or if the allocator runs out of blocks.
+pub fn check_unique_addresses<A: BuddyAllocatorApi>(
+    allocator: &mut A,
+    top_level_blocks: usize,
+    orders: &[u8],
+    allocations: usize,
+) {
+    assert!(!orders.is_empty(), "At least one order must be given!");
+
+    let top_level_size = size_of_order(MAX_ORDER);
+    let mut regions = RegionTracker::new();
+    for block_number in 0..top_level_blocks {
+        allocator.create_top_level(top_level_size * block_number);
+        regions.add(top_level_size * block_number, top_level_size);
+    }
+
+    let mut allocated = BlockSet::new();
+    for (allocation, &order) in orders.iter().cycle().take(allocations).enumerate() {
+        let size = size_of_order(order);
+        let addr = allocator.allocate(order).unwrap_or_else(|| {
+            panic!("Allocation {} of order {} failed!", allocation, order)
+        });
+
+        regions.assert_valid(addr, size);
+        assert!(
+            allocated.insert(addr, size),
+            "Block {:#x} of order {} overlaps a block allocated before it!",
+            addr,
+            order
+        );
+    }
+}
+
+/// An observer which records every event into a list shared by all of its clones, so that a test
+/// can keep a clone to read the events after giving one to an allocator.
+#[derive(Debug, Default, Clone)]
+pub struct RecordingObserver {
+    events: Arc<Mutex<Vec<AllocEvent>>>,
+}
+
+impl RecordingObserver {
+    pub fn new() -> Self {
+        RecordingObserver::default()
+    }
+
+    /// Take the events recorded so far, leaving none recorded.
+    pub fn take_events(&self) -> Vec<AllocEvent> {
+        self.events.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl AllocObserver for RecordingObserver {
+    fn on_alloc(&mut self, addr: usize, order: u8) {
+        self.events.lock().unwrap().push(AllocEvent::Alloc { addr, order });
+    }
+
+    fn on_dealloc(&mut self, addr: usize, order: u8) {
+        self.events.lock().unwrap().push(AllocEvent::Dealloc { addr, order });
+    }
+
+    fn on_split(&mut self, addr: usize, order: u8) {
+        self.events.lock().unwrap().push(AllocEvent::Split { addr, order });
+    }
+
+    fn on_merge(&mut self, addr: usize, order: u8) {
+        self.events.lock().unwrap().push(AllocEvent::Merge { addr, order });
+    }
+}
+
+/// A xorshift pseudorandom number generator, so that randomised tests are reproducible without
+/// depending on an external crate.
+#[derive(Debug, Clone)]
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    /// Create a generator from a seed. A seed of 0 is replaced as the generator would only ever
+    /// output 0 otherwise.
+    pub fn new(seed: u64) -> Self {
+        XorShift {
+            state: if seed == 0 { 0x2545_f491_4f6c_dd1d } else { seed },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A number in `0..bound`. `bound` must be non zero.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_region_tracker_edges() {
+        let mut tracker = RegionTracker::new();
+        tracker.add(0x1000, 0x1000);
+
+        assert!(tracker.contains(0x1000, 0x1000));
+        assert!(tracker.contains(0x1000, 1));
+        assert!(tracker.contains(0x1fff, 1));
+        assert!(!tracker.contains(0x2000, 1));
+        assert!(!tracker.contains(0xfff, 1));
+        assert!(!tracker.contains(0xfff, 0x1000));
+        assert!(!tracker.contains(0x1001, 0x1000));
+        assert!(!tracker.contains(0x1000, 0x1001));
+        assert!(!tracker.contains(0x1000, 0));
+    }
+
+    #[test]
+    fn test_region_tracker_does_not_merge_regions() {
+        let mut tracker = RegionTracker::new();
+        tracker.add(0, 0x1000);
+        tracker.add(0x1000, 0x1000);
+        tracker.add(0x4000, 0x1000);
+
+        assert!(tracker.contains(0, 0x1000));
+        assert!(tracker.contains(0x1000, 0x1000));
+        assert!(tracker.contains(0x4000, 0x1000));
+        // Adjacent regions are still separate regions
+        assert!(!tracker.contains(0x800, 0x1000));
+        assert!(!tracker.contains(0x2000, 0x1000));
+    }
+
+    #[test]
+    fn test_region_tracker_end_of_address_space() {
+        let mut tracker = RegionTracker::new();
+        tracker.add(usize::max_value() - 0xfff, 0x1000);
+
+        assert!(tracker.contains(usize::max_value() - 0xfff, 0x1000));
+        assert!(!tracker.contains(usize::max_value() - 0xfff, 0x1001));
+        assert!(!tracker.contains(usize::max_value(), 2));
+    }
+
+    #[test]
+    fn test_block_set_overlaps() {
+        let mut blocks = BlockSet::new();
+        assert!(blocks.insert(0x4000, 0x4000));
+
+        // Blocks of different sizes inside, around and partially over the existing one
+        assert!(!blocks.insert(0x4000, 0x4000));
+        assert!(!blocks.insert(0x5000, 0x1000));
+        assert!(!blocks.insert(0, 0x8000));
+        assert!(!blocks.insert(0x3000, 0x2000));
+        assert!(!blocks.insert(0x7fff, 0x1000));
+
+        // Adjacent blocks do not overlap
+        assert!(blocks.insert(0x3000, 0x1000));
+        assert!(blocks.insert(0x8000, 0x8000));
+        assert_eq!(blocks.len(), 3);
+    }
+
+    /// Hands out a block of order 1 and then the second half of it as a block of order 0, which
+    /// exact address comparison would not notice.
+    struct OverlappingAllocator {
+        allocations: usize,
+    }
+
+    impl BuddyAllocatorApi for OverlappingAllocator {
+        fn create_top_level(&mut self, _begin_address: usize) {}
+
+        fn allocate(&mut self, _order: u8) -> Option<usize> {
+            self.allocations += 1;
+            match self.allocations {
+                1 => Some(0),
+                _ => Some(size_of_order(0)),
+            }
+        }
+
+        fn deallocate(&mut self, _address: usize, _order: u8) -> bool {
+            false
+        }
+
+        fn warm_up(&mut self, _order: u8, _count: usize) {}
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn test_check_unique_addresses_different_orders_overlap() {
+        let mut allocator = OverlappingAllocator { allocations: 0 };
+        check_unique_addresses(&mut allocator, 1, &[1, 0], 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_region_tracker_assert_unaligned() {
+        let mut tracker = RegionTracker::new();
+        tracker.add(0, 0x4000);
+        tracker.assert_valid(0x1000, 0x2000);
+    }
+}
@@ -0,0 +1,377 @@
+//! Randomised workloads of allocations and frees, generated deterministically from a seed so that
+//! demos, benches and tests can share one definition of "a random workload" and replay it exactly.
+//!
+//! A [Workload] only decides which operations happen. It knows nothing of the allocator, so the
+//! same seed gives the same operations whichever allocator they are applied to. A [Driver] applies
+//! them to anything implementing [BuddyAllocatorApi], keeping track of the live blocks.
+
+use testing::XorShift;
+use super::BuddyAllocatorApi;
+
+/// What a [Workload] should generate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkloadSpec {
+    /// How many operations to generate
+    pub total_ops: usize,
+    /// The relative weight of each order, indexed by order. Order `k` is allocated with the
+    /// probability `order_distribution[k] / sum(order_distribution)`.
+    pub order_distribution: Vec<u32>,
+    /// The probability, from 0 to 1, that an operation is a free rather than an allocation
+    pub free_ratio: f64,
+    pub seed: u64,
+}
+
+impl WorkloadSpec {
+    /// A spec allocating every order up to and including `max_order` equally often, and freeing
+    /// once for every two allocations.
+    pub fn uniform(total_ops: usize, max_order: u8, seed: u64) -> Self {
+        WorkloadSpec {
+            total_ops,
+            order_distribution: vec![1; max_order as usize + 1],
+            free_ratio: 1.0 / 3.0,
+            seed,
+        }
+    }
+}
+
+/// One operation of a workload.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Op {
+    /// Allocate a block of the given order
+    Alloc(u8),
+    /// Free a live block. Which block is given by this number modulo the number of live blocks, so
+    /// that the operations do not depend on which allocations succeeded.
+    Free(usize),
+}
+
+/// The operations of a [WorkloadSpec], in order.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    rng: XorShift,
+    remaining: usize,
+    /// The running totals of the order weights, so that an order can be found by its total
+    cumulative_weights: Vec<u64>,
+    free_ratio: f64,
+}
+
+impl Workload {
+    /// # Panicking
+    ///
+    /// Panics if every order has a weight of 0, or if the free ratio is not between 0 and 1.
+    pub fn new(spec: &WorkloadSpec) -> Self {
+        assert!(
+            spec.free_ratio >= 0.0 && spec.free_ratio <= 1.0,
+            "Free ratio {} is not between 0 and 1!",
+            spec.free_ratio
+        );
+
+        let cumulative_weights: Vec<u64> = spec.order_distribution
+            .iter()
+            .scan(0, |total, &weight| {
+                *total += u64::from(weight);
+                Some(*total)
+            })
+            .collect();
+        assert!(
+            cumulative_weights.last().map_or(false, |&total| total > 0),
+            "At least one order must have a weight!"
+        );
+
+        Workload {
+            rng: XorShift::new(spec.seed),
+            remaining: spec.total_ops,
+            cumulative_weights,
+            free_ratio: spec.free_ratio,
+        }
+    }
+
+    /// A number from 0 up to but not including 1
+    fn unit(&mut self) -> f64 {
+        (self.rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Iterator for Workload {
+    type Item = Op;
+
+    fn next(&mut self) -> Option<Op> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        if self.unit() < self.free_ratio {
+            return Some(Op::Free(self.rng.next_u64() as usize));
+        }
+
+        let total = *self.cumulative_weights.last().unwrap();
+        let point = self.rng.below(total);
+        let order = self.cumulative_weights
+            .iter()
+            .position(|&cumulative| point < cumulative)
+            .unwrap();
+
+        Some(Op::Alloc(order as u8))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+/// What applying an [Op] did.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    Allocated { address: usize, order: u8 },
+    /// The allocator had no free block of the order
+    OutOfBlocks { order: u8 },
+    Freed { address: usize, order: u8 },
+    /// A free was skipped as no blocks were live
+    NothingToFree,
+}
+
+/// Applies the operations of a workload to an allocator, keeping track of the live blocks.
+#[derive(Debug, Default, Clone)]
+pub struct Driver {
+    /// The address and order of every live block
+    live: Vec<(usize, u8)>,
+}
+
+impl Driver {
+    pub fn new() -> Self {
+        Driver::default()
+    }
+
+    /// Apply one operation to `allocator`.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the allocator does not accept one of its own blocks back.
+    pub fn apply<A: BuddyAllocatorApi>(&mut self, allocator: &mut A, op: Op) -> Outcome {
+        match op {
+            Op::Alloc(order) => match allocator.allocate(order) {
+                Some(address) => {
+                    self.live.push((address, order));
+                    Outcome::Allocated { address, order }
+                }
+                None => Outcome::OutOfBlocks { order },
+            },
+            Op::Free(_) if self.live.is_empty() => Outcome::NothingToFree,
+            Op::Free(nth) => {
+                let (address, order) = self.live.swap_remove(nth % self.live.len());
+                assert!(
+                    allocator.deallocate(address, order),
+                    "Block {:#x} of order {} could not be freed!",
+                    address,
+                    order
+                );
+
+                Outcome::Freed { address, order }
+            }
+        }
+    }
+
+    /// The address and order of every live block, in no particular order
+    pub fn live(&self) -> &[(usize, u8)] {
+        &self.live
+    }
+}
+
+/// Apply every operation of `spec` to `allocator`, calling `f` with the allocator and the outcome
+/// after each one. Returns the driver, which holds the blocks still live at the end.
+pub fn run_with<A, F>(allocator: &mut A, spec: &WorkloadSpec, mut f: F) -> Driver
+where
+    A: BuddyAllocatorApi,
+    F: FnMut(&mut A, Outcome),
+{
+    let mut driver = Driver::new();
+    for op in Workload::new(spec) {
+        let outcome = driver.apply(allocator, op);
+        f(allocator, outcome);
+    }
+
+    driver
+}
+
+/// Apply every operation of `spec` to `allocator`. Returns the driver, which holds the blocks
+/// still live at the end.
+pub fn run<A: BuddyAllocatorApi>(allocator: &mut A, spec: &WorkloadSpec) -> Driver {
+    run_with(allocator, spec, |_, _| {})
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buddy_allocator_bitmap::Forest;
+    use buddy_allocator_lists::{Block, BuddyAllocator};
+    use buddy_allocator_tree::{self, BlockPtrAdapter};
+    use intrusive_collections::SinglyLinkedList;
+    use std::collections::LinkedList;
+    use stats::AllocatorStats;
+    use {AllocationPolicy, MAX_ORDER, MAX_ORDER_SIZE};
+
+    #[test]
+    fn test_deterministic() {
+        let spec = WorkloadSpec::uniform(1000, 8, 474);
+        let ops: Vec<Op> = Workload::new(&spec).collect();
+        assert_eq!(ops.len(), 1000);
+        assert_eq!(Workload::new(&spec).collect::<Vec<_>>(), ops);
+
+        let other = WorkloadSpec { seed: 475, ..spec };
+        assert_ne!(Workload::new(&other).collect::<Vec<_>>(), ops);
+    }
+
+    #[test]
+    fn test_order_distribution() {
+        let spec = WorkloadSpec {
+            total_ops: 40_000,
+            order_distribution: vec![1, 2, 0, 3, 4],
+            free_ratio: 0.0,
+            seed: 474,
+        };
+
+        let mut counts = [0u32; 5];
+        for op in Workload::new(&spec) {
+            match op {
+                Op::Alloc(order) => counts[order as usize] += 1,
+                Op::Free(_) => panic!("Free generated with a free ratio of 0!"),
+            }
+        }
+        assert_eq!(counts[2], 0);
+
+        // The critical value for 3 degrees of freedom at p = 0.001 is about 16.3
+        let total_weight: u32 = spec.order_distribution.iter().sum();
+        let chi_squared: f64 = counts
+            .iter()
+            .zip(&spec.order_distribution)
+            .filter(|&(_, &weight)| weight > 0)
+            .map(|(&count, &weight)| {
+                let expected = f64::from(weight) / f64::from(total_weight) * 40_000.0;
+                (f64::from(count) - expected).powi(2) / expected
+            })
+            .sum();
+        assert!(chi_squared < 16.3, "Chi squared of {} is too large", chi_squared);
+    }
+
+    #[test]
+    fn test_free_ratio() {
+        let spec = WorkloadSpec {
+            free_ratio: 0.25,
+            ..WorkloadSpec::uniform(40_000, 4, 474)
+        };
+
+        let frees = Workload::new(&spec)
+            .filter(|op| match op {
+                Op::Free(_) => true,
+                Op::Alloc(_) => false,
+            })
+            .count();
+        assert!(frees > 9_500 && frees < 10_500, "{} frees of 40 000 operations", frees);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_no_weights() {
+        Workload::new(&WorkloadSpec {
+            order_distribution: vec![0, 0],
+            ..WorkloadSpec::uniform(1, 1, 474)
+        });
+    }
+
+    #[test]
+    fn test_driver_tracks_live_blocks() {
+        let mut allocator = BuddyAllocator::<Vec<Block>>::new();
+        let mut driver = Driver::new();
+        assert_eq!(driver.apply(&mut allocator, Op::Free(3)), Outcome::NothingToFree);
+        assert_eq!(driver.apply(&mut allocator, Op::Alloc(0)), Outcome::OutOfBlocks { order: 0 });
+
+        allocator.create_top_level(0).unwrap();
+        allocator.create_top_level(1 << MAX_ORDER_SIZE).unwrap();
+        let spec = WorkloadSpec::uniform(2000, 6, 474);
+        let (mut ops, mut outstanding) = (0, 0);
+        let driver = run_with(&mut allocator, &spec, |allocator, outcome| {
+            ops += 1;
+            match outcome {
+                Outcome::Allocated { .. } => outstanding += 1,
+                Outcome::Freed { .. } => outstanding -= 1,
+                Outcome::OutOfBlocks { .. } | Outcome::NothingToFree => {}
+            }
+            assert_eq!(allocator.usage().outstanding_allocations(), outstanding);
+        });
+
+        assert_eq!(ops, 2000);
+        assert_eq!(driver.live().len(), outstanding);
+
+        // The same workload on another allocator does the same
+        let mut other = BuddyAllocator::<Vec<Block>>::new();
+        other.create_top_level(0).unwrap();
+        other.create_top_level(1 << MAX_ORDER_SIZE).unwrap();
+        assert_eq!(run(&mut other, &spec).live(), driver.live());
+    }
+    /// Every outcome of `spec` on `allocator`, given the top level blocks at `bases` in that order
+    fn outcomes<A: BuddyAllocatorApi>(
+        mut allocator: A,
+        bases: &[usize],
+        spec: &WorkloadSpec,
+    ) -> Vec<Outcome> {
+        for &base in bases {
+            allocator.create_top_level(base);
+        }
+
+        let mut outcomes = Vec::with_capacity(spec.total_ops);
+        run_with(&mut allocator, spec, |_, outcome| outcomes.push(outcome));
+        outcomes
+    }
+
+    #[test]
+    fn test_deterministic_policy_agrees() {
+        // The regions are not given in address order, so the first region is not the lowest
+        let bases = [2 << MAX_ORDER_SIZE, 0, 1 << MAX_ORDER_SIZE];
+        let specs = [
+            WorkloadSpec::uniform(1500, 10, 493),
+            WorkloadSpec {
+                total_ops: 1500,
+                order_distribution: vec![8, 4, 2, 1],
+                free_ratio: 0.45,
+                seed: 494,
+            },
+            // Large blocks run out, so allocations fail part of the time
+            WorkloadSpec::uniform(300, MAX_ORDER, 495),
+        ];
+
+        for spec in &specs {
+            let mut lists = BuddyAllocator::<Vec<Block>>::new();
+            lists.set_policy(AllocationPolicy::Deterministic);
+            let expected = outcomes(lists, &bases, spec);
+
+            // The lowest block of the lowest region is allocated first
+            let first = expected
+                .iter()
+                .filter_map(|outcome| match *outcome {
+                    Outcome::Allocated { address, .. } => Some(address),
+                    _ => None,
+                })
+                .next();
+            assert_eq!(first, Some(0));
+
+            let mut linked_lists = BuddyAllocator::<LinkedList<Block>>::new();
+            linked_lists.set_policy(AllocationPolicy::Deterministic);
+            assert_eq!(outcomes(linked_lists, &bases, spec), expected, "{:?}", spec);
+
+            let mut rb_tree = buddy_allocator_tree::BuddyAllocator::<
+                Vec<*const buddy_allocator_tree::Block>,
+            >::new();
+            rb_tree.set_policy(AllocationPolicy::Deterministic);
+            assert_eq!(outcomes(rb_tree, &bases, spec), expected, "{:?}", spec);
+
+            let mut rb_tree_linked_lists =
+                buddy_allocator_tree::BuddyAllocator::<SinglyLinkedList<BlockPtrAdapter>>::new();
+            rb_tree_linked_lists.set_policy(AllocationPolicy::Deterministic);
+            assert_eq!(outcomes(rb_tree_linked_lists, &bases, spec), expected, "{:?}", spec);
+
+            let mut forest = Forest::new();
+            forest.set_policy(AllocationPolicy::Deterministic);
+            assert_eq!(outcomes(forest, &bases, spec), expected, "{:?}", spec);
+        }
+    }
+}